@@ -0,0 +1,47 @@
+#![forbid(unsafe_code)]
+
+//! Time the compiled code on the benchmark modules.
+//!
+//! ```
+//! cargo run --release --example bench [RUNS]
+//! ```
+
+use std::time::{Duration, Instant};
+
+use s1vm::*;
+
+/// `(file, function, argument)`
+const BENCHES: &[(&str, &str, i32)] = &[
+  ("fib.wasm", "fib", 32),
+  ("for_loop.wasm", "for_loop", 100_000_000),
+  ("locals.wasm", "locals", 100_000_000),
+];
+
+fn main() -> Result<(), Error> {
+  let runs = match std::env::args().nth(1) {
+    Some(runs) => runs.parse().expect("invalid number of runs"),
+    None => 5,
+  };
+
+  for (file, func, arg) in BENCHES {
+    let path = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), file);
+    let mut vm = VM::new();
+    vm.load_file("main", &path)?;
+
+    let mut times = Vec::with_capacity(runs);
+    for _ in 0..runs {
+      let start = Instant::now();
+      vm.call("main", func, &[Value::I32(*arg)])?;
+      times.push(start.elapsed());
+    }
+    times.sort();
+    let median = times.get(runs / 2).copied().unwrap_or_default();
+    let min = times.first().copied().unwrap_or_default();
+    println!("{:<14} {:<9} {:>10}  min {}  median {}", file, func, arg, secs(min), secs(median));
+  }
+  Ok(())
+}
+
+fn secs(time: Duration) -> String {
+  format!("{:.3}s", time.as_secs_f64())
+}
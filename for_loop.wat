@@ -6,18 +6,18 @@
   (block $label$0
    (br_if $label$0
     (i32.lt_s
-     (get_local $0)
+     (local.get $0)
      (i32.const 1)
     )
    )
-   (set_local $1
-    (get_local $0)
+   (local.set $1
+    (local.get $0)
    )
    (loop $label$1
     (br_if $label$1
-     (tee_local $1
+     (local.tee $1
       (i32.add
-       (get_local $1)
+       (local.get $1)
        (i32.const -1)
       )
      )
@@ -26,7 +26,7 @@
   )
   (return
     (i32.add
-     (get_local $0)
+     (local.get $0)
      (i32.const 42)
     )
   )
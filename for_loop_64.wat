@@ -6,20 +6,20 @@
   (block $label$0
    (br_if $label$0
     (i64.lt_s
-     (get_local $0)
+     (local.get $0)
      (i64.const 1)
     )
    )
-   (set_local $1
-    (get_local $0)
+   (local.set $1
+    (local.get $0)
    )
    (loop $label$1
     (br_if $label$1
      (i32.eqz
       (i64.eqz
-       (tee_local $1
+       (local.tee $1
         (i64.add
-         (get_local $1)
+         (local.get $1)
          (i64.const -1)
         )
        )
@@ -30,7 +30,7 @@
   )
   (return
     (i64.add
-     (get_local $0)
+     (local.get $0)
      (i64.const 42)
     )
   )
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Loading and compiling a module must return an error for invalid input,
+// never panic.
+fuzz_target!(|data: &[u8]| {
+  let _ = s1vm::Module::from_bytes(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Parsing and compiling the text format must return an error for invalid
+// input, never panic.
+fuzz_target!(|data: &[u8]| {
+  if let Ok(text) = std::str::from_utf8(data) {
+    let _ = s1vm::Module::from_wat(text);
+  }
+});
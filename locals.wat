@@ -0,0 +1,58 @@
+(module
+ (type (;0;) (func (param i32) (result i32)))
+ (export "locals" (func $locals))
+ ;; The loop uses five locals, the locals before them are only used once.
+ (func $locals (type 0) (param $n i32) (result i32)
+  (local $x i32)
+  (local $y i32)
+  (local $z i32)
+  (local $i i32)
+  (local $a i32)
+  (local $b i32)
+  (local $c i32)
+  (local.set $x (i32.const 1))
+  (local.set $y (i32.const 2))
+  (local.set $z (i32.const 3))
+  (loop $loop
+   (local.set $a
+    (i32.add
+     (local.get $a)
+     (local.get $i)
+    )
+   )
+   (local.set $b
+    (i32.xor
+     (local.get $b)
+     (local.get $a)
+    )
+   )
+   (local.set $c
+    (i32.add
+     (local.get $c)
+     (local.get $b)
+    )
+   )
+   (br_if $loop
+    (i32.lt_u
+     (local.tee $i
+      (i32.add
+       (local.get $i)
+       (i32.const 1)
+      )
+     )
+     (local.get $n)
+    )
+   )
+  )
+  (i32.add
+   (local.get $c)
+   (i32.add
+    (local.get $x)
+    (i32.add
+     (local.get $y)
+     (local.get $z)
+    )
+   )
+  )
+ )
+)
@@ -3,33 +3,74 @@
 use s1vm::*;
 
 fn main() -> Result<(), Error> {
-  let mut args = std::env::args();
+  let mut args = std::env::args().peekable();
   args.next(); // skip program name.
-  let file = args.next().expect("missing file name");
-  let func = args.next().expect("missing function name");
-  let params: Vec<Value> = args.map(|x| {
-    match x.parse::<i64>() {
-      Ok(v) => Value::I64(v),
-      Err(e) => {
-        eprintln!("failed to parse '{}': {}", x, e);
-        Value::I64(0)
+
+  #[cfg(feature = "wasi")]
+  let mut opts = WasiOptions::default();
+  #[cfg(feature = "wasi")]
+  loop {
+    match args.peek().map(String::as_str) {
+      Some("--dir") => {
+        args.next();
+        opts.dirs.push(args.next().expect("missing directory for '--dir'"));
       },
+      Some("--env") => {
+        args.next();
+        opts.envs.push(args.next().expect("missing variable for '--env'"));
+      },
+      Some("--inherit-env") => {
+        args.next();
+        opts.inherit_env = true;
+      },
+      _ => break,
     }
-  }).collect();
+  }
 
-  //println!("Type sizes:");
-  //println!("isa::Instruction = {}", std::mem::size_of::<Instruction>());
-  //println!("bwasm::Instruction = {}", std::mem::size_of::<bwasm::Instruction>());
+  let file = args.next().expect("missing file name");
 
   // Create VM.
   let mut vm = VM::new();
+  #[cfg(feature = "wasi")]
+  wasi::add_to_imports(vm.imports_mut());
 
   // Load wasm file
-  //println!("--- Loading module: {}", file);
-  vm.load_file("main", &file)?;
+  load_module(&mut vm, &file)?;
+
+  // Run WASI commands.
+  #[cfg(feature = "wasi")]
+  if vm.get_exported("main", "_start").is_ok() {
+    return run_wasi(vm, &file, &opts, args);
+  }
+
+  let func = args.next().expect("missing function name");
+  let func_addr = vm.get_exported("main", &func)?;
+  let func_type = vm.get_module_instance("main")?
+    .state.get_function(func_addr)?
+    .func_type.clone();
+  let params: Vec<Value> = args.zip(func_type.params.iter()).map(|(x, val_type)| {
+    let val = match val_type {
+      ValueType::I32 => x.parse::<i32>().map(Value::I32).map_err(|e| e.to_string()),
+      ValueType::I64 => x.parse::<i64>().map(Value::I64).map_err(|e| e.to_string()),
+      ValueType::F32 => x.parse::<f32>().map(Value::F32).map_err(|e| e.to_string()),
+      ValueType::F64 => x.parse::<f64>().map(Value::F64).map_err(|e| e.to_string()),
+      ValueType::V128 => x.strip_prefix("0x")
+        .map_or_else(|| x.parse::<u128>(), |hex| u128::from_str_radix(hex, 16))
+        .map(Value::V128).map_err(|e| e.to_string()),
+      ValueType::FuncRef | ValueType::ExternRef | ValueType::ExnRef => {
+        Err("references can't be passed as arguments".to_string())
+      },
+    };
+    match val {
+      Ok(v) => v,
+      Err(e) => {
+        eprintln!("failed to parse '{}': {}", x, e);
+        StackValue(0).to_value(*val_type)
+      },
+    }
+  }).collect();
 
   // Call module function
-  //println!("Calling:  {}({:?})", func, params);
   let ret = vm.call("main", &func, &params)?;
   if let Some(ret) = ret {
     println!("{}", ret);
@@ -40,3 +81,71 @@ fn main() -> Result<(), Error> {
   Ok(())
 }
 
+/// Command line options for WASI programs.
+#[cfg(feature = "wasi")]
+#[derive(Default)]
+struct WasiOptions {
+  /// Host directories the program can access: `--dir HOST[::GUEST]`
+  dirs: Vec<String>,
+  /// Environment variables of the program: `--env KEY=VAL`
+  envs: Vec<String>,
+  /// Pass the host's environment to the program: `--inherit-env`
+  inherit_env: bool,
+}
+
+/// Load the module, files ending in `.wat` are loaded as text with the
+/// `wat` feature.
+fn load_module(vm: &mut VM, file: &str) -> Result<(), Error> {
+  #[cfg(feature = "wat")]
+  if file.ends_with(".wat") {
+    let text = std::fs::read_to_string(file).expect("failed to read file");
+    return vm.load_wat("main", &text).map(|_| ());
+  }
+  vm.load_file("main", file).map(|_| ())
+}
+
+#[cfg(feature = "wasi")]
+fn run_wasi(mut vm: VM, file: &str, opts: &WasiOptions, args: impl Iterator<Item = String>) -> Result<(), Error> {
+  let mut ctx = wasi::WasiCtx::new();
+  ctx.push_arg(file);
+  for arg in args {
+    ctx.push_arg(&arg);
+  }
+  // The program only sees the host's environment when asked, `--env`
+  // overrides inherited variables.
+  let mut envs: Vec<(String, String)> = if opts.inherit_env {
+    std::env::vars().collect()
+  } else {
+    Vec::new()
+  };
+  for env in opts.envs.iter() {
+    let (key, value) = match env.split_once('=') {
+      Some(var) => var,
+      None => {
+        eprintln!("invalid variable '{}', expected KEY=VAL", env);
+        std::process::exit(1);
+      },
+    };
+    envs.retain(|(k, _)| k != key);
+    envs.push((key.to_string(), value.to_string()));
+  }
+  for (key, value) in envs.iter() {
+    ctx.push_env(key, value);
+  }
+  for dir in opts.dirs.iter() {
+    let (host, guest) = dir.split_once("::").unwrap_or((dir, dir));
+    if let Err(e) = ctx.preopen_dir(host, guest) {
+      eprintln!("failed to open directory '{}': {}", host, e);
+      std::process::exit(1);
+    }
+  }
+  vm.get_module_instance("main")?.store.insert_data(ctx);
+
+  match vm.call("main", "_start", &[]) {
+    Ok(_) => Ok(()),
+    Err(e) => match e.exit_code() {
+      Some(code) => std::process::exit(code),
+      None => Err(e),
+    },
+  }
+}
@@ -3,12 +3,27 @@ use crate::*;
 use crate::function::*;
 use crate::error::*;
 
+/// Maximum depth of nested blocks in a function.
+pub(crate) const MAX_BLOCK_DEPTH: u32 = 512;
+
+/// Maximum nesting of lazily evaluated ops.  Nested ops call each other
+/// recursively, deeper ops are spilled to their operand slots.
+const MAX_OP_DEPTH: u32 = 64;
+
+/// Exception handlers are identified by the instruction index of their `try` or
+/// `try_table`.  Exceptions delegated to this handler leave the function.
+const CALLER_HANDLER: u32 = u32::MAX;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum BlockKind {
   Block,
   Loop,
   If,
   Else,
+  /// The body of a legacy `try` block.
+  Try,
+  /// A legacy `catch` or `catch_all` block.
+  Catch,
 }
 
 #[derive(Debug, Clone)]
@@ -16,59 +31,168 @@ pub enum Action {
   Return(Option<StackValue>),
   End,
   Branch(u32),
+  /// Return from the function and call another function in its place.
+  /// The parameters are passed like a normal call.
+  TailCall(FuncAddr),
 }
 
 type Local = u32;
 
-type EvalFunc = Box<dyn Fn(&vm::State, &mut Store, &mut StackValue) -> Trap<Action>>;
+/// An operand value saved on the stack, relative to the frame's stack base pointer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Slot(u32);
+
+type EvalFunc = Box<dyn Fn(&vm::State, &mut Store, &mut Registers) -> Trap<Action> + Send + Sync>;
 
-type OpFunc = Box<dyn Fn(&vm::State, &mut Store, &mut StackValue) -> Trap<StackValue>>;
+type OpFunc = Box<dyn Fn(&vm::State, &mut Store, &mut Registers) -> Trap<StackValue> + Send + Sync>;
 
 enum Input {
   Local(Local),
   Const(StackValue),
+  Stack(Slot),
   Op(OpFunc),
 }
 
 impl Input {
-  pub fn resolv(&self, state: &vm::State, store: &mut Store, l0: &mut StackValue) -> Trap<StackValue> {
+  pub fn resolv(&self, state: &vm::State, store: &mut Store, regs: &mut Registers) -> Trap<StackValue> {
     match self {
       Input::Local(0) => {
-        Ok(*l0)
+        Ok(regs.0[0])
       },
       Input::Local(local_idx) => {
-        Ok(store.stack.get_local_val(*local_idx, l0))
+        Ok(store.stack.get_local_val(*local_idx, regs))
       },
       Input::Const(const_val) => {
         Ok(*const_val)
       },
-      Input::Op(closure) => closure(state, store, l0),
+      Input::Stack(slot) => {
+        Ok(store.stack.get_operand(slot.0))
+      },
+      Input::Op(closure) => closure(state, store, regs),
+    }
+  }
+
+  /// Copy the input, only possible if it is not an `Op`.
+  fn try_copy(&self) -> Option<Input> {
+    match self {
+      Input::Local(local_idx) => Some(Input::Local(*local_idx)),
+      Input::Const(const_val) => Some(Input::Const(*const_val)),
+      Input::Stack(slot) => Some(Input::Stack(*slot)),
+      Input::Op(_) => None,
     }
   }
 }
 
 pub trait Eval {
-  fn eval(&self, state: &vm::State, store: &mut Store, l0: &mut StackValue) -> Trap<StackValue>;
+  fn eval(&self, state: &vm::State, store: &mut Store, regs: &mut Registers) -> Trap<StackValue>;
 }
 
 impl Eval for Local {
-  fn eval(&self, _state: &vm::State, store: &mut Store, l0: &mut StackValue) -> Trap<StackValue> {
-    Ok(store.stack.get_local_val(*self, l0))
+  fn eval(&self, _state: &vm::State, store: &mut Store, regs: &mut Registers) -> Trap<StackValue> {
+    Ok(store.stack.get_local_val(*self, regs))
   }
 }
 
 impl Eval for StackValue {
-  fn eval(&self, _state: &vm::State, _store: &mut Store, _l0: &mut StackValue) -> Trap<StackValue> {
+  fn eval(&self, _state: &vm::State, _store: &mut Store, _regs: &mut Registers) -> Trap<StackValue> {
     Ok(*self)
   }
 }
 
+impl Eval for Slot {
+  fn eval(&self, _state: &vm::State, store: &mut Store, _regs: &mut Registers) -> Trap<StackValue> {
+    Ok(store.stack.get_operand(self.0))
+  }
+}
+
 impl Eval for OpFunc {
-  fn eval(&self, state: &vm::State, store: &mut Store, l0: &mut StackValue) -> Trap<StackValue> {
-    self(state, store, l0)
+  fn eval(&self, state: &vm::State, store: &mut Store, regs: &mut Registers) -> Trap<StackValue> {
+    self(state, store, regs)
   }
 }
 
+macro_rules! impl_ops_match_input {
+  ($name:ident, $op:expr) => {
+    {
+      match $name {
+        Input::Local($name) => {
+          $op
+        },
+        Input::Const($name) => {
+          $op
+        },
+        Input::Stack($name) => {
+          $op
+        },
+        Input::Op($name) => {
+          $op
+        }
+      }
+    }
+  };
+}
+
+macro_rules! impl_unops_match_input {
+  ($state:ident, $left:ident, $op:expr) => {
+    {
+      let left = $state.pop()?;
+      $state.push(Input::Op(impl_ops_match_input!(left, {
+        Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+          let $left = left.eval(state, store, regs)?.0;
+          let res = $op;
+          Ok(StackValue(res as _))
+        })
+      })));
+    }
+  };
+  ($state:ident, $vm_state:ident, $store:ident, $regs:ident, $left:ident, $op:expr) => {
+    {
+      let left = $state.pop()?;
+      $state.push(Input::Op(impl_ops_match_input!(left, {
+        Box::new(move |$vm_state: &vm::State, $store: &mut Store, $regs: &mut Registers| -> Trap<StackValue> {
+          let $left = left.eval($vm_state, $store, $regs)?.0;
+          let res = $op;
+          Ok(StackValue(res as _))
+        })
+      })));
+    }
+  };
+}
+
+macro_rules! impl_binops_match_input {
+  ($state:ident, $left:ident, $right:ident, $op:expr) => {
+    {
+      let right = $state.pop()?;
+      let left = $state.pop()?;
+      $state.push(Input::Op(impl_ops_match_input!(left, {
+        impl_ops_match_input!(right, {
+          Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+            let $left = left.eval(state, store, regs)?.0;
+            let $right = right.eval(state, store, regs)?.0;
+            let res = $op;
+            Ok(StackValue(res as _))
+          })
+        })
+      })));
+    }
+  };  ($state:ident, $vm_state:ident, $store:ident, $regs:ident, $left:ident, $right:ident, $op:expr) => {
+    {
+      let right = $state.pop()?;
+      let left = $state.pop()?;
+      $state.push(Input::Op(impl_ops_match_input!(left, {
+        impl_ops_match_input!(right, {
+          Box::new(move |$vm_state: &vm::State, $store: &mut Store, $regs: &mut Registers| -> Trap<StackValue> {
+            let $left = left.eval($vm_state, $store, $regs)?.0;
+            let $right = right.eval($vm_state, $store, $regs)?.0;
+            let res = $op;
+            Ok(StackValue(res as _))
+          })
+        })
+      })));
+    }
+  };
+}
+
 struct Block
 {
   kind: BlockKind,
@@ -93,14 +217,12 @@ impl Block {
     self.eval.push(f);
   }
 
-  pub fn run(&self, state: &vm::State, store: &mut Store, _l0: &mut StackValue) -> Trap<Action> {
-    //eprintln!("---- run block: {:?}, len={}, depth={}", self.kind, self.eval.len(), self.depth);
+  pub fn run(&self, state: &vm::State, store: &mut Store, _regs: &mut Registers) -> Trap<Action> {
     'repeat: loop {
       for f in self.eval.iter() {
-        let ret = f(state, store, _l0)?;
-        //eprintln!("---- evaled: ret = {:?}", ret);
+        let ret = f(state, store, _regs)?;
         match ret {
-          Action::Return(_) => {
+          Action::Return(_) | Action::TailCall(_) => {
             // Keep passing return value up, until we get to the function block.
             return Ok(ret);
           },
@@ -109,7 +231,6 @@ impl Block {
             continue;
           },
           Action::Branch(depth) => {
-            //eprintln!("---- Branch({})", depth);
             if depth > 0 {
               // keep passing action lower.
               return Ok(Action::Branch(depth-1));
@@ -132,121 +253,425 @@ impl Block {
   }
 }
 
+/// Branch target of a block.
+#[derive(Debug, Clone, Copy)]
+struct Label {
+  /// Height of the value stack at the start of the block.  Block results
+  /// are saved in the operand slot at this height.
+  height: u32,
+  /// Number of values passed by a branch to this label.
+  arity: u32,
+  kind: BlockKind,
+  /// The exception handler of the code in the block.
+  handler: u32,
+}
+
+/// Which pending inputs to spill.
+#[derive(Debug, Clone, Copy)]
+enum Spill {
+  /// Only spill operations.
+  Ops,
+  /// Spill operations and reads of a local that is about to be changed.
+  Local(Local),
+  /// Spill operations and all local reads.
+  All,
+}
+
+impl Spill {
+  fn needed(&self, input: &Input) -> bool {
+    match (input, self) {
+      (Input::Op(_), _) => true,
+      (Input::Local(_), Spill::All) => true,
+      (Input::Local(idx), Spill::Local(set_idx)) => idx == set_idx,
+      _ => false,
+    }
+  }
+}
+
 pub struct State {
   values: Vec<Input>,
+  /// How deep the ops of each value are nested, 0 if it isn't an op.
+  op_depths: Vec<u32>,
+  /// The value is the high half of a `v128`, its low half is below it.
+  highs: Vec<bool>,
+  /// The deepest op popped since the last push, it is an input of the next op.
+  popped_depth: u32,
+  labels: Vec<Label>,
+  /// Number of operand slots used by the function.
+  max_height: u32,
+  /// The rest of the current block is unreachable.
+  unreachable: bool,
+  /// Mask of the address bits of each memory, see `memory::address_mask`.
+  address_masks: Vec<u64>,
+  /// The index each local is stored at, see `hot_locals`.  A `v128` local
+  /// also has the index of its high half.
+  locals: Vec<(Local, Option<Local>)>,
   pub depth: u32,
   pub pc: usize,
 }
 
 impl State {
-  pub fn new() -> Self {
+  pub fn new(address_masks: Vec<u64>, locals: Vec<(Local, Option<Local>)>) -> Self {
     Self {
       values: vec![],
+      op_depths: vec![],
+      highs: vec![],
+      popped_depth: 0,
+      labels: vec![],
+      max_height: 0,
+      unreachable: false,
+      address_masks,
+      locals,
       depth: 0,
       pc: 0,
     }
   }
 
+  fn address_mask(&self, memory: u32) -> u64 {
+    self.address_masks[memory as usize]
+  }
+
+  /// The index `local` is stored at.
+  fn local(&self, local: Local) -> Local {
+    self.locals[local as usize].0
+  }
+
+  /// The index of the high half of a `v128` local, `None` for other types.
+  fn local_high(&self, local: Local) -> Option<Local> {
+    self.locals[local as usize].1
+  }
+
   fn pop(&mut self) -> Result<Input> {
+    self.highs.pop();
+    let depth = self.op_depths.pop().unwrap_or(0);
+    self.popped_depth = self.popped_depth.max(depth);
     self.values.pop()
       .ok_or_else(|| {
-        Error::ValidationError(format!("Value stack empty"))
+        Error::ValidationError("Value stack empty".to_string())
       })
   }
 
   fn pop_n(&mut self, n: usize) -> Result<Vec<Input>> {
     let at = self.values.len().checked_sub(n)
       .ok_or_else(|| {
-        Error::ValidationError(format!("Value stack empty"))
+        Error::ValidationError("Value stack empty".to_string())
       })?;
+    self.highs.truncate(at);
+    let depth = self.op_depths.split_off(at).into_iter().max().unwrap_or(0);
+    self.popped_depth = self.popped_depth.max(depth);
     Ok(self.values.split_off(at))
   }
 
   fn push(&mut self, input: Input) {
+    let depth = match input {
+      Input::Op(_) => self.popped_depth + 1,
+      _ => 0,
+    };
+    self.popped_depth = 0;
+    self.op_depths.push(depth);
+    self.highs.push(false);
     self.values.push(input);
   }
 
+  /// Push the two halves of a `v128`.
+  fn push_v128(&mut self, low: Input, high: Input) {
+    self.push(low);
+    self.push(high);
+    if let Some(high) = self.highs.last_mut() {
+      *high = true;
+    }
+  }
+
+  /// Pop a value, with the low half of a `v128`.
+  fn pop_value(&mut self) -> Result<Vec<Input>> {
+    let n = if self.is_v128(0) { 2 } else { 1 };
+    self.pop_n(n)
+  }
+
+  /// The value `depth` values below the top of the stack is the high half
+  /// of a `v128`.
+  fn is_v128(&self, depth: usize) -> bool {
+    self.highs.len().checked_sub(depth + 1).is_some_and(|idx| self.highs[idx])
+  }
+
+  /// Drop the values above `height`.
+  fn truncate(&mut self, height: usize) {
+    self.values.truncate(height);
+    self.op_depths.truncate(height);
+    self.highs.truncate(height);
+  }
+
+  /// The ops on top of the stack are nested too deep.
+  fn is_too_deep(&self) -> bool {
+    self.op_depths.last().is_some_and(|depth| *depth > MAX_OP_DEPTH)
+  }
+
   fn len(&self) -> usize {
     self.values.len()
   }
-}
 
-macro_rules! impl_ops_match_input {
-  ($name:ident, $op:expr) => {
-    {
-      match $name {
-        Input::Local($name) => {
-          $op
-        },
-        Input::Const($name) => {
-          $op
-        },
-        Input::Op($name) => {
-          $op
-        }
-      }
+  fn label(&self, depth: u32) -> Result<Label> {
+    (self.labels.len() as u32).checked_sub(depth)
+      .and_then(|len| len.checked_sub(1))
+      .and_then(|idx| self.labels.get(idx as usize))
+      .copied()
+      .ok_or_else(|| {
+        Error::ValidationError(format!("Invalid branch depth: {}", depth))
+      })
+  }
+
+  /// The exception handler of the current block.
+  fn handler(&self) -> u32 {
+    self.labels.last().map_or(CALLER_HANDLER, |label| label.handler)
+  }
+
+  /// The label at `depth` is the function's block, a branch to it returns
+  /// from the function.
+  fn is_function_label(&self, depth: u32) -> bool {
+    depth as usize + 1 == self.labels.len()
+  }
+
+  fn update_max_height(&mut self) {
+    self.max_height = self.max_height.max(self.values.len() as u32);
+  }
+
+  /// Push the result of a block, it was saved in the operand slots at
+  /// `height`.  Blocks have one result, two slots are a `v128`.
+  fn push_results(&mut self, height: usize, arity: u32) {
+    self.truncate(height);
+    self.push_slots(height as u32, arity);
+  }
+
+  /// Push the `count` values saved in the operand slots at `slot`, two are
+  /// a `v128`.
+  fn push_slots(&mut self, slot: u32, count: u32) {
+    match count {
+      0 => (),
+      2 => self.push_v128(Input::Stack(Slot(slot)), Input::Stack(Slot(slot + 1))),
+      _ => self.push(Input::Stack(Slot(slot))),
     }
-  };
-}
+    self.update_max_height();
+  }
 
-macro_rules! impl_unops_match_input {
-  ($state:ident, $left:ident, $op:expr) => {
-    {
-      let left = $state.pop()?;
-      $state.push(Input::Op(impl_ops_match_input!(left, {
-        Box::new(move |state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<StackValue> {
-          let $left = left.eval(state, store, l0)?.0;
-          let res = $op;
-          Ok(StackValue(res as _))
-        })
-      })));
+  /// Copy the `arity` values of a branch, they stay on the stack when the
+  /// branch isn't taken.  Pending inputs must be spilled.
+  fn copy_results(&self, arity: u32) -> Result<Vec<Input>> {
+    let at = self.values.len().checked_sub(arity as usize)
+      .ok_or_else(|| Error::ValidationError("Value stack empty".to_string()))?;
+    self.values[at..].iter().map(|ret| ret.try_copy())
+      .collect::<Option<Vec<_>>>()
+      .ok_or_else(|| Error::ValidationError("Value stack empty".to_string()))
+  }
+
+  /// Evaluate pending inputs and save them in their operand slots.
+  ///
+  /// Inputs are evaluated lazily by the opcode that uses them.  This must be
+  /// called before emitting an opcode with side-effects, to keep the order
+  /// of evaluation.
+  fn spill(&mut self, block: &mut Block, mode: Spill) {
+    let start = match self.values.iter().position(|input| mode.needed(input)) {
+      Some(start) => start,
+      None => return,
+    };
+    let mut inputs = self.values.split_off(start);
+    self.op_depths.truncate(start);
+    for (idx, input) in inputs.iter().enumerate() {
+      self.values.push(match input {
+        Input::Const(const_val) => Input::Const(*const_val),
+        _ => Input::Stack(Slot((start + idx) as u32)),
+      });
+      self.op_depths.push(0);
     }
-  };
-  ($state:ident, $vm_state:ident, $store:ident, $l0:ident, $left:ident, $op:expr) => {
-    {
-      let left = $state.pop()?;
-      $state.push(Input::Op(impl_ops_match_input!(left, {
-        Box::new(move |$vm_state: &vm::State, $store: &mut Store, $l0: &mut StackValue| -> Trap<StackValue> {
-          let $left = left.eval($vm_state, $store, $l0)?.0;
-          let res = $op;
-          Ok(StackValue(res as _))
+    self.update_max_height();
+
+    let slot = start as u32;
+    let single = if inputs.len() == 1 { inputs.pop() } else { None };
+    if let Some(input) = single {
+      block.push(impl_ops_match_input!(input, {
+        Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+          let val = input.eval(state, store, regs)?;
+          store.stack.set_operand(slot, val)?;
+          Ok(Action::End)
         })
-      })));
+      }));
+    } else {
+      block.push(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+        let vals = inputs.iter().map(|input| {
+          input.resolv(state, store, regs)
+        }).collect::<Trap<Vec<_>>>()?;
+        store.stack.set_operands(slot, &vals)?;
+        Ok(Action::End)
+      }));
     }
-  };
+  }
 }
 
-macro_rules! impl_binops_match_input {
-  ($state:ident, $left:ident, $right:ident, $op:expr) => {
-    {
-      let right = $state.pop()?;
-      let left = $state.pop()?;
-      $state.push(Input::Op(impl_ops_match_input!(left, {
-        impl_ops_match_input!(right, {
-          Box::new(move |state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<StackValue> {
-            let $left = left.eval(state, store, l0)?.0;
-            let $right = right.eval(state, store, l0)?.0;
-            let res = $op;
-            Ok(StackValue(res as _))
-          })
-        })
-      })));
+/// Call a function with the first parameter in register 0 of its registers.
+/// The caller's registers are also passed for inputs that need to be
+/// evaluated before the call.
+trait CallFunc: Fn(&vm::State, &mut Store, &mut Registers, &mut Registers) -> Trap<Option<StackValue>> + Send + Sync + 'static {}
+
+impl<F> CallFunc for F
+  where F: Fn(&vm::State, &mut Store, &mut Registers, &mut Registers) -> Trap<Option<StackValue>> + Send + Sync + 'static
+{}
+
+/// Resolve the values of a block result, a `v128` takes two slots.  Only
+/// the first `rets.len()` values are set.
+fn resolv_results(rets: &[Input], state: &vm::State, store: &mut Store, regs: &mut Registers) -> Trap<[StackValue; 2]> {
+  let mut vals = [StackValue(0); 2];
+  for (val, ret) in vals.iter_mut().zip(rets) {
+    *val = ret.resolv(state, store, regs)?;
+  }
+  Ok(vals)
+}
+
+/// Return the function's result, the high half of a `v128` is saved in
+/// `Stack::ret_high`.
+fn return_results(store: &mut Store, vals: &[StackValue]) -> Action {
+  if let Some(high) = vals.get(1) {
+    store.stack.set_ret_high(*high);
+  }
+  Action::Return(vals.first().copied())
+}
+
+/// Get the function at `idx` in a table for `call_indirect`, checking its type.
+fn indirect_func(vm_state: &vm::State, store: &Store, table_idx: usize, idx: u32, func_type: &FunctionType) -> Trap<FuncAddr> {
+  let func_addr = store.tables[table_idx].get(idx as usize)
+    .ok_or(TrapKind::TableAccessOutOfBounds)?
+    .ok_or(TrapKind::ElemUninitialized)?;
+  if vm_state.get_function(func_addr)?.func_type != *func_type {
+    return Err(TrapKind::UnexpectedSignature);
+  }
+  Ok(func_addr)
+}
+
+/// Check if a handler for `tag` catches an exception, `None` catches all
+/// exceptions.
+fn is_caught(vm_state: &vm::State, tag: Option<u32>, exn: &Exception) -> bool {
+  match tag {
+    Some(tag) => exn.is(vm_state.tag(tag)),
+    None => true,
+  }
+}
+
+/// A handler of a `try_table`, resolved to the branch it takes.
+struct CatchHandler {
+  tag: Option<u32>,
+  /// Pass the exception as an `exnref`.
+  is_ref: bool,
+  depth: u32,
+  /// The operand slot of the label's result.
+  height: u32,
+  /// The label is the function's block.
+  is_return: bool,
+}
+
+/// Number of operand slots of a block's result, a `v128` takes two.
+fn block_arity(block_type: &loader::BlockType) -> u32 {
+  match block_type {
+    loader::BlockType::NoResult => 0,
+    loader::BlockType::Value(val_type) => ValueType::from(val_type).slots() as u32,
+  }
+}
+
+/// The first slot of each local, and the number of slots after the last
+/// local.  A `v128` local takes two slots.
+fn local_slots(types: &[loader::ValueType]) -> Vec<usize> {
+  let mut slots = vec![0];
+  for val_type in types {
+    slots.push(slots[slots.len() - 1] + ValueType::from(val_type).slots());
+  }
+  slots
+}
+
+/// Pick the hottest locals of a function to keep in registers, uses inside
+/// loops count more.  Returns the pairs of local slots that swap places, a
+/// slot below `REGISTERS` with a hot slot above it.  `exnref` locals are
+/// never kept in registers, the store only sees the caught exceptions
+/// referenced from the stack.  They move to spare slots after the locals
+/// when there is no hot local to swap with.
+fn hot_locals(code: &[loader::Instruction], types: &[loader::ValueType]) -> Vec<(Local, Local)> {
+  use loader::Instruction::*;
+  let slots = local_slots(types);
+  let count = slots[types.len()];
+  let mut uses = vec![0u64; count];
+  // The kind of each open block, `true` for loops.
+  let mut blocks = vec![];
+  let mut loops = 0u32;
+  for op in code {
+    match op {
+      Loop(_) => {
+        blocks.push(true);
+        loops += 1;
+      },
+      Block(_) | If(_) | Try(_) | TryTable(..) => blocks.push(false),
+      End | Delegate(_) => loops -= blocks.pop().map_or(0, u32::from),
+      GetLocal(idx) | SetLocal(idx) | TeeLocal(idx) if (*idx as usize) < types.len() => {
+        let idx = *idx as usize;
+        for count in &mut uses[slots[idx]..slots[idx + 1]] {
+          *count = count.saturating_add(1 << (3 * loops.min(16)));
+        }
+      },
+      _ => (),
     }
-  };
+  }
+
+  // Local 0 is always in a register, it is the first parameter.
+  let is_ref = |slot: usize| slots.binary_search(&slot).is_ok_and(|idx| types.get(idx) == Some(&loader::ValueType::ExnRef));
+  let mut hot: Vec<usize> = (1..count).filter(|idx| uses[*idx] > 0 && !is_ref(*idx)).collect();
+  hot.sort_by_key(|idx| std::cmp::Reverse(uses[*idx]));
+  hot.truncate(REGISTERS - 1);
+  let mut incoming = hot.iter().filter(|idx| **idx >= REGISTERS);
+  let mut spare = count.max(REGISTERS);
+  let mut swaps = vec![];
+  for reg in 0..REGISTERS.min(count) {
+    if hot.contains(&reg) || (reg == 0 && !is_ref(0)) {
+      continue;
+    }
+    match incoming.next() {
+      Some(local) => swaps.push((reg as Local, *local as Local)),
+      None if is_ref(reg) => {
+        swaps.push((reg as Local, spare as Local));
+        spare += 1;
+      },
+      None => (),
+    }
+  }
+  swaps
+}
+
+/// Check if a module can hold `exnref` values.
+fn uses_exnref(module: &loader::Module) -> bool {
+  use loader::Instruction::*;
+  let is_ref = |val_type: &loader::ValueType| *val_type == loader::ValueType::ExnRef;
+  module.types().iter().any(|func_type| func_type.params().iter().chain(&func_type.return_type()).any(is_ref))
+    || module.tags().iter().any(|tag| tag.params().iter().any(is_ref))
+    || module.globals().iter().any(|global| is_ref(&global.value_type()))
+    || module.functions().iter().any(|func| {
+      func.locals().iter().any(is_ref) || func.instructions().iter().any(|op| match op {
+        Block(block_type) | Loop(block_type) | If(block_type) | Try(block_type) | TryTable(block_type, _) =>
+          *block_type == loader::BlockType::Value(loader::ValueType::ExnRef),
+        _ => false,
+      })
+    })
 }
 
 pub struct Compiler {
-  module: bwasm::Module,
+  module: loader::Module,
   compiled: Vec<Function>,
 
   func_idx: u32,
   ret_type: Option<ValueType>,
-  code: Vec<bwasm::Instruction>,
+  code: Vec<loader::Instruction>,
   pc_end: usize,
+  /// Spill pending operations before calls, see `Store::add_exception`.
+  uses_exnref: bool,
+  /// The index of each global in `Store::globals`.
+  global_slots: Vec<u32>,
 }
 
 impl Compiler {
-  pub fn new(module: &bwasm::Module) -> Self {
+  pub fn new(module: &loader::Module) -> Self {
     Self {
       module: module.clone(),
       compiled: vec![],
@@ -255,15 +680,21 @@ impl Compiler {
       ret_type: None,
       code: vec![],
       pc_end: 0,
+      uses_exnref: uses_exnref(module),
+      global_slots: module.global_slots(),
     }
   }
 
-  pub fn compile(mut self) -> Result<Vec<Function>> {
+  fn is_v128_global(&self, global_idx: GlobalIdx) -> bool {
+    self.module.globals()[global_idx as usize].value_type() == loader::ValueType::V128
+  }
+
+  pub fn compile(mut self) -> Result<Module> {
     let len = self.module.functions().len() as u32;
     for idx in 0..len {
       self.compile_function(idx)?;
     }
-    Ok(self.compiled)
+    Module::new(self.compiled, &self.module)
   }
 
   fn compile_function(&mut self, func_idx: u32) -> Result<()> {
@@ -279,296 +710,1139 @@ impl Compiler {
     self.ret_type = func.return_type().map(ValueType::from);
     self.pc_end = self.code.len();
 
-    let mut state = State::new();
-    let block = self.compile_block(&mut state, BlockKind::Block)?;
-
-    self.compiled.push(Function::new(func,
-    Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Option<StackValue>>
+    let address_masks = self.module.memories().iter()
+      .map(|memory| memory::address_mask(memory.is_64()))
+      .collect();
+    let types: Vec<_> = func.params().iter().chain(func.locals()).copied().collect();
+    let swaps = hot_locals(&self.code, &types);
+    let slots = local_slots(&types);
+    let count = swaps.iter().map(|(_, local)| *local as usize + 1).fold(slots[types.len()], usize::max);
+    let mut stored: Vec<Local> = (0..count as Local).collect();
+    for (reg, local) in &swaps {
+      stored.swap(*reg as usize, *local as usize);
+    }
+    let locals = types.iter().zip(&slots).map(|(val_type, slot)| {
+      let high = (*val_type == loader::ValueType::V128).then(|| stored[slot + 1]);
+      (stored[*slot], high)
+    }).collect();
+    let mut state = State::new(address_masks, locals);
+    let arity = self.ret_type.map_or(0, |ret_type| ret_type.slots() as u32);
+    let block = self.compile_block(&mut state, BlockKind::Block, arity)?;
+
+    self.compiled.push(Function::new(func, func_idx, state.max_height, swaps,
+    Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
     {
-      match block.run(state, store, _l0)? {
+      match block.run(state, store, _regs)? {
         Action::Return(ret_value) => {
-          //eprintln!("--- Function return: {:?}", ret_value);
-          return Ok(ret_value);
+          Ok(Action::Return(ret_value))
         },
+        Action::TailCall(func_addr) => Ok(Action::TailCall(func_addr)),
         _ => {
-          unreachable!("Compiled function missing 'Return' action.");
+          // A `br_table` to the function's block, the result was saved in
+          // the first operand slots.
+          if arity > 1 {
+            let high = store.stack.get_operand(1);
+            store.stack.set_ret_high(high);
+          }
+          if arity > 0 {
+            Ok(Action::Return(Some(store.stack.get_operand(0))))
+          } else {
+            Ok(Action::Return(None))
+          }
         },
       }
     })));
 
-    //eprintln!("---------- depth = {}, values = {}", state.depth, state.len());
     Ok(())
   }
 
-  fn compile_block(&self, state: &mut State, kind: BlockKind) -> Result<Block> {
+  /// Skip unreachable code, until the end of the current block.
+  fn skip_unreachable(&self, state: &mut State) {
+    use loader::Instruction::*;
+    let mut depth = 0;
+    while state.pc < self.pc_end {
+      match &self.code[state.pc] {
+        Block(_) | Loop(_) | If(_) | Try(_) | TryTable(..) => {
+          depth += 1;
+        },
+        Else | Catch(_) | CatchAll | Delegate(_) if depth == 0 => break,
+        End if depth == 0 => break,
+        // `delegate` ends its `try` block.
+        End | Delegate(_) => {
+          depth -= 1;
+        },
+        _ => (),
+      }
+      state.pc += 1;
+    }
+  }
+
+  fn compile_block(&self, state: &mut State, kind: BlockKind, arity: u32) -> Result<Block> {
+    let height = state.len();
+    let handler = state.handler();
+    self.compile_block_at(state, kind, arity, height, handler)
+  }
+
+  /// Compile a block with the label's result in the operand slot at
+  /// `height`.  The values above `height` are the values the block starts
+  /// with, the values of a caught exception.  `handler` is the exception
+  /// handler of the code in the block.
+  fn compile_block_at(&self, state: &mut State, kind: BlockKind, arity: u32, height: usize, handler: u32) -> Result<Block> {
     let mut block = Block::new(kind, state.depth);
-    //eprintln!("compile block: depth: {} {:?}, stack: {}", block.depth(), kind, state.len());
     state.depth += 1;
-    if state.depth > 4 {
-      panic!("compile overflow, increase the max depth if needed");
+    if state.depth > MAX_BLOCK_DEPTH {
+      return Err(Error::ValidationError(format!("blocks are nested deeper than {}", MAX_BLOCK_DEPTH)));
     }
+    state.labels.push(Label {
+      height: height as u32,
+      // Branches to a loop restart it, without any values.
+      arity: if kind == BlockKind::Loop { 0 } else { arity },
+      kind,
+      handler,
+    });
     // compile function opcodes.
     loop {
-      use parity_wasm::elements::Instruction::*;
-      if state.pc > self.pc_end {
+      use loader::Instruction::*;
+      if state.unreachable {
+        self.skip_unreachable(state);
+      }
+      if state.pc >= self.pc_end {
         break;
       }
+      if state.is_too_deep() {
+        state.spill(&mut block, Spill::Ops);
+      }
       let pc = state.pc;
+      // Compile common instruction sequences into one closure.
+      let fused = fused_ops::compile(state, &mut block, &self.code[pc..])?;
+      if fused > 0 {
+        state.pc += fused;
+        continue;
+      }
       let op = &self.code[pc];
-      //eprintln!("compile {}: {:?}", pc, op);
       match op {
-        Block(_) => {
+        Unreachable => {
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            Err(store.trap_at(pc, TrapKind::Unreachable))
+          }));
+          state.unreachable = true;
+        },
+        Nop => (),
+        Block(block_type) => {
+          state.spill(&mut block, Spill::All);
+          let height = state.len();
+          let arity = block_arity(block_type);
           state.pc += 1;
-          let sub_block = self.compile_block(state, BlockKind::Block)?;
-          block.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
-            sub_block.run(state, store, _l0)
+          let sub_block = self.compile_block(state, BlockKind::Block, arity)?;
+          block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            sub_block.run(state, store, _regs)
           }));
+          state.push_results(height, arity);
         },
-        Loop(_) => {
+        Loop(block_type) => {
+          state.spill(&mut block, Spill::All);
+          let height = state.len();
+          let arity = block_arity(block_type);
           state.pc += 1;
-          let loop_block = self.compile_loop(state)?;
-          block.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
-            loop_block.run(state, store, _l0)
+          let loop_block = self.compile_loop(state, arity)?;
+          block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            loop_block.run(state, store, _regs)
           }));
+          state.push_results(height, arity);
         },
-        If(_) => {
+        If(block_type) => {
           state.pc += 1;
-          self.compile_if(&mut block, state)?;
+          self.compile_if(&mut block, state, block_arity(block_type))?;
         },
         Else => {
           match kind {
             BlockKind::If => {
+              self.end_block(state, &mut block, arity)?;
               break;
             },
             _ => {
-              return Err(Error::ValidationError(format!("invalid 'else' block, missing 'if'")));
+              return Err(Error::ValidationError("invalid 'else' block, missing 'if'".to_string()));
             },
           }
         },
-        End => {
-          if block.depth() == 0 && state.len() > 0 {
-            self.emit_return(state, &mut block)?;
+        Catch(_) | CatchAll | Delegate(_) => {
+          match kind {
+            BlockKind::Try | BlockKind::Catch => {
+              self.end_block(state, &mut block, arity)?;
+              break;
+            },
+            _ => {
+              return Err(Error::ValidationError("invalid 'catch' block, missing 'try'".to_string()));
+            },
           }
+        },
+        End => {
+          self.end_block(state, &mut block, arity)?;
           break;
         },
         Return => {
           self.emit_return(state, &mut block)?;
+          state.unreachable = true;
         },
         Br(block_depth) => {
-          self.compile_br(&mut block, *block_depth)?;
+          self.compile_br(&mut block, state, *block_depth)?;
+          state.unreachable = true;
         },
         BrIf(block_depth) => {
           self.compile_br_if(&mut block, state, *block_depth)?;
         },
-        BrTable(ref _br_table) => {
-          todo!("");
+        BrTable(ref br_table) => {
+          self.compile_br_table(&mut block, state, &br_table.table, br_table.default)?;
+          state.unreachable = true;
+        },
+
+        TryTable(block_type, catches) => {
+          self.compile_try_table(&mut block, state, block_arity(block_type), catches)?;
+        },
+        Try(block_type) => {
+          self.compile_try(&mut block, state, block_arity(block_type))?;
+        },
+        Throw(tag_idx) => {
+          let tag_idx = *tag_idx;
+          let count = ValueType::count_slots(self.module.tags()[tag_idx as usize].params());
+          let params = state.pop_n(count)?;
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let values = params.iter().map(|param| {
+              param.resolv(vm_state, store, regs)
+            }).collect::<Trap<Vec<_>>>()?;
+            let exn = Exception::from_stack(vm_state.tag(tag_idx), &values);
+            Err(store.trap_at(pc, TrapKind::Exception(exn)))
+          }));
+          state.unreachable = true;
+        },
+        ThrowRef => {
+          let val = state.pop()?;
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let handle = val.resolv(vm_state, store, regs)?.to_ref();
+            let trap = match handle.and_then(|handle| store.exception(handle)) {
+              Some(exn) => TrapKind::Exception(exn.clone()),
+              None => TrapKind::NullReference,
+            };
+            Err(store.trap_at(pc, trap))
+          }));
+          state.unreachable = true;
+        },
+        Rethrow(depth) => {
+          // Count the `catch` blocks inside the target, their exceptions
+          // are above its exception.
+          let mut inner = 0;
+          for depth in 0..*depth {
+            if state.label(depth)?.kind == BlockKind::Catch {
+              inner += 1;
+            }
+          }
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |_vm_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            let exn = store.caught[store.caught.len() - 1 - inner].clone();
+            Err(store.trap_at(pc, TrapKind::Exception(exn)))
+          }));
+          state.unreachable = true;
         },
 
         Call(func_idx) => {
           let func_idx = *func_idx;
           let func = self.module.get_func(func_idx)
             .ok_or(Error::FuncNotFound)?;
-          let count = func.param_count();
-          //eprintln!("Call: params={}", count);
-          if count > 1 {
-            let params = state.pop_n(count as usize - 1)?;
-            impl_unops_match_input!(state, vm_state, store, l0, val, {
-              let mut val = StackValue(val);
-              store.stack.push_val(val)?;
-              // Resolve inputs and ..
-              let params = params.iter().map(|p| {
-                p.resolv(vm_state, store, l0)
-              }).collect::<Result<Vec<_>, _>>()?;
-              // .. push the values onto the stack.
-              store.stack.push_values(&params[..])?;
-              if let Some(ret) = vm_state.invoke_function(store, func_idx, &mut val)? {
-                ret.0
-              } else {
-                0
-              }
+          let count = ValueType::count_slots(func.params());
+          let ret_type = func.return_type().map(ValueType::from);
+          let params = state.pop_n(count)?;
+          self.emit_call(&mut block, state, params, ret_type,
+            move |vm_state: &vm::State, store: &mut Store, _caller_regs: &mut Registers, regs: &mut Registers| {
+              vm_state.invoke_function(store, func_idx, regs)
             });
-          } else {
-            impl_unops_match_input!(state, vm_state, store, l0, val, {
-              let mut val = StackValue(val);
-              if let Some(ret) = vm_state.invoke_function(store, func_idx, &mut val)? {
-                ret.0
-              } else {
-                0
-              }
+        },
+        CallIndirect(type_idx, table_idx) => {
+          let table_idx = *table_idx as usize;
+          let func_type = self.module.types().get(*type_idx as usize)
+            .map(FunctionType::from)
+            .ok_or_else(|| Error::ValidationError(format!("Invalid type index: {}", type_idx)))?;
+          let ret_type = func_type.ret_type;
+          let idx = state.pop()?;
+          let params = state.pop_n(func_type.param_slots())?;
+          self.emit_call(&mut block, state, params, ret_type,
+            move |vm_state: &vm::State, store: &mut Store, caller_regs: &mut Registers, regs: &mut Registers| {
+              let idx = idx.resolv(vm_state, store, caller_regs)?.0 as u32;
+              let func_addr = indirect_func(vm_state, store, table_idx, idx, &func_type)?;
+              vm_state.invoke_function(store, func_addr, regs)
+            });
+        },
+        ReturnCall(func_idx) => {
+          let func_idx = *func_idx;
+          let func = self.module.get_func(func_idx)
+            .ok_or(Error::FuncNotFound)?;
+          let params = state.pop_n(ValueType::count_slots(func.params()))?;
+          self.emit_tail_call(&mut block, state, params,
+            move |_vm_state: &vm::State, _store: &mut Store, _regs: &mut Registers| {
+              Ok(func_idx)
             });
+          state.unreachable = true;
+        },
+        ReturnCallIndirect(type_idx, table_idx) => {
+          let table_idx = *table_idx as usize;
+          let func_type = self.module.types().get(*type_idx as usize)
+            .map(FunctionType::from)
+            .ok_or_else(|| Error::ValidationError(format!("Invalid type index: {}", type_idx)))?;
+          let idx = state.pop()?;
+          let params = state.pop_n(func_type.param_slots())?;
+          self.emit_tail_call(&mut block, state, params,
+            move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| {
+              let idx = idx.resolv(vm_state, store, regs)?.0 as u32;
+              indirect_func(vm_state, store, table_idx, idx, &func_type)
+            });
+          state.unreachable = true;
+        },
+
+        Drop => {
+          for val in state.pop_value()? {
+            if let Input::Op(_) = val {
+              // The dropped value still needs to be evaluated.
+              state.push(val);
+              state.spill(&mut block, Spill::Ops);
+              state.pop()?;
+            }
           }
         },
+        Select | TypedSelect(_) if state.is_v128(1) => {
+          // Select both halves of a `v128` in one closure, the condition is
+          // only evaluated once.
+          let cond = state.pop()?;
+          let val2 = state.pop_n(2)?;
+          let val1 = state.pop_n(2)?;
+          state.spill(&mut block, Spill::Ops);
+          let slot = state.len() as u32;
+          block.push(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val1 = resolv_results(&val1, state, store, regs)?;
+            let val2 = resolv_results(&val2, state, store, regs)?;
+            let val = if cond.resolv(state, store, regs)?.0 as u32 != 0 { val1 } else { val2 };
+            store.stack.set_operands(slot, &val)?;
+            Ok(Action::End)
+          }));
+          state.push_slots(slot, 2);
+        },
+        Select | TypedSelect(_) => {
+          let cond = state.pop()?;
+          let val2 = state.pop()?;
+          let val1 = state.pop()?;
+          state.push(Input::Op(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+            let val1 = val1.resolv(state, store, regs)?;
+            let val2 = val2.resolv(state, store, regs)?;
+            if cond.resolv(state, store, regs)?.0 as u32 != 0 {
+              Ok(val1)
+            } else {
+              Ok(val2)
+            }
+          })));
+        },
 
         GetLocal(local_idx) => {
-          state.push(Input::Local(*local_idx));
+          let local = Input::Local(state.local(*local_idx));
+          match state.local_high(*local_idx) {
+            Some(high) => state.push_v128(local, Input::Local(high)),
+            None => state.push(local),
+          }
         },
-        SetLocal(set_idx) => {
-          let set_idx = *set_idx;
-          let val = state.pop()?;
-          if set_idx == 0 {
-            match val {
-              Input::Local(0) => {
-                // noop.  Get local 0 and set local 0.
-              },
-              Input::Local(local_idx) => {
-                block.push(Box::new(move |_state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  let val = store.stack.get_local_val(local_idx, l0);
-                  *l0 = val;
-                  Ok(Action::End)
-                }));
-              },
-              Input::Const(const_val) => {
-                block.push(Box::new(move |_state: &vm::State, _store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  *l0 = const_val;
-                  Ok(Action::End)
-                }));
-              },
-              Input::Op(closure) => {
-                block.push(Box::new(move |state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  *l0 = closure(state, store, l0)?;
-                  Ok(Action::End)
-                }));
-              },
-            }
+        SetLocal(local_idx) => {
+          let set_idx = state.local(*local_idx);
+          if let Some(high_idx) = state.local_high(*local_idx) {
+            let high = state.pop()?;
+            let low = state.pop()?;
+            state.spill(&mut block, Spill::Local(set_idx));
+            state.spill(&mut block, Spill::Local(high_idx));
+            self.emit_set_local(&mut block, set_idx, low);
+            self.emit_set_local(&mut block, high_idx, high);
+          } else {
+            let val = state.pop()?;
+            state.spill(&mut block, Spill::Local(set_idx));
+            self.emit_set_local(&mut block, set_idx, val);
+          }
+        },
+        TeeLocal(local_idx) => {
+          let set_idx = state.local(*local_idx);
+          if let Some(high_idx) = state.local_high(*local_idx) {
+            // Set both halves, then read them back.
+            let high = state.pop()?;
+            let low = state.pop()?;
+            state.spill(&mut block, Spill::Local(set_idx));
+            state.spill(&mut block, Spill::Local(high_idx));
+            self.emit_set_local(&mut block, set_idx, low);
+            self.emit_set_local(&mut block, high_idx, high);
+            state.push_v128(Input::Local(set_idx), Input::Local(high_idx));
           } else {
-            block.push(match val {
-              Input::Local(0) => {
-                Box::new(move |_state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  let val = *l0;
-                  store.stack.set_local_val(set_idx, val, l0);
-                  Ok(Action::End)
-                })
-              },
-              Input::Local(local_idx) => {
-                Box::new(move |_state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  let val = store.stack.get_local_val(local_idx, l0);
-                  store.stack.set_local_val(set_idx, val, l0);
-                  Ok(Action::End)
-                })
-              },
-              Input::Const(const_val) => {
-                Box::new(move |_state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  let val = const_val;
-                  store.stack.set_local_val(set_idx, val, l0);
-                  Ok(Action::End)
-                })
-              },
-              Input::Op(closure) => {
-                Box::new(move |state: &vm::State, store: &mut Store, l0: &mut StackValue| -> Trap<Action> {
-                  let val = closure(state, store, l0)?;
-                  store.stack.set_local_val(set_idx, val, l0);
-                  Ok(Action::End)
-                })
-              },
+            let val = state.pop()?;
+            state.spill(&mut block, Spill::Local(set_idx));
+            state.push(val);
+            impl_unops_match_input!(state, vm_state, store, regs, val, {
+              store.stack.set_local_val(set_idx, StackValue(val), regs);
+              val
             });
           }
         },
-        TeeLocal(set_idx) => {
-          let set_idx = *set_idx;
-          impl_unops_match_input!(state, vm_state, store, l0, val, {
-            store.stack.set_local_val(set_idx, StackValue(val), l0);
-            val
+        GetGlobal(global_idx) => {
+          let slot = self.global_slots[*global_idx as usize] as usize;
+          let get = |slot: usize| -> Input {
+            Input::Op(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<StackValue> {
+              Ok(store.globals[slot])
+            }))
+          };
+          if self.is_v128_global(*global_idx) {
+            state.push_v128(get(slot), get(slot + 1));
+          } else {
+            state.push(get(slot));
+          }
+        },
+        SetGlobal(global_idx) if self.is_v128_global(*global_idx) => {
+          let slot = self.global_slots[*global_idx as usize] as usize;
+          let val = state.pop_n(2)?;
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let low = val[0].resolv(state, store, regs)?;
+            let high = val[1].resolv(state, store, regs)?;
+            store.globals[slot] = low;
+            store.globals[slot + 1] = high;
+            Ok(Action::End)
+          }));
+        },
+        SetGlobal(global_idx) => {
+          let slot = self.global_slots[*global_idx as usize] as usize;
+          let val = state.pop()?;
+          state.spill(&mut block, Spill::Ops);
+          block.push(impl_ops_match_input!(val, {
+            Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+              store.globals[slot] = val.eval(state, store, regs)?;
+              Ok(Action::End)
+            })
+          }));
+        },
+
+        I32Load(memarg) => i32_ops::load(state, *memarg)?,
+        I64Load(memarg) => i64_ops::load(state, *memarg)?,
+        F32Load(memarg) => f32_ops::load(state, *memarg)?,
+        F64Load(memarg) => f64_ops::load(state, *memarg)?,
+        I32Load8S(memarg) => i32_ops::load8_s(state, *memarg)?,
+        I32Load8U(memarg) => i32_ops::load8_u(state, *memarg)?,
+        I32Load16S(memarg) => i32_ops::load16_s(state, *memarg)?,
+        I32Load16U(memarg) => i32_ops::load16_u(state, *memarg)?,
+        I64Load8S(memarg) => i64_ops::load8_s(state, *memarg)?,
+        I64Load8U(memarg) => i64_ops::load8_u(state, *memarg)?,
+        I64Load16S(memarg) => i64_ops::load16_s(state, *memarg)?,
+        I64Load16U(memarg) => i64_ops::load16_u(state, *memarg)?,
+        I64Load32S(memarg) => i64_ops::load32_s(state, *memarg)?,
+        I64Load32U(memarg) => i64_ops::load32_u(state, *memarg)?,
+        I32Store(memarg) => i32_ops::store(state, &mut block, *memarg)?,
+        I64Store(memarg) => i64_ops::store(state, &mut block, *memarg)?,
+        F32Store(memarg) => f32_ops::store(state, &mut block, *memarg)?,
+        F64Store(memarg) => f64_ops::store(state, &mut block, *memarg)?,
+        I32Store8(memarg) => i32_ops::store8(state, &mut block, *memarg)?,
+        I32Store16(memarg) => i32_ops::store16(state, &mut block, *memarg)?,
+        I64Store8(memarg) => i64_ops::store8(state, &mut block, *memarg)?,
+        I64Store16(memarg) => i64_ops::store16(state, &mut block, *memarg)?,
+        I64Store32(memarg) => i64_ops::store32(state, &mut block, *memarg)?,
+
+        CurrentMemory(mem_idx) => {
+          let mem_idx = *mem_idx as usize;
+          state.push(Input::Op(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<StackValue> {
+            Ok(StackValue((store.memories[mem_idx].len() / loader::PAGE_SIZE as usize) as u64))
+          })));
+        },
+        GrowMemory(mem_idx) => {
+          let (mem_idx, mask) = (*mem_idx, state.address_mask(*mem_idx));
+          // The result is sign-extended, -1 is the same for `i32` and `i64`.
+          impl_unops_match_input!(state, vm_state, store, regs, delta, {
+            let max_pages = (vm_state.memory_max_pages(mem_idx) as u64).min(store.memory_limit(mem_idx));
+            store.memories[mem_idx as usize].grow(max_pages, delta as u64 & mask)
           });
         },
+
         I32Const(val) => {
           state.push(Input::Const(StackValue(*val as _)));
         },
         I64Const(val) => {
           state.push(Input::Const(StackValue(*val as _)));
         },
+        F32Const(val) => {
+          state.push(Input::Const(StackValue(*val as _)));
+        },
+        F64Const(val) => {
+          state.push(Input::Const(StackValue(*val as _)));
+        },
 
-        I32Add => i32_ops::add(state)?,
-        I32Sub => i32_ops::sub(state)?,
-        I32LtS => i32_ops::lt_s(state)?,
-        I32Eq => i32_ops::eq(state)?,
         I32Eqz => i32_ops::eqz(state)?,
+        I32Eq => i32_ops::eq(state)?,
+        I32Ne => i32_ops::ne(state)?,
+        I32LtS => i32_ops::lt_s(state)?,
+        I32LtU => i32_ops::lt_u(state)?,
+        I32GtS => i32_ops::gt_s(state)?,
+        I32GtU => i32_ops::gt_u(state)?,
+        I32LeS => i32_ops::le_s(state)?,
+        I32LeU => i32_ops::le_u(state)?,
+        I32GeS => i32_ops::ge_s(state)?,
+        I32GeU => i32_ops::ge_u(state)?,
 
+        I64Eqz => i64_ops::eqz(state)?,
+        I64Eq => i64_ops::eq(state)?,
+        I64Ne => i64_ops::ne(state)?,
+        I64LtS => i64_ops::lt_s(state)?,
+        I64LtU => i64_ops::lt_u(state)?,
+        I64GtS => i64_ops::gt_s(state)?,
+        I64GtU => i64_ops::gt_u(state)?,
+        I64LeS => i64_ops::le_s(state)?,
+        I64LeU => i64_ops::le_u(state)?,
+        I64GeS => i64_ops::ge_s(state)?,
+        I64GeU => i64_ops::ge_u(state)?,
+
+        F32Eq => f32_ops::eq(state)?,
+        F32Ne => f32_ops::ne(state)?,
+        F32Lt => f32_ops::lt(state)?,
+        F32Gt => f32_ops::gt(state)?,
+        F32Le => f32_ops::le(state)?,
+        F32Ge => f32_ops::ge(state)?,
+
+        F64Eq => f64_ops::eq(state)?,
+        F64Ne => f64_ops::ne(state)?,
+        F64Lt => f64_ops::lt(state)?,
+        F64Gt => f64_ops::gt(state)?,
+        F64Le => f64_ops::le(state)?,
+        F64Ge => f64_ops::ge(state)?,
+
+        I32Clz => i32_ops::clz(state)?,
+        I32Ctz => i32_ops::ctz(state)?,
+        I32Popcnt => i32_ops::popcnt(state)?,
+        I32Add => i32_ops::add(state)?,
+        I32Sub => i32_ops::sub(state)?,
+        I32Mul => i32_ops::mul(state)?,
+        I32DivS => i32_ops::div_s(state)?,
+        I32DivU => i32_ops::div_u(state)?,
+        I32RemS => i32_ops::rem_s(state)?,
+        I32RemU => i32_ops::rem_u(state)?,
+        I32And => i32_ops::and(state)?,
+        I32Or => i32_ops::or(state)?,
+        I32Xor => i32_ops::xor(state)?,
+        I32Shl => i32_ops::shl(state)?,
+        I32ShrS => i32_ops::shr_s(state)?,
+        I32ShrU => i32_ops::shr_u(state)?,
+        I32Rotl => i32_ops::rotl(state)?,
+        I32Rotr => i32_ops::rotr(state)?,
+
+        I64Clz => i64_ops::clz(state)?,
+        I64Ctz => i64_ops::ctz(state)?,
+        I64Popcnt => i64_ops::popcnt(state)?,
         I64Add => i64_ops::add(state)?,
         I64Sub => i64_ops::sub(state)?,
-        I64LtS => i64_ops::lt_s(state)?,
-        I64Eq => i64_ops::eq(state)?,
-        I64Eqz => i64_ops::eqz(state)?,
-        op => todo!("implment opcode: {:?}", op),
+        I64Mul => i64_ops::mul(state)?,
+        I64DivS => i64_ops::div_s(state)?,
+        I64DivU => i64_ops::div_u(state)?,
+        I64RemS => i64_ops::rem_s(state)?,
+        I64RemU => i64_ops::rem_u(state)?,
+        I64And => i64_ops::and(state)?,
+        I64Or => i64_ops::or(state)?,
+        I64Xor => i64_ops::xor(state)?,
+        I64Shl => i64_ops::shl(state)?,
+        I64ShrS => i64_ops::shr_s(state)?,
+        I64ShrU => i64_ops::shr_u(state)?,
+        I64Rotl => i64_ops::rotl(state)?,
+        I64Rotr => i64_ops::rotr(state)?,
+
+        F32Abs => f32_ops::abs(state)?,
+        F32Neg => f32_ops::neg(state)?,
+        F32Ceil => f32_ops::ceil(state)?,
+        F32Floor => f32_ops::floor(state)?,
+        F32Trunc => f32_ops::trunc(state)?,
+        F32Nearest => f32_ops::nearest(state)?,
+        F32Sqrt => f32_ops::sqrt(state)?,
+        F32Add => f32_ops::add(state)?,
+        F32Sub => f32_ops::sub(state)?,
+        F32Mul => f32_ops::mul(state)?,
+        F32Div => f32_ops::div(state)?,
+        F32Min => f32_ops::min(state)?,
+        F32Max => f32_ops::max(state)?,
+        F32Copysign => f32_ops::copysign(state)?,
+
+        F64Abs => f64_ops::abs(state)?,
+        F64Neg => f64_ops::neg(state)?,
+        F64Ceil => f64_ops::ceil(state)?,
+        F64Floor => f64_ops::floor(state)?,
+        F64Trunc => f64_ops::trunc(state)?,
+        F64Nearest => f64_ops::nearest(state)?,
+        F64Sqrt => f64_ops::sqrt(state)?,
+        F64Add => f64_ops::add(state)?,
+        F64Sub => f64_ops::sub(state)?,
+        F64Mul => f64_ops::mul(state)?,
+        F64Div => f64_ops::div(state)?,
+        F64Min => f64_ops::min(state)?,
+        F64Max => f64_ops::max(state)?,
+        F64Copysign => f64_ops::copysign(state)?,
+
+        I32WrapI64 => conv_ops::wrap_i64(state)?,
+        I32TruncSF32 => i32_ops::trunc_s_f32(state)?,
+        I32TruncUF32 => i32_ops::trunc_u_f32(state)?,
+        I32TruncSF64 => i32_ops::trunc_s_f64(state)?,
+        I32TruncUF64 => i32_ops::trunc_u_f64(state)?,
+        I64ExtendSI32 => conv_ops::extend_s_i32(state)?,
+        I64ExtendUI32 => conv_ops::extend_u_i32(state)?,
+        I64TruncSF32 => i64_ops::trunc_s_f32(state)?,
+        I64TruncUF32 => i64_ops::trunc_u_f32(state)?,
+        I64TruncSF64 => i64_ops::trunc_s_f64(state)?,
+        I64TruncUF64 => i64_ops::trunc_u_f64(state)?,
+        F32ConvertSI32 => f32_ops::convert_s_i32(state)?,
+        F32ConvertUI32 => f32_ops::convert_u_i32(state)?,
+        F32ConvertSI64 => f32_ops::convert_s_i64(state)?,
+        F32ConvertUI64 => f32_ops::convert_u_i64(state)?,
+        F32DemoteF64 => conv_ops::demote_f64(state)?,
+        F64ConvertSI32 => f64_ops::convert_s_i32(state)?,
+        F64ConvertUI32 => f64_ops::convert_u_i32(state)?,
+        F64ConvertSI64 => f64_ops::convert_s_i64(state)?,
+        F64ConvertUI64 => f64_ops::convert_u_i64(state)?,
+        F64PromoteF32 => conv_ops::promote_f32(state)?,
+
+        // The values are stored as raw bits, reinterpret is a noop.
+        I32ReinterpretF32 => conv_ops::reinterpret_32(state)?,
+        I64ReinterpretF64 => (),
+        F32ReinterpretI32 => conv_ops::reinterpret_32(state)?,
+        F64ReinterpretI64 => (),
+
+        I32Extend8S => i32_ops::extend8_s(state)?,
+        I32Extend16S => i32_ops::extend16_s(state)?,
+        I64Extend8S => i64_ops::extend8_s(state)?,
+        I64Extend16S => i64_ops::extend16_s(state)?,
+        I64Extend32S => i64_ops::extend32_s(state)?,
+
+        I32TruncSatSF32 => i32_ops::trunc_sat_s_f32(state)?,
+        I32TruncSatUF32 => i32_ops::trunc_sat_u_f32(state)?,
+        I32TruncSatSF64 => i32_ops::trunc_sat_s_f64(state)?,
+        I32TruncSatUF64 => i32_ops::trunc_sat_u_f64(state)?,
+        I64TruncSatSF32 => i64_ops::trunc_sat_s_f32(state)?,
+        I64TruncSatUF32 => i64_ops::trunc_sat_u_f32(state)?,
+        I64TruncSatSF64 => i64_ops::trunc_sat_s_f64(state)?,
+        I64TruncSatUF64 => i64_ops::trunc_sat_u_f64(state)?,
+
+        I64Add128 => wide_ops::add128(state, &mut block)?,
+        I64Sub128 => wide_ops::sub128(state, &mut block)?,
+        I64MulWideS => wide_ops::mul_wide_s(state, &mut block)?,
+        I64MulWideU => wide_ops::mul_wide_u(state, &mut block)?,
+
+        MemoryInit(data_idx, mem_idx) => {
+          let masks = [state.address_mask(*mem_idx), memory::address_mask(false), memory::address_mask(false)];
+          let (data_idx, mem_idx) = (*data_idx, *mem_idx as usize);
+          self.emit_memory_op(&mut block, state, masks, move |vm_state: &vm::State, store: &mut Store, dst, src, len| {
+            let data = vm_state.module().data_segment(store, data_idx);
+            store.memories[mem_idx].init(dst, data, src, len)
+          })?;
+        },
+        DataDrop(data_idx) => {
+          let data_idx = *data_idx as usize;
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            store.dropped_data[data_idx] = true;
+            Ok(Action::End)
+          }));
+        },
+        MemoryCopy(dst_idx, src_idx) => {
+          let (dst_idx, src_idx) = (*dst_idx, *src_idx);
+          let (dst_mask, src_mask) = (state.address_mask(dst_idx), state.address_mask(src_idx));
+          self.emit_memory_op(&mut block, state, [dst_mask, src_mask, dst_mask & src_mask], move |_vm_state: &vm::State, store: &mut Store, dst, src, len| {
+            memory::copy(&mut store.memories, dst_idx, dst, src_idx, src, len)
+          })?;
+        },
+        MemoryFill(mem_idx) => {
+          let mask = state.address_mask(*mem_idx);
+          let mem_idx = *mem_idx as usize;
+          self.emit_memory_op(&mut block, state, [mask, memory::address_mask(false), mask], move |_vm_state: &vm::State, store: &mut Store, dst, val, len| {
+            store.memories[mem_idx].fill(dst, val as u8, len)
+          })?;
+        },
+        TableInit(elem_idx, table_idx) => {
+          let (elem_idx, table_idx) = (*elem_idx, *table_idx as usize);
+          self.emit_bulk_op(&mut block, state, move |vm_state: &vm::State, store: &mut Store, dst, src, len| {
+            let elems = vm_state.module().elem_segment(store, elem_idx);
+            table::init(&mut store.tables[table_idx], dst, elems, src, len)
+          })?;
+        },
+        ElemDrop(elem_idx) => {
+          let elem_idx = *elem_idx as usize;
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            store.dropped_elems[elem_idx] = true;
+            Ok(Action::End)
+          }));
+        },
+        TableCopy(dst_table, src_table) => {
+          let (dst_table, src_table) = (*dst_table, *src_table);
+          self.emit_bulk_op(&mut block, state, move |_vm_state: &vm::State, store: &mut Store, dst, src, len| {
+            table::copy(&mut store.tables, dst_table, dst, src_table, src, len)
+          })?;
+        },
+
+        RefNull(_) => {
+          state.push(Input::Const(StackValue::from_ref(None)));
+        },
+        RefIsNull => {
+          impl_unops_match_input!(state, val, {
+            val == 0
+          });
+        },
+        RefFunc(func_idx) => {
+          state.push(Input::Const(StackValue::from_ref(Some(*func_idx))));
+        },
+        TableGet(table_idx) => {
+          let table_idx = *table_idx as usize;
+          impl_unops_match_input!(state, vm_state, store, regs, idx, {
+            let elem = table::get(&store.tables[table_idx], idx as u32)
+              .map_err(|trap| store.trap_at(pc, trap))?;
+            StackValue::from_ref(elem).0
+          });
+        },
+        TableSet(table_idx) => {
+          let table_idx = *table_idx as usize;
+          let val = state.pop()?;
+          let idx = state.pop()?;
+          state.spill(&mut block, Spill::Ops);
+          block.push(impl_ops_match_input!(idx, {
+            impl_ops_match_input!(val, {
+              Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+                let idx = idx.eval(state, store, regs)?.0 as u32;
+                let val = val.eval(state, store, regs)?.to_ref();
+                table::set(&mut store.tables[table_idx], idx, val)
+                  .map_err(|trap| store.trap_at(pc, trap))?;
+                Ok(Action::End)
+              })
+            })
+          }));
+        },
+        TableSize(table_idx) => {
+          let table_idx = *table_idx as usize;
+          state.push(Input::Op(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<StackValue> {
+            Ok(StackValue(store.tables[table_idx].len() as u64))
+          })));
+        },
+        TableGrow(table_idx) => {
+          let table_idx = *table_idx as usize;
+          let max = self.module.tables()[table_idx].limits().maximum().unwrap_or(u32::MAX);
+          impl_binops_match_input!(state, vm_state, store, regs, val, delta, {
+            table::grow(&mut store.tables[table_idx], max, delta as u32, StackValue(val).to_ref()) as u32
+          });
+        },
+        TableFill(table_idx) => {
+          let table_idx = *table_idx as usize;
+          let len = state.pop()?;
+          let val = state.pop()?;
+          let dst = state.pop()?;
+          state.spill(&mut block, Spill::Ops);
+          block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let dst = dst.resolv(vm_state, store, regs)?.0 as u32;
+            let val = val.resolv(vm_state, store, regs)?.to_ref();
+            let len = len.resolv(vm_state, store, regs)?.0 as u32;
+            table::fill(&mut store.tables[table_idx], dst, val, len)
+              .map_err(|trap| store.trap_at(pc, trap))?;
+            Ok(Action::End)
+          }));
+        },
+
+        Simd(op) => v128_ops::compile(state, &mut block, op)?,
+        Atomic(op) => atomic_ops::compile(state, &mut block, op)?,
       };
       state.pc += 1;
     }
 
+    state.labels.pop();
     state.depth -= 1;
-    //eprintln!("end block: depth: {} {:?}, stack: {}", block.depth(), kind, state.len());
     Ok(block)
   }
 
+  /// Set a local to `val`, pending reads of the local must be spilled.
+  fn emit_set_local(&self, block: &mut Block, set_idx: Local, val: Input) {
+    if set_idx == 0 {
+      match val {
+        Input::Local(0) => {
+          // noop.  Get local 0 and set local 0.
+        },
+        Input::Local(local_idx) => {
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val = store.stack.get_local_val(local_idx, regs);
+            regs.0[0] = val;
+            Ok(Action::End)
+          }));
+        },
+        Input::Const(const_val) => {
+          block.push(Box::new(move |_state: &vm::State, _store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            regs.0[0] = const_val;
+            Ok(Action::End)
+          }));
+        },
+        Input::Stack(slot) => {
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            regs.0[0] = store.stack.get_operand(slot.0);
+            Ok(Action::End)
+          }));
+        },
+        Input::Op(closure) => {
+          block.push(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            regs.0[0] = closure(state, store, regs)?;
+            Ok(Action::End)
+          }));
+        },
+      }
+    } else {
+      block.push(match val {
+        Input::Local(0) => {
+          Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val = regs.0[0];
+            store.stack.set_local_val(set_idx, val, regs);
+            Ok(Action::End)
+          })
+        },
+        Input::Local(local_idx) => {
+          Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val = store.stack.get_local_val(local_idx, regs);
+            store.stack.set_local_val(set_idx, val, regs);
+            Ok(Action::End)
+          })
+        },
+        Input::Const(const_val) => {
+          Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val = const_val;
+            store.stack.set_local_val(set_idx, val, regs);
+            Ok(Action::End)
+          })
+        },
+        Input::Stack(slot) => {
+          Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val = store.stack.get_operand(slot.0);
+            store.stack.set_local_val(set_idx, val, regs);
+            Ok(Action::End)
+          })
+        },
+        Input::Op(closure) => {
+          Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let val = closure(state, store, regs)?;
+            store.stack.set_local_val(set_idx, val, regs);
+            Ok(Action::End)
+          })
+        },
+      });
+    }
+  }
+
+  /// Finish a block, saving its result.
+  fn end_block(&self, state: &mut State, block: &mut Block, arity: u32) -> Result<()> {
+    let label = state.label(0)?;
+    if !state.unreachable {
+      if block.depth() == 0 {
+        self.emit_return(state, block)?;
+      } else if arity > 0 {
+        let vals = state.pop_n(arity as usize)?;
+        self.emit_set_result(block, label.height, vals);
+      }
+    }
+    state.truncate(label.height as usize);
+    state.unreachable = false;
+    Ok(())
+  }
+
+  /// Save a block result in the operand slots at `height`.
+  fn emit_set_result(&self, block: &mut Block, height: u32, mut vals: Vec<Input>) {
+    let in_place = vals.iter().enumerate().all(|(idx, val)| {
+      matches!(val, Input::Stack(Slot(slot)) if *slot == height + idx as u32)
+    });
+    if in_place {
+      // Already in the right slots.
+      return;
+    }
+    if vals.len() > 1 {
+      block.push(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+        let res = resolv_results(&vals, state, store, regs)?;
+        store.stack.set_operands(height, &res[..vals.len()])?;
+        Ok(Action::End)
+      }));
+      return;
+    }
+    let Some(val) = vals.pop() else { return };
+    block.push(impl_ops_match_input!(val, {
+      Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+        let val = val.eval(state, store, regs)?;
+        store.stack.set_operand(height, val)?;
+        Ok(Action::End)
+      })
+    }));
+  }
+
   fn emit_return(&self, state: &mut State, block: &mut Block) -> Result<()> {
-    if self.ret_type.is_some() {
+    if self.ret_type == Some(ValueType::V128) {
+      let rets = state.pop_n(2)?;
+      state.spill(block, Spill::Ops);
+      block.push(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+        let vals = resolv_results(&rets, state, store, regs)?;
+        Ok(return_results(store, &vals))
+      }));
+    } else if self.ret_type.is_some() {
       let ret = state.pop()?;
+      state.spill(block, Spill::Ops);
       match ret {
         Input::Local(local_idx) => {
-          block.push(Box::new(move |_state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
-            let ret = store.stack.get_local_val(local_idx, _l0);
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            let ret = store.stack.get_local_val(local_idx, _regs);
             Ok(Action::Return(Some(StackValue(ret.0 as _))))
           }));
         },
         Input::Const(const_val) => {
-          block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
+          block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
             let ret = const_val;
             Ok(Action::Return(Some(StackValue(ret.0 as _))))
           }));
         },
+        Input::Stack(slot) => {
+          block.push(Box::new(move |_state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            let ret = store.stack.get_operand(slot.0);
+            Ok(Action::Return(Some(ret)))
+          }));
+        },
         Input::Op(closure) => {
-          block.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
-            let ret = closure(state, store, _l0)?;
+          block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+            let ret = closure(state, store, _regs)?;
             Ok(Action::Return(Some(StackValue(ret.0 as _))))
           }));
         },
       }
     } else {
-      block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
-        //eprintln!("--- run compiled RETURN: no value");
+      state.spill(block, Spill::Ops);
+      block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
         Ok(Action::Return(None))
       }));
     }
     Ok(())
   }
 
-  fn compile_loop(&self, state: &mut State) -> Result<Block> {
-     self.compile_block(state, BlockKind::Loop)
+  /// Emit a function call.  Calls that return a value are evaluated lazily
+  /// like other operations, calls without results are emitted as statements.
+  /// A `v128` result is saved in two operand slots right after the call.
+  fn emit_call<F: CallFunc>(&self, block: &mut Block, state: &mut State, mut params: Vec<Input>, ret_type: Option<ValueType>, call: F) {
+    if self.uses_exnref {
+      // The values of pending operations would be out of reach of the store
+      // while the callee catches exceptions.
+      state.spill(block, Spill::Ops);
+    }
+    let pc = state.pc;
+    let single = if params.len() == 1 { params.pop() } else { None };
+    let op: OpFunc = match (params.len(), single) {
+      (_, Some(param)) => {
+        impl_ops_match_input!(param, {
+          Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+            let val = param.eval(vm_state, store, regs)?;
+            let ret = call(vm_state, store, regs, &mut Registers::new(val))
+              .map_err(|trap| store.trap_at(pc, trap))?;
+            Ok(ret.unwrap_or(StackValue(0)))
+          })
+        })
+      },
+      (0, None) => {
+        Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+          let ret = call(vm_state, store, regs, &mut Registers::new(StackValue(0)))
+            .map_err(|trap| store.trap_at(pc, trap))?;
+          Ok(ret.unwrap_or(StackValue(0)))
+        })
+      },
+      _ => {
+        Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+          // Resolve inputs and push the values onto the stack.
+          let mut val = StackValue(0);
+          for (idx, param) in params.iter().enumerate() {
+            let param = param.resolv(vm_state, store, regs)?;
+            if idx == 0 {
+              val = param;
+            }
+            store.stack.push_val(param)?;
+          }
+          let ret = call(vm_state, store, regs, &mut Registers::new(val))
+            .map_err(|trap| store.trap_at(pc, trap))?;
+          Ok(ret.unwrap_or(StackValue(0)))
+        })
+      },
+    };
+    match ret_type {
+      Some(ValueType::V128) => {
+        state.spill(block, Spill::Ops);
+        let slot = state.len() as u32;
+        block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+          let low = op(vm_state, store, regs)?;
+          let high = store.stack.ret_high();
+          store.stack.set_operands(slot, &[low, high])?;
+          Ok(Action::End)
+        }));
+        state.push_slots(slot, 2);
+      },
+      Some(_) => state.push(Input::Op(op)),
+      None => {
+        state.spill(block, Spill::Ops);
+        block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+          op(vm_state, store, regs)?;
+          Ok(Action::End)
+        }));
+      },
+    }
+  }
+
+  /// Emit a tail call.  The parameters are passed like a normal call, then
+  /// the `TailCall` action unwinds to `Function::call`, which calls the
+  /// target in place of the current function without using native stack.
+  fn emit_tail_call<F>(&self, block: &mut Block, state: &mut State, params: Vec<Input>, target: F)
+    where F: Fn(&vm::State, &mut Store, &mut Registers) -> Trap<FuncAddr> + Send + Sync + 'static
+  {
+    state.spill(block, Spill::Ops);
+    let pc = state.pc;
+    block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      // Only push the parameters when there is more than one, a single
+      // parameter is passed in register 0.
+      let mut first = StackValue(0);
+      for (idx, param) in params.iter().enumerate() {
+        let param = param.resolv(vm_state, store, regs)?;
+        if idx == 0 {
+          first = param;
+        }
+        if params.len() > 1 {
+          store.stack.push_val(param)?;
+        }
+      }
+      let func_addr = target(vm_state, store, regs)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      // The target reuses the registers of this call.
+      *regs = Registers::new(first);
+      Ok(Action::TailCall(func_addr))
+    }));
+  }
+
+  /// Emit a bulk table operation, it takes three `i32` operands.
+  fn emit_bulk_op<F>(&self, block: &mut Block, state: &mut State, op: F) -> Result<()>
+    where F: Fn(&vm::State, &mut Store, u32, u32, u32) -> Trap<()> + Send + Sync + 'static
+  {
+    self.emit_memory_op(block, state, [memory::address_mask(false); 3], move |vm_state, store, dst, src, len| {
+      op(vm_state, store, dst as u32, src as u32, len as u32)
+    })
+  }
+
+  /// Emit a bulk memory operation, its three operands are `i32` or `i64`
+  /// values, truncated by `masks`.
+  fn emit_memory_op<F>(&self, block: &mut Block, state: &mut State, masks: [u64; 3], op: F) -> Result<()>
+    where F: Fn(&vm::State, &mut Store, u64, u64, u64) -> Trap<()> + Send + Sync + 'static
+  {
+    let len = state.pop()?;
+    let src = state.pop()?;
+    let dst = state.pop()?;
+    state.spill(block, Spill::Ops);
+    let pc = state.pc;
+    block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      let dst = dst.resolv(vm_state, store, regs)?.0 & masks[0];
+      let src = src.resolv(vm_state, store, regs)?.0 & masks[1];
+      let len = len.resolv(vm_state, store, regs)?.0 & masks[2];
+      op(vm_state, store, dst, src, len)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      Ok(Action::End)
+    }));
+    Ok(())
+  }
+
+  fn compile_loop(&self, state: &mut State, arity: u32) -> Result<Block> {
+     self.compile_block(state, BlockKind::Loop, arity)
   }
 
-  fn compile_br(&self, block: &mut Block, block_depth: u32) -> Result<()> {
-    //eprintln!("emit br: {:?}", block_depth);
-     block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _l0: &mut StackValue| -> Trap<Action> {
-       Ok(Action::Branch(block_depth))
-     }));
-     Ok(())
+  fn compile_br(&self, block: &mut Block, state: &mut State, block_depth: u32) -> Result<()> {
+    if state.is_function_label(block_depth) {
+      return self.emit_return(state, block);
+    }
+    let label = state.label(block_depth)?;
+    if label.arity > 0 {
+      let vals = state.pop_n(label.arity as usize)?;
+      state.spill(block, Spill::Ops);
+      self.emit_set_result(block, label.height, vals);
+    } else {
+      state.spill(block, Spill::Ops);
+    }
+    block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+      Ok(Action::Branch(block_depth))
+    }));
+    Ok(())
   }
 
   fn compile_br_if(&self, block: &mut Block, state: &mut State, block_depth: u32) -> Result<()> {
-    //eprintln!("emit br_if: {:?}", block_depth);
     // pop condition value.
     let val = state.pop()?;
+    let label = state.label(block_depth)?;
+    let is_return = state.is_function_label(block_depth);
+    if label.arity > 1 {
+      // The branch values stay on the stack, when the branch isn't taken.
+      state.spill(block, Spill::All);
+      let rets = state.copy_results(label.arity)?;
+      let height = label.height;
+      block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
+      {
+        let val = val.resolv(state, store, _regs)?;
+        if val.0 as u32 != 0 {
+          let vals = resolv_results(&rets, state, store, _regs)?;
+          if is_return {
+            return Ok(return_results(store, &vals));
+          }
+          store.stack.set_operands(height, &vals)?;
+          Ok(Action::Branch(block_depth))
+        } else {
+          Ok(Action::End)
+        }
+      }));
+      return Ok(());
+    }
+    if label.arity > 0 {
+      // The branch value stays on the stack, when the branch isn't taken.
+      state.spill(block, Spill::All);
+      let ret = state.values.last()
+        .and_then(|ret| ret.try_copy())
+        .ok_or_else(|| Error::ValidationError("Value stack empty".to_string()))?;
+      let height = label.height;
+      block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
+      {
+        let val = val.resolv(state, store, _regs)?;
+        if val.0 as u32 != 0 {
+          let ret = ret.resolv(state, store, _regs)?;
+          if is_return {
+            return Ok(Action::Return(Some(ret)));
+          }
+          store.stack.set_operand(height, ret)?;
+          Ok(Action::Branch(block_depth))
+        } else {
+          Ok(Action::End)
+        }
+      }));
+      return Ok(());
+    }
+    state.spill(block, Spill::Ops);
+    let taken = if is_return {
+      Action::Return(None)
+    } else {
+      Action::Branch(block_depth)
+    };
     match val {
       Input::Op(closure) => {
-        block.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action>
+        block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
         {
-          let val = closure(state, store, _l0)?;
-          if val.0 != 0 {
-            //eprintln!("branch: {:?}", val);
-            Ok(Action::Branch(block_depth))
+          let val = closure(state, store, _regs)?;
+          if val.0 as u32 != 0 {
+            Ok(taken.clone())
           } else {
-            //eprintln!("continue: {:?}", val);
             Ok(Action::End)
           }
         }));
       },
       _ => {
-        block.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action>
+        block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
         {
-          let val = val.resolv(state, store, _l0)?;
-          if val.0 != 0 {
-            //eprintln!("branch: {:?}", val);
-            Ok(Action::Branch(block_depth))
+          let val = val.resolv(state, store, _regs)?;
+          if val.0 as u32 != 0 {
+            Ok(taken.clone())
           } else {
-            //eprintln!("continue: {:?}", val);
             Ok(Action::End)
           }
         }));
@@ -577,49 +1851,82 @@ impl Compiler {
     Ok(())
   }
 
-  fn compile_if(&self, parent: &mut Block, state: &mut State) -> Result<()> {
+  fn compile_br_table(&self, block: &mut Block, state: &mut State, table: &[u32], default: u32) -> Result<()> {
+    // pop branch index.
+    let idx = state.pop()?;
+    let label = state.label(default)?;
+    let rets = if label.arity > 0 {
+      state.spill(block, Spill::All);
+      state.copy_results(label.arity)?
+    } else {
+      state.spill(block, Spill::Ops);
+      vec![]
+    };
+    // Lookup the depth and result slot for each target.
+    let targets = table.iter().map(|depth| {
+      state.label(*depth).map(|label| (*depth, label.height))
+    }).collect::<Result<Vec<_>>>()?;
+    let default = (default, label.height);
+    block.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
+    {
+      let idx = idx.resolv(state, store, _regs)?.0 as u32;
+      let (depth, height) = targets.get(idx as usize).copied().unwrap_or(default);
+      if !rets.is_empty() {
+        let vals = resolv_results(&rets, state, store, _regs)?;
+        store.stack.set_operands(height, &vals[..rets.len()])?;
+      }
+      Ok(Action::Branch(depth))
+    }));
+    Ok(())
+  }
+
+  fn compile_if(&self, parent: &mut Block, state: &mut State, arity: u32) -> Result<()> {
     // pop condition value.
     let val = state.pop()?;
+    state.spill(parent, Spill::All);
+    let height = state.len();
 
     // compile 'If' block.
-    let if_block = self.compile_block(state, BlockKind::If)?;
+    let if_block = self.compile_block(state, BlockKind::If, arity)?;
 
     // Check for Else block
-    use parity_wasm::elements::Instruction::*;
-    let else_block = match &self.code[state.pc] {
-      Else => {
-        Some(self.compile_else(state)?)
+    use loader::Instruction::*;
+    let else_block = match self.code.get(state.pc) {
+      Some(Else) => {
+        state.pc += 1;
+        Some(self.compile_else(state, arity)?)
       },
-      End => {
+      Some(End) => {
         None
       },
       _ => {
-        unreachable!("missing end of 'If' block");
+        return Err(Error::ValidationError("missing end of 'if' block".to_string()));
       }
     };
+    state.push_results(height, arity);
 
     // Build closure.
     if let Some(else_block) = else_block {
       match val {
         Input::Op(closure) => {
-          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action>
+          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
           {
-            let val = closure(state, store, _l0)?;
-            if val.0 == 0 {
-              else_block.run(state, store, _l0)
+            let val = closure(state, store, _regs)?;
+            if val.0 as u32 == 0 {
+              else_block.run(state, store, _regs)
             } else {
-              if_block.run(state, store, _l0)
+              if_block.run(state, store, _regs)
             }
           }));
         },
         _ => {
-          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action>
+          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
           {
-            let val = val.resolv(state, store, _l0)?;
-            if val.0 == 0 {
-              else_block.run(state, store, _l0)
+            let val = val.resolv(state, store, _regs)?;
+            if val.0 as u32 == 0 {
+              else_block.run(state, store, _regs)
             } else {
-              if_block.run(state, store, _l0)
+              if_block.run(state, store, _regs)
             }
           }));
         },
@@ -627,24 +1934,24 @@ impl Compiler {
     } else {
       match val {
         Input::Op(closure) => {
-          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action>
+          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
           {
-            let val = closure(state, store, _l0)?;
-            if val.0 == 0 {
+            let val = closure(state, store, _regs)?;
+            if val.0 as u32 == 0 {
               Ok(Action::End)
             } else {
-              if_block.run(state, store, _l0)
+              if_block.run(state, store, _regs)
             }
           }));
         },
         _ => {
-          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _l0: &mut StackValue| -> Trap<Action>
+          parent.push(Box::new(move |state: &vm::State, store: &mut Store, _regs: &mut Registers| -> Trap<Action>
           {
-            let val = val.resolv(state, store, _l0)?;
-            if val.0 == 0 {
+            let val = val.resolv(state, store, _regs)?;
+            if val.0 as u32 == 0 {
               Ok(Action::End)
             } else {
-              if_block.run(state, store, _l0)
+              if_block.run(state, store, _regs)
             }
           }));
         },
@@ -653,11 +1960,177 @@ impl Compiler {
     Ok(())
   }
 
-  fn compile_else(&self, state: &mut State) -> Result<Block> {
-    self.compile_block(state, BlockKind::Else)
+  fn compile_else(&self, state: &mut State, arity: u32) -> Result<Block> {
+    self.compile_block(state, BlockKind::Else, arity)
+  }
+
+  /// Compile a `try_table`.  An exception caught by one of its handlers
+  /// branches to the handler's label, with the values of the exception.
+  fn compile_try_table(&self, parent: &mut Block, state: &mut State, arity: u32, catches: &[loader::Catch]) -> Result<()> {
+    // The labels of the handlers are outside of the `try_table` block.
+    let handlers = catches.iter().map(|catch| {
+      let (tag, is_ref, depth) = match *catch {
+        loader::Catch::Catch(tag, depth) => (Some(tag), false, depth),
+        loader::Catch::CatchRef(tag, depth) => (Some(tag), true, depth),
+        loader::Catch::CatchAll(depth) => (None, false, depth),
+        loader::Catch::CatchAllRef(depth) => (None, true, depth),
+      };
+      Ok(CatchHandler {
+        tag,
+        is_ref,
+        depth,
+        height: state.label(depth)?.height,
+        is_return: state.is_function_label(depth),
+      })
+    }).collect::<Result<Vec<_>>>()?;
+    state.spill(parent, Spill::All);
+    let height = state.len();
+    let id = state.pc as u32;
+    state.pc += 1;
+    let body = self.compile_block_at(state, BlockKind::Block, arity, height, id)?;
+    state.push_results(height, arity);
+
+    parent.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      let len = store.stack.len();
+      let exn = match body.run(vm_state, store, regs) {
+        Err(TrapKind::Exception(exn)) if store.can_catch(id) => exn,
+        ret => return ret,
+      };
+      let handler = match handlers.iter().find(|handler| is_caught(vm_state, handler.tag, &exn)) {
+        Some(handler) => handler,
+        None => return Err(TrapKind::Exception(exn)),
+      };
+      store.catch_exception();
+      store.stack.truncate(len);
+      // Labels have one value, a `v128` takes two slots.
+      let mut values = match handler.tag {
+        Some(_) => exn.to_stack(),
+        None => vec![],
+      };
+      if handler.is_ref {
+        values.push(StackValue::from_ref(Some(store.add_exception(exn))));
+      }
+      if handler.is_return {
+        return Ok(return_results(store, &values));
+      }
+      store.stack.set_operands(handler.height, &values)?;
+      Ok(Action::Branch(handler.depth))
+    }));
+    Ok(())
+  }
+
+  /// Compile a legacy `try` block, with its `catch` blocks or `delegate`.
+  fn compile_try(&self, parent: &mut Block, state: &mut State, arity: u32) -> Result<()> {
+    state.spill(parent, Spill::All);
+    let height = state.len();
+    let id = state.pc as u32;
+    let outer = state.handler();
+    state.pc += 1;
+    let body = self.compile_block_at(state, BlockKind::Try, arity, height, id)?;
+
+    use loader::Instruction::*;
+    let mut handlers = Vec::new();
+    let mut delegate = None;
+    loop {
+      match self.code.get(state.pc) {
+        Some(Catch(tag_idx)) => {
+          state.pc += 1;
+          // The values of the exception are saved in the operand slots of
+          // the block.
+          let mut slot = height as u32;
+          for param in self.module.tags()[*tag_idx as usize].params() {
+            let count = ValueType::from(param).slots() as u32;
+            state.push_slots(slot, count);
+            slot += count;
+          }
+          let handler = self.compile_block_at(state, BlockKind::Catch, arity, height, outer)?;
+          handlers.push((Some(*tag_idx), handler));
+        },
+        Some(CatchAll) => {
+          state.pc += 1;
+          let handler = self.compile_block_at(state, BlockKind::Catch, arity, height, outer)?;
+          handlers.push((None, handler));
+        },
+        Some(Delegate(depth)) => {
+          // The label is outside of the `try` block.
+          delegate = Some(state.label(*depth)?.handler);
+          break;
+        },
+        Some(End) => break,
+        _ => {
+          return Err(Error::ValidationError("missing end of 'try' block".to_string()));
+        },
+      }
+    }
+    state.push_results(height, arity);
+
+    parent.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      let len = store.stack.len();
+      let exn = match body.run(vm_state, store, regs) {
+        Err(TrapKind::Exception(exn)) if store.can_catch(id) => exn,
+        ret => return ret,
+      };
+      if let Some(target) = delegate {
+        store.delegate_to(target);
+        return Err(TrapKind::Exception(exn));
+      }
+      let (tag, handler) = match handlers.iter().find(|(tag, _)| is_caught(vm_state, *tag, &exn)) {
+        Some(handler) => handler,
+        None => return Err(TrapKind::Exception(exn)),
+      };
+      store.catch_exception();
+      store.stack.truncate(len);
+      if tag.is_some() {
+        store.stack.set_operands(height as u32, &exn.to_stack())?;
+      }
+      store.caught.push(exn);
+      let ret = handler.run(vm_state, store, regs);
+      store.caught.pop();
+      ret
+    }));
+    Ok(())
   }
 }
 
+macro_rules! impl_mem_load {
+  ($name: ident, $mem_type: ty, $as_type: ty) => {
+    pub fn $name(state: &mut State, memarg: loader::MemArg) -> Result<()> {
+      let (memory, offset) = (memarg.memory as usize, memarg.offset);
+      let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+      impl_unops_match_input!(state, vm_state, store, regs, addr, {
+        let bytes = store.memories[memory].load(addr as u64 & mask, offset)
+          .map_err(|trap| store.trap_at(pc, trap))?;
+        <$mem_type>::from_le_bytes(bytes) as $as_type
+      });
+      Ok(())
+    }
+  };
+}
+
+macro_rules! impl_mem_store {
+  ($name: ident, $mem_type: ty) => {
+    pub fn $name(state: &mut State, block: &mut Block, memarg: loader::MemArg) -> Result<()> {
+      let (memory, offset) = (memarg.memory as usize, memarg.offset);
+      let val = state.pop()?;
+      let addr = state.pop()?;
+      state.spill(block, Spill::Ops);
+      let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+      block.push(impl_ops_match_input!(addr, {
+        impl_ops_match_input!(val, {
+          Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+            let addr = addr.eval(state, store, regs)?.0 as u64 & mask;
+            let val = val.eval(state, store, regs)?.0 as $mem_type;
+            store.memories[memory].store(addr, offset, val.to_le_bytes())
+              .map_err(|trap| store.trap_at(pc, trap))?;
+            Ok(Action::End)
+          })
+        })
+      }));
+      Ok(())
+    }
+  };
+}
+
 macro_rules! impl_int_binops {
   ($name: ident, $type: ty, $op: ident) => {
     pub fn $name(state: &mut State) -> Result<()> {
@@ -697,14 +2170,16 @@ macro_rules! impl_int_binops {
 macro_rules! impl_int_binops_div {
   ($name: ident, $type: ty, $op: ident, $as_type: ty) => {
     pub fn $name(state: &mut State) -> Result<()> {
-      impl_binops_match_input!(state, left, right, {
+      let pc = state.pc;
+      impl_binops_match_input!(state, vm_state, store, regs, left, right, {
         let res = (left as $type).$op(right as $type)
           .ok_or_else(|| {
-            if (right as $type) == 0 {
+            let trap = if (right as $type) == 0 {
               TrapKind::DivisionByZero
             } else {
-              TrapKind::InvalidConversionToInt
-            }
+              TrapKind::IntegerOverflow
+            };
+            store.trap_at(pc, trap)
           })?;
         (res as $as_type)
       });
@@ -713,6 +2188,22 @@ macro_rules! impl_int_binops_div {
   };
 }
 
+macro_rules! impl_int_binops_rem {
+  ($name: ident, $type: ty, $as_type: ty) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      let pc = state.pc;
+      impl_binops_match_input!(state, vm_state, store, regs, left, right, {
+        if (right as $type) == 0 {
+          return Err(store.trap_at(pc, TrapKind::DivisionByZero));
+        }
+        // `MIN % -1` overflows, but the result is zero.
+        (left as $type).wrapping_rem(right as $type) as $as_type
+      });
+      Ok(())
+    }
+  };
+}
+
 macro_rules! impl_int_relops {
   ($name: ident, $type: ty, $relop: expr) => {
     pub fn $name(state: &mut State) -> Result<()> {
@@ -732,47 +2223,67 @@ macro_rules! impl_int_relops {
   };
 }
 
-macro_rules! impl_numeric_ops {
-  ($op_mod: ident, $type: ty, $type_u: ty) => {
+/// Truncate a float towards zero, trapping if the result isn't in the range `min..max`.
+fn trunc_checked(val: f64, min: f64, max: f64) -> Trap<f64> {
+  if val.is_nan() {
+    return Err(TrapKind::InvalidConversionToInt);
+  }
+  let val = val.trunc();
+  if val < min || val >= max {
+    return Err(TrapKind::IntegerOverflow);
+  }
+  Ok(val)
+}
+
+macro_rules! impl_int_trunc {
+  ($name: ident, $float: ty, $bits: ty, $as_type: ty, $min: expr, $max: expr) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      let pc = state.pc;
+      impl_unops_match_input!(state, vm_state, store, regs, val, {
+        let val = <$float>::from_bits(val as $bits) as f64;
+        trunc_checked(val, $min, $max)
+          .map_err(|trap| store.trap_at(pc, trap))? as $as_type
+      });
+      Ok(())
+    }
+  };
+}
+
+/// Rust's float to int casts saturate, and convert NaN to zero.
+macro_rules! impl_int_trunc_sat {
+  ($name: ident, $float: ty, $bits: ty, $as_type: ty) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      impl_unops_match_input!(state, val, {
+        <$float>::from_bits(val as $bits) as $as_type
+      });
+      Ok(())
+    }
+  };
+}
+
+macro_rules! impl_numeric_ops {
+  ($op_mod: ident, $type: ty, $type_u: ty) => {
     #[allow(dead_code)]
     mod $op_mod {
       use std::ops::*;
       use super::*;
 
-      pub fn load(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn load8_s(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn load8_u(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn load16_s(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn load16_u(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn load32_s(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn load32_u(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
+      /// The range of the signed type as floats: `MIN..(MAX + 1)`.
+      const MIN: f64 = <$type>::MIN as f64;
+      const MAX: f64 = -MIN;
 
-      pub fn store(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn store8(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn store16(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
-      pub fn store32(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
+      impl_mem_load!(load, $type, $type);
+      impl_mem_load!(load8_s, i8, $type);
+      impl_mem_load!(load8_u, u8, $type);
+      impl_mem_load!(load16_s, i16, $type);
+      impl_mem_load!(load16_u, u16, $type);
+      impl_mem_load!(load32_s, i32, $type);
+      impl_mem_load!(load32_u, u32, $type);
+
+      impl_mem_store!(store, $type);
+      impl_mem_store!(store8, u8);
+      impl_mem_store!(store16, u16);
+      impl_mem_store!(store32, u32);
 
       impl_int_relops!(clz, $type, |val: $type| {
         val.leading_zeros()
@@ -783,25 +2294,34 @@ macro_rules! impl_numeric_ops {
       impl_int_relops!(popcnt, $type, |val: $type| {
         val.count_ones()
       });
+      impl_int_relops!(extend8_s, $type, |val: $type| {
+        val as i8 as $type
+      });
+      impl_int_relops!(extend16_s, $type, |val: $type| {
+        val as i16 as $type
+      });
+      impl_int_relops!(extend32_s, $type, |val: $type| {
+        val as i32 as $type
+      });
 
       impl_int_binops!(add, $type, wrapping_add);
       impl_int_binops!(sub, $type, wrapping_sub);
 
       impl_int_binops!(mul, $type, wrapping_mul);
 
-      impl_int_binops_div!(div_s, $type, checked_div, i64);
-      impl_int_binops_div!(div_u, $type, checked_div, u64);
-      impl_int_binops_div!(rem_s, $type, checked_rem, i64);
-      impl_int_binops_div!(rem_u, $type, checked_rem, u64);
+      impl_int_binops_div!(div_s, $type, checked_div, $type);
+      impl_int_binops_div!(div_u, $type_u, checked_div, $type_u);
+      impl_int_binops_rem!(rem_s, $type, $type);
+      impl_int_binops_rem!(rem_u, $type_u, $type_u);
 
       impl_int_binops!(and, $type, bitand);
       impl_int_binops!(or, $type, bitor);
       impl_int_binops!(xor, $type, bitxor);
-      impl_int_binops!(shl, $type, wrapping_shl, $type_u, 0x1F);
-      impl_int_binops!(shr_s, $type, wrapping_shr, $type_u, 0x1F);
-      impl_int_binops!(shr_u, $type, wrapping_shr, $type_u, 0x1F);
-      impl_int_binops!(rotl, $type, u32, rotate_left, u64);
-      impl_int_binops!(rotr, $type, u32, rotate_right, u64);
+      impl_int_binops!(shl, $type, wrapping_shl, $type, (<$type>::BITS - 1) as $type);
+      impl_int_binops!(shr_s, $type, wrapping_shr, $type, (<$type>::BITS - 1) as $type);
+      impl_int_binops!(shr_u, $type_u, wrapping_shr, $type_u, (<$type>::BITS - 1) as $type_u);
+      impl_int_binops!(rotl, $type, u32, rotate_left, $type);
+      impl_int_binops!(rotr, $type, u32, rotate_right, $type);
 
       impl_int_relops!(eqz, $type, |val| {
         val == Default::default()
@@ -837,19 +2357,15 @@ macro_rules! impl_numeric_ops {
         left >= right
       });
 
-      pub fn trunc_s_f32(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn trunc_u_f32(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn trunc_s_f64(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn trunc_u_f64(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
+      impl_int_trunc!(trunc_s_f32, f32, u32, $type, MIN, MAX);
+      impl_int_trunc!(trunc_u_f32, f32, u32, $type_u, 0.0, MAX * 2.0);
+      impl_int_trunc!(trunc_s_f64, f64, u64, $type, MIN, MAX);
+      impl_int_trunc!(trunc_u_f64, f64, u64, $type_u, 0.0, MAX * 2.0);
 
+      impl_int_trunc_sat!(trunc_sat_s_f32, f32, u32, $type);
+      impl_int_trunc_sat!(trunc_sat_u_f32, f32, u32, $type_u);
+      impl_int_trunc_sat!(trunc_sat_s_f64, f64, u64, $type);
+      impl_int_trunc_sat!(trunc_sat_u_f64, f64, u64, $type_u);
     }
   };
 }
@@ -857,110 +2373,834 @@ macro_rules! impl_numeric_ops {
 impl_numeric_ops!(i32_ops, i32, u32);
 impl_numeric_ops!(i64_ops, i64, u64);
 
+macro_rules! impl_float_unops {
+  ($name: ident, $type: ty, $bits: ty, $op: expr) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      impl_unops_match_input!(state, val, {
+        let res: $type = $op(<$type>::from_bits(val as $bits));
+        res.to_bits()
+      });
+      Ok(())
+    }
+  };
+}
+
+macro_rules! impl_float_binops {
+  ($name: ident, $type: ty, $bits: ty, $op: expr) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      impl_binops_match_input!(state, left, right, {
+        let res: $type = $op(<$type>::from_bits(left as $bits), <$type>::from_bits(right as $bits));
+        res.to_bits()
+      });
+      Ok(())
+    }
+  };
+}
+
+macro_rules! impl_float_relops {
+  ($name: ident, $type: ty, $bits: ty, $relop: expr) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      impl_binops_match_input!(state, left, right, {
+        $relop(<$type>::from_bits(left as $bits), <$type>::from_bits(right as $bits))
+      });
+      Ok(())
+    }
+  };
+}
+
+macro_rules! impl_float_convert {
+  ($name: ident, $type: ty, $from_type: ty) => {
+    pub fn $name(state: &mut State) -> Result<()> {
+      impl_unops_match_input!(state, val, {
+        ((val as $from_type) as $type).to_bits()
+      });
+      Ok(())
+    }
+  };
+}
+
 macro_rules! impl_float_numeric_ops {
-  ($op_mod: ident, $type: ty) => {
+  ($op_mod: ident, $type: ty, $bits: ty) => {
     #[allow(dead_code)]
     mod $op_mod {
 
       use super::*;
 
-      pub fn load(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
+      impl_mem_load!(load, $bits, $bits);
+
+      impl_mem_store!(store, $bits);
+
+      impl_float_unops!(abs, $type, $bits, |val: $type| val.abs());
+      impl_float_unops!(neg, $type, $bits, |val: $type| -val);
+      impl_float_unops!(ceil, $type, $bits, |val: $type| val.ceil());
+      impl_float_unops!(floor, $type, $bits, |val: $type| val.floor());
+      impl_float_unops!(trunc, $type, $bits, |val: $type| val.trunc());
+      impl_float_unops!(nearest, $type, $bits, |val: $type| val.round_ties_even());
+      impl_float_unops!(sqrt, $type, $bits, |val: $type| val.sqrt());
+
+      impl_float_binops!(add, $type, $bits, |left: $type, right: $type| left + right);
+      impl_float_binops!(sub, $type, $bits, |left: $type, right: $type| left - right);
+      impl_float_binops!(mul, $type, $bits, |left: $type, right: $type| left * right);
+      impl_float_binops!(div, $type, $bits, |left: $type, right: $type| left / right);
+      impl_float_binops!(min, $type, $bits, |left: $type, right: $type| {
+        if left.is_nan() || right.is_nan() {
+          <$type>::NAN
+        } else if left == right {
+          // `min(-0.0, 0.0)` is `-0.0`
+          if left.is_sign_negative() { left } else { right }
+        } else {
+          left.min(right)
+        }
+      });
+      impl_float_binops!(max, $type, $bits, |left: $type, right: $type| {
+        if left.is_nan() || right.is_nan() {
+          <$type>::NAN
+        } else if left == right {
+          // `max(-0.0, 0.0)` is `0.0`
+          if left.is_sign_positive() { left } else { right }
+        } else {
+          left.max(right)
+        }
+      });
+      impl_float_binops!(copysign, $type, $bits, |left: $type, right: $type| left.copysign(right));
+
+      impl_float_relops!(eq, $type, $bits, |left, right| left == right);
+      impl_float_relops!(ne, $type, $bits, |left, right| left != right);
+      impl_float_relops!(lt, $type, $bits, |left, right| left < right);
+      impl_float_relops!(gt, $type, $bits, |left, right| left > right);
+      impl_float_relops!(le, $type, $bits, |left, right| left <= right);
+      impl_float_relops!(ge, $type, $bits, |left, right| left >= right);
+
+      impl_float_convert!(convert_s_i32, $type, i32);
+      impl_float_convert!(convert_u_i32, $type, u32);
+      impl_float_convert!(convert_s_i64, $type, i64);
+      impl_float_convert!(convert_u_i64, $type, u64);
+    }
+  };
+}
 
-      pub fn store(_store: &mut Store, _l0: &mut StackValue, _offset: u32) -> Trap<()> {
-        todo!();
-      }
+impl_float_numeric_ops!(f32_ops, f32, u32);
+impl_float_numeric_ops!(f64_ops, f64, u64);
 
-      pub fn abs(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn neg(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn ceil(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn floor(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn trunc(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn nearest(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn sqrt(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
+mod conv_ops {
+  use super::*;
 
-      pub fn add(store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        let (left, right) = store.stack.pop_pair()? as ($type, $type);
-        let res = left + right;
-        store.stack.push(res)?;
-        Ok(())
-      }
+  pub fn wrap_i64(state: &mut State) -> Result<()> {
+    impl_unops_match_input!(state, val, {
+      val as i32
+    });
+    Ok(())
+  }
 
-      pub fn sub(store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        let (left, right) = store.stack.pop_pair()? as ($type, $type);
-        let res = left - right;
-        store.stack.push(res)?;
-        Ok(())
-      }
+  pub fn extend_s_i32(state: &mut State) -> Result<()> {
+    impl_unops_match_input!(state, val, {
+      val as i32 as i64
+    });
+    Ok(())
+  }
 
-      pub fn mul(store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        let (left, right) = store.stack.pop_pair()? as ($type, $type);
-        let res = left * right;
-        store.stack.push(res)?;
-        Ok(())
-      }
-      pub fn div(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn min(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn max(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn copysign(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
+  pub fn extend_u_i32(state: &mut State) -> Result<()> {
+    impl_unops_match_input!(state, val, {
+      val as u32 as u64
+    });
+    Ok(())
+  }
 
-      pub fn eq(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn ne(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn lt(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn gt(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn le(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn ge(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn convert_s_i32(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
-      }
-      pub fn convert_u_i32(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
+  pub fn demote_f64(state: &mut State) -> Result<()> {
+    impl_unops_match_input!(state, val, {
+      (f64::from_bits(val as u64) as f32).to_bits()
+    });
+    Ok(())
+  }
+
+  pub fn promote_f32(state: &mut State) -> Result<()> {
+    impl_unops_match_input!(state, val, {
+      (f32::from_bits(val as u32) as f64).to_bits()
+    });
+    Ok(())
+  }
+
+  /// Only the low 32 bits of 32-bit values are used, this just normalizes
+  /// the upper bits.
+  pub fn reinterpret_32(state: &mut State) -> Result<()> {
+    impl_unops_match_input!(state, val, {
+      val as u32
+    });
+    Ok(())
+  }
+}
+
+/// Wide arithmetic, the 128-bit results are pushed as two `i64` values: the
+/// low and high halves.  Inputs produce one value, so both halves are saved
+/// in operand slots right away.  Without multi-value support a block or
+/// function can't return them together.
+mod wide_ops {
+  use super::*;
+
+  pub fn add128(state: &mut State, block: &mut Block) -> Result<()> {
+    wide(state, block, |[lo1, hi1, lo2, hi2]: [u64; 4]| {
+      join(lo1, hi1).wrapping_add(join(lo2, hi2))
+    })
+  }
+
+  pub fn sub128(state: &mut State, block: &mut Block) -> Result<()> {
+    wide(state, block, |[lo1, hi1, lo2, hi2]: [u64; 4]| {
+      join(lo1, hi1).wrapping_sub(join(lo2, hi2))
+    })
+  }
+
+  pub fn mul_wide_s(state: &mut State, block: &mut Block) -> Result<()> {
+    wide(state, block, |[left, right]: [u64; 2]| {
+      (left as i64 as i128 * right as i64 as i128) as u128
+    })
+  }
+
+  pub fn mul_wide_u(state: &mut State, block: &mut Block) -> Result<()> {
+    wide(state, block, |[left, right]: [u64; 2]| {
+      left as u128 * right as u128
+    })
+  }
+
+  fn join(lo: u64, hi: u64) -> u128 {
+    (hi as u128) << 64 | lo as u128
+  }
+
+  /// Evaluate the `N` operands, compute the result and save both halves in
+  /// their operand slots, all in one closure.
+  fn wide<const N: usize, F>(state: &mut State, block: &mut Block, op: F) -> Result<()>
+    where F: Fn([u64; N]) -> u128 + Send + Sync + 'static
+  {
+    let inputs = state.pop_n(N)?;
+    state.spill(block, Spill::Ops);
+    let slot = state.len() as u32;
+    block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      let mut args = [0u64; N];
+      for (arg, input) in args.iter_mut().zip(inputs.iter()) {
+        *arg = input.resolv(vm_state, store, regs)?.0;
       }
-      pub fn convert_s_i64(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
+      let res = op(args);
+      store.stack.set_operands(slot, &[StackValue::from(res as i64), StackValue::from((res >> 64) as i64)])?;
+      Ok(Action::End)
+    }));
+    state.push(Input::Stack(Slot(slot)));
+    state.push(Input::Stack(Slot(slot + 1)));
+    state.update_max_height();
+    Ok(())
+  }
+}
+
+/// SIMD instructions, a `v128` takes two operand slots: the low and high
+/// halves.  Vector results are saved in their operand slots right away, like
+/// the results of `wide_ops`, scalar results are evaluated lazily.
+mod v128_ops {
+  use super::*;
+  use crate::isa::SimdInstruction;
+  use crate::simd::{self, Lanes};
+
+  /// An operand of a SIMD instruction.
+  #[derive(Clone, Copy)]
+  enum Operand {
+    Vector,
+    Scalar,
+  }
+
+  use Operand::*;
+
+  pub fn compile(state: &mut State, block: &mut Block, op: &SimdInstruction) -> Result<()> {
+    use SimdInstruction::*;
+    // Most instructions are a lane-wise function of their operands.
+    if let Some(op) = simd::unop(op) {
+      return vector(state, block, [Vector], move |_, [val]| Ok(op(val)));
+    }
+    if let Some(op) = simd::test(op) {
+      return scalar(state, move |val| op(val) as u64);
+    }
+    if let Some(op) = simd::splat(op) {
+      return vector(state, block, [Scalar], move |_, [val]| Ok(op(val)));
+    }
+    if let Some(op) = simd::binop(op) {
+      return vector(state, block, [Vector, Vector], move |_, [left, right]| Ok(op(left, right)));
+    }
+    if let Some(op) = simd::shift(op) {
+      return vector(state, block, [Vector, Scalar], move |_, [val, count]| Ok(op(val, count as u32)));
+    }
+    match *op {
+      V128Load(memarg) => load::<16, _>(state, block, memarg, u128::from_le_bytes)?,
+      V128Load8x8S(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        simd::extend::<i8, i16, 16, 8>(u64::from_le_bytes(bytes) as u128, 0)
+      })?,
+      V128Load8x8U(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        simd::extend::<u8, u16, 16, 8>(u64::from_le_bytes(bytes) as u128, 0)
+      })?,
+      V128Load16x4S(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        simd::extend::<i16, i32, 8, 4>(u64::from_le_bytes(bytes) as u128, 0)
+      })?,
+      V128Load16x4U(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        simd::extend::<u16, u32, 8, 4>(u64::from_le_bytes(bytes) as u128, 0)
+      })?,
+      V128Load32x2S(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        simd::extend::<i32, i64, 4, 2>(u64::from_le_bytes(bytes) as u128, 0)
+      })?,
+      V128Load32x2U(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        simd::extend::<u32, u64, 4, 2>(u64::from_le_bytes(bytes) as u128, 0)
+      })?,
+      V128Load8Splat(memarg) => load::<1, _>(state, block, memarg, |bytes| {
+        [bytes[0]; 16].to_v128()
+      })?,
+      V128Load16Splat(memarg) => load::<2, _>(state, block, memarg, |bytes| {
+        [u16::from_le_bytes(bytes); 8].to_v128()
+      })?,
+      V128Load32Splat(memarg) => load::<4, _>(state, block, memarg, |bytes| {
+        [u32::from_le_bytes(bytes); 4].to_v128()
+      })?,
+      V128Load64Splat(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        [u64::from_le_bytes(bytes); 2].to_v128()
+      })?,
+      V128Load32Zero(memarg) => load::<4, _>(state, block, memarg, |bytes| {
+        u32::from_le_bytes(bytes) as u128
+      })?,
+      V128Load64Zero(memarg) => load::<8, _>(state, block, memarg, |bytes| {
+        u64::from_le_bytes(bytes) as u128
+      })?,
+      V128Store(memarg) => store_lane::<16>(state, block, memarg, 0)?,
+      V128Load8Lane(memarg, lane) => load_lane::<1>(state, block, memarg, lane)?,
+      V128Load16Lane(memarg, lane) => load_lane::<2>(state, block, memarg, lane)?,
+      V128Load32Lane(memarg, lane) => load_lane::<4>(state, block, memarg, lane)?,
+      V128Load64Lane(memarg, lane) => load_lane::<8>(state, block, memarg, lane)?,
+      V128Store8Lane(memarg, lane) => store_lane::<1>(state, block, memarg, lane)?,
+      V128Store16Lane(memarg, lane) => store_lane::<2>(state, block, memarg, lane)?,
+      V128Store32Lane(memarg, lane) => store_lane::<4>(state, block, memarg, lane)?,
+      V128Store64Lane(memarg, lane) => store_lane::<8>(state, block, memarg, lane)?,
+
+      V128Const(val) => {
+        let [low, high] = StackValue::split_v128(val);
+        state.push_v128(Input::Const(low), Input::Const(high));
+      },
+      V128Bitselect => {
+        vector(state, block, [Vector, Vector, Vector], |_, [left, right, mask]| {
+          Ok(simd::bitselect(left, right, mask))
+        })?;
+      },
+      I8x16Shuffle(lanes) => {
+        vector(state, block, [Vector, Vector], move |_, [left, right]| {
+          Ok(simd::shuffle(left, right, lanes))
+        })?;
+      },
+
+      I8x16ExtractLaneS(lane) => {
+        scalar(state, move |val| simd::extract_lane(val, 1, lane) as i8 as u64)?;
+      },
+      I8x16ExtractLaneU(lane) => {
+        scalar(state, move |val| simd::extract_lane(val, 1, lane) as u64)?;
+      },
+      I16x8ExtractLaneS(lane) => {
+        scalar(state, move |val| simd::extract_lane(val, 2, lane) as i16 as u64)?;
+      },
+      I16x8ExtractLaneU(lane) => {
+        scalar(state, move |val| simd::extract_lane(val, 2, lane) as u64)?;
+      },
+      I32x4ExtractLane(lane) | F32x4ExtractLane(lane) => {
+        scalar(state, move |val| simd::extract_lane(val, 4, lane) as u64)?;
+      },
+      I64x2ExtractLane(lane) | F64x2ExtractLane(lane) => {
+        scalar(state, move |val| simd::extract_lane(val, 8, lane) as u64)?;
+      },
+      I8x16ReplaceLane(lane) => replace_lane(state, block, 1, lane)?,
+      I16x8ReplaceLane(lane) => replace_lane(state, block, 2, lane)?,
+      I32x4ReplaceLane(lane) | F32x4ReplaceLane(lane) => replace_lane(state, block, 4, lane)?,
+      I64x2ReplaceLane(lane) | F64x2ReplaceLane(lane) => replace_lane(state, block, 8, lane)?,
+      _ => {
+        return Err(Error::ValidationError(format!("Unsupported SIMD instruction: {:?}", op)));
+      },
+    }
+    Ok(())
+  }
+
+  fn replace_lane(state: &mut State, block: &mut Block, width: u32, lane: u8) -> Result<()> {
+    vector(state, block, [Vector, Scalar], move |_, [val, x]| {
+      Ok(simd::replace_lane(val, width, lane, x))
+    })
+  }
+
+  /// Load `N` bytes and convert them to a vector.
+  fn load<const N: usize, F>(state: &mut State, block: &mut Block, memarg: loader::MemArg, convert: F) -> Result<()>
+    where F: Fn([u8; N]) -> u128 + Send + Sync + 'static
+  {
+    let (memory, offset) = (memarg.memory as usize, memarg.offset);
+    let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+    vector(state, block, [Scalar], move |store, [addr]| {
+      let bytes = store.memories[memory].load::<N>(addr as u64 & mask, offset)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      Ok(convert(bytes))
+    })
+  }
+
+  /// Load `N` bytes into a lane of a vector.
+  fn load_lane<const N: usize>(state: &mut State, block: &mut Block, memarg: loader::MemArg, lane: u8) -> Result<()> {
+    let (memory, offset) = (memarg.memory as usize, memarg.offset);
+    let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+    vector(state, block, [Scalar, Vector], move |store, [addr, val]| {
+      let bytes = store.memories[memory].load::<N>(addr as u64 & mask, offset)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      let mut lane_bytes = [0u8; 16];
+      lane_bytes[..N].copy_from_slice(&bytes);
+      Ok(simd::replace_lane(val, N as u32, lane, u128::from_le_bytes(lane_bytes)))
+    })
+  }
+
+  /// Store the `N` bytes of a lane, or the whole vector.
+  fn store_lane<const N: usize>(state: &mut State, block: &mut Block, memarg: loader::MemArg, lane: u8) -> Result<()> {
+    let (memory, offset) = (memarg.memory as usize, memarg.offset);
+    let inputs = state.pop_n(slots(&[Scalar, Vector]))?;
+    state.spill(block, Spill::Ops);
+    let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+    block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      let [addr, val] = resolv(&inputs, [Scalar, Vector], vm_state, store, regs)?;
+      let val = if N == 16 { val } else { simd::extract_lane(val, N as u32, lane) };
+      let mut bytes = [0u8; N];
+      bytes.copy_from_slice(&val.to_le_bytes()[..N]);
+      store.memories[memory].store(addr as u64 & mask, offset, bytes)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      Ok(Action::End)
+    }));
+    Ok(())
+  }
+
+  /// Number of operand slots of `operands`.
+  fn slots(operands: &[Operand]) -> usize {
+    operands.iter().map(|operand| match operand {
+      Vector => 2,
+      Scalar => 1,
+    }).sum()
+  }
+
+  /// Resolve the inputs of `operands`, a scalar is zero extended.
+  fn resolv<const N: usize>(inputs: &[Input], operands: [Operand; N], vm_state: &vm::State, store: &mut Store, regs: &mut Registers) -> Trap<[u128; N]> {
+    let mut vals = [0u128; N];
+    let mut idx = 0;
+    for (val, operand) in vals.iter_mut().zip(operands) {
+      let low = inputs[idx].resolv(vm_state, store, regs)?;
+      *val = match operand {
+        Vector => StackValue::join_v128(low, inputs[idx + 1].resolv(vm_state, store, regs)?),
+        Scalar => low.0 as u128,
+      };
+      idx += slots(&[operand]);
+    }
+    Ok(vals)
+  }
+
+  /// Evaluate the operands, compute the vector result and save both halves
+  /// in their operand slots, all in one closure.
+  fn vector<const N: usize, F>(state: &mut State, block: &mut Block, operands: [Operand; N], op: F) -> Result<()>
+    where F: Fn(&mut Store, [u128; N]) -> Trap<u128> + Send + Sync + 'static
+  {
+    let inputs = state.pop_n(slots(&operands))?;
+    state.spill(block, Spill::Ops);
+    let slot = state.len() as u32;
+    block.push(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+      let vals = resolv(&inputs, operands, vm_state, store, regs)?;
+      let res = op(store, vals)?;
+      store.stack.set_operands(slot, &StackValue::split_v128(res))?;
+      Ok(Action::End)
+    }));
+    state.push_slots(slot, 2);
+    Ok(())
+  }
+
+  /// A scalar function of a vector, evaluated lazily.
+  fn scalar<F>(state: &mut State, op: F) -> Result<()>
+    where F: Fn(u128) -> u64 + Send + Sync + 'static
+  {
+    let inputs = state.pop_n(2)?;
+    state.push(Input::Op(Box::new(move |vm_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+      let [val] = resolv(&inputs, [Vector], vm_state, store, regs)?;
+      Ok(StackValue(op(val)))
+    })));
+    Ok(())
+  }
+}
+
+mod atomic_ops {
+  use super::*;
+  use crate::isa::{AtomicInstruction, AtomicRmwOp};
+
+  pub fn compile(state: &mut State, block: &mut Block, op: &AtomicInstruction) -> Result<()> {
+    match *op {
+      AtomicInstruction::MemoryAtomicNotify(memarg) => {
+        let (memory, offset) = (memarg.memory as usize, memarg.offset);
+        let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+        impl_binops_match_input!(state, vm_state, store, regs, addr, count, {
+          store.memories[memory].atomic_notify(addr as u64 & mask, offset, count as u32)
+            .map_err(|trap| store.trap_at(pc, trap))?
+        });
+      },
+      AtomicInstruction::MemoryAtomicWait32(memarg) => wait(state, memarg, 4)?,
+      AtomicInstruction::MemoryAtomicWait64(memarg) => wait(state, memarg, 8)?,
+      AtomicInstruction::AtomicFence => {
+        state.spill(block, Spill::Ops);
+        block.push(Box::new(move |_state: &vm::State, _store: &mut Store, _regs: &mut Registers| -> Trap<Action> {
+          std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+          Ok(Action::End)
+        }));
+      },
+      AtomicInstruction::Load(ty, bytes, memarg) => {
+        let (memory, offset) = (memarg.memory as usize, memarg.offset);
+        let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+        impl_unops_match_input!(state, vm_state, store, regs, addr, {
+          let val = store.memories[memory].atomic_load(addr as u64 & mask, offset, bytes)
+            .map_err(|trap| store.trap_at(pc, trap))?;
+          extend(ty, val)
+        });
+      },
+      AtomicInstruction::Store(_, bytes, memarg) => {
+        let (memory, offset) = (memarg.memory as usize, memarg.offset);
+        let val = state.pop()?;
+        let addr = state.pop()?;
+        state.spill(block, Spill::Ops);
+        let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+        block.push(impl_ops_match_input!(addr, {
+          impl_ops_match_input!(val, {
+            Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+              let addr = addr.eval(state, store, regs)?.0 & mask;
+              let val = val.eval(state, store, regs)?.0;
+              store.memories[memory].atomic_rmw(addr, offset, bytes, |_| Some(val))
+                .map_err(|trap| store.trap_at(pc, trap))?;
+              Ok(Action::End)
+            })
+          })
+        }));
+      },
+      AtomicInstruction::Rmw(rmw_op, ty, bytes, memarg) => {
+        let (memory, offset) = (memarg.memory as usize, memarg.offset);
+        let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+        impl_binops_match_input!(state, vm_state, store, regs, addr, val, {
+          let val = val as u64;
+          let old = store.memories[memory].atomic_rmw(addr as u64 & mask, offset, bytes, |old| {
+            Some(match rmw_op {
+              AtomicRmwOp::Add => old.wrapping_add(val),
+              AtomicRmwOp::Sub => old.wrapping_sub(val),
+              AtomicRmwOp::And => old & val,
+              AtomicRmwOp::Or => old | val,
+              AtomicRmwOp::Xor => old ^ val,
+              AtomicRmwOp::Xchg => val,
+            })
+          }).map_err(|trap| store.trap_at(pc, trap))?;
+          extend(ty, old)
+        });
+      },
+      AtomicInstruction::Cmpxchg(ty, bytes, memarg) => {
+        let (memory, offset) = (memarg.memory as usize, memarg.offset);
+        let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+        let width_mask = if bytes >= 8 { u64::MAX } else { (1 << (bytes * 8)) - 1 };
+        let replacement = state.pop()?;
+        let expected = state.pop()?;
+        let addr = state.pop()?;
+        state.push(Input::Op(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+          let addr = addr.resolv(state, store, regs)?.0 & mask;
+          let expected = expected.resolv(state, store, regs)?.0 & width_mask;
+          let replacement = replacement.resolv(state, store, regs)?.0;
+          let old = store.memories[memory].atomic_rmw(addr, offset, bytes, |old| {
+            Some(replacement).filter(|_| old == expected)
+          }).map_err(|trap| store.trap_at(pc, trap))?;
+          Ok(StackValue(extend(ty, old) as _))
+        })));
+      },
+    }
+    Ok(())
+  }
+
+  /// Extend a loaded value to the stack representation of `ty`.
+  fn extend(ty: loader::ValueType, val: u64) -> i64 {
+    match ty {
+      loader::ValueType::I32 => val as u32 as i32 as i64,
+      _ => val as i64,
+    }
+  }
+
+  /// `memory.atomic.wait32` and `memory.atomic.wait64`, returns 0 when
+  /// notified, 1 if the value isn't the expected value and 2 on a timeout.
+  fn wait(state: &mut State, memarg: loader::MemArg, bytes: u32) -> Result<()> {
+    let (memory, offset) = (memarg.memory as usize, memarg.offset);
+    let (mask, pc) = (state.address_mask(memarg.memory), state.pc);
+    let timeout = state.pop()?;
+    let expected = state.pop()?;
+    let addr = state.pop()?;
+    state.push(Input::Op(Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+      let addr = addr.resolv(state, store, regs)?.0 & mask;
+      let expected = expected.resolv(state, store, regs)?.0;
+      let timeout = timeout.resolv(state, store, regs)?.0 as i64;
+      let res = store.memories[memory].atomic_wait(addr, offset, bytes, expected, timeout)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      Ok(StackValue(res as u64))
+    })));
+    Ok(())
+  }
+}
+
+/// Superinstructions: common sequences of instructions compiled into one
+/// closure, without the boxed `Input::Op` of the inner instructions.
+mod fused_ops {
+  use super::*;
+  use super::Block;
+  use loader::Instruction::{self, *};
+
+  /// The integer types of the fused instructions.
+  trait Int: FromValue + Into<StackValue> + Copy + PartialEq + Send + Sync + 'static {
+    const ZERO: Self;
+
+    fn add(self, other: Self) -> Self;
+  }
+
+  impl Int for i32 {
+    const ZERO: Self = 0;
+
+    fn add(self, other: Self) -> Self {
+      self.wrapping_add(other)
+    }
+  }
+
+  impl Int for i64 {
+    const ZERO: Self = 0;
+
+    fn add(self, other: Self) -> Self {
+      self.wrapping_add(other)
+    }
+  }
+
+  /// Call `$func` with the operator of a binop that can't trap, or evaluate
+  /// to `Ok(false)` for other instructions.
+  macro_rules! match_binop {
+    ($op:expr, $t:ty, $t_u:ty, [$add:ident, $sub:ident, $mul:ident, $and:ident, $or:ident, $xor:ident,
+      $shl:ident, $shr_s:ident, $shr_u:ident], $func:ident($($arg:expr),*)) => {
+      match $op {
+        $add => $func($($arg,)* <$t>::wrapping_add),
+        $sub => $func($($arg,)* <$t>::wrapping_sub),
+        $mul => $func($($arg,)* <$t>::wrapping_mul),
+        $and => $func($($arg,)* |left: $t, right: $t| left & right),
+        $or => $func($($arg,)* |left: $t, right: $t| left | right),
+        $xor => $func($($arg,)* |left: $t, right: $t| left ^ right),
+        $shl => $func($($arg,)* |left: $t, right: $t| left.wrapping_shl(right as u32)),
+        $shr_s => $func($($arg,)* |left: $t, right: $t| left.wrapping_shr(right as u32)),
+        $shr_u => $func($($arg,)* |left: $t, right: $t| (left as $t_u).wrapping_shr(right as u32) as $t),
+        _ => Ok(false),
       }
-      pub fn convert_u_i64(_store: &mut Store, _l0: &mut StackValue) -> Trap<()> {
-        todo!();
+    };
+  }
+
+  /// Call `$func` with the comparison of a relop, or evaluate to `Ok(false)`
+  /// for other instructions.
+  macro_rules! match_relop {
+    ($op:expr, $t:ty, $t_u:ty, [$eq:ident, $ne:ident, $lt_s:ident, $lt_u:ident, $gt_s:ident, $gt_u:ident,
+      $le_s:ident, $le_u:ident, $ge_s:ident, $ge_u:ident], $func:ident($($arg:expr),*)) => {
+      match $op {
+        $eq => $func($($arg,)* |left: $t, right: $t| left == right),
+        $ne => $func($($arg,)* |left: $t, right: $t| left != right),
+        $lt_s => $func($($arg,)* |left: $t, right: $t| left < right),
+        $lt_u => $func($($arg,)* |left: $t, right: $t| (left as $t_u) < (right as $t_u)),
+        $gt_s => $func($($arg,)* |left: $t, right: $t| left > right),
+        $gt_u => $func($($arg,)* |left: $t, right: $t| (left as $t_u) > (right as $t_u)),
+        $le_s => $func($($arg,)* |left: $t, right: $t| left <= right),
+        $le_u => $func($($arg,)* |left: $t, right: $t| (left as $t_u) <= (right as $t_u)),
+        $ge_s => $func($($arg,)* |left: $t, right: $t| left >= right),
+        $ge_u => $func($($arg,)* |left: $t, right: $t| (left as $t_u) >= (right as $t_u)),
+        _ => Ok(false),
       }
+    };
+  }
+
+  /// Compile the instructions at the start of `code` as one closure, if
+  /// they match a pattern.  Returns the number of instructions compiled.
+  pub fn compile(state: &mut State, block: &mut Block, code: &[Instruction]) -> Result<usize> {
+    // `local.get` and a constant, with an arithmetic op.  An add stored to
+    // a local is fused with the `local.set` instead.
+    let stores_add = matches!(code.get(3), Some(SetLocal(_)) | Some(TeeLocal(_)));
+    match code {
+      [GetLocal(local), I32Const(val), op, ..] if !(stores_add && *op == I32Add) => {
+        let fused = match_binop!(op, i32, u32, [I32Add, I32Sub, I32Mul, I32And, I32Or, I32Xor, I32Shl, I32ShrS, I32ShrU],
+          local_const(state, *local, *val))?;
+        if fused {
+          return Ok(3);
+        }
+      },
+      [GetLocal(local), I64Const(val), op, ..] if !(stores_add && *op == I64Add) => {
+        let fused = match_binop!(op, i64, u64, [I64Add, I64Sub, I64Mul, I64And, I64Or, I64Xor, I64Shl, I64ShrS, I64ShrU],
+          local_const(state, *local, *val))?;
+        if fused {
+          return Ok(3);
+        }
+      },
+      _ => (),
     }
-  };
-}
+    match code {
+      // `local.get` and a load.
+      [GetLocal(local), op, ..] => {
+        return local_load(state, *local, op).map(|fused| if fused { 2 } else { 0 });
+      },
+      // An add stored to a local, `local.tee` is fused with a `br_if`.
+      [I32Add, SetLocal(idx), ..] => return add_set_local::<i32>(state, block, *idx, None).map(|_| 2),
+      [I64Add, SetLocal(idx), ..] => return add_set_local::<i64>(state, block, *idx, None).map(|_| 2),
+      [I32Add, TeeLocal(idx), BrIf(depth), ..] => {
+        if let Some(taken) = branch(state, *depth)? {
+          return add_set_local::<i32>(state, block, *idx, Some(taken)).map(|_| 3);
+        }
+      },
+      [I64Add, TeeLocal(idx), BrIf(depth), ..] => {
+        if let Some(taken) = branch(state, *depth)? {
+          return add_set_local::<i64>(state, block, *idx, Some(taken)).map(|_| 3);
+        }
+      },
+      // A comparison and a `br_if`.
+      [op, BrIf(depth), ..] => {
+        if let Some(taken) = branch(state, *depth)? {
+          let fused = match op {
+            I32Eqz => eqz_br_if::<i32>(state, block, taken)?,
+            I64Eqz => eqz_br_if::<i64>(state, block, taken)?,
+            _ => {
+              match_relop!(op, i32, u32, [I32Eq, I32Ne, I32LtS, I32LtU, I32GtS, I32GtU, I32LeS, I32LeU, I32GeS, I32GeU],
+                cmp_br_if(state, block, taken.clone()))? ||
+              match_relop!(op, i64, u64, [I64Eq, I64Ne, I64LtS, I64LtU, I64GtS, I64GtU, I64LeS, I64LeU, I64GeS, I64GeU],
+                cmp_br_if(state, block, taken))?
+            },
+          };
+          if fused {
+            return Ok(2);
+          }
+        }
+      },
+      _ => (),
+    }
+    Ok(0)
+  }
 
-impl_float_numeric_ops!(f32_ops, f32);
-impl_float_numeric_ops!(f64_ops, f64);
+  /// The action of a taken `br_if` to a label without results, `None` if
+  /// the label has a result.
+  fn branch(state: &State, depth: u32) -> Result<Option<Action>> {
+    if state.label(depth)?.arity > 0 {
+      return Ok(None);
+    }
+    Ok(Some(if state.is_function_label(depth) {
+      Action::Return(None)
+    } else {
+      Action::Branch(depth)
+    }))
+  }
 
+  fn local_const<T: Int, F>(state: &mut State, local: u32, val: T, op: F) -> Result<bool>
+    where F: Fn(T, T) -> T + Send + Sync + 'static
+  {
+    let local = state.local(local);
+    state.push(Input::Op(if local == 0 {
+      Box::new(move |_state: &vm::State, _store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+        Ok(op(T::from_value(regs.0[0]), val).into())
+      })
+    } else {
+      Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+        Ok(op(T::from_value(store.stack.get_local_val(local, regs)), val).into())
+      })
+    }));
+    Ok(true)
+  }
+
+  /// Load from the address in a local, `false` if `op` isn't an integer load.
+  fn local_load(state: &mut State, local: u32, op: &Instruction) -> Result<bool> {
+    let local = state.local(local);
+    match *op {
+      I32Load(memarg) => load::<4, _>(state, local, memarg, |bytes| i32::from_le_bytes(bytes).into()),
+      I32Load8S(memarg) => load::<1, _>(state, local, memarg, |bytes| (i8::from_le_bytes(bytes) as i32).into()),
+      I32Load8U(memarg) => load::<1, _>(state, local, memarg, |bytes| (u8::from_le_bytes(bytes) as i32).into()),
+      I32Load16S(memarg) => load::<2, _>(state, local, memarg, |bytes| (i16::from_le_bytes(bytes) as i32).into()),
+      I32Load16U(memarg) => load::<2, _>(state, local, memarg, |bytes| (u16::from_le_bytes(bytes) as i32).into()),
+      I64Load(memarg) => load::<8, _>(state, local, memarg, |bytes| i64::from_le_bytes(bytes).into()),
+      I64Load8S(memarg) => load::<1, _>(state, local, memarg, |bytes| (i8::from_le_bytes(bytes) as i64).into()),
+      I64Load8U(memarg) => load::<1, _>(state, local, memarg, |bytes| (u8::from_le_bytes(bytes) as i64).into()),
+      I64Load16S(memarg) => load::<2, _>(state, local, memarg, |bytes| (i16::from_le_bytes(bytes) as i64).into()),
+      I64Load16U(memarg) => load::<2, _>(state, local, memarg, |bytes| (u16::from_le_bytes(bytes) as i64).into()),
+      I64Load32S(memarg) => load::<4, _>(state, local, memarg, |bytes| (i32::from_le_bytes(bytes) as i64).into()),
+      I64Load32U(memarg) => load::<4, _>(state, local, memarg, |bytes| (u32::from_le_bytes(bytes) as i64).into()),
+      _ => Ok(false),
+    }
+  }
+
+  fn load<const N: usize, F>(state: &mut State, local: u32, memarg: loader::MemArg, convert: F) -> Result<bool>
+    where F: Fn([u8; N]) -> StackValue + Send + Sync + 'static
+  {
+    let (memory, offset) = (memarg.memory as usize, memarg.offset);
+    // Traps are reported at the load, after the `local.get`.
+    let (mask, pc) = (state.address_mask(memarg.memory), state.pc + 1);
+    state.push(Input::Op(Box::new(move |_state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<StackValue> {
+      let addr = store.stack.get_local_val(local, regs).0 & mask;
+      let bytes = store.memories[memory].load::<N>(addr, offset)
+        .map_err(|trap| store.trap_at(pc, trap))?;
+      Ok(convert(bytes))
+    })));
+    Ok(true)
+  }
+
+  /// Store an add in a local, with the `br_if` of a `local.tee` when
+  /// `taken` is set.
+  fn add_set_local<T: Int>(state: &mut State, block: &mut Block, set_idx: u32, taken: Option<Action>) -> Result<()> {
+    let set_idx = state.local(set_idx);
+    let right = state.pop()?;
+    let left = state.pop()?;
+    state.spill(block, Spill::Local(set_idx));
+    match taken {
+      None => {
+        block.push(impl_ops_match_input!(left, {
+          impl_ops_match_input!(right, {
+            Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+              let left = T::from_value(left.eval(state, store, regs)?);
+              let right = T::from_value(right.eval(state, store, regs)?);
+              store.stack.set_local_val(set_idx, left.add(right).into(), regs);
+              Ok(Action::End)
+            })
+          })
+        }));
+      },
+      Some(taken) => {
+        block.push(impl_ops_match_input!(left, {
+          impl_ops_match_input!(right, {
+            Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+              let left = T::from_value(left.eval(state, store, regs)?);
+              let right = T::from_value(right.eval(state, store, regs)?);
+              let res = left.add(right);
+              store.stack.set_local_val(set_idx, res.into(), regs);
+              if res != T::ZERO {
+                Ok(taken.clone())
+              } else {
+                Ok(Action::End)
+              }
+            })
+          })
+        }));
+      },
+    }
+    Ok(())
+  }
+
+  fn eqz_br_if<T: Int>(state: &mut State, block: &mut Block, taken: Action) -> Result<bool> {
+    state.push(Input::Const(T::ZERO.into()));
+    cmp_br_if(state, block, taken, |left: T, right: T| left == right)
+  }
+
+  fn cmp_br_if<T: Int, F>(state: &mut State, block: &mut Block, taken: Action, cmp: F) -> Result<bool>
+    where F: Fn(T, T) -> bool + Send + Sync + 'static
+  {
+    let right = state.pop()?;
+    let left = state.pop()?;
+    state.spill(block, Spill::Ops);
+    block.push(impl_ops_match_input!(left, {
+      impl_ops_match_input!(right, {
+        Box::new(move |state: &vm::State, store: &mut Store, regs: &mut Registers| -> Trap<Action> {
+          let left = T::from_value(left.eval(state, store, regs)?);
+          let right = T::from_value(right.eval(state, store, regs)?);
+          if cmp(left, right) {
+            Ok(taken.clone())
+          } else {
+            Ok(Action::End)
+          }
+        })
+      })
+    }));
+    Ok(true)
+  }
+}
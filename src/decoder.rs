@@ -0,0 +1,990 @@
+//! Decoder for the WebAssembly binary format.
+//!
+//! Errors name the byte offset in the module where decoding failed.
+
+use std::convert::TryFrom;
+
+use crate::elements::*;
+use crate::error::*;
+use crate::isa::{AtomicInstruction, BlockType, BrTableData, Catch, Instruction, MemArg, SimdInstruction, ValueType};
+
+const MAGIC: &[u8; 4] = b"\0asm";
+const VERSION: u32 = 1;
+
+/// Decode a module from a wasm binary.
+pub fn decode(bytes: &[u8]) -> Result<Module> {
+  let mut reader = Reader::new(bytes, 0);
+  if reader.bytes(4)? != MAGIC {
+    return Err(reader.error_at(0, "magic header not detected"));
+  }
+  let version = reader.fixed32()?;
+  if version != VERSION {
+    return Err(reader.error_at(4, format!("unknown binary version: {}", version)));
+  }
+
+  let mut module = Module::default();
+  let mut last_order = 0;
+  while !reader.is_empty() {
+    let start = reader.pos;
+    let id = reader.u8()?;
+    let len = reader.u32()?;
+    let mut section = reader.sub(len as usize)?;
+    if id != 0 {
+      let order = section_order(id)
+        .ok_or_else(|| reader.error_at(start, format!("unknown section id: {}", id)))?;
+      if order <= last_order {
+        return Err(reader.error_at(start, "unexpected section, sections are out of order or duplicated"));
+      }
+      last_order = order;
+    }
+    match id {
+      0 => section.custom_section(&mut module)?,
+      1 => module.types = section.vec(Reader::func_type)?,
+      2 => module.imports = section.vec(Reader::import_entry)?,
+      3 => module.functions = section.vec(Reader::u32)?,
+      4 => module.tables = section.vec(Reader::table_type)?,
+      5 => module.memories = section.vec(Reader::memory_type)?,
+      13 => module.tags = section.vec(Reader::tag_type)?,
+      6 => module.globals = section.vec(Reader::global_entry)?,
+      7 => module.exports = section.vec(Reader::export_entry)?,
+      8 => module.start = Some(section.u32()?),
+      9 => module.elements = section.vec(Reader::element_segment)?,
+      10 => module.code = section.vec(Reader::func_body)?,
+      11 => module.data = section.vec(Reader::data_segment)?,
+      12 => module.data_count = Some(section.u32()?),
+      _ => unreachable!("unknown section id: {}", id),
+    }
+    section.end()?;
+  }
+  Ok(module)
+}
+
+/// Sections must be in this order, the tag section is after the memories
+/// and the data count section is before the code.
+fn section_order(id: u8) -> Option<u8> {
+  match id {
+    1..=5 => Some(id),
+    13 => Some(6),
+    6..=9 => Some(id + 1),
+    12 => Some(11),
+    10 | 11 => Some(id + 2),
+    _ => None,
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  /// Offset of `bytes` in the module, for error messages.
+  base: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8], base: usize) -> Self {
+    Reader { bytes, pos: 0, base }
+  }
+
+  fn error(&self, msg: impl AsRef<str>) -> Error {
+    self.error_at(self.pos, msg)
+  }
+
+  fn error_at(&self, pos: usize, msg: impl AsRef<str>) -> Error {
+    Error::ParseError(format!("at offset {:#x}: {}", self.base + pos, msg.as_ref()))
+  }
+
+  fn is_empty(&self) -> bool {
+    self.pos >= self.bytes.len()
+  }
+
+  fn remaining(&self) -> usize {
+    self.bytes.len() - self.pos
+  }
+
+  /// All bytes must be used.
+  fn end(&self) -> Result<()> {
+    if !self.is_empty() {
+      return Err(self.error("section size mismatch"));
+    }
+    Ok(())
+  }
+
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+    if len > self.remaining() {
+      return Err(self.error("unexpected end"));
+    }
+    let bytes = &self.bytes[self.pos..self.pos + len];
+    self.pos += len;
+    Ok(bytes)
+  }
+
+  /// A reader for the next `len` bytes.
+  fn sub(&mut self, len: usize) -> Result<Reader<'a>> {
+    let base = self.base + self.pos;
+    Ok(Reader::new(self.bytes(len)?, base))
+  }
+
+  fn u8(&mut self) -> Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn fixed32(&mut self) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(self.bytes(4)?);
+    Ok(u32::from_le_bytes(buf))
+  }
+
+  fn fixed64(&mut self) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(self.bytes(8)?);
+    Ok(u64::from_le_bytes(buf))
+  }
+
+  /// Read a LEB128 integer of `bits` bits.  Signed integers are sign
+  /// extended to 64 bits.
+  fn leb(&mut self, bits: u32, signed: bool) -> Result<u64> {
+    let start = self.pos;
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+      let byte = self.u8()?;
+      let payload = byte & 0x7f;
+      if shift + 7 > bits {
+        // The last byte, the bits past `bits` must be unused.
+        let used = bits - shift;
+        let unused = payload >> used;
+        let expected = if signed && (payload >> (used - 1)) & 1 == 1 { 0x7f >> used } else { 0 };
+        if byte & 0x80 != 0 {
+          return Err(self.error_at(start, "integer representation too long"));
+        }
+        if unused != expected {
+          return Err(self.error_at(start, "integer too large"));
+        }
+      }
+      result |= (payload as u64) << shift;
+      shift += 7;
+      if byte & 0x80 == 0 {
+        if signed && shift < 64 && byte & 0x40 != 0 {
+          result |= !0u64 << shift;
+        }
+        return Ok(result);
+      }
+    }
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    Ok(self.leb(32, false)? as u32)
+  }
+
+  fn i32(&mut self) -> Result<i32> {
+    Ok(self.leb(32, true)? as i32)
+  }
+
+  fn u64(&mut self) -> Result<u64> {
+    self.leb(64, false)
+  }
+
+  fn i64(&mut self) -> Result<i64> {
+    Ok(self.leb(64, true)? as i64)
+  }
+
+  /// Read the length of a vector, each item takes at least one byte.
+  fn count(&mut self) -> Result<usize> {
+    let count = self.u32()? as usize;
+    if count > self.remaining() {
+      return Err(self.error("length out of bounds"));
+    }
+    Ok(count)
+  }
+
+  fn vec<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+    let count = self.count()?;
+    let mut items = Vec::with_capacity(count);
+    for _ in 0..count {
+      items.push(item(self)?);
+    }
+    Ok(items)
+  }
+
+  fn byte_vec(&mut self) -> Result<Vec<u8>> {
+    let len = self.count()?;
+    Ok(self.bytes(len)?.to_vec())
+  }
+
+  fn name(&mut self) -> Result<String> {
+    let start = self.pos;
+    let bytes = self.byte_vec()?;
+    String::from_utf8(bytes)
+      .map_err(|_| self.error_at(start, "malformed UTF-8 encoding"))
+  }
+
+  fn value_type(&mut self) -> Result<ValueType> {
+    let start = self.pos;
+    Ok(match self.u8()? {
+      0x7f => ValueType::I32,
+      0x7e => ValueType::I64,
+      0x7d => ValueType::F32,
+      0x7c => ValueType::F64,
+      0x7b => ValueType::V128,
+      0x70 => ValueType::FuncRef,
+      0x6f => ValueType::ExternRef,
+      0x69 => ValueType::ExnRef,
+      byte => return Err(self.error_at(start, format!("invalid value type: {:#x}", byte))),
+    })
+  }
+
+  fn ref_type(&mut self) -> Result<TableElementType> {
+    let start = self.pos;
+    match self.u8()? {
+      0x70 => Ok(TableElementType::AnyFunc),
+      0x6f => Ok(TableElementType::ExternRef),
+      byte => Err(self.error_at(start, format!("invalid reference type: {:#x}", byte))),
+    }
+  }
+
+  /// The heap type of `ref.null`, exception references can't be stored
+  /// in tables.
+  fn heap_type(&mut self) -> Result<ValueType> {
+    if self.bytes.get(self.pos) == Some(&0x69) {
+      self.pos += 1;
+      return Ok(ValueType::ExnRef);
+    }
+    Ok(self.ref_type()?.value_type())
+  }
+
+  fn block_type(&mut self) -> Result<BlockType> {
+    if self.bytes.get(self.pos) == Some(&0x40) {
+      self.pos += 1;
+      return Ok(BlockType::NoResult);
+    }
+    Ok(BlockType::Value(self.value_type()?))
+  }
+
+  fn func_type(&mut self) -> Result<FunctionType> {
+    let start = self.pos;
+    if self.u8()? != 0x60 {
+      return Err(self.error_at(start, "invalid function type"));
+    }
+    let params = self.vec(Reader::value_type)?;
+    let start = self.pos;
+    let mut results = self.vec(Reader::value_type)?;
+    if results.len() > 1 {
+      return Err(self.error_at(start, "multiple results are not supported"));
+    }
+    Ok(FunctionType::new(params, results.pop()))
+  }
+
+  fn limits(&mut self) -> Result<ResizableLimits> {
+    let start = self.pos;
+    match self.u8()? {
+      0x00 => Ok(ResizableLimits::new(self.u32()?, None)),
+      0x01 => Ok(ResizableLimits::new(self.u32()?, Some(self.u32()?))),
+      flags => Err(self.error_at(start, format!("invalid limits flags: {:#x}", flags))),
+    }
+  }
+
+  fn table_type(&mut self) -> Result<TableType> {
+    let elem_type = self.ref_type()?;
+    let limits = self.limits()?;
+    Ok(TableType::new(elem_type, limits.initial(), limits.maximum()))
+  }
+
+  /// The limits flags of a memory: 0x01 has a maximum, 0x02 is shared and
+  /// 0x04 is a 64-bit memory, with `u64` limits.
+  fn memory_type(&mut self) -> Result<MemoryType> {
+    let start = self.pos;
+    let flags = self.u8()?;
+    if flags > 0x07 {
+      return Err(self.error_at(start, format!("invalid limits flags: {:#x}", flags)));
+    }
+    let memory_type = if flags & 0x04 != 0 {
+      let initial = self.pages()?;
+      let maximum = if flags & 0x01 != 0 { Some(self.pages()?) } else { None };
+      MemoryType::new_64(initial, maximum)
+    } else {
+      let initial = self.u32()?;
+      let maximum = if flags & 0x01 != 0 { Some(self.u32()?) } else { None };
+      MemoryType::new(initial, maximum)
+    };
+    Ok(if flags & 0x02 != 0 { memory_type.shared() } else { memory_type })
+  }
+
+  /// A 64-bit memory size in pages, only sizes up to 2^32 pages are supported.
+  fn pages(&mut self) -> Result<u32> {
+    let start = self.pos;
+    u32::try_from(self.u64()?)
+      .map_err(|_| self.error_at(start, "memory size must be at most 4294967295 pages"))
+  }
+
+  /// An exception tag, only the exception attribute is defined.
+  fn tag_type(&mut self) -> Result<u32> {
+    let start = self.pos;
+    if self.u8()? != 0x00 {
+      return Err(self.error_at(start, "invalid tag attribute"));
+    }
+    self.u32()
+  }
+
+  fn global_type(&mut self) -> Result<GlobalType> {
+    let value_type = self.value_type()?;
+    let start = self.pos;
+    let is_mutable = match self.u8()? {
+      0x00 => false,
+      0x01 => true,
+      _ => return Err(self.error_at(start, "invalid mutability")),
+    };
+    Ok(GlobalType::new(value_type, is_mutable))
+  }
+
+  fn import_entry(&mut self) -> Result<ImportEntry> {
+    let module = self.name()?;
+    let field = self.name()?;
+    let start = self.pos;
+    let external = match self.u8()? {
+      0x00 => External::Function(self.u32()?),
+      0x01 => External::Table(self.table_type()?),
+      0x02 => External::Memory(self.memory_type()?),
+      0x03 => External::Global(self.global_type()?),
+      0x04 => External::Tag(self.tag_type()?),
+      kind => return Err(self.error_at(start, format!("invalid import kind: {:#x}", kind))),
+    };
+    Ok(ImportEntry::new(module, field, external))
+  }
+
+  fn export_entry(&mut self) -> Result<ExportEntry> {
+    let field = self.name()?;
+    let start = self.pos;
+    let internal = match self.u8()? {
+      0x00 => Internal::Function(self.u32()?),
+      0x01 => Internal::Table(self.u32()?),
+      0x02 => Internal::Memory(self.u32()?),
+      0x03 => Internal::Global(self.u32()?),
+      0x04 => Internal::Tag(self.u32()?),
+      kind => return Err(self.error_at(start, format!("invalid export kind: {:#x}", kind))),
+    };
+    Ok(ExportEntry::new(field, internal))
+  }
+
+  /// A constant expression, up to and including the `End`.
+  fn init_expr(&mut self) -> Result<InitExpr> {
+    let mut code = Vec::new();
+    loop {
+      let instr = self.instruction()?;
+      let end = instr == Instruction::End;
+      code.push(instr);
+      if end {
+        return Ok(InitExpr::new(code));
+      }
+    }
+  }
+
+  fn global_entry(&mut self) -> Result<GlobalEntry> {
+    let global_type = self.global_type()?;
+    Ok(GlobalEntry::new(global_type, self.init_expr()?))
+  }
+
+  /// The flags of an element segment: bit 0 is set for passive and declared
+  /// segments, bit 1 for an explicit table index (or a declared segment) and
+  /// bit 2 when the members are constant expressions.
+  fn element_segment(&mut self) -> Result<ElementSegment> {
+    let start = self.pos;
+    let flags = self.u32()?;
+    if flags > 7 {
+      return Err(self.error_at(start, format!("invalid element segment kind: {}", flags)));
+    }
+    let (index, offset) = match flags & 3 {
+      0 => (0, Some(self.init_expr()?)),
+      2 => (self.u32()?, Some(self.init_expr()?)),
+      _ => (0, None),
+    };
+    // The first kind of each encoding has an implicit `funcref` type.
+    let elem_type = match flags {
+      0 | 4 => TableElementType::AnyFunc,
+      1..=3 => self.elem_kind()?,
+      _ => self.ref_type()?,
+    };
+    let members = if flags & 4 != 0 {
+      self.vec(Reader::elem_expr)?
+    } else {
+      self.vec(|reader| reader.u32().map(Some))?
+    };
+    if flags == 3 || flags == 7 {
+      return Ok(ElementSegment::declared(elem_type, members));
+    }
+    Ok(ElementSegment::new(index, offset, elem_type, members))
+  }
+
+  fn elem_kind(&mut self) -> Result<TableElementType> {
+    let start = self.pos;
+    match self.u8()? {
+      0x00 => Ok(TableElementType::AnyFunc),
+      kind => Err(self.error_at(start, format!("invalid element kind: {:#x}", kind))),
+    }
+  }
+
+  /// A member of an element segment: `ref.null` or `ref.func`.
+  fn elem_expr(&mut self) -> Result<Option<u32>> {
+    let start = self.pos;
+    match self.init_expr()?.code() {
+      [Instruction::RefNull(_), Instruction::End] => Ok(None),
+      [Instruction::RefFunc(idx), Instruction::End] => Ok(Some(*idx)),
+      _ => Err(self.error_at(start, "unsupported element expression")),
+    }
+  }
+
+  fn data_segment(&mut self) -> Result<DataSegment> {
+    let start = self.pos;
+    match self.u32()? {
+      0 => {
+        let offset = self.init_expr()?;
+        Ok(DataSegment::new(0, Some(offset), self.byte_vec()?))
+      },
+      1 => Ok(DataSegment::new(0, None, self.byte_vec()?)),
+      2 => {
+        let index = self.u32()?;
+        let offset = self.init_expr()?;
+        Ok(DataSegment::new(index, Some(offset), self.byte_vec()?))
+      },
+      flags => Err(self.error_at(start, format!("invalid data segment kind: {}", flags))),
+    }
+  }
+
+  fn func_body(&mut self) -> Result<FuncBody> {
+    let len = self.u32()?;
+    let mut body = self.sub(len as usize)?;
+    let locals = body.vec(|body| Ok(Local::new(body.u32()?, body.value_type()?)))?;
+    // Structure is checked by the validator, decode up to the end of the body.
+    let mut code = Vec::new();
+    while !body.is_empty() {
+      code.push(body.instruction()?);
+    }
+    Ok(FuncBody::new(locals, code))
+  }
+
+  fn custom_section(&mut self, module: &mut Module) -> Result<()> {
+    let name = self.name()?;
+    let payload = self.bytes(self.remaining())?;
+    if name == "name" {
+      // Like other custom sections, a malformed name section is ignored.
+      let base = self.base + self.pos - payload.len();
+      if Reader::new(payload, base).name_section(module).is_ok() {
+        return Ok(());
+      }
+      module.name = None;
+      module.func_names.clear();
+    }
+    module.custom_sections.push(CustomSection::new(name, payload.to_vec()));
+    Ok(())
+  }
+
+  fn name_section(&mut self, module: &mut Module) -> Result<()> {
+    while !self.is_empty() {
+      let id = self.u8()?;
+      let len = self.u32()?;
+      let mut sub = self.sub(len as usize)?;
+      match id {
+        0 => module.name = Some(sub.name()?),
+        1 => {
+          for _ in 0..sub.count()? {
+            let idx = sub.u32()?;
+            module.func_names.insert(idx, sub.name()?);
+          }
+        },
+        // Local names and the extended name subsections aren't used.
+        _ => continue,
+      }
+      sub.end()?;
+    }
+    Ok(())
+  }
+
+  /// A handler of a `try_table`.
+  fn catch(&mut self) -> Result<Catch> {
+    let start = self.pos;
+    Ok(match self.u8()? {
+      0x00 => Catch::Catch(self.u32()?, self.u32()?),
+      0x01 => Catch::CatchRef(self.u32()?, self.u32()?),
+      0x02 => Catch::CatchAll(self.u32()?),
+      0x03 => Catch::CatchAllRef(self.u32()?),
+      kind => return Err(self.error_at(start, format!("invalid catch kind: {:#x}", kind))),
+    })
+  }
+
+  /// Memory immediates: log2 of the alignment and the offset.  Bit 6 of
+  /// the alignment flags a memory index other than 0.
+  fn memarg(&mut self) -> Result<MemArg> {
+    let align = self.u32()?;
+    let memory = if align & 0x40 != 0 { self.u32()? } else { 0 };
+    Ok(MemArg {
+      align: align & !0x40,
+      offset: self.u64()?,
+      memory,
+    })
+  }
+
+  fn instruction(&mut self) -> Result<Instruction> {
+    use Instruction::*;
+    let start = self.pos;
+    Ok(match self.u8()? {
+      0x00 => Unreachable,
+      0x01 => Nop,
+      0x02 => Block(self.block_type()?),
+      0x03 => Loop(self.block_type()?),
+      0x04 => If(self.block_type()?),
+      0x05 => Else,
+      0x06 => Try(self.block_type()?),
+      0x07 => Catch(self.u32()?),
+      0x08 => Throw(self.u32()?),
+      0x09 => Rethrow(self.u32()?),
+      0x0a => ThrowRef,
+      0x0b => End,
+      0x0c => Br(self.u32()?),
+      0x0d => BrIf(self.u32()?),
+      0x0e => {
+        let table = self.vec(Reader::u32)?.into_boxed_slice();
+        let default = self.u32()?;
+        BrTable(Box::new(BrTableData { table, default }))
+      },
+      0x0f => Return,
+      0x10 => Call(self.u32()?),
+      0x11 => CallIndirect(self.u32()?, self.u32()?),
+      0x12 => ReturnCall(self.u32()?),
+      0x13 => ReturnCallIndirect(self.u32()?, self.u32()?),
+      0x18 => Delegate(self.u32()?),
+      0x19 => CatchAll,
+
+      0x1a => Drop,
+      0x1b => Select,
+      0x1c => {
+        let types = self.vec(Reader::value_type)?;
+        match types[..] {
+          [value_type] => TypedSelect(value_type),
+          _ => return Err(self.error_at(start, "invalid result arity of typed select")),
+        }
+      },
+      0x1f => {
+        let block_type = self.block_type()?;
+        TryTable(block_type, self.vec(Reader::catch)?.into_boxed_slice())
+      },
+
+      0x20 => GetLocal(self.u32()?),
+      0x21 => SetLocal(self.u32()?),
+      0x22 => TeeLocal(self.u32()?),
+      0x23 => GetGlobal(self.u32()?),
+      0x24 => SetGlobal(self.u32()?),
+      0x25 => TableGet(self.u32()?),
+      0x26 => TableSet(self.u32()?),
+
+      0x28 => I32Load(self.memarg()?),
+      0x29 => I64Load(self.memarg()?),
+      0x2a => F32Load(self.memarg()?),
+      0x2b => F64Load(self.memarg()?),
+      0x2c => I32Load8S(self.memarg()?),
+      0x2d => I32Load8U(self.memarg()?),
+      0x2e => I32Load16S(self.memarg()?),
+      0x2f => I32Load16U(self.memarg()?),
+      0x30 => I64Load8S(self.memarg()?),
+      0x31 => I64Load8U(self.memarg()?),
+      0x32 => I64Load16S(self.memarg()?),
+      0x33 => I64Load16U(self.memarg()?),
+      0x34 => I64Load32S(self.memarg()?),
+      0x35 => I64Load32U(self.memarg()?),
+      0x36 => I32Store(self.memarg()?),
+      0x37 => I64Store(self.memarg()?),
+      0x38 => F32Store(self.memarg()?),
+      0x39 => F64Store(self.memarg()?),
+      0x3a => I32Store8(self.memarg()?),
+      0x3b => I32Store16(self.memarg()?),
+      0x3c => I64Store8(self.memarg()?),
+      0x3d => I64Store16(self.memarg()?),
+      0x3e => I64Store32(self.memarg()?),
+      0x3f => CurrentMemory(self.u32()?),
+      0x40 => GrowMemory(self.u32()?),
+
+      0x41 => I32Const(self.i32()?),
+      0x42 => I64Const(self.i64()?),
+      0x43 => F32Const(self.fixed32()?),
+      0x44 => F64Const(self.fixed64()?),
+
+      0x45 => I32Eqz,
+      0x46 => I32Eq,
+      0x47 => I32Ne,
+      0x48 => I32LtS,
+      0x49 => I32LtU,
+      0x4a => I32GtS,
+      0x4b => I32GtU,
+      0x4c => I32LeS,
+      0x4d => I32LeU,
+      0x4e => I32GeS,
+      0x4f => I32GeU,
+
+      0x50 => I64Eqz,
+      0x51 => I64Eq,
+      0x52 => I64Ne,
+      0x53 => I64LtS,
+      0x54 => I64LtU,
+      0x55 => I64GtS,
+      0x56 => I64GtU,
+      0x57 => I64LeS,
+      0x58 => I64LeU,
+      0x59 => I64GeS,
+      0x5a => I64GeU,
+
+      0x5b => F32Eq,
+      0x5c => F32Ne,
+      0x5d => F32Lt,
+      0x5e => F32Gt,
+      0x5f => F32Le,
+      0x60 => F32Ge,
+
+      0x61 => F64Eq,
+      0x62 => F64Ne,
+      0x63 => F64Lt,
+      0x64 => F64Gt,
+      0x65 => F64Le,
+      0x66 => F64Ge,
+
+      0x67 => I32Clz,
+      0x68 => I32Ctz,
+      0x69 => I32Popcnt,
+      0x6a => I32Add,
+      0x6b => I32Sub,
+      0x6c => I32Mul,
+      0x6d => I32DivS,
+      0x6e => I32DivU,
+      0x6f => I32RemS,
+      0x70 => I32RemU,
+      0x71 => I32And,
+      0x72 => I32Or,
+      0x73 => I32Xor,
+      0x74 => I32Shl,
+      0x75 => I32ShrS,
+      0x76 => I32ShrU,
+      0x77 => I32Rotl,
+      0x78 => I32Rotr,
+
+      0x79 => I64Clz,
+      0x7a => I64Ctz,
+      0x7b => I64Popcnt,
+      0x7c => I64Add,
+      0x7d => I64Sub,
+      0x7e => I64Mul,
+      0x7f => I64DivS,
+      0x80 => I64DivU,
+      0x81 => I64RemS,
+      0x82 => I64RemU,
+      0x83 => I64And,
+      0x84 => I64Or,
+      0x85 => I64Xor,
+      0x86 => I64Shl,
+      0x87 => I64ShrS,
+      0x88 => I64ShrU,
+      0x89 => I64Rotl,
+      0x8a => I64Rotr,
+
+      0x8b => F32Abs,
+      0x8c => F32Neg,
+      0x8d => F32Ceil,
+      0x8e => F32Floor,
+      0x8f => F32Trunc,
+      0x90 => F32Nearest,
+      0x91 => F32Sqrt,
+      0x92 => F32Add,
+      0x93 => F32Sub,
+      0x94 => F32Mul,
+      0x95 => F32Div,
+      0x96 => F32Min,
+      0x97 => F32Max,
+      0x98 => F32Copysign,
+
+      0x99 => F64Abs,
+      0x9a => F64Neg,
+      0x9b => F64Ceil,
+      0x9c => F64Floor,
+      0x9d => F64Trunc,
+      0x9e => F64Nearest,
+      0x9f => F64Sqrt,
+      0xa0 => F64Add,
+      0xa1 => F64Sub,
+      0xa2 => F64Mul,
+      0xa3 => F64Div,
+      0xa4 => F64Min,
+      0xa5 => F64Max,
+      0xa6 => F64Copysign,
+
+      0xa7 => I32WrapI64,
+      0xa8 => I32TruncSF32,
+      0xa9 => I32TruncUF32,
+      0xaa => I32TruncSF64,
+      0xab => I32TruncUF64,
+      0xac => I64ExtendSI32,
+      0xad => I64ExtendUI32,
+      0xae => I64TruncSF32,
+      0xaf => I64TruncUF32,
+      0xb0 => I64TruncSF64,
+      0xb1 => I64TruncUF64,
+      0xb2 => F32ConvertSI32,
+      0xb3 => F32ConvertUI32,
+      0xb4 => F32ConvertSI64,
+      0xb5 => F32ConvertUI64,
+      0xb6 => F32DemoteF64,
+      0xb7 => F64ConvertSI32,
+      0xb8 => F64ConvertUI32,
+      0xb9 => F64ConvertSI64,
+      0xba => F64ConvertUI64,
+      0xbb => F64PromoteF32,
+
+      0xbc => I32ReinterpretF32,
+      0xbd => I64ReinterpretF64,
+      0xbe => F32ReinterpretI32,
+      0xbf => F64ReinterpretI64,
+
+      0xc0 => I32Extend8S,
+      0xc1 => I32Extend16S,
+      0xc2 => I64Extend8S,
+      0xc3 => I64Extend16S,
+      0xc4 => I64Extend32S,
+
+      0xd0 => RefNull(self.heap_type()?),
+      0xd1 => RefIsNull,
+      0xd2 => RefFunc(self.u32()?),
+
+      0xfc => match self.u32()? {
+        0x00 => I32TruncSatSF32,
+        0x01 => I32TruncSatUF32,
+        0x02 => I32TruncSatSF64,
+        0x03 => I32TruncSatUF64,
+        0x04 => I64TruncSatSF32,
+        0x05 => I64TruncSatUF32,
+        0x06 => I64TruncSatSF64,
+        0x07 => I64TruncSatUF64,
+        0x08 => {
+          let data_idx = self.u32()?;
+          MemoryInit(data_idx, self.u32()?)
+        },
+        0x09 => DataDrop(self.u32()?),
+        0x0a => {
+          let dst = self.u32()?;
+          MemoryCopy(dst, self.u32()?)
+        },
+        0x0b => MemoryFill(self.u32()?),
+        0x0c => {
+          let elem_idx = self.u32()?;
+          TableInit(elem_idx, self.u32()?)
+        },
+        0x0d => ElemDrop(self.u32()?),
+        0x0e => {
+          let dst = self.u32()?;
+          TableCopy(dst, self.u32()?)
+        },
+        0x0f => TableGrow(self.u32()?),
+        0x10 => TableSize(self.u32()?),
+        0x11 => TableFill(self.u32()?),
+        0x13 => I64Add128,
+        0x14 => I64Sub128,
+        0x15 => I64MulWideS,
+        0x16 => I64MulWideU,
+        op => return Err(self.error_at(start, format!("unknown opcode: 0xfc {:#x}", op))),
+      },
+      0xfd => Simd(Box::new(self.simd_instruction(start)?)),
+      0xfe => Atomic(Box::new(self.atomic_instruction(start)?)),
+
+      op => return Err(self.error_at(start, format!("unknown opcode: {:#x}", op))),
+    })
+  }
+
+  /// A SIMD instruction, after the `0xfd` prefix.
+  fn simd_instruction(&mut self, start: usize) -> Result<SimdInstruction> {
+    use SimdInstruction::*;
+    let opcode = self.u32()?;
+    if let Some(op) = SimdInstruction::from_opcode(opcode) {
+      return Ok(op);
+    }
+    Ok(match opcode {
+      0x00 => V128Load(self.memarg()?),
+      0x01 => V128Load8x8S(self.memarg()?),
+      0x02 => V128Load8x8U(self.memarg()?),
+      0x03 => V128Load16x4S(self.memarg()?),
+      0x04 => V128Load16x4U(self.memarg()?),
+      0x05 => V128Load32x2S(self.memarg()?),
+      0x06 => V128Load32x2U(self.memarg()?),
+      0x07 => V128Load8Splat(self.memarg()?),
+      0x08 => V128Load16Splat(self.memarg()?),
+      0x09 => V128Load32Splat(self.memarg()?),
+      0x0a => V128Load64Splat(self.memarg()?),
+      0x0b => V128Store(self.memarg()?),
+      0x0c => {
+        let mut buf = [0u8; 16];
+        buf.copy_from_slice(self.bytes(16)?);
+        V128Const(u128::from_le_bytes(buf))
+      },
+      0x0d => {
+        let mut lanes = [0u8; 16];
+        lanes.copy_from_slice(self.bytes(16)?);
+        I8x16Shuffle(lanes)
+      },
+      0x15 => I8x16ExtractLaneS(self.u8()?),
+      0x16 => I8x16ExtractLaneU(self.u8()?),
+      0x17 => I8x16ReplaceLane(self.u8()?),
+      0x18 => I16x8ExtractLaneS(self.u8()?),
+      0x19 => I16x8ExtractLaneU(self.u8()?),
+      0x1a => I16x8ReplaceLane(self.u8()?),
+      0x1b => I32x4ExtractLane(self.u8()?),
+      0x1c => I32x4ReplaceLane(self.u8()?),
+      0x1d => I64x2ExtractLane(self.u8()?),
+      0x1e => I64x2ReplaceLane(self.u8()?),
+      0x1f => F32x4ExtractLane(self.u8()?),
+      0x20 => F32x4ReplaceLane(self.u8()?),
+      0x21 => F64x2ExtractLane(self.u8()?),
+      0x22 => F64x2ReplaceLane(self.u8()?),
+      0x54 => V128Load8Lane(self.memarg()?, self.u8()?),
+      0x55 => V128Load16Lane(self.memarg()?, self.u8()?),
+      0x56 => V128Load32Lane(self.memarg()?, self.u8()?),
+      0x57 => V128Load64Lane(self.memarg()?, self.u8()?),
+      0x58 => V128Store8Lane(self.memarg()?, self.u8()?),
+      0x59 => V128Store16Lane(self.memarg()?, self.u8()?),
+      0x5a => V128Store32Lane(self.memarg()?, self.u8()?),
+      0x5b => V128Store64Lane(self.memarg()?, self.u8()?),
+      0x5c => V128Load32Zero(self.memarg()?),
+      0x5d => V128Load64Zero(self.memarg()?),
+      op => return Err(self.error_at(start, format!("unknown opcode: 0xfd {:#x}", op))),
+    })
+  }
+
+  /// An atomic instruction, after the `0xfe` prefix.
+  fn atomic_instruction(&mut self, start: usize) -> Result<AtomicInstruction> {
+    let opcode = self.u32()?;
+    if opcode == 0x03 {
+      if self.u8()? != 0x00 {
+        return Err(self.error_at(start, "invalid atomic.fence flags"));
+      }
+      return Ok(AtomicInstruction::AtomicFence);
+    }
+    let unknown = |reader: &Self| reader.error_at(start, format!("unknown opcode: 0xfe {:#x}", opcode));
+    if AtomicInstruction::from_opcode(opcode, MemArg::default()).is_none() {
+      return Err(unknown(self));
+    }
+    let memarg = self.memarg()?;
+    AtomicInstruction::from_opcode(opcode, memarg).ok_or_else(|| unknown(self))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn leb128() {
+    let read = |bytes: &[u8], bits: u32, signed: bool| Reader::new(bytes, 0).leb(bits, signed).ok();
+    assert_eq!(read(&[0x00], 32, false), Some(0));
+    assert_eq!(read(&[0xe5, 0x8e, 0x26], 32, false), Some(624485));
+    assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x00], 32, false), Some(0));
+    assert_eq!(read(&[0xff, 0xff, 0xff, 0xff, 0x0f], 32, false), Some(u32::MAX as u64));
+    assert_eq!(read(&[0xff, 0xff, 0xff, 0xff, 0x1f], 32, false), None);
+    assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00], 32, false), None);
+    assert_eq!(read(&[0x7f], 32, true).map(|v| v as i32), Some(-1));
+    assert_eq!(read(&[0xc0, 0xbb, 0x78], 32, true).map(|v| v as i32), Some(-123456));
+    assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x78], 32, true).map(|v| v as i32), Some(i32::MIN));
+    assert_eq!(read(&[0x80, 0x80, 0x80, 0x80, 0x70], 32, true), None);
+    let i64_min = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f];
+    assert_eq!(read(&i64_min, 64, true).map(|v| v as i64), Some(i64::MIN));
+    assert_eq!(read(&[0x80], 32, false), None);
+  }
+
+  #[test]
+  fn element_segments() {
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).element_segment().ok();
+    let offset = |val| Some(InitExpr::new(vec![Instruction::I32Const(val), Instruction::End]));
+    assert_eq!(read(&[0x00, 0x41, 0x01, 0x0b, 0x02, 0x00, 0x01]),
+      Some(ElementSegment::new(0, offset(1), TableElementType::AnyFunc, vec![Some(0), Some(1)])));
+    assert_eq!(read(&[0x05, 0x70, 0x02, 0xd2, 0x00, 0x0b, 0xd0, 0x70, 0x0b]),
+      Some(ElementSegment::new(0, None, TableElementType::AnyFunc, vec![Some(0), None])));
+    assert_eq!(read(&[0x06, 0x01, 0x41, 0x00, 0x0b, 0x6f, 0x01, 0xd0, 0x6f, 0x0b]),
+      Some(ElementSegment::new(1, offset(0), TableElementType::ExternRef, vec![None])));
+    assert_eq!(read(&[0x07, 0x70, 0x01, 0xd2, 0x02, 0x0b]),
+      Some(ElementSegment::declared(TableElementType::AnyFunc, vec![Some(2)])));
+    // Only `ref.null` and `ref.func` are supported as members.
+    assert_eq!(read(&[0x05, 0x70, 0x01, 0x41, 0x00, 0x0b]), None);
+    assert_eq!(read(&[0x08]), None);
+  }
+
+  #[test]
+  fn simd_instructions() {
+    use SimdInstruction::*;
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).instruction().ok();
+    let simd = |op| Some(Instruction::Simd(Box::new(op)));
+    assert_eq!(read(&[0xfd, 0xae, 0x01]), simd(I32x4Add));
+    assert_eq!(read(&[0xfd, 0x00, 0x04, 0x10]), simd(V128Load(MemArg { align: 4, offset: 16, memory: 0 })));
+    assert_eq!(read(&[0xfd, 0x54, 0x00, 0x08, 0x0f]), simd(V128Load8Lane(MemArg { align: 0, offset: 8, memory: 0 }, 15)));
+    assert_eq!(read(&[0xfd, 0x21, 0x01]), simd(F64x2ExtractLane(1)));
+    let mut bytes = vec![0xfd, 0x0c];
+    bytes.extend_from_slice(&1u128.to_le_bytes());
+    assert_eq!(read(&bytes), simd(V128Const(1)));
+    // Reserved opcode.
+    assert_eq!(read(&[0xfd, 0x9a, 0x01]), None);
+  }
+
+  #[test]
+  fn memory_instructions() {
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).instruction().ok();
+    assert_eq!(read(&[0x28, 0x02, 0x08]), Some(Instruction::I32Load(MemArg { align: 2, offset: 8, memory: 0 })));
+    assert_eq!(read(&[0x36, 0x42, 0x01, 0x04]), Some(Instruction::I32Store(MemArg { align: 2, offset: 4, memory: 1 })));
+    assert_eq!(read(&[0x40, 0x02]), Some(Instruction::GrowMemory(2)));
+    assert_eq!(read(&[0xfc, 0x08, 0x03, 0x01]), Some(Instruction::MemoryInit(3, 1)));
+    assert_eq!(read(&[0xfc, 0x0a, 0x01, 0x00]), Some(Instruction::MemoryCopy(1, 0)));
+    assert_eq!(read(&[0xfc, 0x0b, 0x02]), Some(Instruction::MemoryFill(2)));
+  }
+
+  #[test]
+  fn memory64() {
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).memory_type().ok();
+    assert_eq!(read(&[0x01, 0x01, 0x02]), Some(MemoryType::new(1, Some(2))));
+    assert_eq!(read(&[0x04, 0x01]), Some(MemoryType::new_64(1, None)));
+    assert_eq!(read(&[0x05, 0x00, 0xff, 0xff, 0xff, 0xff, 0x0f]), Some(MemoryType::new_64(0, Some(u32::MAX))));
+    assert_eq!(read(&[0x04, 0x80, 0x80, 0x80, 0x80, 0x10]), None);
+    let offset = [0x28, 0x00, 0x80, 0x80, 0x80, 0x80, 0x10];
+    assert_eq!(Reader::new(&offset, 0).instruction().ok(),
+      Some(Instruction::I32Load(MemArg { align: 0, offset: 1 << 32, memory: 0 })));
+  }
+
+  #[test]
+  fn atomic_instructions() {
+    use crate::isa::AtomicRmwOp;
+    use AtomicInstruction::*;
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).instruction().ok();
+    let atomic = |op| Some(Instruction::Atomic(Box::new(op)));
+    let memarg = |align| MemArg { align, offset: 4, memory: 0 };
+    assert_eq!(read(&[0xfe, 0x00, 0x02, 0x04]), atomic(MemoryAtomicNotify(memarg(2))));
+    assert_eq!(read(&[0xfe, 0x03, 0x00]), atomic(AtomicFence));
+    assert_eq!(read(&[0xfe, 0x16, 0x02, 0x04]), atomic(Load(ValueType::I64, 4, memarg(2))));
+    assert_eq!(read(&[0xfe, 0x19, 0x00, 0x04]), atomic(Store(ValueType::I32, 1, memarg(0))));
+    assert_eq!(read(&[0xfe, 0x25, 0x02, 0x04]), atomic(Rmw(AtomicRmwOp::Sub, ValueType::I32, 4, memarg(2))));
+    assert_eq!(read(&[0xfe, 0x4e, 0x02, 0x04]), atomic(Cmpxchg(ValueType::I64, 4, memarg(2))));
+    assert_eq!(read(&[0xfe, 0x4f, 0x02, 0x04]), None);
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).memory_type().ok();
+    assert_eq!(read(&[0x03, 0x01, 0x02]), Some(MemoryType::new(1, Some(2)).shared()));
+    assert_eq!(read(&[0x08, 0x01]), None);
+  }
+
+  #[test]
+  fn wide_arithmetic() {
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).instruction().ok();
+    assert_eq!(read(&[0xfc, 0x13]), Some(Instruction::I64Add128));
+    assert_eq!(read(&[0xfc, 0x16]), Some(Instruction::I64MulWideU));
+    assert_eq!(read(&[0xfc, 0x17]), None);
+  }
+
+  #[test]
+  fn exception_instructions() {
+    let read = |bytes: &[u8]| Reader::new(bytes, 0).instruction().ok();
+    let catches = vec![Catch::Catch(0, 1), Catch::CatchRef(1, 0), Catch::CatchAll(2), Catch::CatchAllRef(3)];
+    assert_eq!(read(&[0x1f, 0x40, 0x04, 0x00, 0x00, 0x01, 0x01, 0x01, 0x00, 0x02, 0x02, 0x03, 0x03]),
+      Some(Instruction::TryTable(BlockType::NoResult, catches.into_boxed_slice())));
+    assert_eq!(read(&[0x08, 0x02]), Some(Instruction::Throw(2)));
+    assert_eq!(read(&[0x0a]), Some(Instruction::ThrowRef));
+    assert_eq!(read(&[0x06, 0x7f]), Some(Instruction::Try(BlockType::Value(ValueType::I32))));
+    assert_eq!(read(&[0x18, 0x01]), Some(Instruction::Delegate(1)));
+    assert_eq!(read(&[0xd0, 0x69]), Some(Instruction::RefNull(ValueType::ExnRef)));
+    // Unknown catch kind.
+    assert_eq!(read(&[0x1f, 0x40, 0x01, 0x04, 0x00]), None);
+  }
+}
@@ -0,0 +1,433 @@
+//! The sections of a wasm module, before they are validated and loaded.
+//!
+//! Both the binary decoder and the text format parser build these.
+
+use std::collections::BTreeMap;
+
+use crate::isa::{Instruction, ValueType};
+
+/// Signature of a function.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FunctionType {
+  params: Vec<ValueType>,
+  return_type: Option<ValueType>,
+}
+
+impl FunctionType {
+  pub fn new(params: Vec<ValueType>, return_type: Option<ValueType>) -> Self {
+    FunctionType { params, return_type }
+  }
+
+  pub fn params(&self) -> &[ValueType] {
+    &self.params
+  }
+
+  pub fn params_mut(&mut self) -> &mut Vec<ValueType> {
+    &mut self.params
+  }
+
+  pub const fn return_type(&self) -> Option<ValueType> {
+    self.return_type
+  }
+
+  pub fn return_type_mut(&mut self) -> &mut Option<ValueType> {
+    &mut self.return_type
+  }
+}
+
+/// Minimum and optional maximum size of a table or memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResizableLimits {
+  initial: u32,
+  maximum: Option<u32>,
+}
+
+impl ResizableLimits {
+  pub const fn new(initial: u32, maximum: Option<u32>) -> Self {
+    ResizableLimits { initial, maximum }
+  }
+
+  pub const fn initial(&self) -> u32 {
+    self.initial
+  }
+
+  pub const fn maximum(&self) -> Option<u32> {
+    self.maximum
+  }
+}
+
+/// Type of the elements of a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TableElementType {
+  AnyFunc,
+  ExternRef,
+}
+
+impl TableElementType {
+  /// The reference type of the elements.
+  pub const fn value_type(&self) -> ValueType {
+    match self {
+      TableElementType::AnyFunc => ValueType::FuncRef,
+      TableElementType::ExternRef => ValueType::ExternRef,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TableType {
+  elem_type: TableElementType,
+  limits: ResizableLimits,
+}
+
+impl TableType {
+  pub const fn new(elem_type: TableElementType, initial: u32, maximum: Option<u32>) -> Self {
+    TableType {
+      elem_type,
+      limits: ResizableLimits::new(initial, maximum),
+    }
+  }
+
+  pub const fn elem_type(&self) -> TableElementType {
+    self.elem_type
+  }
+
+  pub const fn limits(&self) -> &ResizableLimits {
+    &self.limits
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MemoryType {
+  limits: ResizableLimits,
+  is_64: bool,
+  is_shared: bool,
+}
+
+impl MemoryType {
+  pub const fn new(initial: u32, maximum: Option<u32>) -> Self {
+    MemoryType {
+      limits: ResizableLimits::new(initial, maximum),
+      is_64: false,
+      is_shared: false,
+    }
+  }
+
+  /// A memory addressed with `i64` values, from the memory64 proposal.
+  pub const fn new_64(initial: u32, maximum: Option<u32>) -> Self {
+    MemoryType {
+      limits: ResizableLimits::new(initial, maximum),
+      is_64: true,
+      is_shared: false,
+    }
+  }
+
+  /// The same memory type, shared between threads.
+  pub const fn shared(self) -> Self {
+    MemoryType { is_shared: true, ..self }
+  }
+
+  pub const fn limits(&self) -> &ResizableLimits {
+    &self.limits
+  }
+
+  pub const fn is_64(&self) -> bool {
+    self.is_64
+  }
+
+  pub const fn is_shared(&self) -> bool {
+    self.is_shared
+  }
+
+  /// The type of addresses, `i64` for 64-bit memories.
+  pub const fn index_type(&self) -> ValueType {
+    if self.is_64 { ValueType::I64 } else { ValueType::I32 }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GlobalType {
+  content_type: ValueType,
+  is_mutable: bool,
+}
+
+impl GlobalType {
+  pub const fn new(content_type: ValueType, is_mutable: bool) -> Self {
+    GlobalType { content_type, is_mutable }
+  }
+
+  pub const fn content_type(&self) -> ValueType {
+    self.content_type
+  }
+
+  pub const fn is_mutable(&self) -> bool {
+    self.is_mutable
+  }
+}
+
+/// A constant expression, including the final `End`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InitExpr {
+  code: Vec<Instruction>,
+}
+
+impl InitExpr {
+  pub fn new(code: Vec<Instruction>) -> Self {
+    InitExpr { code }
+  }
+
+  pub fn code(&self) -> &[Instruction] {
+    &self.code
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GlobalEntry {
+  global_type: GlobalType,
+  init_expr: InitExpr,
+}
+
+impl GlobalEntry {
+  pub fn new(global_type: GlobalType, init_expr: InitExpr) -> Self {
+    GlobalEntry { global_type, init_expr }
+  }
+
+  pub const fn global_type(&self) -> &GlobalType {
+    &self.global_type
+  }
+
+  pub const fn init_expr(&self) -> &InitExpr {
+    &self.init_expr
+  }
+}
+
+/// Description of an import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum External {
+  /// Type index of the function.
+  Function(u32),
+  Table(TableType),
+  Memory(MemoryType),
+  Global(GlobalType),
+  /// Type index of the exception tag.
+  Tag(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImportEntry {
+  module: String,
+  field: String,
+  external: External,
+}
+
+impl ImportEntry {
+  pub fn new(module: String, field: String, external: External) -> Self {
+    ImportEntry { module, field, external }
+  }
+
+  pub fn module(&self) -> &str {
+    &self.module
+  }
+
+  pub fn field(&self) -> &str {
+    &self.field
+  }
+
+  pub const fn external(&self) -> &External {
+    &self.external
+  }
+}
+
+/// The item exported, by its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Internal {
+  Function(u32),
+  Table(u32),
+  Memory(u32),
+  Global(u32),
+  Tag(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExportEntry {
+  field: String,
+  internal: Internal,
+}
+
+impl ExportEntry {
+  pub fn new(field: String, internal: Internal) -> Self {
+    ExportEntry { field, internal }
+  }
+
+  pub fn field(&self) -> &str {
+    &self.field
+  }
+
+  pub const fn internal(&self) -> &Internal {
+    &self.internal
+  }
+}
+
+/// Element segment, passive and declared segments don't have an offset.
+///
+/// The members are function indices, `None` for `ref.null`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ElementSegment {
+  index: u32,
+  offset: Option<InitExpr>,
+  is_declared: bool,
+  elem_type: TableElementType,
+  members: Vec<Option<u32>>,
+}
+
+impl ElementSegment {
+  pub fn new(index: u32, offset: Option<InitExpr>, elem_type: TableElementType, members: Vec<Option<u32>>) -> Self {
+    ElementSegment { index, offset, is_declared: false, elem_type, members }
+  }
+
+  /// A declared segment, it only declares the functions referenced by the code.
+  pub fn declared(elem_type: TableElementType, members: Vec<Option<u32>>) -> Self {
+    ElementSegment { index: 0, offset: None, is_declared: true, elem_type, members }
+  }
+
+  pub const fn index(&self) -> u32 {
+    self.index
+  }
+
+  pub const fn offset(&self) -> &Option<InitExpr> {
+    &self.offset
+  }
+
+  pub const fn is_declared(&self) -> bool {
+    self.is_declared
+  }
+
+  pub const fn elem_type(&self) -> TableElementType {
+    self.elem_type
+  }
+
+  pub fn members(&self) -> &[Option<u32>] {
+    &self.members
+  }
+
+  pub fn members_mut(&mut self) -> &mut Vec<Option<u32>> {
+    &mut self.members
+  }
+}
+
+/// Data segment, passive segments don't have an offset.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DataSegment {
+  index: u32,
+  offset: Option<InitExpr>,
+  value: Vec<u8>,
+}
+
+impl DataSegment {
+  pub fn new(index: u32, offset: Option<InitExpr>, value: Vec<u8>) -> Self {
+    DataSegment { index, offset, value }
+  }
+
+  pub const fn index(&self) -> u32 {
+    self.index
+  }
+
+  pub const fn offset(&self) -> &Option<InitExpr> {
+    &self.offset
+  }
+
+  pub fn value_mut(&mut self) -> &mut Vec<u8> {
+    &mut self.value
+  }
+}
+
+/// `count` locals of the same type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Local {
+  count: u32,
+  value_type: ValueType,
+}
+
+impl Local {
+  pub const fn new(count: u32, value_type: ValueType) -> Self {
+    Local { count, value_type }
+  }
+
+  pub const fn count(&self) -> u32 {
+    self.count
+  }
+
+  pub const fn value_type(&self) -> ValueType {
+    self.value_type
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FuncBody {
+  locals: Vec<Local>,
+  code: Vec<Instruction>,
+}
+
+impl FuncBody {
+  pub fn new(locals: Vec<Local>, code: Vec<Instruction>) -> Self {
+    FuncBody { locals, code }
+  }
+
+  pub fn locals(&self) -> &[Local] {
+    &self.locals
+  }
+
+  pub fn code(&self) -> &[Instruction] {
+    &self.code
+  }
+
+  pub fn code_mut(&mut self) -> &mut Vec<Instruction> {
+    &mut self.code
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CustomSection {
+  name: String,
+  payload: Vec<u8>,
+}
+
+impl CustomSection {
+  pub fn new(name: String, payload: Vec<u8>) -> Self {
+    CustomSection { name, payload }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn payload(&self) -> &[u8] {
+    &self.payload
+  }
+}
+
+/// The sections of a module.  Each field holds the entries of one section,
+/// the name section is split into the module and function names.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Module {
+  pub types: Vec<FunctionType>,
+  pub imports: Vec<ImportEntry>,
+  /// Type index of each function defined by the module.
+  pub functions: Vec<u32>,
+  pub tables: Vec<TableType>,
+  pub memories: Vec<MemoryType>,
+  /// Type index of each exception tag defined by the module.
+  pub tags: Vec<u32>,
+  pub globals: Vec<GlobalEntry>,
+  pub exports: Vec<ExportEntry>,
+  pub start: Option<u32>,
+  pub elements: Vec<ElementSegment>,
+  pub code: Vec<FuncBody>,
+  pub data: Vec<DataSegment>,
+  /// Number of data segments, from the data count section.
+  pub data_count: Option<u32>,
+  /// Module name from the name section.
+  pub name: Option<String>,
+  /// Function names from the name section.
+  pub func_names: BTreeMap<u32, String>,
+  pub custom_sections: Vec<CustomSection>,
+}
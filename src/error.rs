@@ -1,4 +1,7 @@
 use std::fmt;
+use std::sync::Arc;
+
+use crate::{Exception, Tag, Value};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TrapKind {
@@ -6,27 +9,175 @@ pub enum TrapKind {
 
   Unreachable,
   MemoryAccessOutOfBounds,
+  /// An atomic access that isn't naturally aligned.
+  UnalignedAtomic,
+  /// `memory.atomic.wait` can only block on a shared memory.
+  WaitOnUnsharedMemory,
   TableAccessOutOfBounds,
   ElemUninitialized,
   DivisionByZero,
   InvalidConversionToInt,
-  StackOverflow,
+  IntegerOverflow,
+  NullReference,
+  /// The value stack would grow to `len` values, past its `limit`.
+  StackOverflow { limit: usize, len: usize },
+  /// The current frame has `len` values, but `needed` values are popped.
+  StackUnderflow { len: usize, needed: usize },
+  /// Too many nested function calls, or they used up the native stack
+  /// limit.  `limit` is the depth of the call that trapped.
+  CallStackExhausted { limit: usize },
   UnexpectedSignature,
+  /// The program asked to exit with a status code.
+  Exit(i32),
+  /// A host function aborted the program with an error.
+  Host(HostError),
+  /// A wasm exception, it unwinds the stack until it is caught.
+  Exception(Exception),
+}
+
+impl TrapKind {
+  /// Abort the program with an error, from a host function.
+  pub fn host<E: std::error::Error + Send + Sync + 'static>(error: E) -> TrapKind {
+    TrapKind::Host(HostError(Arc::new(error)))
+  }
+
+  /// Throw a wasm exception, from a host function.  The values must match
+  /// the params of the tag.
+  pub fn throw(tag: &Tag, values: &[Value]) -> TrapKind {
+    match Exception::new(tag, values) {
+      Ok(exn) => TrapKind::Exception(exn),
+      Err(trap) => trap,
+    }
+  }
+}
+
+/// An error raised by a host function.
+#[derive(Clone)]
+pub struct HostError(Arc<dyn std::error::Error + Send + Sync>);
+
+impl HostError {
+  pub fn downcast_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+    self.0.downcast_ref()
+  }
 }
+
+impl fmt::Debug for HostError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", self.0.to_string())
+  }
+}
+
+impl fmt::Display for HostError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.0.fmt(f)
+  }
+}
+
+/// Host errors are only equal to themselves.
+impl PartialEq for HostError {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for HostError {}
 pub type Trap<T, K = TrapKind> = std::result::Result<T, K>;
 
+/// A function frame in the backtrace of a trap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+  /// Module name from the name section, or the name it was loaded as.
+  pub module: Option<String>,
+  pub func_index: u32,
+  /// Function name from the name section, or the import name of a host function.
+  pub func_name: Option<String>,
+  /// Index of the trapping instruction in the function body, counted in
+  /// instructions from 0, not a byte offset.  Validation errors show the
+  /// same index after `func[N]:`.  `None` for host functions.
+  pub instr_index: Option<u32>,
+}
+
+impl fmt::Display for FrameInfo {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(module) = &self.module {
+      write!(f, "{}::", module)?;
+    }
+    match &self.func_name {
+      Some(name) => write!(f, "{}", name)?,
+      None => write!(f, "<func {}>", self.func_index)?,
+    }
+    write!(f, "\n             at func[{}]", self.func_index)?;
+    if let Some(instr_index) = self.instr_index {
+      write!(f, ":{}", instr_index)?;
+    }
+    Ok(())
+  }
+}
+
+/// Frames printed at each end of a long backtrace, the frames between them
+/// are skipped.
+const DISPLAY_FRAMES: usize = 10;
+
+/// The wasm call stack of a trap, the innermost frame first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Backtrace {
+  frames: Vec<FrameInfo>,
+}
+
+impl Backtrace {
+  pub fn new(frames: Vec<FrameInfo>) -> Backtrace {
+    Backtrace { frames }
+  }
+
+  pub fn frames(&self) -> &[FrameInfo] {
+    &self.frames
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.frames.is_empty()
+  }
+
+  /// Set the module name of frames that don't have one.
+  pub fn set_module_name(&mut self, name: &str) {
+    for frame in self.frames.iter_mut().filter(|frame| frame.module.is_none()) {
+      frame.module = Some(name.to_string());
+    }
+  }
+}
+
+impl fmt::Display for Backtrace {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "wasm backtrace:")?;
+    let len = self.frames.len();
+    for (idx, frame) in self.frames.iter().enumerate() {
+      if len > DISPLAY_FRAMES * 2 && idx >= DISPLAY_FRAMES && idx < len - DISPLAY_FRAMES {
+        if idx == DISPLAY_FRAMES {
+          write!(f, "\n      ... {} frames skipped", len - DISPLAY_FRAMES * 2)?;
+        }
+        continue;
+      }
+      write!(f, "\n{:>4}: {}", idx, frame)?;
+    }
+    Ok(())
+  }
+}
+
 #[derive(Debug, Clone)]
 pub enum Error {
   FuncNotFound,
   FuncExists,
+  TagNotFound,
+  MemoryNotFound,
 
   ModuleNotFound,
   ModuleExists,
 
-  ParseError(parity_wasm::SerializationError),
+  ParseError(String),
+  WatError(String),
   ValidationError(String),
+  LinkError(String),
 
-  RuntimeError(TrapKind),
+  RuntimeError(TrapKind, Backtrace),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -36,36 +187,62 @@ impl fmt::Display for Error {
     match self {
       Error::FuncNotFound => write!(f, "function not found"),
       Error::FuncExists => write!(f, "function already exists"),
+      Error::TagNotFound => write!(f, "tag not found"),
+      Error::MemoryNotFound => write!(f, "memory not found"),
       Error::ModuleNotFound => write!(f, "module not found"),
       Error::ModuleExists => write!(f, "module already exists"),
       Error::ParseError(e) => write!(f, "failed to parse wasm: {}", e),
+      Error::WatError(e) => write!(f, "failed to parse wat: {}", e),
       Error::ValidationError(e) => write!(f, "failed to validate wasm: {}", e),
-      Error::RuntimeError(trap) => write!(f, "runtime trap: {:?}", trap),
+      Error::LinkError(e) => write!(f, "failed to link module: {}", e),
+      Error::RuntimeError(trap, backtrace) if backtrace.is_empty() => {
+        write!(f, "runtime trap: {:?}", trap)
+      },
+      Error::RuntimeError(trap, backtrace) => {
+        write!(f, "runtime trap: {:?}\n{}", trap, backtrace)
+      },
     }
   }
 }
 
-impl std::error::Error for Error {
-}
+impl Error {
+  /// Get the error raised by a host function.
+  pub fn downcast_ref<E: std::error::Error + 'static>(&self) -> Option<&E> {
+    match self {
+      Error::RuntimeError(TrapKind::Host(error), _) => error.downcast_ref(),
+      _ => None,
+    }
+  }
 
-impl From<TrapKind> for Error {
-  fn from(trap: TrapKind) -> Self {
-    Error::RuntimeError(trap)
+  /// The wasm exception, if it wasn't caught.
+  pub fn exception(&self) -> Option<&Exception> {
+    match self {
+      Error::RuntimeError(TrapKind::Exception(exn), _) => Some(exn),
+      _ => None,
+    }
   }
-}
 
-impl From<parity_wasm::SerializationError> for Error {
-  fn from(error: parity_wasm::SerializationError) -> Self {
-    Error::ParseError(error)
+  /// The status code, if the program asked to exit.
+  pub fn exit_code(&self) -> Option<i32> {
+    match self {
+      Error::RuntimeError(TrapKind::Exit(code), _) => Some(*code),
+      _ => None,
+    }
   }
 }
 
-impl From<bwasm::LoadError> for Error {
-  fn from(error: bwasm::LoadError) -> Self {
-    match error {
-      bwasm::LoadError::SerializationError(error) => Error::ParseError(error),
-      bwasm::LoadError::ValidationError(error) => Error::ValidationError(format!("{}", error)),
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::RuntimeError(TrapKind::Host(error), _) => Some(&*error.0),
+      _ => None,
     }
   }
 }
 
+impl From<TrapKind> for Error {
+  fn from(trap: TrapKind) -> Self {
+    Error::RuntimeError(trap, Backtrace::default())
+  }
+}
+
@@ -0,0 +1,112 @@
+//! Exception tags, and the exceptions thrown with them.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::*;
+use crate::error::*;
+
+/// An exception tag.  Tags are compared by identity, each module instance
+/// creates its own tags.  The host creates tags to share them with modules
+/// as imports.
+#[derive(Clone)]
+pub struct Tag(Arc<Vec<ValueType>>);
+
+impl Tag {
+  /// Create a tag for exceptions with values of the `params` types.
+  pub fn new(params: &[ValueType]) -> Tag {
+    Tag(Arc::new(params.to_vec()))
+  }
+
+  pub fn params(&self) -> &[ValueType] {
+    &self.0
+  }
+}
+
+impl PartialEq for Tag {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for Tag {}
+
+impl fmt::Debug for Tag {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Tag({:?})", self.params())
+  }
+}
+
+struct ExceptionData {
+  tag: Tag,
+  values: Vec<Value>,
+}
+
+/// A wasm exception, thrown by `throw` or by a host function.  Exceptions are
+/// compared by identity, a rethrown exception is the same exception.
+#[derive(Clone)]
+pub struct Exception(Arc<ExceptionData>);
+
+impl Exception {
+  /// Create an exception, the values must match the params of the tag.
+  pub fn new(tag: &Tag, values: &[Value]) -> Trap<Exception> {
+    if !values.iter().map(Value::value_type).eq(tag.params().iter().copied()) {
+      return Err(TrapKind::UnexpectedSignature);
+    }
+    Ok(Exception(Arc::new(ExceptionData {
+      tag: tag.clone(),
+      values: values.to_vec(),
+    })))
+  }
+
+  /// Create an exception from the values popped by `throw`.
+  pub(crate) fn from_stack(tag: &Tag, values: &[StackValue]) -> Exception {
+    Exception(Arc::new(ExceptionData {
+      tag: tag.clone(),
+      values: from_stack_values(values, tag.params()),
+    }))
+  }
+
+  /// The values to push when the exception is caught.
+  pub(crate) fn to_stack(&self) -> Vec<StackValue> {
+    to_stack_values(&self.0.values)
+  }
+
+  pub fn tag(&self) -> &Tag {
+    &self.0.tag
+  }
+
+  pub fn values(&self) -> &[Value] {
+    &self.0.values
+  }
+
+  /// The handles of the `exnref` values of the exception.
+  pub(crate) fn handles(&self) -> impl Iterator<Item = u32> + '_ {
+    self.0.values.iter().filter_map(|val| match val {
+      Value::ExnRef(handle) => *handle,
+      _ => None,
+    })
+  }
+
+  /// Check if the exception was thrown with `tag`.
+  pub fn is(&self, tag: &Tag) -> bool {
+    self.0.tag == *tag
+  }
+}
+
+impl PartialEq for Exception {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for Exception {}
+
+impl fmt::Debug for Exception {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Exception")
+      .field("tag", self.tag())
+      .field("values", &self.values())
+      .finish()
+  }
+}
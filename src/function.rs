@@ -1,18 +1,40 @@
 
+use std::sync::Arc;
+
 use crate::*;
+use crate::compiler::Action;
 use crate::error::*;
 
-type CompiledFunc = Box<dyn Fn(&State, &mut Store, &mut StackValue) -> Trap<Option<StackValue>>>;
+/// A compiled function finishes with a `Return` or a `TailCall` action.
+type CompiledFunc = Box<dyn Fn(&State, &mut Store, &mut Registers) -> Trap<Action> + Send + Sync>;
+
+pub type HostFunc = Arc<dyn Fn(&mut Store, &[Value]) -> Trap<RetValue> + Send + Sync>;
 
 pub struct CompiledFunction {
   pub local_types: Vec<ValueType>,
+  /// Number of operand values the function saves on the stack.
+  pub operands: u32,
+  /// Hot locals moved to registers, with the register they swapped with.
+  pub swaps: Vec<(LocalIdx, LocalIdx)>,
   pub run: CompiledFunc,
 }
 
-#[derive(Debug, Clone)]
+/// A function provided by the host.
+#[derive(Clone)]
 pub struct HostFunction {
-  pub mod_idx: usize,
-  pub func_idx: usize,
+  pub func_type: FunctionType,
+  pub func: HostFunc,
+}
+
+impl HostFunction {
+  pub fn new<F>(func_type: FunctionType, func: F) -> HostFunction
+    where F: Fn(&mut Store, &[Value]) -> Trap<RetValue> + Send + Sync + 'static
+  {
+    HostFunction {
+      func_type,
+      func: Arc::new(func),
+    }
+  }
 }
 
 pub enum FunctionBody {
@@ -22,53 +44,144 @@ pub enum FunctionBody {
 
 pub struct Function {
   pub name: String,
+  /// Index in the module's function index space.
+  pub index: FuncIdx,
+  /// Name shown in trap backtraces.
+  pub debug_name: Option<String>,
   pub func_type: FunctionType,
   pub body: FunctionBody,
 }
 
 impl Function {
-  pub fn new(func: &bwasm::Function, run: CompiledFunc) -> Function {
+  pub fn new(func: &loader::Function, index: FuncIdx, operands: u32, swaps: Vec<(LocalIdx, LocalIdx)>, run: CompiledFunc) -> Function {
+    // A `v128` local takes two slots.  Locals moved out of registers can use
+    // spare slots after the locals.
+    let mut local_types: Vec<ValueType> = func.locals().iter().flat_map(|val_type| {
+      let val_type = ValueType::from(val_type);
+      std::iter::repeat_n(val_type, val_type.slots())
+    }).collect();
+    let count = ValueType::count_slots(func.params()) + local_types.len();
+    let slots = swaps.iter().map(|(_, local)| *local as usize + 1).fold(count, usize::max);
+    local_types.resize(local_types.len() + slots - count, ValueType::I32);
     Function {
       name: func.name().to_string(),
+      index,
+      debug_name: func.debug_name().map(String::from),
       func_type: FunctionType::from(func.func_type()),
       body: FunctionBody::Compiled(CompiledFunction{
-        local_types: ValueType::from_slice(func.locals()),
+        local_types,
+        operands,
+        swaps,
         run,
       }),
     }
   }
 
+  pub fn new_host(name: &str, host: HostFunction) -> Function {
+    Function {
+      name: name.to_string(),
+      index: 0,
+      debug_name: Some(name.to_string()),
+      func_type: host.func_type.clone(),
+      body: FunctionBody::Host(host),
+    }
+  }
+
   pub fn param_count(&self) -> usize {
     self.func_type.param_count()
   }
 
+  /// Number of stack values of the parameters, a `v128` takes two.
+  pub fn param_slots(&self) -> usize {
+    self.func_type.param_slots()
+  }
+
   pub fn ret_type(&self) -> Option<ValueType> {
     self.func_type.ret_type
   }
 
-  pub fn call(&self, state: &State, store: &mut Store, l0: &mut StackValue) -> Trap<Option<StackValue>> {
+  /// Call the function.  The first parameter is passed in register 0 of
+  /// `regs`, the other registers must be zero.  When there are more
+  /// parameters all of them must be pushed onto the stack.  A `v128` takes
+  /// two stack values, a `v128` result returns its low half and leaves the
+  /// high half in `Stack::ret_high`.
+  ///
+  /// Tail calls made by the function are run here in a loop, so a chain of
+  /// tail calls runs in constant native stack space.
+  pub fn call(&self, state: &State, store: &mut Store, regs: &mut Registers) -> Trap<Option<StackValue>> {
+    store.stack.enter_call()?;
+    let mut func = self;
+    let ret = loop {
+      match func.run(state, store, regs) {
+        Ok(Action::TailCall(func_addr)) => match state.get_function(func_addr) {
+          Ok(next) => func = next,
+          Err(trap) => break Err(trap),
+        },
+        Ok(Action::Return(ret)) => break Ok(ret),
+        // Branches and the end of the body are returned as `Return`.
+        Ok(_) => break Ok(None),
+        Err(trap) => {
+          store.push_trap_frame(func);
+          break Err(trap);
+        },
+      }
+    };
+    store.stack.leave_call();
+    ret
+  }
+
+  /// Run the function's body once, without following tail calls.
+  fn run(&self, state: &State, store: &mut Store, regs: &mut Registers) -> Trap<Action> {
     match self.body {
       FunctionBody::Compiled(ref body) => {
-        let p_count = self.param_count();
+        let p_count = self.param_slots();
         let l_count = body.local_types.len();
-        let ret = if p_count <= 1 && l_count == 0 {
+        if p_count == 0 {
+          // Clear local 0.
+          regs.0[0] = StackValue(0);
+        }
+        if p_count <= 1 && l_count == 0 && body.operands == 0 {
           // Fast function call.
-          (body.run)(state, store, l0)?
+          (body.run)(state, store, regs)
         } else {
-          // Setup stack frame for function.
-          let old_frame = store.stack.push_frame(p_count, l_count)?;
+          // Setup stack frame for function.  When there is only one parameter
+          // it is passed in register 0, reserve its slot with the other locals.
+          let old_frame = if p_count <= 1 {
+            let old_frame = store.stack.push_frame(0, p_count + l_count)?;
+            // Swaps are sorted by register, only a swap of local 0 moves a
+            // parameter.
+            if body.swaps.first().is_some_and(|(reg, _)| *reg == 0) {
+              store.stack.load_params(regs, p_count, &body.swaps);
+            }
+            old_frame
+          } else {
+            let old_frame = store.stack.push_frame(p_count, l_count)?;
+            store.stack.load_params(regs, p_count, &body.swaps);
+            old_frame
+          };
 
           // run function
-          let ret = (body.run)(state, store, l0)?;
+          let ret = (body.run)(state, store, regs);
 
-          // cleanup stack frame.
-          store.stack.pop_frame(old_frame);
+          // cleanup stack frame, keeping the parameters of a tail call.
+          match ret {
+            Ok(Action::TailCall(func_addr)) => {
+              let params = state.get_function(func_addr)?.param_slots();
+              store.stack.pop_frame_keep(old_frame, if params > 1 { params } else { 0 });
+            },
+            _ => store.stack.pop_frame(old_frame),
+          }
           ret
-        };
-        Ok(ret)
+        }
       },
-      FunctionBody::Host(_) => {
-        todo!("");
+      FunctionBody::Host(ref host) => {
+        let params = match self.param_slots() {
+          0 => vec![],
+          1 => vec![regs.0[0].to_value(self.func_type.params[0])],
+          count => from_stack_values(&store.stack.pop_values(count)?, &self.func_type.params),
+        };
+        let ret = (host.func)(store, &params)?;
+        Ok(Action::Return(ret.map(|ret| store.stack.ret_value(ret))))
       },
     }
   }
@@ -0,0 +1,127 @@
+
+use std::collections::HashMap;
+
+use crate::*;
+use crate::error::*;
+
+/// Host functions, memories and exception tags that can be imported by
+/// modules.
+#[derive(Default, Clone)]
+pub struct Imports {
+  funcs: HashMap<(String, String), HostFunction>,
+  memories: HashMap<(String, String), loader::MemoryType>,
+  shared_memories: HashMap<(String, String), SharedMemory>,
+  tags: HashMap<(String, String), Tag>,
+}
+
+impl Imports {
+  pub fn new() -> Imports {
+    Default::default()
+  }
+
+  /// Add a host function that modules can import as `module.name`.
+  pub fn add_function<F>(&mut self, module: &str, name: &str, params: &[ValueType], ret_type: Option<ValueType>, func: F)
+    where F: Fn(&mut Store, &[Value]) -> Trap<RetValue> + Send + Sync + 'static
+  {
+    let func_type = FunctionType::with_types(params, ret_type);
+    self.add_host_function(module, name, HostFunction::new(func_type, func));
+  }
+
+  pub fn add_host_function(&mut self, module: &str, name: &str, func: HostFunction) {
+    self.funcs.insert((module.to_string(), name.to_string()), func);
+  }
+
+  pub fn get_function(&self, module: &str, name: &str) -> Option<&HostFunction> {
+    self.funcs.get(&(module.to_string(), name.to_string()))
+  }
+
+  /// Add a memory of `initial` pages that modules can import as
+  /// `module.name`.  Each `Store` allocates its own copy of the memory.
+  pub fn add_memory(&mut self, module: &str, name: &str, initial: u32, maximum: Option<u32>) {
+    let memory_type = loader::MemoryType::new(initial, maximum);
+    self.memories.insert((module.to_string(), name.to_string()), memory_type);
+  }
+
+  /// Add a 64-bit memory of `initial` pages, see `add_memory`.
+  pub fn add_memory_64(&mut self, module: &str, name: &str, initial: u32, maximum: Option<u32>) {
+    let memory_type = loader::MemoryType::new_64(initial, maximum);
+    self.memories.insert((module.to_string(), name.to_string()), memory_type);
+  }
+
+  pub fn get_memory(&self, module: &str, name: &str) -> Option<&loader::MemoryType> {
+    self.memories.get(&(module.to_string(), name.to_string()))
+  }
+
+  /// Resolve a memory import, the host memory must be at least as large as
+  /// the import and can't grow past the import's maximum.  Both must have
+  /// the same address type.
+  pub fn resolve_memory(&self, module: &str, name: &str, memory_type: &loader::MemoryType) -> Result<loader::MemoryType> {
+    let host_type = self.get_memory(module, name)
+      .ok_or_else(|| Error::LinkError(format!("Unknown import: {}.{}", module, name)))?;
+    check_memory_type(module, name, memory_type, host_type)?;
+    Ok(*host_type)
+  }
+
+  /// Add a shared memory that modules can import as `module.name`.  All
+  /// stores that import it use the same memory.
+  pub fn add_shared_memory(&mut self, module: &str, name: &str, memory: SharedMemory) {
+    self.shared_memories.insert((module.to_string(), name.to_string()), memory);
+  }
+
+  pub fn get_shared_memory(&self, module: &str, name: &str) -> Option<&SharedMemory> {
+    self.shared_memories.get(&(module.to_string(), name.to_string()))
+  }
+
+  /// Resolve a shared memory import, see `resolve_memory`.
+  pub fn resolve_shared_memory(&self, module: &str, name: &str, memory_type: &loader::MemoryType) -> Result<SharedMemory> {
+    let memory = self.get_shared_memory(module, name)
+      .ok_or_else(|| Error::LinkError(format!("Unknown import: {}.{}", module, name)))?;
+    check_memory_type(module, name, memory_type, memory.memory_type())?;
+    Ok(memory.clone())
+  }
+
+  /// Add an exception tag that modules can import as `module.name`.  The
+  /// host can throw and catch exceptions with the same tag.
+  pub fn add_tag(&mut self, module: &str, name: &str, tag: Tag) {
+    self.tags.insert((module.to_string(), name.to_string()), tag);
+  }
+
+  pub fn get_tag(&self, module: &str, name: &str) -> Option<&Tag> {
+    self.tags.get(&(module.to_string(), name.to_string()))
+  }
+
+  /// Resolve a tag import, checking that the types match.
+  pub fn resolve_tag(&self, module: &str, name: &str, params: &[ValueType]) -> Result<Tag> {
+    let tag = self.get_tag(module, name)
+      .ok_or_else(|| Error::LinkError(format!("Unknown import: {}.{}", module, name)))?;
+    if tag.params() != params {
+      return Err(Error::LinkError(format!("Incompatible import type: {}.{}", module, name)));
+    }
+    Ok(tag.clone())
+  }
+
+  /// Resolve a function import, checking that the types match.
+  pub fn resolve(&self, module: &str, name: &str, func_type: &FunctionType) -> Result<Function> {
+    let host = self.get_function(module, name)
+      .ok_or_else(|| Error::LinkError(format!("Unknown import: {}.{}", module, name)))?;
+    if host.func_type != *func_type {
+      return Err(Error::LinkError(format!("Incompatible import type: {}.{}", module, name)));
+    }
+    Ok(Function::new_host(&format!("{}.{}", module, name), host.clone()))
+  }
+}
+
+fn check_memory_type(module: &str, name: &str, memory_type: &loader::MemoryType, host_type: &loader::MemoryType) -> Result<()> {
+  let (limits, memory) = (memory_type.limits(), host_type.limits());
+  let max_ok = match (limits.maximum(), memory.maximum()) {
+    (None, _) => true,
+    (Some(max), Some(host_max)) => host_max <= max,
+    (Some(_), None) => false,
+  };
+  if memory.initial() < limits.initial() || !max_ok
+    || host_type.is_64() != memory_type.is_64() || host_type.is_shared() != memory_type.is_shared()
+  {
+    return Err(Error::LinkError(format!("Incompatible import type: {}.{}", module, name)));
+  }
+  Ok(())
+}
@@ -0,0 +1,788 @@
+//! The instruction set, as decoded from the binary or text format.
+//!
+//! The names follow `parity_wasm`, which was used to decode modules before.
+
+use std::fmt;
+
+/// Type of a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+  I32,
+  I64,
+  F32,
+  F64,
+  V128,
+  FuncRef,
+  ExternRef,
+  /// A caught exception.
+  ExnRef,
+}
+
+impl ValueType {
+  /// Reference types can't be used by numeric instructions.
+  pub const fn is_ref(&self) -> bool {
+    matches!(self, ValueType::FuncRef | ValueType::ExternRef | ValueType::ExnRef)
+  }
+}
+
+impl fmt::Display for ValueType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ValueType::I32 => write!(f, "i32"),
+      ValueType::I64 => write!(f, "i64"),
+      ValueType::F32 => write!(f, "f32"),
+      ValueType::F64 => write!(f, "f64"),
+      ValueType::V128 => write!(f, "v128"),
+      ValueType::FuncRef => write!(f, "funcref"),
+      ValueType::ExternRef => write!(f, "externref"),
+      ValueType::ExnRef => write!(f, "exnref"),
+    }
+  }
+}
+
+/// Result type of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockType {
+  Value(ValueType),
+  NoResult,
+}
+
+/// Branch targets of a `br_table`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BrTableData {
+  pub table: Box<[u32]>,
+  pub default: u32,
+}
+
+/// The immediate of a load or store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MemArg {
+  /// Log2 of the alignment.
+  pub align: u32,
+  /// Offset added to the address, it must fit in 32 bits for 32-bit memories.
+  pub offset: u64,
+  /// Memory index.
+  pub memory: u32,
+}
+
+/// A handler of a `try_table`, the labels are relative to the block around
+/// the `try_table`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Catch {
+  /// Tag and label.
+  Catch(u32, u32),
+  /// Tag and label, the exception is also passed as an `exnref`.
+  CatchRef(u32, u32),
+  CatchAll(u32),
+  CatchAllRef(u32),
+}
+
+/// A wasm instruction.  Memory instructions have the log2 of the alignment
+/// and the offset as immediates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Instruction {
+  Unreachable,
+  Nop,
+  Block(BlockType),
+  Loop(BlockType),
+  If(BlockType),
+  Else,
+  End,
+  Br(u32),
+  BrIf(u32),
+  BrTable(Box<BrTableData>),
+  Return,
+
+  TryTable(BlockType, Box<[Catch]>),
+  /// Tag index.
+  Throw(u32),
+  ThrowRef,
+  /// The legacy exception instructions.  `catch` has a tag index, the
+  /// labels of `rethrow` and `delegate` are branch depths.
+  Try(BlockType),
+  Catch(u32),
+  CatchAll,
+  Rethrow(u32),
+  Delegate(u32),
+
+  Call(u32),
+  /// Type and table index.
+  CallIndirect(u32, u32),
+  /// Tail calls, the callee replaces the current function.
+  ReturnCall(u32),
+  /// Type and table index.
+  ReturnCallIndirect(u32, u32),
+
+  Drop,
+  Select,
+  /// `select` with an explicit result type, needed for references.
+  TypedSelect(ValueType),
+
+  GetLocal(u32),
+  SetLocal(u32),
+  TeeLocal(u32),
+  GetGlobal(u32),
+  SetGlobal(u32),
+
+  I32Load(MemArg),
+  I64Load(MemArg),
+  F32Load(MemArg),
+  F64Load(MemArg),
+  I32Load8S(MemArg),
+  I32Load8U(MemArg),
+  I32Load16S(MemArg),
+  I32Load16U(MemArg),
+  I64Load8S(MemArg),
+  I64Load8U(MemArg),
+  I64Load16S(MemArg),
+  I64Load16U(MemArg),
+  I64Load32S(MemArg),
+  I64Load32U(MemArg),
+  I32Store(MemArg),
+  I64Store(MemArg),
+  F32Store(MemArg),
+  F64Store(MemArg),
+  I32Store8(MemArg),
+  I32Store16(MemArg),
+  I64Store8(MemArg),
+  I64Store16(MemArg),
+  I64Store32(MemArg),
+
+  /// Memory index.
+  CurrentMemory(u32),
+  GrowMemory(u32),
+
+  I32Const(i32),
+  I64Const(i64),
+  /// Raw bits of the float.
+  F32Const(u32),
+  /// Raw bits of the float.
+  F64Const(u64),
+
+  I32Eqz,
+  I32Eq,
+  I32Ne,
+  I32LtS,
+  I32LtU,
+  I32GtS,
+  I32GtU,
+  I32LeS,
+  I32LeU,
+  I32GeS,
+  I32GeU,
+
+  I64Eqz,
+  I64Eq,
+  I64Ne,
+  I64LtS,
+  I64LtU,
+  I64GtS,
+  I64GtU,
+  I64LeS,
+  I64LeU,
+  I64GeS,
+  I64GeU,
+
+  F32Eq,
+  F32Ne,
+  F32Lt,
+  F32Gt,
+  F32Le,
+  F32Ge,
+
+  F64Eq,
+  F64Ne,
+  F64Lt,
+  F64Gt,
+  F64Le,
+  F64Ge,
+
+  I32Clz,
+  I32Ctz,
+  I32Popcnt,
+  I32Add,
+  I32Sub,
+  I32Mul,
+  I32DivS,
+  I32DivU,
+  I32RemS,
+  I32RemU,
+  I32And,
+  I32Or,
+  I32Xor,
+  I32Shl,
+  I32ShrS,
+  I32ShrU,
+  I32Rotl,
+  I32Rotr,
+
+  I64Clz,
+  I64Ctz,
+  I64Popcnt,
+  I64Add,
+  I64Sub,
+  I64Mul,
+  I64DivS,
+  I64DivU,
+  I64RemS,
+  I64RemU,
+  I64And,
+  I64Or,
+  I64Xor,
+  I64Shl,
+  I64ShrS,
+  I64ShrU,
+  I64Rotl,
+  I64Rotr,
+
+  F32Abs,
+  F32Neg,
+  F32Ceil,
+  F32Floor,
+  F32Trunc,
+  F32Nearest,
+  F32Sqrt,
+  F32Add,
+  F32Sub,
+  F32Mul,
+  F32Div,
+  F32Min,
+  F32Max,
+  F32Copysign,
+
+  F64Abs,
+  F64Neg,
+  F64Ceil,
+  F64Floor,
+  F64Trunc,
+  F64Nearest,
+  F64Sqrt,
+  F64Add,
+  F64Sub,
+  F64Mul,
+  F64Div,
+  F64Min,
+  F64Max,
+  F64Copysign,
+
+  I32WrapI64,
+  I32TruncSF32,
+  I32TruncUF32,
+  I32TruncSF64,
+  I32TruncUF64,
+  I64ExtendSI32,
+  I64ExtendUI32,
+  I64TruncSF32,
+  I64TruncUF32,
+  I64TruncSF64,
+  I64TruncUF64,
+  F32ConvertSI32,
+  F32ConvertUI32,
+  F32ConvertSI64,
+  F32ConvertUI64,
+  F32DemoteF64,
+  F64ConvertSI32,
+  F64ConvertUI32,
+  F64ConvertSI64,
+  F64ConvertUI64,
+  F64PromoteF32,
+
+  I32ReinterpretF32,
+  I64ReinterpretF64,
+  F32ReinterpretI32,
+  F64ReinterpretI64,
+
+  // Sign-extension operators.
+  I32Extend8S,
+  I32Extend16S,
+  I64Extend8S,
+  I64Extend16S,
+  I64Extend32S,
+
+  // Non-trapping float-to-int conversions.
+  I32TruncSatSF32,
+  I32TruncSatUF32,
+  I32TruncSatSF64,
+  I32TruncSatUF64,
+  I64TruncSatSF32,
+  I64TruncSatUF32,
+  I64TruncSatSF64,
+  I64TruncSatUF64,
+
+  // Wide arithmetic, with two `i64` results: the low and high halves.
+  I64Add128,
+  I64Sub128,
+  I64MulWideS,
+  I64MulWideU,
+
+  // Bulk memory operations.
+  /// Data segment and memory index.
+  MemoryInit(u32, u32),
+  DataDrop(u32),
+  /// Destination and source memory index.
+  MemoryCopy(u32, u32),
+  MemoryFill(u32),
+  /// Element segment and table index.
+  TableInit(u32, u32),
+  ElemDrop(u32),
+  /// Destination and source table index.
+  TableCopy(u32, u32),
+
+  // Reference types.
+  /// Null reference of the `funcref` or `externref` type.
+  RefNull(ValueType),
+  RefIsNull,
+  RefFunc(u32),
+  /// Table index of the table instructions.
+  TableGet(u32),
+  TableSet(u32),
+  TableSize(u32),
+  TableGrow(u32),
+  TableFill(u32),
+
+  /// Fixed-width SIMD instructions, boxed to keep `Instruction` small.
+  Simd(Box<SimdInstruction>),
+  /// Atomic memory instructions from the threads proposal.
+  Atomic(Box<AtomicInstruction>),
+}
+
+/// Operand and result types of a SIMD instruction without immediates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SimdKind {
+  /// `v128 -> v128`
+  Unop,
+  /// `v128 v128 -> v128`
+  Binop,
+  /// `v128 v128 v128 -> v128`
+  Ternop,
+  /// `v128 -> i32`
+  Test,
+  /// `v128 i32 -> v128`
+  Shift,
+  /// `t -> v128`
+  Splat(ValueType),
+}
+
+macro_rules! simd_instructions {
+  ($($opcode:literal => $name:ident, $text:literal, $kind:expr;)*) => {
+    /// A SIMD instruction, with the `0xfd` prefix.  Memory instructions have
+    /// a `MemArg` immediate, lane instructions have the lane index.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub enum SimdInstruction {
+      V128Load(MemArg),
+      V128Load8x8S(MemArg),
+      V128Load8x8U(MemArg),
+      V128Load16x4S(MemArg),
+      V128Load16x4U(MemArg),
+      V128Load32x2S(MemArg),
+      V128Load32x2U(MemArg),
+      V128Load8Splat(MemArg),
+      V128Load16Splat(MemArg),
+      V128Load32Splat(MemArg),
+      V128Load64Splat(MemArg),
+      V128Load32Zero(MemArg),
+      V128Load64Zero(MemArg),
+      V128Store(MemArg),
+      V128Load8Lane(MemArg, u8),
+      V128Load16Lane(MemArg, u8),
+      V128Load32Lane(MemArg, u8),
+      V128Load64Lane(MemArg, u8),
+      V128Store8Lane(MemArg, u8),
+      V128Store16Lane(MemArg, u8),
+      V128Store32Lane(MemArg, u8),
+      V128Store64Lane(MemArg, u8),
+
+      /// Lane 0 is the least significant.
+      V128Const(u128),
+      /// Lane indices into the two operands.
+      I8x16Shuffle([u8; 16]),
+
+      I8x16ExtractLaneS(u8),
+      I8x16ExtractLaneU(u8),
+      I8x16ReplaceLane(u8),
+      I16x8ExtractLaneS(u8),
+      I16x8ExtractLaneU(u8),
+      I16x8ReplaceLane(u8),
+      I32x4ExtractLane(u8),
+      I32x4ReplaceLane(u8),
+      I64x2ExtractLane(u8),
+      I64x2ReplaceLane(u8),
+      F32x4ExtractLane(u8),
+      F32x4ReplaceLane(u8),
+      F64x2ExtractLane(u8),
+      F64x2ReplaceLane(u8),
+
+      $($name,)*
+    }
+
+    impl SimdInstruction {
+      /// The instruction without immediates for an opcode.
+      pub fn from_opcode(opcode: u32) -> Option<SimdInstruction> {
+        match opcode {
+          $($opcode => Some(SimdInstruction::$name),)*
+          _ => None,
+        }
+      }
+
+      /// The instruction without immediates for a text format name.
+      pub fn from_name(name: &str) -> Option<SimdInstruction> {
+        match name {
+          $($text => Some(SimdInstruction::$name),)*
+          _ => None,
+        }
+      }
+
+      /// The operand and result types, for instructions without immediates.
+      pub fn kind(&self) -> Option<SimdKind> {
+        use SimdKind::*;
+        use ValueType::*;
+        match self {
+          $(SimdInstruction::$name => Some($kind),)*
+          _ => None,
+        }
+      }
+    }
+  };
+}
+
+simd_instructions! {
+  0x0e => I8x16Swizzle, "i8x16.swizzle", Binop;
+  0x0f => I8x16Splat, "i8x16.splat", Splat(I32);
+  0x10 => I16x8Splat, "i16x8.splat", Splat(I32);
+  0x11 => I32x4Splat, "i32x4.splat", Splat(I32);
+  0x12 => I64x2Splat, "i64x2.splat", Splat(I64);
+  0x13 => F32x4Splat, "f32x4.splat", Splat(F32);
+  0x14 => F64x2Splat, "f64x2.splat", Splat(F64);
+
+  0x23 => I8x16Eq, "i8x16.eq", Binop;
+  0x24 => I8x16Ne, "i8x16.ne", Binop;
+  0x25 => I8x16LtS, "i8x16.lt_s", Binop;
+  0x26 => I8x16LtU, "i8x16.lt_u", Binop;
+  0x27 => I8x16GtS, "i8x16.gt_s", Binop;
+  0x28 => I8x16GtU, "i8x16.gt_u", Binop;
+  0x29 => I8x16LeS, "i8x16.le_s", Binop;
+  0x2a => I8x16LeU, "i8x16.le_u", Binop;
+  0x2b => I8x16GeS, "i8x16.ge_s", Binop;
+  0x2c => I8x16GeU, "i8x16.ge_u", Binop;
+  0x2d => I16x8Eq, "i16x8.eq", Binop;
+  0x2e => I16x8Ne, "i16x8.ne", Binop;
+  0x2f => I16x8LtS, "i16x8.lt_s", Binop;
+  0x30 => I16x8LtU, "i16x8.lt_u", Binop;
+  0x31 => I16x8GtS, "i16x8.gt_s", Binop;
+  0x32 => I16x8GtU, "i16x8.gt_u", Binop;
+  0x33 => I16x8LeS, "i16x8.le_s", Binop;
+  0x34 => I16x8LeU, "i16x8.le_u", Binop;
+  0x35 => I16x8GeS, "i16x8.ge_s", Binop;
+  0x36 => I16x8GeU, "i16x8.ge_u", Binop;
+  0x37 => I32x4Eq, "i32x4.eq", Binop;
+  0x38 => I32x4Ne, "i32x4.ne", Binop;
+  0x39 => I32x4LtS, "i32x4.lt_s", Binop;
+  0x3a => I32x4LtU, "i32x4.lt_u", Binop;
+  0x3b => I32x4GtS, "i32x4.gt_s", Binop;
+  0x3c => I32x4GtU, "i32x4.gt_u", Binop;
+  0x3d => I32x4LeS, "i32x4.le_s", Binop;
+  0x3e => I32x4LeU, "i32x4.le_u", Binop;
+  0x3f => I32x4GeS, "i32x4.ge_s", Binop;
+  0x40 => I32x4GeU, "i32x4.ge_u", Binop;
+  0x41 => F32x4Eq, "f32x4.eq", Binop;
+  0x42 => F32x4Ne, "f32x4.ne", Binop;
+  0x43 => F32x4Lt, "f32x4.lt", Binop;
+  0x44 => F32x4Gt, "f32x4.gt", Binop;
+  0x45 => F32x4Le, "f32x4.le", Binop;
+  0x46 => F32x4Ge, "f32x4.ge", Binop;
+  0x47 => F64x2Eq, "f64x2.eq", Binop;
+  0x48 => F64x2Ne, "f64x2.ne", Binop;
+  0x49 => F64x2Lt, "f64x2.lt", Binop;
+  0x4a => F64x2Gt, "f64x2.gt", Binop;
+  0x4b => F64x2Le, "f64x2.le", Binop;
+  0x4c => F64x2Ge, "f64x2.ge", Binop;
+
+  0x4d => V128Not, "v128.not", Unop;
+  0x4e => V128And, "v128.and", Binop;
+  0x4f => V128AndNot, "v128.andnot", Binop;
+  0x50 => V128Or, "v128.or", Binop;
+  0x51 => V128Xor, "v128.xor", Binop;
+  0x52 => V128Bitselect, "v128.bitselect", Ternop;
+  0x53 => V128AnyTrue, "v128.any_true", Test;
+
+  0x5e => F32x4DemoteF64x2Zero, "f32x4.demote_f64x2_zero", Unop;
+  0x5f => F64x2PromoteLowF32x4, "f64x2.promote_low_f32x4", Unop;
+
+  0x60 => I8x16Abs, "i8x16.abs", Unop;
+  0x61 => I8x16Neg, "i8x16.neg", Unop;
+  0x62 => I8x16Popcnt, "i8x16.popcnt", Unop;
+  0x63 => I8x16AllTrue, "i8x16.all_true", Test;
+  0x64 => I8x16Bitmask, "i8x16.bitmask", Test;
+  0x65 => I8x16NarrowI16x8S, "i8x16.narrow_i16x8_s", Binop;
+  0x66 => I8x16NarrowI16x8U, "i8x16.narrow_i16x8_u", Binop;
+  0x67 => F32x4Ceil, "f32x4.ceil", Unop;
+  0x68 => F32x4Floor, "f32x4.floor", Unop;
+  0x69 => F32x4Trunc, "f32x4.trunc", Unop;
+  0x6a => F32x4Nearest, "f32x4.nearest", Unop;
+  0x6b => I8x16Shl, "i8x16.shl", Shift;
+  0x6c => I8x16ShrS, "i8x16.shr_s", Shift;
+  0x6d => I8x16ShrU, "i8x16.shr_u", Shift;
+  0x6e => I8x16Add, "i8x16.add", Binop;
+  0x6f => I8x16AddSatS, "i8x16.add_sat_s", Binop;
+  0x70 => I8x16AddSatU, "i8x16.add_sat_u", Binop;
+  0x71 => I8x16Sub, "i8x16.sub", Binop;
+  0x72 => I8x16SubSatS, "i8x16.sub_sat_s", Binop;
+  0x73 => I8x16SubSatU, "i8x16.sub_sat_u", Binop;
+  0x74 => F64x2Ceil, "f64x2.ceil", Unop;
+  0x75 => F64x2Floor, "f64x2.floor", Unop;
+  0x76 => I8x16MinS, "i8x16.min_s", Binop;
+  0x77 => I8x16MinU, "i8x16.min_u", Binop;
+  0x78 => I8x16MaxS, "i8x16.max_s", Binop;
+  0x79 => I8x16MaxU, "i8x16.max_u", Binop;
+  0x7a => F64x2Trunc, "f64x2.trunc", Unop;
+  0x7b => I8x16AvgrU, "i8x16.avgr_u", Binop;
+  0x7c => I16x8ExtaddPairwiseI8x16S, "i16x8.extadd_pairwise_i8x16_s", Unop;
+  0x7d => I16x8ExtaddPairwiseI8x16U, "i16x8.extadd_pairwise_i8x16_u", Unop;
+  0x7e => I32x4ExtaddPairwiseI16x8S, "i32x4.extadd_pairwise_i16x8_s", Unop;
+  0x7f => I32x4ExtaddPairwiseI16x8U, "i32x4.extadd_pairwise_i16x8_u", Unop;
+
+  0x80 => I16x8Abs, "i16x8.abs", Unop;
+  0x81 => I16x8Neg, "i16x8.neg", Unop;
+  0x82 => I16x8Q15mulrSatS, "i16x8.q15mulr_sat_s", Binop;
+  0x83 => I16x8AllTrue, "i16x8.all_true", Test;
+  0x84 => I16x8Bitmask, "i16x8.bitmask", Test;
+  0x85 => I16x8NarrowI32x4S, "i16x8.narrow_i32x4_s", Binop;
+  0x86 => I16x8NarrowI32x4U, "i16x8.narrow_i32x4_u", Binop;
+  0x87 => I16x8ExtendLowI8x16S, "i16x8.extend_low_i8x16_s", Unop;
+  0x88 => I16x8ExtendHighI8x16S, "i16x8.extend_high_i8x16_s", Unop;
+  0x89 => I16x8ExtendLowI8x16U, "i16x8.extend_low_i8x16_u", Unop;
+  0x8a => I16x8ExtendHighI8x16U, "i16x8.extend_high_i8x16_u", Unop;
+  0x8b => I16x8Shl, "i16x8.shl", Shift;
+  0x8c => I16x8ShrS, "i16x8.shr_s", Shift;
+  0x8d => I16x8ShrU, "i16x8.shr_u", Shift;
+  0x8e => I16x8Add, "i16x8.add", Binop;
+  0x8f => I16x8AddSatS, "i16x8.add_sat_s", Binop;
+  0x90 => I16x8AddSatU, "i16x8.add_sat_u", Binop;
+  0x91 => I16x8Sub, "i16x8.sub", Binop;
+  0x92 => I16x8SubSatS, "i16x8.sub_sat_s", Binop;
+  0x93 => I16x8SubSatU, "i16x8.sub_sat_u", Binop;
+  0x94 => F64x2Nearest, "f64x2.nearest", Unop;
+  0x95 => I16x8Mul, "i16x8.mul", Binop;
+  0x96 => I16x8MinS, "i16x8.min_s", Binop;
+  0x97 => I16x8MinU, "i16x8.min_u", Binop;
+  0x98 => I16x8MaxS, "i16x8.max_s", Binop;
+  0x99 => I16x8MaxU, "i16x8.max_u", Binop;
+  0x9b => I16x8AvgrU, "i16x8.avgr_u", Binop;
+  0x9c => I16x8ExtmulLowI8x16S, "i16x8.extmul_low_i8x16_s", Binop;
+  0x9d => I16x8ExtmulHighI8x16S, "i16x8.extmul_high_i8x16_s", Binop;
+  0x9e => I16x8ExtmulLowI8x16U, "i16x8.extmul_low_i8x16_u", Binop;
+  0x9f => I16x8ExtmulHighI8x16U, "i16x8.extmul_high_i8x16_u", Binop;
+
+  0xa0 => I32x4Abs, "i32x4.abs", Unop;
+  0xa1 => I32x4Neg, "i32x4.neg", Unop;
+  0xa3 => I32x4AllTrue, "i32x4.all_true", Test;
+  0xa4 => I32x4Bitmask, "i32x4.bitmask", Test;
+  0xa7 => I32x4ExtendLowI16x8S, "i32x4.extend_low_i16x8_s", Unop;
+  0xa8 => I32x4ExtendHighI16x8S, "i32x4.extend_high_i16x8_s", Unop;
+  0xa9 => I32x4ExtendLowI16x8U, "i32x4.extend_low_i16x8_u", Unop;
+  0xaa => I32x4ExtendHighI16x8U, "i32x4.extend_high_i16x8_u", Unop;
+  0xab => I32x4Shl, "i32x4.shl", Shift;
+  0xac => I32x4ShrS, "i32x4.shr_s", Shift;
+  0xad => I32x4ShrU, "i32x4.shr_u", Shift;
+  0xae => I32x4Add, "i32x4.add", Binop;
+  0xb1 => I32x4Sub, "i32x4.sub", Binop;
+  0xb5 => I32x4Mul, "i32x4.mul", Binop;
+  0xb6 => I32x4MinS, "i32x4.min_s", Binop;
+  0xb7 => I32x4MinU, "i32x4.min_u", Binop;
+  0xb8 => I32x4MaxS, "i32x4.max_s", Binop;
+  0xb9 => I32x4MaxU, "i32x4.max_u", Binop;
+  0xba => I32x4DotI16x8S, "i32x4.dot_i16x8_s", Binop;
+  0xbc => I32x4ExtmulLowI16x8S, "i32x4.extmul_low_i16x8_s", Binop;
+  0xbd => I32x4ExtmulHighI16x8S, "i32x4.extmul_high_i16x8_s", Binop;
+  0xbe => I32x4ExtmulLowI16x8U, "i32x4.extmul_low_i16x8_u", Binop;
+  0xbf => I32x4ExtmulHighI16x8U, "i32x4.extmul_high_i16x8_u", Binop;
+
+  0xc0 => I64x2Abs, "i64x2.abs", Unop;
+  0xc1 => I64x2Neg, "i64x2.neg", Unop;
+  0xc3 => I64x2AllTrue, "i64x2.all_true", Test;
+  0xc4 => I64x2Bitmask, "i64x2.bitmask", Test;
+  0xc7 => I64x2ExtendLowI32x4S, "i64x2.extend_low_i32x4_s", Unop;
+  0xc8 => I64x2ExtendHighI32x4S, "i64x2.extend_high_i32x4_s", Unop;
+  0xc9 => I64x2ExtendLowI32x4U, "i64x2.extend_low_i32x4_u", Unop;
+  0xca => I64x2ExtendHighI32x4U, "i64x2.extend_high_i32x4_u", Unop;
+  0xcb => I64x2Shl, "i64x2.shl", Shift;
+  0xcc => I64x2ShrS, "i64x2.shr_s", Shift;
+  0xcd => I64x2ShrU, "i64x2.shr_u", Shift;
+  0xce => I64x2Add, "i64x2.add", Binop;
+  0xd1 => I64x2Sub, "i64x2.sub", Binop;
+  0xd5 => I64x2Mul, "i64x2.mul", Binop;
+  0xd6 => I64x2Eq, "i64x2.eq", Binop;
+  0xd7 => I64x2Ne, "i64x2.ne", Binop;
+  0xd8 => I64x2LtS, "i64x2.lt_s", Binop;
+  0xd9 => I64x2GtS, "i64x2.gt_s", Binop;
+  0xda => I64x2LeS, "i64x2.le_s", Binop;
+  0xdb => I64x2GeS, "i64x2.ge_s", Binop;
+  0xdc => I64x2ExtmulLowI32x4S, "i64x2.extmul_low_i32x4_s", Binop;
+  0xdd => I64x2ExtmulHighI32x4S, "i64x2.extmul_high_i32x4_s", Binop;
+  0xde => I64x2ExtmulLowI32x4U, "i64x2.extmul_low_i32x4_u", Binop;
+  0xdf => I64x2ExtmulHighI32x4U, "i64x2.extmul_high_i32x4_u", Binop;
+
+  0xe0 => F32x4Abs, "f32x4.abs", Unop;
+  0xe1 => F32x4Neg, "f32x4.neg", Unop;
+  0xe3 => F32x4Sqrt, "f32x4.sqrt", Unop;
+  0xe4 => F32x4Add, "f32x4.add", Binop;
+  0xe5 => F32x4Sub, "f32x4.sub", Binop;
+  0xe6 => F32x4Mul, "f32x4.mul", Binop;
+  0xe7 => F32x4Div, "f32x4.div", Binop;
+  0xe8 => F32x4Min, "f32x4.min", Binop;
+  0xe9 => F32x4Max, "f32x4.max", Binop;
+  0xea => F32x4Pmin, "f32x4.pmin", Binop;
+  0xeb => F32x4Pmax, "f32x4.pmax", Binop;
+  0xec => F64x2Abs, "f64x2.abs", Unop;
+  0xed => F64x2Neg, "f64x2.neg", Unop;
+  0xef => F64x2Sqrt, "f64x2.sqrt", Unop;
+  0xf0 => F64x2Add, "f64x2.add", Binop;
+  0xf1 => F64x2Sub, "f64x2.sub", Binop;
+  0xf2 => F64x2Mul, "f64x2.mul", Binop;
+  0xf3 => F64x2Div, "f64x2.div", Binop;
+  0xf4 => F64x2Min, "f64x2.min", Binop;
+  0xf5 => F64x2Max, "f64x2.max", Binop;
+  0xf6 => F64x2Pmin, "f64x2.pmin", Binop;
+  0xf7 => F64x2Pmax, "f64x2.pmax", Binop;
+
+  0xf8 => I32x4TruncSatF32x4S, "i32x4.trunc_sat_f32x4_s", Unop;
+  0xf9 => I32x4TruncSatF32x4U, "i32x4.trunc_sat_f32x4_u", Unop;
+  0xfa => F32x4ConvertI32x4S, "f32x4.convert_i32x4_s", Unop;
+  0xfb => F32x4ConvertI32x4U, "f32x4.convert_i32x4_u", Unop;
+  0xfc => I32x4TruncSatF64x2SZero, "i32x4.trunc_sat_f64x2_s_zero", Unop;
+  0xfd => I32x4TruncSatF64x2UZero, "i32x4.trunc_sat_f64x2_u_zero", Unop;
+  0xfe => F64x2ConvertLowI32x4S, "f64x2.convert_low_i32x4_s", Unop;
+  0xff => F64x2ConvertLowI32x4U, "f64x2.convert_low_i32x4_u", Unop;
+}
+
+/// The operation of an atomic read-modify-write instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AtomicRmwOp {
+  Add,
+  Sub,
+  And,
+  Or,
+  Xor,
+  Xchg,
+}
+
+impl AtomicRmwOp {
+  const ALL: [AtomicRmwOp; 6] = [
+    AtomicRmwOp::Add, AtomicRmwOp::Sub, AtomicRmwOp::And, AtomicRmwOp::Or, AtomicRmwOp::Xor, AtomicRmwOp::Xchg,
+  ];
+
+  pub const fn name(&self) -> &'static str {
+    match self {
+      AtomicRmwOp::Add => "add",
+      AtomicRmwOp::Sub => "sub",
+      AtomicRmwOp::And => "and",
+      AtomicRmwOp::Or => "or",
+      AtomicRmwOp::Xor => "xor",
+      AtomicRmwOp::Xchg => "xchg",
+    }
+  }
+}
+
+/// An atomic instruction, with the `0xfe` prefix.  Accesses have the value
+/// type and the width in bytes, narrow accesses are zero-extended.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AtomicInstruction {
+  MemoryAtomicNotify(MemArg),
+  MemoryAtomicWait32(MemArg),
+  MemoryAtomicWait64(MemArg),
+  AtomicFence,
+  Load(ValueType, u32, MemArg),
+  Store(ValueType, u32, MemArg),
+  Rmw(AtomicRmwOp, ValueType, u32, MemArg),
+  Cmpxchg(ValueType, u32, MemArg),
+}
+
+/// The value type and width of the accesses of each group of 7 opcodes,
+/// from `0x10`.
+const ATOMIC_ACCESSES: [(ValueType, u32); 7] = [
+  (ValueType::I32, 4), (ValueType::I64, 8), (ValueType::I32, 1), (ValueType::I32, 2),
+  (ValueType::I64, 1), (ValueType::I64, 2), (ValueType::I64, 4),
+];
+
+/// The last atomic opcode.
+const ATOMIC_MAX_OPCODE: u32 = 0x4e;
+
+impl AtomicInstruction {
+  /// The instruction for an opcode, all except `atomic.fence` have a
+  /// `MemArg` immediate.
+  pub fn from_opcode(opcode: u32, memarg: MemArg) -> Option<AtomicInstruction> {
+    use AtomicInstruction::*;
+    Some(match opcode {
+      0x00 => MemoryAtomicNotify(memarg),
+      0x01 => MemoryAtomicWait32(memarg),
+      0x02 => MemoryAtomicWait64(memarg),
+      0x03 => AtomicFence,
+      0x10..=ATOMIC_MAX_OPCODE => {
+        let group = (opcode - 0x10) / 7;
+        let (ty, bytes) = ATOMIC_ACCESSES[((opcode - 0x10) % 7) as usize];
+        match group {
+          0 => Load(ty, bytes, memarg),
+          1 => Store(ty, bytes, memarg),
+          8 => Cmpxchg(ty, bytes, memarg),
+          op => Rmw(AtomicRmwOp::ALL[op as usize - 2], ty, bytes, memarg),
+        }
+      },
+      _ => return None,
+    })
+  }
+
+  /// The instruction for a text format name, with the `MemArg` immediate.
+  pub fn from_name(name: &str, memarg: MemArg) -> Option<AtomicInstruction> {
+    (0..=ATOMIC_MAX_OPCODE)
+      .filter_map(|opcode| AtomicInstruction::from_opcode(opcode, memarg))
+      .find(|op| op.name() == name)
+  }
+
+  pub fn name(&self) -> String {
+    use AtomicInstruction::*;
+    // Narrow accesses name their width in bits.
+    let width = |ty: &ValueType, bytes: u32| {
+      let natural = if *ty == ValueType::I32 { 4 } else { 8 };
+      if bytes == natural { (String::new(), "") } else { ((bytes * 8).to_string(), "_u") }
+    };
+    match self {
+      MemoryAtomicNotify(_) => "memory.atomic.notify".to_string(),
+      MemoryAtomicWait32(_) => "memory.atomic.wait32".to_string(),
+      MemoryAtomicWait64(_) => "memory.atomic.wait64".to_string(),
+      AtomicFence => "atomic.fence".to_string(),
+      Load(ty, bytes, _) => {
+        let (bits, sign) = width(ty, *bytes);
+        format!("{}.atomic.load{}{}", ty, bits, sign)
+      },
+      Store(ty, bytes, _) => format!("{}.atomic.store{}", ty, width(ty, *bytes).0),
+      Rmw(op, ty, bytes, _) => {
+        let (bits, sign) = width(ty, *bytes);
+        format!("{}.atomic.rmw{}.{}{}", ty, bits, op.name(), sign)
+      },
+      Cmpxchg(ty, bytes, _) => {
+        let (bits, sign) = width(ty, *bytes);
+        format!("{}.atomic.rmw{}.cmpxchg{}", ty, bits, sign)
+      },
+    }
+  }
+
+  pub fn memarg(&self) -> Option<MemArg> {
+    use AtomicInstruction::*;
+    match *self {
+      MemoryAtomicNotify(memarg) | MemoryAtomicWait32(memarg) | MemoryAtomicWait64(memarg) => Some(memarg),
+      AtomicFence => None,
+      Load(_, _, memarg) | Store(_, _, memarg) | Rmw(_, _, _, memarg) | Cmpxchg(_, _, memarg) => Some(memarg),
+    }
+  }
+
+  /// The width in bytes of the memory access, it must be aligned.
+  pub fn bytes(&self) -> u32 {
+    use AtomicInstruction::*;
+    match *self {
+      MemoryAtomicNotify(_) | MemoryAtomicWait32(_) => 4,
+      MemoryAtomicWait64(_) => 8,
+      AtomicFence => 0,
+      Load(_, bytes, _) | Store(_, bytes, _) | Rmw(_, _, bytes, _) | Cmpxchg(_, bytes, _) => bytes,
+    }
+  }
+}
@@ -4,26 +4,1802 @@ pub mod error;
 pub use error::{Error, Result};
 
 // VM
-mod memory;
+pub mod memory;
+mod shared_memory;
+pub use shared_memory::*;
+pub mod table;
+pub use table::TableElement;
 mod export;
 mod stack;
 pub use stack::*;
 mod value;
 pub use value::*;
+mod exception;
+pub use exception::*;
+mod simd;
 mod compiler;
 mod vm;
 pub use vm::*;
 
 // Module
+mod isa;
+pub mod elements;
+mod decoder;
+pub mod loader;
+mod validator;
 mod function;
 pub use function::*;
 mod module;
 pub use module::*;
+mod host;
+pub use host::*;
 
-#[cfg(test)]
+// Host APIs
+#[cfg(feature = "wasi")]
+pub mod wasi;
+
+// The tests load their modules from text.
+#[cfg(all(test, feature = "wat"))]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn fib(n: i32) -> i32 {
+        if n < 2 { 1 } else { fib(n - 2) + fib(n - 1) }
+    }
+
+    #[test]
+    fn send_sync() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<Module>();
+        is_send_sync::<State>();
+        is_send_sync::<Store>();
+        is_send_sync::<VM>();
+    }
+
+    #[test]
+    fn shared_module() {
+        // Compile once, then instantiate with isolated stores.
+        let module = std::sync::Arc::new(Module::from_wat(r#"
+            (module
+              (memory 1)
+              (global $count (mut i32) (i32.const 0))
+              (func (export "incr") (result i32)
+                (global.set $count (i32.add (global.get $count) (i32.const 1)))
+                (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (i32.const 10)))
+                (i32.add (global.get $count) (i32.load (i32.const 0)))))
+        "#).unwrap());
+        let first = State::new(module.clone()).unwrap();
+        let second = State::new(module.clone()).unwrap();
+        let func = first.get_exported("incr").unwrap();
+        let mut stores = [first.new_store().unwrap(), first.new_store().unwrap(), second.new_store().unwrap()];
+        assert_eq!(first.call(&mut stores[0], func, &[]).unwrap(), Some(Value::I32(11)));
+        assert_eq!(first.call(&mut stores[0], func, &[]).unwrap(), Some(Value::I32(22)));
+        assert_eq!(first.call(&mut stores[1], func, &[]).unwrap(), Some(Value::I32(11)));
+        assert_eq!(second.call(&mut stores[2], func, &[]).unwrap(), Some(Value::I32(11)));
+        assert!(std::sync::Arc::ptr_eq(first.module(), second.module()));
+
+        let mut vm = VM::new();
+        vm.add_module("a", module.clone()).unwrap();
+        vm.add_module("b", module).unwrap();
+        assert_eq!(vm.call("a", "incr", &[]).unwrap(), Some(Value::I32(11)));
+        assert_eq!(vm.call("a", "incr", &[]).unwrap(), Some(Value::I32(22)));
+        assert_eq!(vm.call("b", "incr", &[]).unwrap(), Some(Value::I32(11)));
+    }
+
+    #[test]
+    fn shared_state_threads() {
+        let module = Module::from_bytes(include_bytes!("../fib.wasm")).unwrap();
+        let state = std::sync::Arc::new(State::new(std::sync::Arc::new(module)).unwrap());
+        let func = state.get_exported("fib").unwrap();
+
+        let threads: Vec<_> = (0..4).map(|t| {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                // Each thread has its own store.
+                let mut store = state.new_store().unwrap();
+                for n in 0..20 {
+                    let n = n + t;
+                    let ret = state.call(&mut store, func, &[Value::I32(n)]).unwrap();
+                    assert_eq!(ret, Some(Value::I32(fib(n))));
+                }
+                // The stack should be empty between calls.
+                assert!(store.stack.is_empty());
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn store_reuse() {
+        // A store is reused for many calls, each call must leave its stack
+        // empty.  A fast function call doesn't pop its parameter.
+        let module = Module::from_wat(r#"
+            (module
+              (func (export "one") (param i32) (result i32) (local.get 0))
+              (func (export "two") (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1)))
+              (func (export "trap") (param i32 i32) (result i32) (i32.div_u (local.get 0) (local.get 1))))
+        "#).unwrap();
+        let state = State::new(std::sync::Arc::new(module)).unwrap();
+        let mut store = state.new_store().unwrap();
+        let [one, two, trap] = [state.get_exported("one").unwrap(), state.get_exported("two").unwrap(),
+            state.get_exported("trap").unwrap()];
+        for n in 0..1000 {
+            assert_eq!(state.call(&mut store, one, &[Value::I32(n)]).unwrap(), Some(Value::I32(n)));
+            assert!(store.stack.is_empty());
+            assert_eq!(state.call(&mut store, two, &[Value::I32(n), Value::I32(1)]).unwrap(), Some(Value::I32(n + 1)));
+            assert!(store.stack.is_empty());
+            assert!(state.call(&mut store, trap, &[Value::I32(n), Value::I32(0)]).is_err());
+            assert!(store.stack.is_empty());
+        }
+    }
+
+    #[test]
+    fn load_bytes() {
+        let mut vm = VM::new();
+        vm.load_bytes("main", include_bytes!("../fib.wasm")).unwrap();
+        let ret = vm.call("main", "fib", &[Value::I32(10)]).unwrap();
+        assert_eq!(ret, Some(Value::I32(89)));
+    }
+
+    #[test]
+    fn load_entry_points() {
+        let bytes = include_bytes!("../fib.wasm");
+        let mut vm = VM::new();
+        vm.load_file("file", "fib.wasm").unwrap();
+        vm.load_reader("reader", std::io::Cursor::new(&bytes[..])).unwrap();
+        vm.load_reader("file_reader", std::fs::File::open("fib.wasm").unwrap()).unwrap();
+        #[cfg(feature = "parity-wasm")]
+        vm.load_parity("parity", parity_wasm::deserialize_buffer(bytes).unwrap()).unwrap();
+        for name in ["file", "reader", "file_reader"].iter() {
+            assert_eq!(vm.call(name, "fib", &[Value::I32(10)]).unwrap(), Some(Value::I32(89)));
+        }
+        #[cfg(feature = "parity-wasm")]
+        assert_eq!(vm.call("parity", "fib", &[Value::I32(10)]).unwrap(), Some(Value::I32(89)));
+
+        // Module names are unique.
+        assert!(matches!(vm.load_bytes("file", bytes), Err(Error::ModuleExists)));
+        // Errors from the reader are returned.
+        let truncated = std::io::Cursor::new(&bytes[..bytes.len() / 2]);
+        assert!(vm.load_reader("truncated", truncated).is_err());
+        assert!(vm.load_file("missing", "missing.wasm").is_err());
+    }
+
+    #[test]
+    fn malformed_modules() {
+        // Invalid modules must fail to load, not panic.
+        let files: [&[u8]; 3] = [
+            include_bytes!("../fib.wasm"),
+            include_bytes!("../for_loop.wasm"),
+            include_bytes!("../for_loop_64.wasm"),
+        ];
+        for bytes in files.iter() {
+            for len in 0..bytes.len() {
+                let _ = Module::from_bytes(&bytes[..len]);
+            }
+            for pos in 0..bytes.len() {
+                for val in [0x00, 0x01, 0x0b, 0x7f, 0x80, 0xff].iter() {
+                    let mut bytes = bytes.to_vec();
+                    bytes[pos] = *val;
+                    let _ = Module::from_bytes(&bytes);
+                }
+            }
+        }
+
+        let nested = format!("(module (func {}{}))", "(block ".repeat(600), ")".repeat(600));
+        match Module::from_wat(&nested) {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "func[0]:511: blocks are nested deeper than 512"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("deeply nested blocks should fail to validate"),
+        }
+
+        // A branch to the function's block returns from the function.
+        let mut vm = VM::new();
+        vm.load_wat("main", "(module (func (export \"br\") (result i32) (br 0 (i32.const 7))))").unwrap();
+        assert_eq!(vm.call("main", "br", &[]).unwrap(), Some(Value::I32(7)));
+    }
+
+    #[test]
+    fn deep_expressions() {
+        // Deeply nested expressions must not overflow the native stack when
+        // compiling, running or dropping the module.
+        let depth = 200_000;
+        let right = format!("(module (func (export \"right\") (param i32) (result i32) {}{}))",
+            "local.get 0 ".repeat(depth + 1), "i32.add ".repeat(depth));
+        let left = format!("(module (func (export \"left\") (param i32) (result i32) local.get 0 {}))",
+            "local.get 0 i32.sub ".repeat(depth));
+        std::thread::Builder::new().stack_size(2 * 1024 * 1024).spawn(move || {
+            let mut vm = VM::new();
+            vm.load_wat("right", &right).unwrap();
+            vm.load_wat("left", &left).unwrap();
+            assert_eq!(vm.call("right", "right", &[Value::I32(3)]).unwrap(), Some(Value::I32(3 * (depth as i32 + 1))));
+            assert_eq!(vm.call("left", "left", &[Value::I32(3)]).unwrap(), Some(Value::I32(3 - 3 * depth as i32)));
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn decode_binary() {
+        let bytes = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f,
+            0x03, 0x02, 0x01, 0x00,
+            0x07, 0x07, 0x01, 0x03, b'a', b'd', b'd', 0x00, 0x00,
+            0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let mut vm = VM::new();
+        vm.load_bytes("main", &bytes).unwrap();
+        assert_eq!(vm.call("main", "add", &[Value::I32(2), Value::I32(3)]).unwrap(), Some(Value::I32(5)));
+
+        // Decode errors name the byte offset.
+        let expect_error = |bytes: &[u8], msg: &str| match Module::from_bytes(bytes) {
+            Err(Error::ParseError(e)) => assert_eq!(e, msg),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expected a parse error: {}", msg),
+        };
+        let mut bad = bytes.to_vec();
+        bad[0] = 0x01;
+        expect_error(&bad, "at offset 0x0: magic header not detected");
+        let mut bad = bytes.to_vec();
+        bad[4] = 0x02;
+        expect_error(&bad, "at offset 0x4: unknown binary version: 2");
+        let mut bad = bytes.to_vec();
+        bad[39] = 0xff;
+        expect_error(&bad, "at offset 0x27: unknown opcode: 0xff");
+    }
+
+    #[test]
+    fn load_wat() {
+        let mut vm = VM::new();
+        vm.load_wat("fib", include_str!("../fib.wat")).unwrap();
+        vm.load_wat("for_loop", include_str!("../for_loop.wat")).unwrap();
+        vm.load_wat("for_loop_64", include_str!("../for_loop_64.wat")).unwrap();
+        assert_eq!(vm.call("fib", "fib", &[Value::I32(10)]).unwrap(), Some(Value::I32(89)));
+        assert_eq!(vm.call("for_loop", "for_loop", &[Value::I32(100)]).unwrap(), Some(Value::I32(142)));
+        assert_eq!(vm.call("for_loop_64", "for_loop", &[Value::I64(100)]).unwrap(), Some(Value::I64(142)));
+    }
+
+    #[test]
+    fn wat_syntax() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory (data "\01\02" "\u{ff}"))
+              (global $g (mut i64) (i64.const 0xffff_ffff_ffff_ffff))
+              (func (export "consts") (result i32)
+                (i32.and (i32.const 0xffffffff) (i32.const -2147483648)))
+              (func (export "floats") (result f64)
+                f64.const 0x1.8p1 ;; 3.0
+                f64.const 1_0.5e1
+                f64.add
+                (f64.promote_f32 (f32.const -inf))
+                f64.max)
+              (func (export "global") (result i64)
+                (global.set $g (i64.add (global.get $g) (i64.const 2)))
+                global.get $g)
+              (func (export "data") (result i32)
+                (i32.load16_u offset=1 (i32.const 0)))
+              (func (export "labels") (param $n i32) (result i32)
+                (block $out (result i32)
+                  (loop $top
+                    (br_if $out (local.get $n) (i32.ge_u (local.get $n) (i32.const 10)))
+                    (local.set $n (i32.add (local.get $n) (i32.const 3)))
+                    br $top)
+                  (unreachable))))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "consts", &[]).unwrap(), Some(Value::I32(i32::MIN)));
+        assert_eq!(vm.call("main", "floats", &[]).unwrap(), Some(Value::F64(108.0)));
+        assert_eq!(vm.call("main", "global", &[]).unwrap(), Some(Value::I64(1)));
+        assert_eq!(vm.call("main", "data", &[]).unwrap(), Some(Value::I32(0xc302)));
+        assert_eq!(vm.call("main", "labels", &[Value::I32(1)]).unwrap(), Some(Value::I32(10)));
+
+        match VM::new().load_wat("main", "(module (func (result i32) (i32.const 1) (i32.foo)))") {
+            Err(Error::WatError(e)) => assert!(e.contains("<anon>:1:43"), "{}", e),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("unknown instruction should fail to parse"),
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let cases = [
+            ("(func (result i32) (i64.const 1))",
+                "func[0]:1: type mismatch: expected i32, found i64"),
+            ("(import \"env\" \"f\" (func)) (func (param i32) (drop (local.get 1)))",
+                "func[1]:0: unknown local 1"),
+            ("(func (block (result i32) (nop)))",
+                "func[0]:2: type mismatch: expected i32, but the operand stack is empty"),
+            ("(func (i32.add (i32.const 1) (f32.const 2)) (drop))",
+                "func[0]:2: type mismatch: expected i32, found f32"),
+            ("(global i32 (i32.const 0)) (func (global.set 0 (i32.const 1)))",
+                "func[0]:1: global 0 is immutable"),
+            ("(memory 1) (func (drop (i32.load align=8 (i32.const 0))))",
+                "func[0]:1: alignment 2**3 is larger than natural alignment 4"),
+            ("(func (drop (memory.size)))",
+                "func[0]:0: unknown memory 0"),
+            ("(func (loop (result i32) (br 0 (i32.const 1))))",
+                "func[0]:4: type mismatch: values remaining on the operand stack at the end of the block"),
+            ("(global i64 (i32.const 0))",
+                "global 0: type mismatch: expected i64, found i32"),
+            ("(global i32 (i32.add (i32.const 0) (i64.const 1)))",
+                "type mismatch: expected i32, found i64 in constant expression"),
+            ("(global i32 (i32.const 0) (i32.const 1))",
+                "type mismatch: init expr must have one result, found 2"),
+            ("(global i32 (i32.div_s (i32.const 0) (i32.const 1)))",
+                "Invalid instruction in init expr: I32DivS"),
+            ("(func) (start 1)",
+                "unknown start function 1"),
+        ];
+        for (wat, msg) in cases.iter() {
+            match Module::from_wat(&format!("(module {})", wat)) {
+                Err(Error::ValidationError(e)) => assert_eq!(&e, msg),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("module should fail to validate: {}", wat),
+            }
+        }
+    }
+
+    #[test]
+    fn extended_const() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory 1)
+              (table 8 funcref)
+              (global $base i32 (i32.const 10))
+              (global $sum i32 (i32.add (global.get $base) (i32.mul (i32.const 2) (i32.const 3))))
+              (global $wrap i32 (i32.sub (i32.const 0x8000_0000) (i32.const 1)))
+              (global $big i64 (i64.mul (i64.const 0x1_0000_0000) (i64.sub (i64.const 5) (i64.const 2))))
+              (data (i32.add (global.get $base) (i32.const 6)) "\2a")
+              (elem (i32.sub (global.get $base) (i32.const 7)) $f)
+              (func $f (result i32) (i32.const 7))
+              (func (export "sum") (result i32) (global.get $sum))
+              (func (export "wrap") (result i32) (global.get $wrap))
+              (func (export "big") (result i64) (global.get $big))
+              (func (export "data") (result i32) (i32.load8_u (i32.const 16)))
+              (func (export "elem") (result i32) (call_indirect (result i32) (i32.const 3))))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "sum", &[]).unwrap(), Some(Value::I32(16)));
+        assert_eq!(vm.call("main", "wrap", &[]).unwrap(), Some(Value::I32(i32::MAX)));
+        assert_eq!(vm.call("main", "big", &[]).unwrap(), Some(Value::I64(3 << 32)));
+        assert_eq!(vm.call("main", "data", &[]).unwrap(), Some(Value::I32(42)));
+        assert_eq!(vm.call("main", "elem", &[]).unwrap(), Some(Value::I32(7)));
+    }
+
+    #[test]
+    fn function_label_branches() {
+        // `br` and `br_if` to the function's label return from the function,
+        // through any enclosing blocks.  `br_table` saves the result in an
+        // operand slot instead.
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (global $g (mut i32) (i32.const 0))
+              (func (export "br") (param i32) (result i32)
+                (i32.add (i32.const 100)
+                  (block (result i32)
+                    (loop
+                      (if (local.get 0) (then (br 3 (i32.const 7))))
+                      (br 1 (i32.const 8)))
+                    (unreachable))))
+              (func (export "br_if") (param i32) (result i32)
+                (drop (br_if 0 (i32.const 10) (local.get 0)))
+                (i32.const 20))
+              (func (export "br_if_loop") (param i32) (result i32)
+                (loop
+                  (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+                  (drop (br_if 1 (local.get 0) (i32.ge_u (local.get 0) (i32.const 5))))
+                  (br 0))
+                (i32.const -1))
+              (func (export "br_table") (param i32) (result i32)
+                (block (result i32) (br_table 0 1 (i32.const 30) (local.get 0)))
+                (i32.add (i32.const 1)))
+              (func (export "set") (param i32)
+                (block (br_if 1 (local.get 0)))
+                (global.set $g (i32.const 1)))
+              (func (export "get") (result i32)
+                (global.get $g)))
+        "#).unwrap();
+        let mut call = |name: &str, param: Option<i32>| {
+            let params: Vec<Value> = param.into_iter().map(Value::I32).collect();
+            vm.call("main", name, &params).unwrap()
+        };
+        assert_eq!(call("br", Some(1)), Some(Value::I32(7)));
+        assert_eq!(call("br", Some(0)), Some(Value::I32(108)));
+        assert_eq!(call("br_if", Some(1)), Some(Value::I32(10)));
+        assert_eq!(call("br_if", Some(0)), Some(Value::I32(20)));
+        assert_eq!(call("br_if_loop", Some(0)), Some(Value::I32(5)));
+        assert_eq!(call("br_table", Some(0)), Some(Value::I32(31)));
+        assert_eq!(call("br_table", Some(1)), Some(Value::I32(30)));
+        assert_eq!(call("br_table", Some(7)), Some(Value::I32(30)));
+        assert_eq!(call("set", Some(1)), None);
+        assert_eq!(call("get", None), Some(Value::I32(0)));
+        assert_eq!(call("set", Some(0)), None);
+        assert_eq!(call("get", None), Some(Value::I32(1)));
+    }
+
+    #[test]
+    fn sign_extension() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (func (export "i32.extend8_s") (param i32) (result i32) (i32.extend8_s (local.get 0)))
+              (func (export "i32.extend16_s") (param i32) (result i32) (i32.extend16_s (local.get 0)))
+              (func (export "i64.extend8_s") (param i64) (result i64) (i64.extend8_s (local.get 0)))
+              (func (export "i64.extend16_s") (param i64) (result i64) (i64.extend16_s (local.get 0)))
+              (func (export "i64.extend32_s") (param i64) (result i64) (i64.extend32_s (local.get 0))))
+        "#).unwrap();
+        let cases = [
+            ("i32.extend8_s", Value::I32(0x7f), Value::I32(127)),
+            ("i32.extend8_s", Value::I32(0x80), Value::I32(-128)),
+            ("i32.extend8_s", Value::I32(0x1234_5680), Value::I32(-128)),
+            ("i32.extend16_s", Value::I32(0x7fff), Value::I32(32767)),
+            ("i32.extend16_s", Value::I32(0x8000), Value::I32(-32768)),
+            ("i64.extend8_s", Value::I64(0x01ff), Value::I64(-1)),
+            ("i64.extend16_s", Value::I64(0x1_8000), Value::I64(-32768)),
+            ("i64.extend32_s", Value::I64(0x7fff_ffff), Value::I64(0x7fff_ffff)),
+            ("i64.extend32_s", Value::I64(0x1_8000_0000), Value::I64(-0x8000_0000)),
+        ];
+        for (name, param, ret) in cases.iter() {
+            assert_eq!(vm.call("main", name, &[*param]).unwrap(), Some(*ret), "{}({:?})", name, param);
+        }
+    }
+
+    #[test]
+    fn saturating_truncation() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (func (export "i32.trunc_sat_f32_s") (param f32) (result i32) (i32.trunc_sat_f32_s (local.get 0)))
+              (func (export "i32.trunc_sat_f32_u") (param f32) (result i32) (i32.trunc_sat_f32_u (local.get 0)))
+              (func (export "i32.trunc_sat_f64_s") (param f64) (result i32) (i32.trunc_sat_f64_s (local.get 0)))
+              (func (export "i32.trunc_sat_f64_u") (param f64) (result i32) (i32.trunc_sat_f64_u (local.get 0)))
+              (func (export "i64.trunc_sat_f32_s") (param f32) (result i64) (i64.trunc_sat_f32_s (local.get 0)))
+              (func (export "i64.trunc_sat_f32_u") (param f32) (result i64) (i64.trunc_sat_f32_u (local.get 0)))
+              (func (export "i64.trunc_sat_f64_s") (param f64) (result i64) (i64.trunc_sat_f64_s (local.get 0)))
+              (func (export "i64.trunc_sat_f64_u") (param f64) (result i64) (i64.trunc_sat_f64_u (local.get 0))))
+        "#).unwrap();
+        let cases = [
+            ("i32.trunc_sat_f32_s", Value::F32(-1.9), Value::I32(-1)),
+            ("i32.trunc_sat_f32_s", Value::F32(2147483648.0), Value::I32(i32::MAX)),
+            ("i32.trunc_sat_f32_s", Value::F32(-2147483904.0), Value::I32(i32::MIN)),
+            ("i32.trunc_sat_f32_s", Value::F32(f32::INFINITY), Value::I32(i32::MAX)),
+            ("i32.trunc_sat_f32_s", Value::F32(f32::NAN), Value::I32(0)),
+            ("i32.trunc_sat_f32_u", Value::F32(-0.9), Value::I32(0)),
+            ("i32.trunc_sat_f32_u", Value::F32(-1.0), Value::I32(0)),
+            ("i32.trunc_sat_f32_u", Value::F32(4294967040.0), Value::I32(-256)),
+            ("i32.trunc_sat_f32_u", Value::F32(4294967296.0), Value::I32(-1)),
+            ("i32.trunc_sat_f32_u", Value::F32(-f32::NAN), Value::I32(0)),
+            ("i32.trunc_sat_f64_s", Value::F64(2147483647.9), Value::I32(i32::MAX)),
+            ("i32.trunc_sat_f64_s", Value::F64(-2147483649.0), Value::I32(i32::MIN)),
+            ("i32.trunc_sat_f64_s", Value::F64(f64::NEG_INFINITY), Value::I32(i32::MIN)),
+            ("i32.trunc_sat_f64_u", Value::F64(4294967295.9), Value::I32(-1)),
+            ("i32.trunc_sat_f64_u", Value::F64(1e16), Value::I32(-1)),
+            ("i32.trunc_sat_f64_u", Value::F64(f64::NAN), Value::I32(0)),
+            ("i64.trunc_sat_f32_s", Value::F32(9223372036854775808.0), Value::I64(i64::MAX)),
+            ("i64.trunc_sat_f32_s", Value::F32(-9223373136366403584.0), Value::I64(i64::MIN)),
+            ("i64.trunc_sat_f32_u", Value::F32(18446744073709551616.0), Value::I64(-1)),
+            ("i64.trunc_sat_f32_u", Value::F32(-1.0), Value::I64(0)),
+            ("i64.trunc_sat_f64_s", Value::F64(-1.5), Value::I64(-1)),
+            ("i64.trunc_sat_f64_s", Value::F64(1e20), Value::I64(i64::MAX)),
+            ("i64.trunc_sat_f64_s", Value::F64(f64::NAN), Value::I64(0)),
+            ("i64.trunc_sat_f64_u", Value::F64(18446744073709549568.0), Value::I64(-2048)),
+            ("i64.trunc_sat_f64_u", Value::F64(f64::INFINITY), Value::I64(-1)),
+            ("i64.trunc_sat_f64_u", Value::F64(-f64::INFINITY), Value::I64(0)),
+        ];
+        for (name, param, ret) in cases.iter() {
+            assert_eq!(vm.call("main", name, &[*param]).unwrap(), Some(*ret), "{}({:?})", name, param);
+        }
+
+        // The binary encoding has a 0xfc prefix.
+        let bytes = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0x06, 0x01, 0x60, 0x01, 0x7d, 0x01, 0x7f,
+            0x03, 0x02, 0x01, 0x00,
+            0x07, 0x05, 0x01, 0x01, b'f', 0x00, 0x00,
+            0x0a, 0x08, 0x01, 0x06, 0x00, 0x20, 0x00, 0xfc, 0x01, 0x0b,
+        ];
+        let mut vm = VM::new();
+        vm.load_bytes("main", &bytes).unwrap();
+        assert_eq!(vm.call("main", "f", &[Value::F32(-3.5)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(vm.call("main", "f", &[Value::F32(3.5)]).unwrap(), Some(Value::I32(3)));
+    }
+
+    #[test]
+    fn bulk_memory() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (type $t (func (result i32)))
+              (memory 1)
+              (table 4 funcref)
+              (data $d "\01\02\03\04")
+              (elem $e func $one $two)
+              (func $one (result i32) (i32.const 1))
+              (func $two (result i32) (i32.const 2))
+              (func (export "load") (param i32) (result i32) (i32.load8_u (local.get 0)))
+              (func (export "copy") (param i32 i32 i32) (memory.copy (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "fill") (param i32 i32 i32) (memory.fill (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "init") (param i32 i32 i32) (memory.init $d (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "data.drop") (data.drop $d))
+              (func (export "table.init") (param i32 i32 i32) (table.init $e (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "table.copy") (param i32 i32 i32) (table.copy (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "elem.drop") (elem.drop $e))
+              (func (export "call") (param i32) (result i32) (call_indirect (type $t) (local.get 0))))
+        "#).unwrap();
+        let mut call = |name: &str, params: &[i32]| {
+            let params: Vec<Value> = params.iter().copied().map(Value::I32).collect();
+            vm.call("main", name, &params)
+        };
+        let trap = |res: Result<Option<Value>>| match res {
+            Err(Error::RuntimeError(kind, _)) => kind,
+            res => panic!("expected a trap: {:?}", res),
+        };
+
+        // Passive segments are only copied by `memory.init`.
+        assert_eq!(call("load", &[0]).unwrap(), Some(Value::I32(0)));
+        call("init", &[10, 1, 3]).unwrap();
+        let bytes: Vec<_> = (9..14).map(|addr| call("load", &[addr]).unwrap()).collect();
+        let expected: Vec<_> = [0, 2, 3, 4, 0].iter().map(|b| Some(Value::I32(*b))).collect();
+        assert_eq!(bytes, expected);
+
+        // Overlapping copies behave like `memmove`.
+        call("copy", &[11, 10, 3]).unwrap();
+        let bytes: Vec<_> = (10..14).map(|addr| call("load", &[addr]).unwrap()).collect();
+        let expected: Vec<_> = [2, 2, 3, 4].iter().map(|b| Some(Value::I32(*b))).collect();
+        assert_eq!(bytes, expected);
+
+        call("fill", &[65530, 0x1ff, 6]).unwrap();
+        assert_eq!(call("load", &[65535]).unwrap(), Some(Value::I32(0xff)));
+
+        // Out of bounds ranges trap before writing anything, empty ranges at the end are fine.
+        assert_eq!(trap(call("fill", &[65535, 1, 2])), error::TrapKind::MemoryAccessOutOfBounds);
+        assert_eq!(call("load", &[65535]).unwrap(), Some(Value::I32(0xff)));
+        call("fill", &[65536, 1, 0]).unwrap();
+        assert_eq!(trap(call("copy", &[65537, 0, 0])), error::TrapKind::MemoryAccessOutOfBounds);
+        assert_eq!(trap(call("init", &[0, 2, 3])), error::TrapKind::MemoryAccessOutOfBounds);
+        call("init", &[0, 4, 0]).unwrap();
+
+        // A dropped segment is empty.
+        call("data.drop", &[]).unwrap();
+        call("init", &[0, 0, 0]).unwrap();
+        assert_eq!(trap(call("init", &[0, 0, 1])), error::TrapKind::MemoryAccessOutOfBounds);
+
+        assert_eq!(trap(call("call", &[0])), error::TrapKind::ElemUninitialized);
+        call("table.init", &[1, 0, 2]).unwrap();
+        assert_eq!(call("call", &[1]).unwrap(), Some(Value::I32(1)));
+        assert_eq!(call("call", &[2]).unwrap(), Some(Value::I32(2)));
+        call("table.copy", &[2, 1, 2]).unwrap();
+        assert_eq!(call("call", &[2]).unwrap(), Some(Value::I32(1)));
+        assert_eq!(call("call", &[3]).unwrap(), Some(Value::I32(2)));
+        assert_eq!(trap(call("table.copy", &[3, 0, 2])), error::TrapKind::TableAccessOutOfBounds);
+        call("elem.drop", &[]).unwrap();
+        assert_eq!(trap(call("table.init", &[0, 0, 1])), error::TrapKind::TableAccessOutOfBounds);
+    }
+
+    #[test]
+    fn multi_memory() {
+        let mut vm = VM::new();
+        vm.imports_mut().add_memory("env", "mem", 1, Some(2));
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "mem" (memory $a 1))
+              (memory $b (export "b") 1 3)
+              (data (memory $b) (i32.const 8) "\2a")
+              (func (export "load") (param i32) (result i32)
+                (i32.add (i32.load8_u $a (local.get 0)) (i32.load8_u $b (local.get 0))))
+              (func (export "store_a") (param i32 i32) (i32.store8 $a (local.get 0) (local.get 1)))
+              (func (export "copy") (param i32 i32 i32) (memory.copy $a $b (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "fill") (param i32 i32 i32) (memory.fill $b (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "grow_a") (param i32) (result i32) (memory.grow $a (local.get 0)))
+              (func (export "grow_b") (param i32) (result i32) (memory.grow $b (local.get 0)))
+              (func (export "sizes") (result i32)
+                (i32.add (i32.mul (memory.size $a) (i32.const 10)) (memory.size $b))))
+        "#).unwrap();
+        let mut call = |name: &str, params: &[i32]| {
+            let params: Vec<Value> = params.iter().copied().map(Value::I32).collect();
+            vm.call("main", name, &params).unwrap()
+        };
+
+        assert_eq!(call("load", &[8]), Some(Value::I32(42)));
+        call("store_a", &[8, 1]);
+        assert_eq!(call("load", &[8]), Some(Value::I32(43)));
+        call("fill", &[0, 7, 4]);
+        call("copy", &[100, 0, 4]);
+        assert_eq!(call("load", &[100]), Some(Value::I32(7)));
+
+        // Each memory has its own limits, the imported one is limited by the host.
+        assert_eq!(call("grow_a", &[1]), Some(Value::I32(1)));
+        assert_eq!(call("grow_a", &[1]), Some(Value::I32(-1)));
+        assert_eq!(call("grow_b", &[2]), Some(Value::I32(1)));
+        assert_eq!(call("sizes", &[]), Some(Value::I32(23)));
+
+        let mem = vm.get_exported_memory("main", "b").unwrap();
+        assert_eq!(mem.len(), 3 * 65536);
+        assert_eq!(&mem[0..4], &[7, 7, 7, 7]);
+        mem[8] = 1;
+        assert_eq!(vm.call("main", "load", &[Value::I32(8)]).unwrap(), Some(Value::I32(2)));
+
+        // The host memory must fit the import's limits.
+        let wat = r#"(module (import "env" "mem" (memory 1 1)))"#;
+        match vm.load_wat("small", wat) {
+            Err(Error::LinkError(e)) => assert_eq!(e, "Incompatible import type: env.mem"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match Module::from_wat("(module (memory 1) (func (drop (i32.load 1 (i32.const 0)))))") {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "func[0]:1: unknown memory 1"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn memory64() {
+        let module = std::sync::Arc::new(Module::from_wat(r#"
+            (module
+              (memory (export "mem") i64 1)
+              (data (i64.const 8) "\2a")
+              (func (export "load") (param i64) (result i32) (i32.load8_u (local.get 0)))
+              (func (export "load_far") (param i64) (result i32) (i32.load8_u offset=4294967296 (local.get 0)))
+              (func (export "store") (param i64 i32) (i32.store (local.get 0) (local.get 1)))
+              (func (export "fill") (param i64 i32 i64) (memory.fill (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "grow") (param i64) (result i64) (memory.grow (local.get 0)))
+              (func (export "size") (result i64) (memory.size)))
+        "#).unwrap());
+        let state = State::new(module).unwrap();
+        let mut store = state.new_store().unwrap();
+        let mut call = |name: &str, params: &[Value]| {
+            let func = state.get_exported(name).unwrap();
+            state.call(&mut store, func, params)
+        };
+
+        assert_eq!(call("load", &[Value::I64(8)]).unwrap(), Some(Value::I32(42)));
+        call("store", &[Value::I64(65532), Value::I32(-1)]).unwrap();
+        assert_eq!(call("load", &[Value::I64(65535)]).unwrap(), Some(Value::I32(255)));
+        call("fill", &[Value::I64(16), Value::I32(7), Value::I64(4)]).unwrap();
+        assert_eq!(call("load", &[Value::I64(19)]).unwrap(), Some(Value::I32(7)));
+
+        // Addresses and offsets past 4 GiB, or that overflow, are out of bounds.
+        for (name, addr) in [("load", 1 << 32), ("load", -1), ("load_far", 8), ("load_far", -1)] {
+            match call(name, &[Value::I64(addr)]) {
+                Err(Error::RuntimeError(error::TrapKind::MemoryAccessOutOfBounds, _)) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+        match call("fill", &[Value::I64(-1), Value::I32(0), Value::I64(2)]) {
+            Err(Error::RuntimeError(error::TrapKind::MemoryAccessOutOfBounds, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        assert_eq!(call("grow", &[Value::I64(1)]).unwrap(), Some(Value::I64(1)));
+        assert_eq!(call("grow", &[Value::I64(1 << 40)]).unwrap(), Some(Value::I64(-1)));
+        assert_eq!(call("size", &[]).unwrap(), Some(Value::I64(2)));
+
+        // The store's memory limit caps the size of the memory.
+        let mut store = Store::default();
+        store.set_memory_limit(3);
+        let mut store = state.init_store(store).unwrap();
+        let grow = state.get_exported("grow").unwrap();
+        assert_eq!(state.call(&mut store, grow, &[Value::I64(2)]).unwrap(), Some(Value::I64(1)));
+        assert_eq!(state.call(&mut store, grow, &[Value::I64(1)]).unwrap(), Some(Value::I64(-1)));
+        let mut store = Store::default();
+        store.set_memory_limit(0);
+        match state.init_store(store) {
+            Err(Error::LinkError(e)) => assert_eq!(e, "memory 0 of 1 pages is larger than the memory limit"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        // A memory's own limit overrides the limit of all memories.
+        let mut store = Store::default();
+        store.set_memory_limit(1);
+        store.set_memory_limit_of(0, 3);
+        store.set_memory_limit_of(1, 0);
+        let mut store = state.init_store(store).unwrap();
+        assert_eq!(state.call(&mut store, grow, &[Value::I64(2)]).unwrap(), Some(Value::I64(1)));
+        assert_eq!(state.call(&mut store, grow, &[Value::I64(1)]).unwrap(), Some(Value::I64(-1)));
+        // Growing past what can be allocated fails instead of aborting.
+        let mut store = Store::default();
+        store.set_memory_limit(u64::MAX);
+        let mut store = state.init_store(store).unwrap();
+        let size = state.get_exported("size").unwrap();
+        assert_eq!(state.call(&mut store, grow, &[Value::I64(u32::MAX as i64 - 1)]).unwrap(), Some(Value::I64(-1)));
+        assert_eq!(state.call(&mut store, size, &[]).unwrap(), Some(Value::I64(1)));
+
+        // The addresses of 64-bit memories are `i64` values.
+        match Module::from_wat("(module (memory i64 1) (func (drop (i32.load (i32.const 0)))))") {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "func[0]:1: type mismatch: expected i64, found i32"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        match Module::from_wat("(module (memory 1) (func (drop (i32.load offset=4294967296 (i32.const 0)))))") {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "func[0]:1: offset 4294967296 is out of range for a 32-bit memory"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn threads() {
+        let module = std::sync::Arc::new(Module::from_wat(r#"
+            (module
+              (import "env" "mem" (memory 1 1 shared))
+              (func (export "add") (param i32) (result i32) (i32.atomic.rmw.add (i32.const 0) (local.get 0)))
+              (func (export "add8") (param i32) (result i32) (i32.atomic.rmw8.add_u (i32.const 8) (local.get 0)))
+              (func (export "load") (param i32) (result i64) (i64.atomic.load (local.get 0)))
+              (func (export "cmpxchg") (param i32 i32) (result i32)
+                (i32.atomic.rmw.cmpxchg (i32.const 16) (local.get 0) (local.get 1)))
+              (func (export "wait") (param i64) (result i32)
+                (memory.atomic.wait32 (i32.const 24) (i32.const 0) (local.get 0)))
+              (func (export "notify") (result i32)
+                (i32.atomic.store (i32.const 24) (i32.const 1))
+                (memory.atomic.notify (i32.const 24) (i32.const 1))))
+        "#).unwrap());
+        let memory = SharedMemory::new(1, 1);
+        let mut imports = Imports::new();
+        imports.add_shared_memory("env", "mem", memory.clone());
+        let state = std::sync::Arc::new(State::with_imports(module, &imports).unwrap());
+
+        // Each thread has its own store, all of them use the same memory.
+        let threads: Vec<_> = (0..4).map(|_| {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                let mut store = state.new_store().unwrap();
+                let (add, add8) = (state.get_exported("add").unwrap(), state.get_exported("add8").unwrap());
+                for _ in 0..1000 {
+                    state.call(&mut store, add, &[Value::I32(1)]).unwrap();
+                    state.call(&mut store, add8, &[Value::I32(1)]).unwrap();
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let mut store = state.new_store().unwrap();
+        let mut call = |name: &str, params: &[Value]| {
+            let func = state.get_exported(name).unwrap();
+            state.call(&mut store, func, params)
+        };
+        assert_eq!(call("add", &[Value::I32(0)]).unwrap(), Some(Value::I32(4000)));
+        // The narrow counter wraps without touching the next byte.
+        assert_eq!(call("load", &[Value::I32(8)]).unwrap(), Some(Value::I64(4000 % 256)));
+        let mut bytes = [0; 4];
+        memory.read(0, &mut bytes).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes), 4000);
+
+        assert_eq!(call("cmpxchg", &[Value::I32(1), Value::I32(2)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(call("cmpxchg", &[Value::I32(0), Value::I32(3)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(call("cmpxchg", &[Value::I32(0), Value::I32(3)]).unwrap(), Some(Value::I32(3)));
+
+        // A waiter times out without a notify, and is woken up by one.
+        assert_eq!(call("wait", &[Value::I64(1000)]).unwrap(), Some(Value::I32(2)));
+        let waiter = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                let mut store = state.new_store().unwrap();
+                let wait = state.get_exported("wait").unwrap();
+                state.call(&mut store, wait, &[Value::I64(-1)]).unwrap()
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        // The store before the notify makes a late waiter return 1.
+        let woken = call("notify", &[]).unwrap();
+        let ret = waiter.join().unwrap();
+        if woken == Some(Value::I32(1)) {
+            assert_eq!(ret, Some(Value::I32(0)));
+        } else {
+            assert_eq!(ret, Some(Value::I32(1)));
+        }
+        // The value isn't the expected value.
+        assert_eq!(call("wait", &[Value::I64(-1)]).unwrap(), Some(Value::I32(1)));
+
+        match call("load", &[Value::I32(4)]) {
+            Err(Error::RuntimeError(error::TrapKind::UnalignedAtomic, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory 1)
+              (func (export "wait") (result i32) (memory.atomic.wait32 (i32.const 0) (i32.const 0) (i64.const 0))))
+        "#).unwrap();
+        match vm.call("main", "wait", &[]) {
+            Err(Error::RuntimeError(error::TrapKind::WaitOnUnsharedMemory, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Shared memories need a maximum, and atomics natural alignment.
+        match Module::from_wat("(module (memory 1 shared))") {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "shared memory must have a maximum"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        match Module::from_wat("(module (memory 1 1 shared) (func (drop (i32.atomic.load align=2 (i32.const 0)))))") {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "func[0]:1: atomic alignment must be the natural alignment 4"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn wide_arithmetic() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (global $hi (mut i64) (i64.const 0))
+              (func $store_hi (param i64 i64) (result i64)
+                (global.set $hi (local.get 1))
+                (local.get 0))
+              (func (export "add128") (param i64 i64 i64 i64) (result i64)
+                (call $store_hi (i64.add128 (local.get 0) (local.get 1) (local.get 2) (local.get 3))))
+              (func (export "sub128") (param i64 i64 i64 i64) (result i64)
+                (call $store_hi (i64.sub128 (local.get 0) (local.get 1) (local.get 2) (local.get 3))))
+              (func (export "mul_wide_s") (param i64 i64) (result i64)
+                (call $store_hi (i64.mul_wide_s (local.get 0) (local.get 1))))
+              (func (export "mul_wide_u") (param i64 i64) (result i64)
+                (call $store_hi (i64.mul_wide_u (local.get 0) (local.get 1))))
+              (func (export "hi") (result i64) (global.get $hi)))
+        "#).unwrap();
+        let mut call = |name: &str, params: &[i64]| {
+            let params: Vec<_> = params.iter().map(|val| Value::I64(*val)).collect();
+            let lo = vm.call("main", name, &params).unwrap();
+            (lo, vm.call("main", "hi", &[]).unwrap())
+        };
+        let pair = |lo, hi| (Some(Value::I64(lo)), Some(Value::I64(hi)));
+        // The carry and borrow go to the high half.
+        assert_eq!(call("add128", &[-1, 0, 1, 0]), pair(0, 1));
+        assert_eq!(call("add128", &[-1, -1, 1, 0]), pair(0, 0));
+        assert_eq!(call("sub128", &[0, 1, 1, 0]), pair(-1, 0));
+        assert_eq!(call("sub128", &[0, 0, 1, 0]), pair(-1, -1));
+        assert_eq!(call("mul_wide_s", &[-2, 3]), pair(-6, -1));
+        assert_eq!(call("mul_wide_u", &[-2, 3]), pair(-6, 2));
+        assert_eq!(call("mul_wide_u", &[-1, -1]), pair(1, -2));
+
+        match Module::from_wat("(module (func (result i64) (i64.mul_wide_u (i64.const 1) (i64.const 2))))") {
+            Err(Error::ValidationError(e)) => assert_eq!(e, "func[0]:3: type mismatch: values remaining on the operand stack at the end of the block"),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reference_types() {
+        let mut vm = VM::new();
+        vm.imports_mut().add_function("env", "handle", &[ValueType::ExternRef], Some(ValueType::I32), |_store, args| {
+            match args {
+                [Value::ExternRef(Some(ExternRef(handle)))] => Ok(Some(Value::I32(*handle as i32))),
+                [Value::ExternRef(None)] => Ok(Some(Value::I32(-1))),
+                _ => Err(error::TrapKind::UnexpectedSignature),
+            }
+        });
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "handle" (func $handle (param externref) (result i32)))
+              (type $t (func (result i32)))
+              (table $funcs 2 funcref)
+              (table $refs 1 4 externref)
+              (global $g (mut externref) (ref.null extern))
+              (elem (table $funcs) (i32.const 0) funcref (ref.func $one) (ref.null func))
+              (elem declare func $two)
+              (func $one (result i32) (i32.const 1))
+              (func $two (result i32) (i32.const 2))
+              (func (export "id") (param externref) (result externref) (local.get 0))
+              (func (export "handle") (param externref) (result i32) (call $handle (local.get 0)))
+              (func (export "is_null") (param externref) (result i32) (ref.is_null (local.get 0)))
+              (func (export "select") (param externref externref i32) (result externref)
+                (select (result externref) (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "swap") (param externref) (result externref)
+                (global.get $g)
+                (global.set $g (local.get 0)))
+              (func (export "get") (param i32) (result externref) (table.get $refs (local.get 0)))
+              (func (export "set") (param i32 externref) (table.set $refs (local.get 0) (local.get 1)))
+              (func (export "grow") (param externref i32) (result i32) (table.grow $refs (local.get 0) (local.get 1)))
+              (func (export "fill") (param i32 externref i32) (table.fill $refs (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "size") (result i32) (table.size $refs))
+              (func (export "func") (param i32) (result funcref) (table.get $funcs (local.get 0)))
+              (func (export "set_two") (table.set $funcs (i32.const 1) (ref.func $two)))
+              (func (export "call") (param i32) (result i32) (call_indirect $funcs (type $t) (local.get 0))))
+        "#).unwrap();
+        let extern_ref = |handle: u32| Value::ExternRef(Some(ExternRef(handle)));
+        let null = Value::ExternRef(None);
+        let i32 = |val: i32| Some(Value::I32(val));
+
+        // Extern references pass through the guest unchanged.
+        assert_eq!(vm.call("main", "id", &[extern_ref(u32::MAX)]).unwrap(), Some(extern_ref(u32::MAX)));
+        assert_eq!(vm.call("main", "id", &[null]).unwrap(), Some(null));
+        assert_eq!(vm.call("main", "handle", &[extern_ref(7)]).unwrap(), i32(7));
+        assert_eq!(vm.call("main", "handle", &[null]).unwrap(), i32(-1));
+        assert_eq!(vm.call("main", "is_null", &[null]).unwrap(), i32(1));
+        assert_eq!(vm.call("main", "is_null", &[extern_ref(0)]).unwrap(), i32(0));
+        assert_eq!(vm.call("main", "select", &[extern_ref(1), extern_ref(2), Value::I32(0)]).unwrap(), Some(extern_ref(2)));
+        assert_eq!(vm.call("main", "swap", &[extern_ref(3)]).unwrap(), Some(null));
+        assert_eq!(vm.call("main", "swap", &[null]).unwrap(), Some(extern_ref(3)));
+
+        // Table instructions.
+        assert_eq!(vm.call("main", "get", &[Value::I32(0)]).unwrap(), Some(null));
+        vm.call("main", "set", &[Value::I32(0), extern_ref(5)]).unwrap();
+        assert_eq!(vm.call("main", "get", &[Value::I32(0)]).unwrap(), Some(extern_ref(5)));
+        assert_eq!(vm.call("main", "grow", &[extern_ref(6), Value::I32(2)]).unwrap(), i32(1));
+        assert_eq!(vm.call("main", "grow", &[null, Value::I32(2)]).unwrap(), i32(-1));
+        assert_eq!(vm.call("main", "size", &[]).unwrap(), i32(3));
+        assert_eq!(vm.call("main", "get", &[Value::I32(2)]).unwrap(), Some(extern_ref(6)));
+        vm.call("main", "fill", &[Value::I32(1), null, Value::I32(2)]).unwrap();
+        assert_eq!(vm.call("main", "get", &[Value::I32(2)]).unwrap(), Some(null));
+        assert_eq!(vm.call("main", "get", &[Value::I32(0)]).unwrap(), Some(extern_ref(5)));
+        match vm.call("main", "get", &[Value::I32(3)]) {
+            Err(Error::RuntimeError(error::TrapKind::TableAccessOutOfBounds, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match vm.call("main", "fill", &[Value::I32(2), null, Value::I32(2)]) {
+            Err(Error::RuntimeError(error::TrapKind::TableAccessOutOfBounds, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // Function references.
+        assert_eq!(vm.call("main", "func", &[Value::I32(0)]).unwrap(), Some(Value::FuncRef(Some(1))));
+        assert_eq!(vm.call("main", "func", &[Value::I32(1)]).unwrap(), Some(Value::FuncRef(None)));
+        assert_eq!(vm.call("main", "call", &[Value::I32(0)]).unwrap(), i32(1));
+        vm.call("main", "set_two", &[]).unwrap();
+        assert_eq!(vm.call("main", "call", &[Value::I32(1)]).unwrap(), i32(2));
+
+        let cases = [
+            ("(func $f) (func (drop (ref.func $f)))",
+                "func[1]:0: undeclared function reference 0"),
+            ("(func (param externref) (drop (select (local.get 0) (local.get 0) (i32.const 1))))",
+                "func[0]:3: type mismatch: select without a type needs numeric operands"),
+            ("(table 1 externref) (type $t (func)) (func (call_indirect (type $t) (i32.const 0)))",
+                "func[0]:1: type mismatch: expected funcref, found externref"),
+            ("(func (drop (ref.is_null (i32.const 0))))",
+                "func[0]:1: type mismatch: expected a reference, found i32"),
+        ];
+        for (wat, msg) in cases.iter() {
+            match Module::from_wat(&format!("(module {})", wat)) {
+                Err(Error::ValidationError(e)) => assert_eq!(&e, msg),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("module should fail to validate: {}", wat),
+            }
+        }
+    }
+
+    #[test]
+    fn simd() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory 1)
+              (data (i32.const 0) "\01\02\03\04\05\06\07\08\09\0a\0b\0c\0d\0e\0f\10")
+              (global $g (mut v128) (v128.const i64x2 0 0))
+              (func (export "add") (param v128 v128) (result v128) (i32x4.add (local.get 0) (local.get 1)))
+              (func (export "dot") (param i32 i32 i32 i32) (result i32)
+                (local $v v128)
+                (local.set $v (i32x4.mul
+                  (i32x4.replace_lane 3 (i32x4.replace_lane 2 (i32x4.replace_lane 1 (i32x4.splat (local.get 0))
+                    (local.get 1)) (local.get 2)) (local.get 3))
+                  (v128.const i32x4 1 2 3 4)))
+                (i32.add
+                  (i32.add (i32x4.extract_lane 0 (local.get $v)) (i32x4.extract_lane 1 (local.get $v)))
+                  (i32.add (i32x4.extract_lane 2 (local.get $v)) (i32x4.extract_lane 3 (local.get $v)))))
+              (func (export "sat") (result v128)
+                (i8x16.add_sat_s (v128.const i8x16 127 -128 1 0 0 0 0 0 0 0 0 0 0 0 0 -1)
+                  (i8x16.splat (i32.const -1))))
+              (func (export "shuffle") (result v128)
+                (i8x16.shuffle 15 14 13 12 11 10 9 8 7 6 5 4 3 2 1 16
+                  (v128.load (i32.const 0)) (v128.const i64x2 0x20 0)))
+              (func (export "bitmask") (param v128) (result i32) (i32x4.bitmask (local.get 0)))
+              (func (export "fmin") (result f32)
+                (f32x4.extract_lane 1 (f32x4.min (v128.const f32x4 1.5 -0.0 nan 4) (v128.const f32x4 2 0.0 1 -inf))))
+              (func (export "lanes") (param i32) (result i64)
+                (v128.store (i32.const 16) (v128.load16x4_u (local.get 0)))
+                (global.set $g (v128.load64_lane 1 (i32.const 24) (global.get $g)))
+                (i64x2.extract_lane 1 (global.get $g))))
+        "#).unwrap();
+        let v128 = |val: u128| Some(Value::V128(val));
+
+        assert_eq!(vm.call("main", "add", &[Value::V128(0x00000003_00000002_00000001_ffffffff), Value::V128(0x00000001_00000001_00000001_00000001)]).unwrap(),
+            v128(0x00000004_00000003_00000002_00000000));
+        assert_eq!(vm.call("main", "dot", &[Value::I32(1), Value::I32(2), Value::I32(3), Value::I32(4)]).unwrap(), Some(Value::I32(30)));
+        assert_eq!(vm.call("main", "sat", &[]).unwrap(), v128(0xfeffffffffffffffffffffffff00807e));
+        assert_eq!(vm.call("main", "shuffle", &[]).unwrap(), v128(0x2002030405060708090a0b0c0d0e0f10));
+        assert_eq!(vm.call("main", "bitmask", &[Value::V128(0x80000000_00000000_ffffffff_00000001)]).unwrap(), Some(Value::I32(0b1010)));
+        match vm.call("main", "fmin", &[]).unwrap() {
+            Some(Value::F32(val)) => assert_eq!(val.to_bits(), (-0.0f32).to_bits()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(vm.call("main", "lanes", &[Value::I32(4)]).unwrap(), Some(Value::I64(0x00000c0b00000a09)));
+        match vm.call("main", "lanes", &[Value::I32(65530)]) {
+            Err(Error::RuntimeError(error::TrapKind::MemoryAccessOutOfBounds, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let cases = [
+            ("(func (drop (i32x4.extract_lane 4 (v128.const i64x2 0 0))))",
+                "func[0]:1: invalid lane index 4"),
+            ("(memory 1) (func (drop (v128.load align=32 (i32.const 0))))",
+                "func[0]:1: alignment 2**5 is larger than natural alignment 16"),
+            ("(func (drop (i8x16.add (v128.const i64x2 0 0) (i32.const 0))))",
+                "func[0]:2: type mismatch: expected v128, found i32"),
+        ];
+        for (wat, msg) in cases.iter() {
+            match Module::from_wat(&format!("(module {})", wat)) {
+                Err(Error::ValidationError(e)) => assert_eq!(&e, msg),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("module should fail to validate: {}", wat),
+            }
+        }
+    }
+
+    #[test]
+    fn v128_slots() {
+        let mut vm = VM::new();
+        vm.imports_mut().add_function("env", "swap", &[ValueType::I32, ValueType::V128], Some(ValueType::V128), |_store, args| {
+            match args {
+                [Value::I32(n), Value::V128(v)] => Ok(Some(Value::V128(v.rotate_left(64) + *n as u128))),
+                _ => Err(error::TrapKind::UnexpectedSignature),
+            }
+        });
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "swap" (func $swap (param i32 v128) (result v128)))
+              (tag $e (param v128))
+              (global $a v128 (v128.const i64x2 1 2))
+              (global $b v128 (global.get $a))
+              (global $g (mut v128) (v128.const i64x2 0 0))
+              (func $pick (param $c i32) (param $x v128) (param $y v128) (result v128)
+                (local $t v128)
+                (local.set $t (select (local.get $x) (local.get $y) (local.get $c)))
+                (drop (local.tee $x (local.get $y)))
+                (block (result v128)
+                  (br_if 0 (local.get $t) (i32.eq (local.get $c) (i32.const 2)))
+                  (drop)
+                  (if (result v128) (local.get $c)
+                    (then (i64x2.add (local.get $t) (local.get $x)))
+                    (else (local.get $t)))))
+              (func (export "pick") (param i32 v128 v128) (result v128)
+                (call $pick (local.get 0) (local.get 1) (local.get 2)))
+              (func (export "table") (param v128 i32) (result v128)
+                (block (result v128)
+                  (block (result v128)
+                    (br_table 0 1 2 (local.get 0) (local.get 1)))
+                  (return (i64x2.add (global.get $b))))
+                (i64x2.add (v128.const i64x2 3 3)))
+              (func (export "host") (param i32) (result i64)
+                (global.set $g (call $swap (local.get 0) (global.get $b)))
+                (i64x2.extract_lane 0 (global.get $g)))
+              (func (export "catch") (param v128) (result v128)
+                (i64x2.add (i64x2.splat (i64.const 7))
+                  (block $h (result v128)
+                    (try_table (catch $e $h) (throw $e (local.get 0)))
+                    (unreachable))))
+              (func (export "legacy") (param v128) (result v128)
+                try (result v128)
+                  (throw $e (local.get 0))
+                catch $e
+                end))
+        "#).unwrap();
+        let v128 = |val: u128| Some(Value::V128(val));
+        let (x, y) = (Value::V128(5 << 64 | 6), Value::V128(7 << 64 | 8));
+
+        assert_eq!(vm.call("main", "pick", &[Value::I32(0), x, y]).unwrap(), v128(7 << 64 | 8));
+        assert_eq!(vm.call("main", "pick", &[Value::I32(1), x, y]).unwrap(), v128(12 << 64 | 14));
+        assert_eq!(vm.call("main", "pick", &[Value::I32(2), x, y]).unwrap(), v128(5 << 64 | 6));
+        for (idx, res) in [(0, 11 << 64 | 10), (1, 12 << 64 | 12), (2, 9 << 64 | 9), (3, 9 << 64 | 9)] {
+            assert_eq!(vm.call("main", "table", &[Value::V128(9 << 64 | 9), Value::I32(idx)]).unwrap(), v128(res));
+        }
+        assert_eq!(vm.call("main", "host", &[Value::I32(10)]).unwrap(), Some(Value::I64(12)));
+        assert_eq!(vm.call("main", "catch", &[Value::V128(1 << 64 | 2)]).unwrap(), v128(8 << 64 | 9));
+        assert_eq!(vm.call("main", "legacy", &[Value::V128(1 << 64 | 2)]).unwrap(), v128(1 << 64 | 2));
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut vm = VM::new();
+        vm.imports_mut().add_function("env", "sum", &[ValueType::I64, ValueType::I64], Some(ValueType::I64), |_store, args| {
+            match args {
+                [Value::I64(a), Value::I64(b)] => Ok(Some(Value::I64(a + b))),
+                _ => Err(error::TrapKind::UnexpectedSignature),
+            }
+        });
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "sum" (func $sum (param i64 i64) (result i64)))
+              (type $step (func (param i64 i64) (result i64)))
+              (table funcref (elem $sum_to $sum))
+              (func $is_even (export "is_even") (param i32) (result i32)
+                (if (result i32) (i32.eqz (local.get 0))
+                  (then (i32.const 1))
+                  (else (return_call $is_odd (i32.sub (local.get 0) (i32.const 1))))))
+              (func $is_odd (param i32) (result i32)
+                (if (result i32) (i32.eqz (local.get 0))
+                  (then (i32.const 0))
+                  (else (return_call $is_even (i32.sub (local.get 0) (i32.const 1))))))
+              ;; Sum `n + ... + 1 + acc`, the last step is a host function.
+              (func $sum_to (export "sum_to") (param $n i64) (param $acc i64) (result i64)
+                (local $next i64)
+                (local.set $next (i64.sub (local.get $n) (i64.const 1)))
+                (return_call_indirect (type $step)
+                  (local.get $next) (i64.add (local.get $acc) (local.get $n))
+                  (i64.eqz (local.get $next))))
+              (func (export "bad") (result i64)
+                (return_call_indirect (type $step) (i64.const 0) (i64.const 0) (i32.const 2))))
+        "#).unwrap();
+
+        // Far deeper than the call limit.
+        assert_eq!(vm.call("main", "is_even", &[Value::I32(100_001)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(vm.call("main", "is_even", &[Value::I32(100_000)]).unwrap(), Some(Value::I32(1)));
+        assert_eq!(vm.call("main", "sum_to", &[Value::I64(100_000), Value::I64(0)]).unwrap(), Some(Value::I64(5_000_050_000)));
+        match vm.call("main", "bad", &[]) {
+            Err(Error::RuntimeError(error::TrapKind::TableAccessOutOfBounds, backtrace)) => {
+                assert_eq!(backtrace.frames().len(), 1);
+            },
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(vm.get_module_instance("main").unwrap().store.stack.is_empty());
+
+        let wat = "(module (func $f (result i32) (i32.const 0)) (func (result i64) (return_call $f)))";
+        match Module::from_wat(wat) {
+            Err(Error::ValidationError(e)) => {
+                assert_eq!(e, "func[1]:0: type mismatch: tail call result doesn't match the function's result");
+            },
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut vm = VM::new();
+        let host_tag = Tag::new(&[ValueType::I64]);
+        vm.imports_mut().add_tag("env", "error", host_tag.clone());
+        let thrown = host_tag.clone();
+        vm.imports_mut().add_function("env", "fail", &[ValueType::I64], None, move |_store, args| {
+            Err(error::TrapKind::throw(&thrown, args))
+        });
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "error" (tag $error (param i64)))
+              (import "env" "fail" (func $fail (param i64)))
+              (tag $e (export "e") (param i32))
+              (tag $empty)
+              (func $throw_if (param i32)
+                (if (i32.ge_s (local.get 0) (i32.const 0))
+                  (then (throw $e (local.get 0)))))
+              ;; Catch `$e` with its value, or -1 for other exceptions.
+              (func (export "try_table") (param i32) (result i32)
+                (block $other
+                  (return (block $caught (result i32)
+                    (try_table (catch $e $caught) (catch_all $other)
+                      (if (i32.eqz (local.get 0)) (then (throw $empty)))
+                      (call $throw_if (i32.sub (local.get 0) (i32.const 1))))
+                    (i32.const 100))))
+                (i32.const -1))
+              ;; Catch everything as an `exnref` and throw it again.
+              (func (export "throw_ref") (param i32)
+                (throw_ref (block $caught (result exnref)
+                  (try_table (catch_all_ref $caught)
+                    (call $throw_if (local.get 0)))
+                  (return))))
+              (func (export "null") (throw_ref (ref.null exn)))
+              (func (export "legacy") (param i32) (result i32)
+                try (result i32)
+                  try (result i32)
+                    (call $throw_if (local.get 0))
+                    (i32.const 100)
+                  catch_all
+                    rethrow 0
+                  end
+                catch $e
+                  (i32.add (i32.const 1000))
+                end)
+              (func (export "delegate") (param i32) (result i32)
+                try $outer (result i32)
+                  try (result i32)
+                    try
+                      (call $throw_if (local.get 0))
+                    delegate $outer
+                    (i32.const 100)
+                  catch_all
+                    (i32.const -1)
+                  end
+                catch $e
+                end)
+              (func (export "host") (param i64) (result i64)
+                (try_table (catch $error 0) (call $fail (local.get 0)))
+                (i64.const -1))
+              (func (export "uncaught") (param i32) (result i32)
+                (block $skip
+                  (try_table (catch $empty $skip) (call $throw_if (local.get 0))))
+                (local.get 0))
+              (func (export "trap") (result i32)
+                (block (try_table (catch_all 0) (unreachable)))
+                (i32.const 0)))
+        "#).unwrap();
+
+        assert_eq!(vm.call("main", "try_table", &[Value::I32(5)]).unwrap(), Some(Value::I32(4)));
+        assert_eq!(vm.call("main", "try_table", &[Value::I32(0)]).unwrap(), Some(Value::I32(-1)));
+        assert_eq!(vm.call("main", "throw_ref", &[Value::I32(-1)]).unwrap(), None);
+        let err = vm.call("main", "throw_ref", &[Value::I32(7)]).unwrap_err();
+        let tag = vm.get_exported_tag("main", "e").unwrap();
+        let exn = err.exception().unwrap();
+        assert!(exn.is(&tag));
+        assert_eq!(exn.values(), &[Value::I32(7)]);
+        match vm.call("main", "null", &[]) {
+            Err(Error::RuntimeError(error::TrapKind::NullReference, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(vm.call("main", "legacy", &[Value::I32(-1)]).unwrap(), Some(Value::I32(100)));
+        assert_eq!(vm.call("main", "legacy", &[Value::I32(7)]).unwrap(), Some(Value::I32(1007)));
+        // `delegate` skips the `catch_all` of the middle `try`.
+        assert_eq!(vm.call("main", "delegate", &[Value::I32(-1)]).unwrap(), Some(Value::I32(100)));
+        assert_eq!(vm.call("main", "delegate", &[Value::I32(3)]).unwrap(), Some(Value::I32(3)));
+        assert_eq!(vm.call("main", "host", &[Value::I64(42)]).unwrap(), Some(Value::I64(42)));
+        let err = vm.call("main", "uncaught", &[Value::I32(3)]).unwrap_err();
+        assert!(err.exception().unwrap().is(&tag));
+        assert!(!err.exception().unwrap().is(&host_tag));
+        assert_eq!(vm.call("main", "uncaught", &[Value::I32(-3)]).unwrap(), Some(Value::I32(-3)));
+        // Traps aren't exceptions.
+        match vm.call("main", "trap", &[]) {
+            Err(Error::RuntimeError(error::TrapKind::Unreachable, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(vm.get_module_instance("main").unwrap().store.stack.is_empty());
+
+        let wat = "(module (tag $e (param i32)) (func (result i64) (block (result i64) (try_table (catch $e 0) (unreachable)))))";
+        match Module::from_wat(wat) {
+            Err(Error::ValidationError(e)) => {
+                assert_eq!(e, "func[0]:1: type mismatch: the values of the exception don't match label 0");
+            },
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn exnref_collection() {
+        // Dropped `exnref`s don't keep their exceptions, the ones held by a
+        // local, a parameter or a global survive many catches.
+        let module = Module::from_wat(r#"
+            (module
+              (tag $e (param i32))
+              (global $g (mut exnref) (ref.null exn))
+              (func $catch (param i32) (result exnref)
+                (block $h (result exnref)
+                  (try_table (catch_all_ref $h) (throw $e (local.get 0)))
+                  (unreachable)))
+              (func $churn (param $n i32)
+                (loop $l
+                  (drop (call $catch (local.get $n)))
+                  (br_if $l (local.tee $n (i32.sub (local.get $n) (i32.const 1))))))
+              (func $hold (param $exn exnref) (result exnref)
+                (call $churn (i32.const 5000))
+                (local.get $exn))
+              (func $spin (result i32)
+                (call $churn (i32.const 5000))
+                (i32.const 1))
+              (func $value (param exnref) (result i32)
+                (block $h (result i32)
+                  (try_table (catch $e $h) (throw_ref (local.get 0)))
+                  (unreachable)))
+              (func (export "run") (param $n i32) (result i32) (local $kept exnref)
+                (local.set $kept (call $catch (i32.const 1)))
+                (global.set $g (call $catch (i32.const 20)))
+                (call $churn (local.get $n))
+                (i32.add (i32.add (call $value (local.get $kept)) (call $value (global.get $g)))
+                  (i32.add (call $value (call $hold (call $catch (i32.const 300))))
+                    ;; The first value is ready while `$spin` catches.
+                    (call $value (select (result exnref) (call $catch (i32.const 4000)) (ref.null exn) (call $spin)))))))
+        "#).unwrap();
+        let state = State::new(std::sync::Arc::new(module)).unwrap();
+        let mut store = state.new_store().unwrap();
+        let run = state.get_exported("run").unwrap();
+        for _ in 0..10 {
+            assert_eq!(state.call(&mut store, run, &[Value::I32(100_000)]).unwrap(), Some(Value::I32(4321)));
+            assert!(store.exception_count() < 4096);
+        }
+    }
+
+    #[test]
+    fn opcode_fusion() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory 1)
+              (data (i32.const 0) "\80\ff")
+              (func (export "count") (param i32) (result i32) (local i32)
+                (local.set 1 (local.get 0))
+                (loop $l (br_if $l (local.tee 1 (i32.add (local.get 1) (i32.const -1)))))
+                (local.get 1))
+              (func (export "below") (param i32 i32) (result i32)
+                (block (br_if 0 (i32.lt_u (local.get 0) (local.get 1))) (return (i32.const 0)))
+                (i32.const 1))
+              (func (export "is_zero") (param i64) (result i32)
+                (block (br_if 0 (i64.eqz (local.get 0))) (return (i32.const 0)))
+                (i32.const 1))
+              (func (export "shr_u") (param i32) (result i32) (i32.shr_u (local.get 0) (i32.const 28)))
+              (func (export "sum") (param i64 i64) (result i64) (local i64)
+                (local.set 2 (i64.add (local.get 0) (local.get 1)))
+                (local.get 2))
+              (func (export "load") (param i32) (result i32) (i32.load8_s (local.get 0))))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "count", &[Value::I32(1000)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(vm.call("main", "below", &[Value::I32(1), Value::I32(-1)]).unwrap(), Some(Value::I32(1)));
+        assert_eq!(vm.call("main", "below", &[Value::I32(-1), Value::I32(1)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(vm.call("main", "is_zero", &[Value::I64(1 << 32)]).unwrap(), Some(Value::I32(0)));
+        assert_eq!(vm.call("main", "is_zero", &[Value::I64(0)]).unwrap(), Some(Value::I32(1)));
+        assert_eq!(vm.call("main", "shr_u", &[Value::I32(-1)]).unwrap(), Some(Value::I32(15)));
+        assert_eq!(vm.call("main", "sum", &[Value::I64(i64::MAX), Value::I64(2)]).unwrap(), Some(Value::I64(i64::MIN + 1)));
+        assert_eq!(vm.call("main", "load", &[Value::I32(0)]).unwrap(), Some(Value::I32(-128)));
+        // A fused load traps at the index of the load.
+        match vm.call("main", "load", &[Value::I32(65536)]) {
+            Err(Error::RuntimeError(error::TrapKind::MemoryAccessOutOfBounds, backtrace)) => {
+                assert_eq!(backtrace.frames()[0].instr_index, Some(1));
+            },
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn hot_locals() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              ;; Locals 4, 5 and 6 are used in the loop, they swap with the
+              ;; parameters 1, 2 and 3.
+              (func $weights (export "weights") (param i32 i32 i32 i32 i32 i32) (result i32) (local i32)
+                (loop $l
+                  (local.set 6 (i32.add (local.get 6) (local.get 4)))
+                  (br_if $l (local.tee 5 (i32.sub (local.get 5) (i32.const 1)))))
+                (i32.add (i32.add (local.get 6) (local.get 0))
+                  (i32.add (i32.mul (local.get 1) (i32.const 10))
+                    (i32.add (i32.mul (local.get 2) (i32.const 100)) (i32.mul (local.get 3) (i32.const 1000))))))
+              (func (export "calls") (param i32) (result i32) (local i32 i32 i32 i32)
+                (loop $l
+                  (local.set 4 (i32.add (local.get 4)
+                    (call $weights (i32.const 1) (i32.const 2) (i32.const 3) (i32.const 4) (i32.const 5) (local.get 0))))
+                  (br_if $l (local.tee 0 (i32.sub (local.get 0) (i32.const 1)))))
+                (local.get 4))
+              (func (export "tail") (param i32) (result i32) (local i32 i32 i32 i32)
+                (local.set 4 (i32.const 7))
+                (return_call $weights (i32.const 1) (i32.const 2) (i32.const 3) (i32.const 4) (local.get 4) (local.get 0))))
+        "#).unwrap();
+        let params: Vec<_> = (1..=6).map(Value::I32).collect();
+        assert_eq!(vm.call("main", "weights", &params).unwrap(), Some(Value::I32(4351)));
+        assert_eq!(vm.call("main", "calls", &[Value::I32(2)]).unwrap(), Some(Value::I32(4321 * 2 + 15)));
+        assert_eq!(vm.call("main", "tail", &[Value::I32(3)]).unwrap(), Some(Value::I32(4321 + 21)));
+    }
+
+    #[test]
+    fn param_operand_slots() {
+        // Block results are kept in operand slots above the params and
+        // locals of the frame, they must not overwrite the locals.
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (func (export "blocks") (param i32 i32 i32) (result i32) (local i32 i32 i32)
+                (local.set 4 (i32.const 10))
+                (local.set 5 (i32.const 20))
+                (i32.add
+                  (i32.add
+                    (block (result i32) (local.get 0))
+                    (i32.add
+                      (if (result i32) (local.get 1)
+                        (then (local.get 1)) (else (local.get 2)))
+                      (block $b (result i32)
+                        (block $a (result i32)
+                          (br_table $a $b (local.get 2) (local.get 2)))
+                        (i32.const 100) (i32.add))))
+                  (i32.add (local.get 4) (local.get 5))))
+              (func (export "loop") (param i32 i32) (result i32) (local i32 i32 i32)
+                (local.set 4 (local.get 0))
+                (loop $l
+                  (local.set 3 (i32.add (local.get 3)
+                    (block (result i32) (local.get 1))))
+                  (local.set 4 (i32.sub (local.get 4) (i32.const 1)))
+                  (br_if $l (local.get 4)))
+                (local.get 3)))
+        "#).unwrap();
+        let params = [Value::I32(1), Value::I32(2), Value::I32(0)];
+        assert_eq!(vm.call("main", "blocks", &params).unwrap(), Some(Value::I32(1 + 2 + 100 + 30)));
+        let params = [Value::I32(1), Value::I32(0), Value::I32(1)];
+        assert_eq!(vm.call("main", "blocks", &params).unwrap(), Some(Value::I32(1 + 1 + 1 + 30)));
+        assert_eq!(vm.call("main", "loop", &[Value::I32(5), Value::I32(3)]).unwrap(), Some(Value::I32(15)));
+    }
+
+    #[test]
+    fn trap_backtrace() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module $calc
+              (func $div (param i32 i32) (result i32)
+                (i32.div_s (local.get 0) (local.get 1)))
+              (func $run (export "run") (param i32) (result i32)
+                (i32.add (i32.const 1) (call $div (i32.const 10) (local.get 0)))))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "run", &[Value::I32(2)]).unwrap(), Some(Value::I32(6)));
+
+        let err = vm.call("main", "run", &[Value::I32(0)]).unwrap_err();
+        let backtrace = match &err {
+            Error::RuntimeError(error::TrapKind::DivisionByZero, backtrace) => backtrace,
+            err => panic!("unexpected error: {}", err),
+        };
+        let frames: Vec<_> = backtrace.frames().iter()
+            .map(|frame| (frame.module.as_deref(), frame.func_index, frame.func_name.as_deref(), frame.instr_index))
+            .collect();
+        assert_eq!(frames, vec![
+            (Some("calc"), 0, Some("div"), Some(2)),
+            (Some("calc"), 1, Some("run"), Some(3)),
+        ]);
+        assert_eq!(err.to_string(), "runtime trap: DivisionByZero\n\
+            wasm backtrace:\n\
+            \x20  0: calc::div\n\
+            \x20            at func[0]:2\n\
+            \x20  1: calc::run\n\
+            \x20            at func[1]:3");
+
+        // The stack is cleaned up, the next call works.
+        assert_eq!(vm.call("main", "run", &[Value::I32(5)]).unwrap(), Some(Value::I32(3)));
+    }
+
+    #[test]
+    fn native_stack_limit() {
+        // Blocks nest closures, each call uses more native stack than the
+        // call depth shows.  It must trap before the thread's stack overflows.
+        // The outermost call gets a stack big enough for the limit, calls
+        // outside of blocks reach the call limit in every build profile.
+        let thread = std::thread::Builder::new().stack_size(2 << 20).spawn(|| {
+            for blocks in [0, 10, 50, 500].iter() {
+                let wat = format!(r#"
+                    (module
+                      (func $r (export "r") (param i32) (result i32)
+                        (if (result i32) (i32.eqz (local.get 0))
+                          (then (i32.const 0))
+                          (else {}(call $r (i32.sub (local.get 0) (i32.const 1))){}))))
+                "#, "(block (result i32) ".repeat(*blocks), ")".repeat(*blocks));
+                let mut vm = VM::new();
+                vm.load_wat("main", &wat).unwrap();
+                assert_eq!(vm.call("main", "r", &[Value::I32(3)]).unwrap(), Some(Value::I32(0)));
+                match vm.call("main", "r", &[Value::I32(1000)]) {
+                    Ok(ret) => assert_eq!(ret, Some(Value::I32(0))),
+                    Err(Error::RuntimeError(error::TrapKind::CallStackExhausted { .. }, _)) if *blocks > 0 => (),
+                    Err(e) => panic!("unexpected error: {}", e),
+                }
+                match vm.call("main", "r", &[Value::I32(100_000)]) {
+                    Err(Error::RuntimeError(error::TrapKind::CallStackExhausted { .. }, _)) => (),
+                    res => panic!("unexpected result: {:?}", res),
+                }
+                // The store can still be used after the trap.
+                assert_eq!(vm.call("main", "r", &[Value::I32(3)]).unwrap(), Some(Value::I32(0)));
+            }
+        }).unwrap();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn stack_limits() {
+        let module = std::sync::Arc::new(Module::from_wat(r#"
+            (module
+              (func $deep (export "deep") (param i32) (result i32)
+                (call $deep (i32.add (local.get 0) (i32.const 1))))
+              (func (export "sum") (param i32 i32 i32) (result i32)
+                (i32.add (local.get 0) (i32.add (local.get 1) (local.get 2)))))
+        "#).unwrap());
+        let state = State::new(module).unwrap();
+
+        let mut store = state.new_store().unwrap();
+        store.stack.set_call_limit(50);
+        let deep = state.get_exported("deep").unwrap();
+        match state.call(&mut store, deep, &[Value::I32(0)]) {
+            Err(Error::RuntimeError(error::TrapKind::CallStackExhausted { limit: 50 }, backtrace)) => {
+                assert_eq!(backtrace.frames().len(), 50);
+                // Only the frames at both ends are printed.
+                let text = backtrace.to_string();
+                assert_eq!(text.lines().count(), 1 + 20 * 2 + 1, "{}", text);
+                assert!(text.contains("\n      ... 30 frames skipped\n  40: "), "{}", text);
+            },
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        store.stack = Stack::new_with_limit(2);
+        let sum = state.get_exported("sum").unwrap();
+        let params = [Value::I32(1), Value::I32(2), Value::I32(3)];
+        match state.call(&mut store, sum, &params) {
+            Err(Error::RuntimeError(error::TrapKind::StackOverflow { limit: 2, len: 3 }, _)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn host_function() {
+        let mut vm = VM::new();
+        vm.imports_mut().add_function("env", "sub", &[ValueType::I32, ValueType::I32], Some(ValueType::I32), |_store, args| {
+            match args {
+                [Value::I32(a), Value::I32(b)] => Ok(Some(Value::I32(a - b))),
+                _ => Err(error::TrapKind::UnexpectedSignature),
+            }
+        });
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "sub" (func $sub (param i32 i32) (result i32)))
+              (func (export "call_sub") (param i32 i32) (result i32)
+                (call $sub (local.get 0) (local.get 1))))
+        "#).unwrap();
+        let ret = vm.call("main", "call_sub", &[Value::I32(10), Value::I32(3)]).unwrap();
+        assert_eq!(ret, Some(Value::I32(7)));
+    }
+
+    #[test]
+    fn host_error() {
+        #[derive(Debug, PartialEq)]
+        struct Denied(String);
+        impl std::fmt::Display for Denied {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "permission denied: {}", self.0)
+            }
+        }
+        impl std::error::Error for Denied {}
+
+        let mut vm = VM::new();
+        vm.imports_mut().add_function("env", "open", &[ValueType::I32], None, |_store, args| {
+            match args {
+                [Value::I32(0)] => Ok(None),
+                [Value::I32(code)] if *code < 0 => Err(error::TrapKind::Exit(-code)),
+                _ => Err(error::TrapKind::host(Denied("/etc".to_string()))),
+            }
+        });
+        vm.load_wat("main", r#"
+            (module
+              (import "env" "open" (func $open (param i32)))
+              (func (export "run") (param i32)
+                (call $open (local.get 0))))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "run", &[Value::I32(0)]).unwrap(), None);
+
+        let err = vm.call("main", "run", &[Value::I32(1)]).unwrap_err();
+        assert_eq!(err.downcast_ref::<Denied>(), Some(&Denied("/etc".to_string())));
+        assert_eq!(err.exit_code(), None);
+        match &err {
+            Error::RuntimeError(_, backtrace) => {
+                let names: Vec<_> = backtrace.frames().iter()
+                    .map(|frame| frame.func_name.as_deref())
+                    .collect();
+                assert_eq!(names, vec![Some("env.open"), None]);
+            },
+            err => panic!("unexpected error: {}", err),
+        }
+
+        let err = vm.call("main", "run", &[Value::I32(-3)]).unwrap_err();
+        assert_eq!(err.exit_code(), Some(3));
+        assert_eq!(err.downcast_ref::<Denied>(), None);
+    }
+
+    #[test]
+    fn missing_import() {
+        let mut vm = VM::new();
+        match vm.load_wat("main", r#"(module (import "env" "sub" (func (param i32 i32) (result i32))))"#) {
+            Err(Error::LinkError(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("missing import should fail to link"),
+        }
+    }
+
+    #[test]
+    fn blocks_and_memory() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory 1)
+              (data (i32.const 0) "\01\02\03\04\05")
+              ;; Sum the bytes `mem[0..n]` in a loop, storing the sum at address 16.
+              (func (export "sum") (param $n i32) (result i32)
+                block
+                  loop
+                    local.get $n
+                    i32.eqz
+                    br_if 1
+                    local.get $n
+                    i32.const 1
+                    i32.sub
+                    local.set $n
+                    i32.const 16
+                    i32.const 16
+                    i32.load
+                    local.get $n
+                    i32.load8_u
+                    i32.add
+                    i32.store
+                    br 0
+                  end
+                end
+                i32.const 16
+                i32.load)
+              ;; Block results and `br_if` with a value.
+              (func (export "pick") (param i32) (result i32)
+                block (result i32)
+                  i32.const 100
+                  local.get 0
+                  i32.const 10
+                  i32.gt_s
+                  br_if 0
+                  drop
+                  i32.const 1
+                  i32.const 2
+                  local.get 0
+                  select
+                end
+                i32.const 1
+                i32.add)
+              ;; Side-effects must happen in order: `local.tee` before the later `local.get`.
+              (func (export "order") (param i32) (result i32)
+                local.get 0
+                i32.const 5
+                local.tee 0
+                i32.add
+                local.get 0
+                i32.mul))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "sum", &[Value::I32(5)]).unwrap(), Some(Value::I32(15)));
+        assert_eq!(vm.call("main", "pick", &[Value::I32(11)]).unwrap(), Some(Value::I32(101)));
+        assert_eq!(vm.call("main", "pick", &[Value::I32(1)]).unwrap(), Some(Value::I32(2)));
+        assert_eq!(vm.call("main", "pick", &[Value::I32(0)]).unwrap(), Some(Value::I32(3)));
+        assert_eq!(vm.call("main", "order", &[Value::I32(2)]).unwrap(), Some(Value::I32(35)));
+    }
+
+    #[test]
+    fn fall_off_end() {
+        let mut vm = VM::new();
+        vm.load_wat("main", r#"
+            (module
+              (memory 1)
+              ;; No `return`, the functions end after the store and the block.
+              (func (export "store") (param i32)
+                (i32.store (i32.const 16) (local.get 0)))
+              (func (export "load") (param i32) (result i32)
+                (block (result i32) (i32.load (i32.const 16)))))
+        "#).unwrap();
+        assert_eq!(vm.call("main", "store", &[Value::I32(7)]).unwrap(), None);
+        assert_eq!(vm.call("main", "load", &[Value::I32(0)]).unwrap(), Some(Value::I32(7)));
+    }
+
+    #[cfg(feature = "wasi")]
+    #[test]
+    fn wasi_hello() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vm = VM::new();
+        wasi::add_to_imports(vm.imports_mut());
+        vm.load_wat("main", r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+              (memory 1)
+              ;; iovec { buf = 8, len = 6 }
+              (data (i32.const 0) "\08\00\00\00\06\00\00\00hello\n")
+              (func (export "_start")
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20)))))
+        "#).unwrap();
+
+        let stdout = Capture::default();
+        let mut ctx = wasi::WasiCtx::new();
+        ctx.set_stdout(Box::new(stdout.clone()));
+        vm.get_module_instance("main").unwrap().store.insert_data(ctx);
+        vm.call("main", "_start", &[]).unwrap();
+        assert_eq!(&stdout.0.lock().unwrap()[..], b"hello\n");
+    }
+
+    #[cfg(feature = "wasi")]
+    #[test]
+    fn wasi_guest_pointers() {
+        const FAULT: i32 = wasi::errno::FAULT as i32;
+
+        let mut vm = VM::new();
+        wasi::add_to_imports(vm.imports_mut());
+        vm.load_wat("main", r#"
+            (module
+              (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+              (memory 1)
+              ;; iovec { buf = 16, len = 2 }
+              (data (i32.const 0) "\10\00\00\00\02\00\00\00")
+              (func (export "write") (param $iovs i32) (param $len i32) (param $ret i32) (result i32)
+                (call $fd_write (i32.const 1) (local.get $iovs) (local.get $len) (local.get $ret))))
+        "#).unwrap();
+        let write = |vm: &mut VM, iovs: u32, len: u32, ret: u32| {
+            let args = [Value::I32(iovs as i32), Value::I32(len as i32), Value::I32(ret as i32)];
+            vm.call("main", "write", &args)
+        };
+
+        // No `WasiCtx` in the store.
+        let err = write(&mut vm, 0, 1, 32).unwrap_err();
+        assert_eq!(err.downcast_ref::<wasi::MissingWasiCtx>(), Some(&wasi::MissingWasiCtx));
+
+        let mut ctx = wasi::WasiCtx::new();
+        ctx.set_stdout(Box::new(std::io::sink()));
+        vm.get_module_instance("main").unwrap().store.insert_data(ctx);
+        assert_eq!(write(&mut vm, 0, 1, 32).unwrap(), Some(Value::I32(0)));
+        // The iovec array or the result wrap around the address space.
+        assert_eq!(write(&mut vm, 0xffff_fffc, 1, 32).unwrap(), Some(Value::I32(FAULT)));
+        assert_eq!(write(&mut vm, 0xffff_fff8, 2, 32).unwrap(), Some(Value::I32(FAULT)));
+        assert_eq!(write(&mut vm, 0, 1, 0xffff_fffe).unwrap(), Some(Value::I32(FAULT)));
+    }
+
+    #[cfg(all(feature = "wasi", unix))]
+    #[test]
+    fn wasi_sandbox() {
+        use std::os::unix::fs::symlink;
+        const NOTCAPABLE: i32 = wasi::errno::NOTCAPABLE as i32;
+        const LOOP: i32 = wasi::errno::LOOP as i32;
+        const SYMLINK_FOLLOW: i32 = 1;
+
+        let tmp = std::env::temp_dir().join(format!("s1vm-wasi-sandbox-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&tmp);
+        let data = tmp.join("data");
+        std::fs::create_dir_all(data.join("sub")).unwrap();
+        std::fs::create_dir(tmp.join("outside")).unwrap();
+        let escaped = tmp.join("escaped.txt");
+        symlink(&escaped, data.join("dangling")).unwrap();
+        symlink("../escaped.txt", data.join("dangling_rel")).unwrap();
+        symlink(tmp.join("outside"), data.join("outside")).unwrap();
+        symlink("sub/../inside.txt", data.join("inside_link")).unwrap();
+        symlink("loop", data.join("loop")).unwrap();
+
+        let mut vm = VM::new();
+        wasi::add_to_imports(vm.imports_mut());
+        vm.load_wat("main", r#"
+            (module
+              (import "wasi_snapshot_preview1" "path_open"
+                (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+              (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+              (memory 1)
+              ;; iovec { buf = 16, len = 5 }
+              (data (i32.const 0) "\10\00\00\00\05\00\00\00")
+              (data (i32.const 16) "pwned")
+              ;; Create the file at the path at 256 in the preopened dir, and
+              ;; write to it.
+              (func (export "create") (param $len i32) (param $lookup i32) (result i32)
+                (local $errno i32)
+                (local.set $errno (call $path_open (i32.const 3) (local.get $lookup) (i32.const 256) (local.get $len)
+                  (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 8)))
+                (if (i32.eqz (local.get $errno)) (then
+                  (local.set $errno (call $fd_write (i32.load (i32.const 8)) (i32.const 0) (i32.const 1) (i32.const 12)))))
+                (local.get $errno)))
+        "#).unwrap();
+        let mut ctx = wasi::WasiCtx::new();
+        ctx.preopen_dir(&data, "/data").unwrap();
+        vm.get_module_instance("main").unwrap().store.insert_data(ctx);
+
+        let mut create = |path: &str, lookup: i32| {
+            let store = &mut vm.get_module_instance("main").unwrap().store;
+            store.memory_mut(0).unwrap()[256..256 + path.len()].copy_from_slice(path.as_bytes());
+            match vm.call("main", "create", &[Value::I32(path.len() as i32), Value::I32(lookup)]).unwrap() {
+                Some(Value::I32(errno)) => errno,
+                ret => panic!("unexpected result: {:?}", ret),
+            }
+        };
+        assert_eq!(create("../escaped.txt", 0), NOTCAPABLE);
+        assert_eq!(create("sub/../../escaped.txt", 0), NOTCAPABLE);
+        assert_eq!(create(escaped.to_str().unwrap(), 0), NOTCAPABLE);
+        assert_eq!(create("/escaped.txt", 0), NOTCAPABLE);
+        // Symlinks out of the preopened dir, followed or not.
+        assert_eq!(create("dangling", 0), LOOP);
+        assert_eq!(create("dangling", SYMLINK_FOLLOW), NOTCAPABLE);
+        assert_eq!(create("dangling_rel", SYMLINK_FOLLOW), NOTCAPABLE);
+        assert_eq!(create("outside/escaped.txt", 0), NOTCAPABLE);
+        assert_eq!(create("loop", SYMLINK_FOLLOW), LOOP);
+        assert!(!escaped.exists());
+        assert_eq!(std::fs::read_dir(tmp.join("outside")).unwrap().count(), 0);
+
+        // Paths inside the preopened dir work.
+        assert_eq!(create("sub/../inside.txt", 0), 0);
+        assert_eq!(std::fs::read(data.join("inside.txt")).unwrap(), b"pwned");
+        assert_eq!(create("inside_link", SYMLINK_FOLLOW), 0);
+        assert_eq!(create("inside_link", 0), LOOP);
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
 }
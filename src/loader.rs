@@ -0,0 +1,744 @@
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::iter;
+
+pub use crate::elements::{
+  CustomSection, ExportEntry, External, GlobalType, ImportEntry, Internal, MemoryType,
+  ResizableLimits, TableElementType, TableType,
+};
+pub use crate::isa::{
+  AtomicInstruction, AtomicRmwOp, BlockType, BrTableData, Catch, Instruction, MemArg, SimdInstruction, SimdKind, ValueType,
+};
+
+use crate::decoder;
+use crate::elements as raw;
+use crate::error::*;
+use crate::validator;
+
+pub const PAGE_SIZE: u32 = 64 * 1024; // 64 KiB
+
+/// Maximum number of locals (including parameters) in a function.
+pub const MAX_LOCALS: u32 = 50_000;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FunctionType {
+  type_ref: u32,
+  params: Vec<ValueType>,
+  return_type: Option<ValueType>,
+}
+
+impl FunctionType {
+  fn new(type_ref: u32, func_type: &mut raw::FunctionType) -> Self {
+    FunctionType {
+      type_ref,
+      params: take(func_type.params_mut()),
+      return_type: take(func_type.return_type_mut()),
+    }
+  }
+
+  pub const fn type_ref(&self) -> u32 {
+    self.type_ref
+  }
+
+  pub fn params(&self) -> &[ValueType] {
+    &self.params
+  }
+
+  pub fn param_count(&self) -> u32 {
+    self.params.len() as u32
+  }
+
+  pub const fn return_type(&self) -> Option<ValueType> {
+    self.return_type
+  }
+}
+
+impl fmt::Display for FunctionType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let params = self.params.iter()
+      .map(|t| t.to_string())
+      .collect::<Vec<String>>()
+      .join(", ");
+    let return_type = match self.return_type {
+      Some(return_type) => return_type.to_string(),
+      None => String::from("()"),
+    };
+    write!(f, "fn ({}) -> {}", params, return_type)
+  }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Function {
+  name: String,
+  /// Name from the name section.
+  debug_name: Option<String>,
+  func_type: FunctionType,
+  is_imported: bool,
+  locals: Vec<ValueType>,
+  instructions: Vec<Instruction>,
+}
+
+impl Function {
+  const fn new(
+    name: String,
+    func_type: FunctionType,
+    locals: Vec<ValueType>,
+    instructions: Vec<Instruction>,
+  ) -> Self {
+    Function {
+      name,
+      debug_name: None,
+      func_type,
+      is_imported: false,
+      locals,
+      instructions,
+    }
+  }
+
+  fn new_imported(name: String, func_type: FunctionType) -> Self {
+    Function {
+      name,
+      debug_name: None,
+      func_type,
+      is_imported: true,
+      locals: Vec::new(),
+      instructions: Vec::new(),
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn debug_name(&self) -> Option<&str> {
+    self.debug_name.as_deref()
+  }
+
+  pub const fn func_type(&self) -> &FunctionType {
+    &self.func_type
+  }
+
+  pub const fn type_ref(&self) -> u32 {
+    self.func_type.type_ref()
+  }
+
+  pub fn params(&self) -> &[ValueType] {
+    self.func_type.params()
+  }
+
+  pub fn param_count(&self) -> u32 {
+    self.func_type.param_count()
+  }
+
+  pub const fn return_type(&self) -> Option<ValueType> {
+    self.func_type().return_type()
+  }
+
+  pub const fn is_imported(&self) -> bool {
+    self.is_imported
+  }
+
+  pub fn locals(&self) -> &[ValueType] {
+    &self.locals
+  }
+
+  pub fn instructions(&self) -> &[Instruction] {
+    &self.instructions
+  }
+}
+
+impl fmt::Display for Function {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "fn {}{}", self.name, &self.func_type.to_string()[3..])
+  }
+}
+
+/// The arithmetic instructions allowed in constant expressions.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConstOp {
+  Add,
+  Sub,
+  Mul,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum InitExpr {
+  I32Const(i32),
+  I64Const(i64),
+  F32Const(u32),
+  F64Const(u64),
+  V128Const(u128),
+  Global(u32),
+  RefNull(ValueType),
+  RefFunc(u32),
+  /// `i32` or `i64` arithmetic on two constant expressions, from the
+  /// extended constant expressions proposal.
+  Binop(ValueType, ConstOp, Box<(InitExpr, InitExpr)>),
+}
+
+impl TryFrom<&raw::InitExpr> for InitExpr {
+  type Error = Error;
+
+  fn try_from(init_expr: &raw::InitExpr) -> Result<Self> {
+    let (last, instrs) = init_expr.code().split_last()
+      .ok_or_else(|| Error::ValidationError("Init expr has invalid length: 0".to_string()))?;
+    if *last != Instruction::End {
+      return Err(Error::ValidationError("Init expr is missing the end".to_string()));
+    }
+    // Build the expression tree with a stack of the operands.
+    let mut stack = Vec::new();
+    for instr in instrs {
+      let expr = match instr {
+        Instruction::I32Const(val) => InitExpr::I32Const(*val),
+        Instruction::I64Const(val) => InitExpr::I64Const(*val),
+        Instruction::F32Const(val) => InitExpr::F32Const(*val),
+        Instruction::F64Const(val) => InitExpr::F64Const(*val),
+        Instruction::Simd(op) => match **op {
+          SimdInstruction::V128Const(val) => InitExpr::V128Const(val),
+          ref other => return Err(Error::ValidationError(format!("Invalid instruction in init expr: {:?}", other))),
+        },
+        Instruction::GetGlobal(index) => InitExpr::Global(*index),
+        Instruction::RefNull(value_type) => InitExpr::RefNull(*value_type),
+        Instruction::RefFunc(index) => InitExpr::RefFunc(*index),
+        other => {
+          let (ty, op) = match other {
+            Instruction::I32Add => (ValueType::I32, ConstOp::Add),
+            Instruction::I32Sub => (ValueType::I32, ConstOp::Sub),
+            Instruction::I32Mul => (ValueType::I32, ConstOp::Mul),
+            Instruction::I64Add => (ValueType::I64, ConstOp::Add),
+            Instruction::I64Sub => (ValueType::I64, ConstOp::Sub),
+            Instruction::I64Mul => (ValueType::I64, ConstOp::Mul),
+            _ => return Err(Error::ValidationError(format!("Invalid instruction in init expr: {:?}", other))),
+          };
+          let right = stack.pop();
+          let left = stack.pop();
+          match left.zip(right) {
+            Some(args) => InitExpr::Binop(ty, op, Box::new(args)),
+            None => return Err(Error::ValidationError("type mismatch: init expr stack underflow".to_string())),
+          }
+        },
+      };
+      stack.push(expr);
+    }
+    if stack.len() != 1 {
+      return Err(Error::ValidationError(format!("type mismatch: init expr must have one result, found {}", stack.len())));
+    }
+    Ok(stack.remove(0))
+  }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Global {
+  name: String,
+  is_imported: bool,
+  is_mutable: bool,
+  value_type: ValueType,
+  init_expr: InitExpr,
+}
+
+impl Global {
+  fn from_entry(name: String, global: &raw::GlobalEntry) -> Result<Self> {
+    let global_type = global.global_type();
+    Ok(Global {
+      name,
+      is_imported: false,
+      is_mutable: global_type.is_mutable(),
+      value_type: global_type.content_type(),
+      init_expr: global.init_expr().try_into()?,
+    })
+  }
+
+  fn from_import(name: String, index: u32, global_type: raw::GlobalType) -> Self {
+    Global {
+      name,
+      is_imported: true,
+      is_mutable: global_type.is_mutable(),
+      value_type: global_type.content_type(),
+      init_expr: InitExpr::Global(index),
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub const fn is_imported(&self) -> bool {
+    self.is_imported
+  }
+
+  pub const fn is_mutable(&self) -> bool {
+    self.is_mutable
+  }
+
+  pub const fn value_type(&self) -> ValueType {
+    self.value_type
+  }
+
+  pub const fn init_expr(&self) -> &InitExpr {
+    &self.init_expr
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Table {
+  is_imported: bool,
+  elem_type: TableElementType,
+  limits: ResizableLimits,
+}
+
+impl Table {
+  pub const fn is_imported(&self) -> bool {
+    self.is_imported
+  }
+
+  pub const fn elem_type(&self) -> TableElementType {
+    self.elem_type
+  }
+
+  pub const fn limits(&self) -> &ResizableLimits {
+    &self.limits
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Memory {
+  is_imported: bool,
+  memory_type: MemoryType,
+}
+
+impl Memory {
+  pub const fn is_imported(&self) -> bool {
+    self.is_imported
+  }
+
+  pub const fn memory_type(&self) -> &MemoryType {
+    &self.memory_type
+  }
+
+  pub const fn limits(&self) -> &ResizableLimits {
+    self.memory_type.limits()
+  }
+
+  /// The memory is addressed with `i64` values.
+  pub const fn is_64(&self) -> bool {
+    self.memory_type.is_64()
+  }
+}
+
+/// An exception tag, the params of its type are the values of the exception.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tag {
+  is_imported: bool,
+  func_type: FunctionType,
+}
+
+impl Tag {
+  pub const fn is_imported(&self) -> bool {
+    self.is_imported
+  }
+
+  pub const fn func_type(&self) -> &FunctionType {
+    &self.func_type
+  }
+
+  pub fn params(&self) -> &[ValueType] {
+    self.func_type.params()
+  }
+}
+
+/// An element segment.  Only active segments have an offset, passive
+/// segments are copied by `table.init`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TableInit {
+  index: u32,
+  offset: Option<InitExpr>,
+  is_declared: bool,
+  elem_type: TableElementType,
+  /// Function indices, `None` for null references.
+  entries: Vec<Option<u32>>,
+}
+
+impl TableInit {
+  pub const fn index(&self) -> u32 {
+    self.index
+  }
+
+  pub const fn offset(&self) -> Option<&InitExpr> {
+    self.offset.as_ref()
+  }
+
+  pub const fn is_active(&self) -> bool {
+    self.offset.is_some()
+  }
+
+  pub const fn is_declared(&self) -> bool {
+    self.is_declared
+  }
+
+  pub const fn elem_type(&self) -> TableElementType {
+    self.elem_type
+  }
+
+  pub fn entries(&self) -> &[Option<u32>] {
+    &self.entries
+  }
+}
+
+/// A data segment.  Only active segments have an offset, passive segments
+/// are copied by `memory.init`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MemoryInit {
+  index: u32,
+  offset: Option<InitExpr>,
+  data: Vec<u8>,
+}
+
+impl MemoryInit {
+  pub const fn index(&self) -> u32 {
+    self.index
+  }
+
+  pub const fn offset(&self) -> Option<&InitExpr> {
+    self.offset.as_ref()
+  }
+
+  pub const fn is_active(&self) -> bool {
+    self.offset.is_some()
+  }
+
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
+}
+
+/// A decoded and validated wasm module, ready to be compiled.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Module {
+  /// Module name from the name section.
+  name: Option<String>,
+  types: Vec<FunctionType>,
+  functions: Vec<Function>,
+  globals: Vec<Global>,
+  tables: Vec<Table>,
+  memories: Vec<Memory>,
+  tags: Vec<Tag>,
+  table_inits: Vec<TableInit>,
+  memory_inits: Vec<MemoryInit>,
+  /// Number of data segments, from the data count section.
+  data_count: Option<u32>,
+  imports: Vec<ImportEntry>,
+  exports: Vec<ExportEntry>,
+  start_func: Option<u32>,
+  custom_sections: Vec<CustomSection>,
+}
+
+impl Module {
+  /// Load a module from a wasm file.
+  pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+    let bytes = std::fs::read(path)
+      .map_err(|e| Error::ParseError(e.to_string()))?;
+    Self::from_bytes(&bytes)
+  }
+
+  /// Load a module from an in-memory wasm binary.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    Self::from_elements(decoder::decode(bytes)?)
+  }
+
+  /// Load a module from a reader (network stream, database blob, etc..).
+  pub fn from_reader<R: std::io::Read>(mut reader: R) -> Result<Self> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)
+      .map_err(|e| Error::ParseError(e.to_string()))?;
+    Self::from_bytes(&bytes)
+  }
+
+  /// Load a module from the WebAssembly text format.
+  #[cfg(feature = "wat")]
+  pub fn from_wat(text: &str) -> Result<Self> {
+    let bytes = wat::parse_str(text)
+      .map_err(|e| Error::WatError(e.to_string()))?;
+    Self::from_bytes(&bytes)
+  }
+
+  /// Validate and load an already parsed `parity_wasm` module.  The module
+  /// is serialized back to bytes and decoded again, it is no faster than
+  /// loading the bytes it was parsed from.
+  #[cfg(feature = "parity-wasm")]
+  pub fn from_parity(module: parity_wasm::elements::Module) -> Result<Self> {
+    let bytes = parity_wasm::serialize(module)
+      .map_err(|e| Error::ParseError(e.to_string()))?;
+    Self::from_bytes(&bytes)
+  }
+
+  /// Validate and load the decoded sections of a module.
+  pub fn from_elements(module: raw::Module) -> Result<Self> {
+    let module = Self::load_elements(module)?;
+    validator::validate_module(&module)?;
+    Ok(module)
+  }
+
+  fn load_elements(mut module: raw::Module) -> Result<Self> {
+    // TODO: What happens when multiple functions have the same name?
+    let types = get_types(&mut module);
+
+    let mut globals = Vec::new();
+    let mut functions = Vec::new();
+    let mut tables = Vec::new();
+    let mut memories = Vec::new();
+    let mut tags = Vec::new();
+
+    for entry in module.imports.iter() {
+      let name = format!("{}.{}", entry.module(), entry.field());
+      match entry.external() {
+        External::Function(type_ref) => {
+          let func_type = get_type(&types, *type_ref)?;
+          functions.push(Function::new_imported(name, func_type))
+        },
+        External::Global(global_type) => {
+          globals.push(Global::from_import(name, globals.len() as u32, *global_type))
+        },
+        External::Table(table_type) => tables.push(Table {
+          is_imported: true,
+          elem_type: table_type.elem_type(),
+          limits: *table_type.limits(),
+        }),
+        External::Memory(memory_type) => memories.push(Memory {
+          is_imported: true,
+          memory_type: *memory_type,
+        }),
+        External::Tag(type_ref) => tags.push(Tag {
+          is_imported: true,
+          func_type: get_type(&types, *type_ref)?,
+        }),
+      }
+    }
+
+    handle_global_section(&mut globals, &module)?;
+    handle_function_section(&mut functions, &mut module, &types)?;
+    handle_table_section(&mut tables, &mut module);
+    handle_memory_section(&mut memories, &mut module);
+    handle_tag_section(&mut tags, &module, &types)?;
+
+    for export in module.exports.iter() {
+      match export.internal() {
+        Internal::Function(index) => {
+          if let Some(func) = functions.get_mut(*index as usize) {
+            func.name = export.field().to_string();
+          }
+        },
+        Internal::Global(index) => {
+          if let Some(global) = globals.get_mut(*index as usize) {
+            global.name = export.field().to_string();
+          }
+        },
+        _ => (),
+      }
+    }
+
+    for (i, name) in module.func_names.iter() {
+      if let Some(func) = functions.get_mut(*i as usize) {
+        func.name = name.clone();
+        func.debug_name = Some(name.clone());
+      }
+    }
+
+    Ok(Module {
+      name: module.name.take(),
+      types,
+      functions,
+      globals,
+      tables,
+      memories,
+      tags,
+      table_inits: get_table_inits(&mut module)?,
+      memory_inits: get_memory_inits(&mut module)?,
+      data_count: module.data_count,
+      imports: take(&mut module.imports),
+      exports: take(&mut module.exports),
+      start_func: module.start,
+      custom_sections: take(&mut module.custom_sections),
+    })
+  }
+
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_deref()
+  }
+
+  pub fn types(&self) -> &[FunctionType] {
+    &self.types
+  }
+
+  pub fn functions(&self) -> &[Function] {
+    &self.functions
+  }
+
+  pub fn func(&self, index: u32) -> &Function {
+    &self.functions[index as usize]
+  }
+
+  pub fn get_func(&self, index: u32) -> Option<&Function> {
+    self.functions.get(index as usize)
+  }
+
+  pub fn globals(&self) -> &[Global] {
+    &self.globals
+  }
+
+  /// The index of each global's first value in `Store::globals`, a `v128`
+  /// global takes two values.
+  pub fn global_slots(&self) -> Vec<u32> {
+    self.globals.iter().scan(0, |slot, global| {
+      let idx = *slot;
+      *slot += crate::ValueType::from(global.value_type()).slots() as u32;
+      Some(idx)
+    }).collect()
+  }
+
+  pub fn tables(&self) -> &[Table] {
+    &self.tables
+  }
+
+  pub fn memories(&self) -> &[Memory] {
+    &self.memories
+  }
+
+  /// The exception tags, imported tags first.
+  pub fn tags(&self) -> &[Tag] {
+    &self.tags
+  }
+
+  pub fn table_inits(&self) -> &[TableInit] {
+    &self.table_inits
+  }
+
+  pub fn memory_inits(&self) -> &[MemoryInit] {
+    &self.memory_inits
+  }
+
+  pub const fn data_count(&self) -> Option<u32> {
+    self.data_count
+  }
+
+  pub fn imports(&self) -> &[ImportEntry] {
+    &self.imports
+  }
+
+  pub fn exports(&self) -> &[ExportEntry] {
+    &self.exports
+  }
+
+  pub const fn start_func(&self) -> Option<u32> {
+    self.start_func
+  }
+
+  pub fn custom_sections(&self) -> &[CustomSection] {
+    &self.custom_sections
+  }
+}
+
+fn get_type(types: &[FunctionType], type_ref: u32) -> Result<FunctionType> {
+  types.get(type_ref as usize).cloned()
+    .ok_or_else(|| Error::ValidationError(format!("Invalid type reference: {}", type_ref)))
+}
+
+fn get_types(module: &mut raw::Module) -> Vec<FunctionType> {
+  module.types.iter_mut()
+    .enumerate()
+    .map(|(i, func_type)| FunctionType::new(i as u32, func_type))
+    .collect()
+}
+
+fn handle_global_section(globals: &mut Vec<Global>, module: &raw::Module) -> Result<()> {
+  for global in module.globals.iter() {
+    let name = format!("global_{}", globals.len());
+    globals.push(Global::from_entry(name, global)?);
+  }
+  Ok(())
+}
+
+fn handle_function_section(
+  functions: &mut Vec<Function>,
+  module: &mut raw::Module,
+  types: &[FunctionType],
+) -> Result<()> {
+  if module.functions.len() != module.code.len() {
+    return Err(Error::ValidationError(format!("Function and code section have different lengths: {} != {}",
+      module.functions.len(), module.code.len())));
+  }
+  for (type_ref, body) in module.functions.iter().zip(module.code.iter_mut()) {
+    let name = format!("func_{}", functions.len());
+    let func_type = get_type(types, *type_ref)?;
+    // Check the number of locals before allocating them.
+    body.locals().iter()
+      .try_fold(func_type.param_count(), |count, locals| count.checked_add(locals.count()))
+      .filter(|count| *count <= MAX_LOCALS)
+      .ok_or_else(|| Error::ValidationError(format!("Too many locals in {}, the limit is {}", name, MAX_LOCALS)))?;
+    let locals = body.locals()
+      .iter()
+      .flat_map(|locals| iter::repeat_n(locals.value_type(), locals.count() as usize))
+      .collect();
+    let instructions = take(body.code_mut());
+    functions.push(Function::new(name, func_type, locals, instructions));
+  }
+  Ok(())
+}
+
+fn handle_table_section(tables: &mut Vec<Table>, module: &mut raw::Module) {
+  tables.extend(module.tables.drain(..).map(|table_type| Table {
+    is_imported: false,
+    elem_type: table_type.elem_type(),
+    limits: *table_type.limits(),
+  }));
+}
+
+fn handle_memory_section(memories: &mut Vec<Memory>, module: &mut raw::Module) {
+  memories.extend(module.memories.drain(..).map(|memory_type| Memory {
+    is_imported: false,
+    memory_type,
+  }));
+}
+
+fn handle_tag_section(tags: &mut Vec<Tag>, module: &raw::Module, types: &[FunctionType]) -> Result<()> {
+  for type_ref in module.tags.iter() {
+    tags.push(Tag {
+      is_imported: false,
+      func_type: get_type(types, *type_ref)?,
+    });
+  }
+  Ok(())
+}
+
+fn get_offset(offset: &Option<raw::InitExpr>) -> Result<Option<InitExpr>> {
+  offset.as_ref().map(InitExpr::try_from).transpose()
+}
+
+fn get_table_inits(module: &mut raw::Module) -> Result<Vec<TableInit>> {
+  let mut inits = Vec::new();
+  for init in module.elements.iter_mut() {
+    inits.push(TableInit {
+      index: init.index(),
+      offset: get_offset(init.offset())?,
+      is_declared: init.is_declared(),
+      elem_type: init.elem_type(),
+      entries: take(init.members_mut()),
+    });
+  }
+  Ok(inits)
+}
+
+fn get_memory_inits(module: &mut raw::Module) -> Result<Vec<MemoryInit>> {
+  let mut inits = Vec::new();
+  for init in module.data.iter_mut() {
+    inits.push(MemoryInit {
+      index: init.index(),
+      offset: get_offset(init.offset())?,
+      data: take(init.value_mut()),
+    });
+  }
+  Ok(inits)
+}
+
+fn take<T: Default>(t: &mut T) -> T {
+  std::mem::take(t)
+}
@@ -0,0 +1,298 @@
+
+use std::convert::TryFrom;
+
+use crate::*;
+use crate::error::*;
+
+/// Maximum number of pages of a memory without a maximum size (4 GiB).
+pub const MAX_PAGES: u32 = 65536;
+
+/// Maximum number of pages of a 64-bit memory without a maximum size.
+pub const MAX_PAGES_64: u32 = u32::MAX;
+
+/// Default cap on the size of a memory in pages, see `Store::set_memory_limit`.
+pub const DEFAULT_MEMORY_LIMIT: u64 = MAX_PAGES as u64;
+
+/// Mask of the bits of an address, 32-bit addresses are stored sign-extended.
+pub const fn address_mask(is_64: bool) -> u64 {
+  if is_64 { u64::MAX } else { u32::MAX as u64 }
+}
+
+/// A linear memory of a `Store`.
+pub enum Memory {
+  /// A memory only used by one `Store`.
+  Local(Vec<u8>),
+  /// A memory shared between stores, which can run on different threads.
+  Shared(SharedMemory),
+}
+
+impl Memory {
+  /// The size in bytes.
+  pub fn len(&self) -> usize {
+    match self {
+      Memory::Local(mem) => mem.len(),
+      Memory::Shared(mem) => mem.len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// The bytes of a local memory, shared memories can only be accessed
+  /// with `read` and `write`.
+  pub fn as_slice(&self) -> Option<&[u8]> {
+    match self {
+      Memory::Local(mem) => Some(mem),
+      Memory::Shared(_) => None,
+    }
+  }
+
+  pub fn as_mut_slice(&mut self) -> Option<&mut [u8]> {
+    match self {
+      Memory::Local(mem) => Some(mem),
+      Memory::Shared(_) => None,
+    }
+  }
+
+  /// Copy the bytes at `addr` into `buf`.
+  pub fn read(&self, addr: u64, buf: &mut [u8]) -> Trap<()> {
+    match self {
+      Memory::Local(mem) => {
+        let src = bulk_range(mem.len(), addr, buf.len() as u64)?;
+        buf.copy_from_slice(&mem[src]);
+        Ok(())
+      },
+      Memory::Shared(mem) => mem.read(addr, buf),
+    }
+  }
+
+  /// Copy `bytes` to `addr`.
+  pub fn write(&mut self, addr: u64, bytes: &[u8]) -> Trap<()> {
+    match self {
+      Memory::Local(mem) => init(mem, addr, bytes, 0, bytes.len() as u64),
+      Memory::Shared(mem) => mem.write(addr, bytes),
+    }
+  }
+
+  #[inline]
+  pub fn load<const N: usize>(&self, addr: u64, offset: u64) -> Trap<[u8; N]> {
+    match self {
+      Memory::Local(mem) => load(mem, addr, offset),
+      Memory::Shared(mem) => mem.load(addr, offset),
+    }
+  }
+
+  #[inline]
+  pub fn store<const N: usize>(&mut self, addr: u64, offset: u64, bytes: [u8; N]) -> Trap<()> {
+    match self {
+      Memory::Local(mem) => store(mem, addr, offset, bytes),
+      Memory::Shared(mem) => mem.store(addr, offset, bytes),
+    }
+  }
+
+  /// Grow memory by `delta` pages.  Returns the old size in pages or -1 on failure.
+  pub fn grow(&mut self, max_pages: u64, delta: u64) -> i64 {
+    match self {
+      Memory::Local(mem) => grow(mem, max_pages, delta),
+      Memory::Shared(mem) => mem.grow(max_pages, delta),
+    }
+  }
+
+  /// Set `len` bytes at `dst` to `val`.
+  pub fn fill(&mut self, dst: u64, val: u8, len: u64) -> Trap<()> {
+    match self {
+      Memory::Local(mem) => fill(mem, dst, val, len),
+      Memory::Shared(mem) => mem.fill(dst, val, len),
+    }
+  }
+
+  /// Copy `len` bytes at `src` in a data segment to `dst`.
+  pub fn init(&mut self, dst: u64, data: &[u8], src: u64, len: u64) -> Trap<()> {
+    let src = bulk_range(data.len(), src, len)?;
+    self.write(dst, &data[src])
+  }
+
+  /// Atomically load `bytes` bytes, zero-extended.
+  pub fn atomic_load(&self, addr: u64, offset: u64, bytes: u32) -> Trap<u64> {
+    match self {
+      Memory::Local(mem) => {
+        let ea = atomic_addr(mem.len(), addr, offset, bytes)?;
+        let mut buf = [0u8; 8];
+        buf[..bytes as usize].copy_from_slice(&mem[ea..ea + bytes as usize]);
+        Ok(u64::from_le_bytes(buf))
+      },
+      Memory::Shared(mem) => mem.atomic_load(addr, offset, bytes),
+    }
+  }
+
+  /// Atomically replace the `bytes` bytes at the address with the value
+  /// returned by `op`, unless it returns `None`.  Returns the old value.
+  pub fn atomic_rmw<F>(&mut self, addr: u64, offset: u64, bytes: u32, op: F) -> Trap<u64>
+    where F: Fn(u64) -> Option<u64>
+  {
+    match self {
+      Memory::Local(mem) => {
+        let ea = atomic_addr(mem.len(), addr, offset, bytes)?;
+        let range = ea..ea + bytes as usize;
+        let mut buf = [0u8; 8];
+        buf[..bytes as usize].copy_from_slice(&mem[range.clone()]);
+        let old = u64::from_le_bytes(buf);
+        if let Some(new) = op(old) {
+          mem[range].copy_from_slice(&new.to_le_bytes()[..bytes as usize]);
+        }
+        Ok(old)
+      },
+      Memory::Shared(mem) => mem.atomic_rmw(addr, offset, bytes, op),
+    }
+  }
+
+  /// Wait for a `notify` if the `bytes` bytes at the address are equal to
+  /// `expected`, for at most `timeout` nanoseconds when it isn't negative.
+  /// Returns 0 when notified, 1 if the value isn't `expected` and 2 on a
+  /// timeout.  Only shared memories can wait.
+  pub fn atomic_wait(&self, addr: u64, offset: u64, bytes: u32, expected: u64, timeout: i64) -> Trap<u32> {
+    match self {
+      Memory::Local(_) => Err(TrapKind::WaitOnUnsharedMemory),
+      Memory::Shared(mem) => mem.wait(addr, offset, bytes, expected, timeout),
+    }
+  }
+
+  /// Wake up to `count` threads waiting on the address.  Returns the number
+  /// of threads woken up, always 0 for local memories.
+  pub fn atomic_notify(&self, addr: u64, offset: u64, count: u32) -> Trap<u32> {
+    match self {
+      Memory::Local(mem) => atomic_addr(mem.len(), addr, offset, 4).map(|_| 0),
+      Memory::Shared(mem) => mem.notify(addr, offset, count),
+    }
+  }
+}
+
+/// Get the effective address of an atomic access of `bytes`, it must be
+/// aligned.
+pub(crate) fn atomic_addr(mem_len: usize, addr: u64, offset: u64, bytes: u32) -> Trap<usize> {
+  let ea = addr.checked_add(offset).ok_or(TrapKind::MemoryAccessOutOfBounds)?;
+  let range = bulk_range(mem_len, ea, bytes as u64)?;
+  if ea % bytes as u64 != 0 {
+    return Err(TrapKind::UnalignedAtomic);
+  }
+  Ok(range.start)
+}
+
+/// Get the range of memory accessed by a load/store of `N` bytes.
+#[inline]
+pub(crate) fn mem_range<const N: usize>(mem_len: usize, addr: u64, offset: u64) -> Trap<std::ops::Range<usize>> {
+  let start = addr.checked_add(offset)
+    .filter(|start| start.checked_add(N as u64).is_some_and(|end| end <= mem_len as u64))
+    .ok_or(TrapKind::MemoryAccessOutOfBounds)?;
+  let start = start as usize;
+  Ok(start..start + N)
+}
+
+/// Read `N` bytes from memory at the effective address `addr + offset`.
+#[inline]
+pub fn load<const N: usize>(mem: &[u8], addr: u64, offset: u64) -> Trap<[u8; N]> {
+  let range = mem_range::<N>(mem.len(), addr, offset)?;
+  let mut buf = [0u8; N];
+  buf.copy_from_slice(&mem[range]);
+  Ok(buf)
+}
+
+/// Write `N` bytes to memory at the effective address `addr + offset`.
+#[inline]
+pub fn store<const N: usize>(mem: &mut [u8], addr: u64, offset: u64, bytes: [u8; N]) -> Trap<()> {
+  let range = mem_range::<N>(mem.len(), addr, offset)?;
+  mem[range].copy_from_slice(&bytes);
+  Ok(())
+}
+
+/// Get the range of `len` bytes at `start`, for bulk memory operations.
+#[inline]
+pub(crate) fn bulk_range(mem_len: usize, start: u64, len: u64) -> Trap<std::ops::Range<usize>> {
+  let end = start.checked_add(len)
+    .filter(|end| *end <= mem_len as u64)
+    .ok_or(TrapKind::MemoryAccessOutOfBounds)?;
+  Ok(start as usize..end as usize)
+}
+
+/// Copy `len` bytes from `src` in `src_mem` to `dst` in `dst_mem`, the
+/// ranges can overlap when both are the same memory.
+pub fn copy(memories: &mut [Memory], dst_mem: u32, dst: u64, src_mem: u32, src: u64, len: u64) -> Trap<()> {
+  let (dst_mem, src_mem) = (dst_mem as usize, src_mem as usize);
+  let src = bulk_range(memories[src_mem].len(), src, len)?;
+  let dst = bulk_range(memories[dst_mem].len(), dst, len)?;
+  if dst_mem == src_mem {
+    if let Memory::Local(mem) = &mut memories[dst_mem] {
+      mem.copy_within(src, dst.start);
+      return Ok(());
+    }
+  } else {
+    let (head, tail) = memories.split_at_mut(dst_mem.max(src_mem));
+    let (dst_ref, src_ref) = if dst_mem < src_mem {
+      (&mut head[dst_mem], &tail[0])
+    } else {
+      (&mut tail[0], &head[src_mem])
+    };
+    if let (Memory::Local(dst_bytes), Memory::Local(src_bytes)) = (dst_ref, src_ref) {
+      dst_bytes[dst].copy_from_slice(&src_bytes[src]);
+      return Ok(());
+    }
+  }
+  // Shared memories are copied through a buffer.
+  let mut buf = vec![0; src.len()];
+  memories[src_mem].read(src.start as u64, &mut buf)?;
+  memories[dst_mem].write(dst.start as u64, &buf)
+}
+
+/// Set `len` bytes at `dst` to `val`.
+pub fn fill(mem: &mut [u8], dst: u64, val: u8, len: u64) -> Trap<()> {
+  let dst = bulk_range(mem.len(), dst, len)?;
+  mem[dst].fill(val);
+  Ok(())
+}
+
+/// Copy `len` bytes at `src` in a data segment to `dst`.
+pub fn init(mem: &mut [u8], dst: u64, data: &[u8], src: u64, len: u64) -> Trap<()> {
+  let src = bulk_range(data.len(), src, len)?;
+  let dst = bulk_range(mem.len(), dst, len)?;
+  mem[dst].copy_from_slice(&data[src]);
+  Ok(())
+}
+
+/// Get a slice of memory, used by host functions to access buffers.
+pub fn slice(mem: &[u8], addr: u32, len: u32) -> Trap<&[u8]> {
+  let start = addr as usize;
+  start.checked_add(len as usize)
+    .and_then(|end| mem.get(start..end))
+    .ok_or(TrapKind::MemoryAccessOutOfBounds)
+}
+
+/// Get a mutable slice of memory, used by host functions to access buffers.
+pub fn slice_mut(mem: &mut [u8], addr: u32, len: u32) -> Trap<&mut [u8]> {
+  let start = addr as usize;
+  start.checked_add(len as usize)
+    .and_then(move |end| mem.get_mut(start..end))
+    .ok_or(TrapKind::MemoryAccessOutOfBounds)
+}
+
+/// Get the size in bytes of `pages` pages, if it can be allocated.
+pub fn pages_to_bytes(pages: u64) -> Option<usize> {
+  pages.checked_mul(crate::loader::PAGE_SIZE as u64)
+    .and_then(|len| usize::try_from(len).ok())
+}
+
+/// Grow memory by `delta` pages.  Returns the old size in pages or -1 on failure.
+pub fn grow(mem: &mut Vec<u8>, max_pages: u64, delta: u64) -> i64 {
+  let old_pages = (mem.len() / crate::loader::PAGE_SIZE as usize) as u64;
+  let len = old_pages.checked_add(delta)
+    .filter(|new_pages| *new_pages <= max_pages)
+    .and_then(pages_to_bytes);
+  match len {
+    // Fail the grow, instead of aborting, when the allocation fails.
+    Some(len) if mem.try_reserve_exact(len - mem.len()).is_ok() => {
+      mem.resize(len, 0);
+      old_pages as i64
+    },
+    _ => -1,
+  }
+}
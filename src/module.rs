@@ -1,43 +1,394 @@
+
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use crate::*;
+use crate::compiler::Compiler;
+use crate::error::*;
 
-#[derive(Debug, Default)]
-pub struct ModuleInstance {
-  //types: Vec<FuncType>,
-  funcs: Vec<FuncAddr>,
-  //globals: Vec<Value>,
+/// A function imported by a module.
+#[derive(Debug, Clone)]
+pub struct FuncImport {
+  pub module: String,
+  pub field: String,
+  pub func_type: FunctionType,
+}
+
+/// An exception tag imported by a module.
+#[derive(Debug, Clone)]
+pub struct TagImport {
+  pub module: String,
+  pub field: String,
+  pub params: Vec<ValueType>,
+}
+
+/// A memory imported by a module.
+#[derive(Debug, Clone)]
+pub struct MemoryImport {
+  pub module: String,
+  pub field: String,
+  pub memory_type: loader::MemoryType,
+}
+
+/// Compiled Module - Immutable.  Compile once and share it (using an `Arc`)
+/// between many instances.
+pub struct Module {
+  /// Module name from the name section.
+  name: Option<String>,
+  imports: Vec<FuncImport>,
+  funcs: Vec<Function>,
   exports: HashMap<String, FuncIdx>,
+  start: Option<FuncIdx>,
+  tables: Vec<loader::Table>,
+  table_inits: Vec<loader::TableInit>,
+  memory_imports: Vec<MemoryImport>,
+  /// The types of the memories defined by the module.
+  memories: Vec<loader::MemoryType>,
+  memory_inits: Vec<loader::MemoryInit>,
+  memory_exports: HashMap<String, u32>,
+  globals: Vec<loader::Global>,
+  /// The index of each global in `Store::globals`.
+  global_slots: Vec<u32>,
+  tag_imports: Vec<TagImport>,
+  /// The params of the tags defined by the module.
+  tags: Vec<Vec<ValueType>>,
+  tag_exports: HashMap<String, u32>,
 }
 
-impl ModuleInstance {
-  pub fn new() -> ModuleInstance {
-    Default::default()
+impl Module {
+  pub fn new(funcs: Vec<Function>, module: &loader::Module) -> Result<Module> {
+    let mut imports = Vec::new();
+    let mut memory_imports = Vec::new();
+    let mut tag_imports = Vec::new();
+    for import in module.imports().iter() {
+      match import.external() {
+        loader::External::Function(type_ref) => {
+          let func_type = module.types().get(*type_ref as usize)
+            .ok_or_else(|| Error::ValidationError(format!("Invalid type index: {}", type_ref)))?;
+          imports.push(FuncImport {
+            module: import.module().to_string(),
+            field: import.field().to_string(),
+            func_type: FunctionType::from(func_type),
+          });
+        },
+        loader::External::Memory(memory_type) => {
+          memory_imports.push(MemoryImport {
+            module: import.module().to_string(),
+            field: import.field().to_string(),
+            memory_type: *memory_type,
+          });
+        },
+        loader::External::Tag(type_ref) => {
+          let func_type = module.types().get(*type_ref as usize)
+            .ok_or_else(|| Error::ValidationError(format!("Invalid type index: {}", type_ref)))?;
+          tag_imports.push(TagImport {
+            module: import.module().to_string(),
+            field: import.field().to_string(),
+            params: ValueType::from_slice(func_type.params()),
+          });
+        },
+        _ => (),
+      }
+    }
+    let tags = module.tags().iter()
+      .filter(|tag| !tag.is_imported())
+      .map(|tag| ValueType::from_slice(tag.params()))
+      .collect();
+    let mut exports = HashMap::new();
+    let mut memory_exports = HashMap::new();
+    let mut tag_exports = HashMap::new();
+    for export in module.exports().iter() {
+      match export.internal() {
+        loader::Internal::Function(idx) if exports.insert(export.field().to_string(), *idx).is_some() => {
+          return Err(Error::FuncExists);
+        },
+        loader::Internal::Memory(idx) => {
+          memory_exports.insert(export.field().to_string(), *idx);
+        },
+        loader::Internal::Tag(idx) => {
+          tag_exports.insert(export.field().to_string(), *idx);
+        },
+        _ => (),
+      }
+    }
+    Ok(Module {
+      name: module.name().map(String::from),
+      imports,
+      funcs,
+      exports,
+      start: module.start_func(),
+      tables: module.tables().to_vec(),
+      table_inits: module.table_inits().to_vec(),
+      memory_imports,
+      memories: module.memories().iter()
+        .filter(|mem| !mem.is_imported())
+        .map(|mem| *mem.memory_type())
+        .collect(),
+      memory_inits: module.memory_inits().to_vec(),
+      memory_exports,
+      globals: module.globals().to_vec(),
+      global_slots: module.global_slots(),
+      tag_imports,
+      tags,
+      tag_exports,
+    })
   }
 
-  // Map function idx to address
-  pub fn add_function(&mut self, addr: FuncAddr) {
-    self.funcs.push(addr);
+  /// Compile a loaded module.
+  pub fn compile(module: &loader::Module) -> Result<Module> {
+    Compiler::new(module).compile()
   }
 
-  // Export a function
-  pub fn add_export(&mut self, name: &str, idx: FuncIdx) -> Result<()> {
-    let name = name.to_string();
-    if self.exports.contains_key(&name) {
-      Err(Error::FuncExists)
-    } else {
-      self.exports.insert(name, idx);
-      Ok(())
-    }
+  pub fn from_file(file: &str) -> Result<Module> {
+    Self::compile(&loader::Module::from_file(file)?)
   }
 
-  pub fn find_function(&self, name: &str) -> Result<FuncAddr> {
-    if let Some(idx) = self.exports.get(&name.to_string()) {
-      if let Some(func) = self.funcs.get(*idx as usize) {
-        return Ok(*func);
+  pub fn from_bytes(bytes: &[u8]) -> Result<Module> {
+    Self::compile(&loader::Module::from_bytes(bytes)?)
+  }
+
+  pub fn from_reader<R: std::io::Read>(reader: R) -> Result<Module> {
+    Self::compile(&loader::Module::from_reader(reader)?)
+  }
+
+  #[cfg(feature = "wat")]
+  pub fn from_wat(text: &str) -> Result<Module> {
+    Self::compile(&loader::Module::from_wat(text)?)
+  }
+
+  /// Compile an already parsed `parity_wasm` module, see
+  /// `loader::Module::from_parity`.
+  #[cfg(feature = "parity-wasm")]
+  pub fn from_parity(module: parity_wasm::elements::Module) -> Result<Module> {
+    Self::compile(&loader::Module::from_parity(module)?)
+  }
+
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_deref()
+  }
+
+  /// The functions imported by this module, they are at the start of
+  /// the function index space.
+  pub fn imports(&self) -> &[FuncImport] {
+    &self.imports
+  }
+
+  /// The compiled functions, without the imported functions.
+  pub fn functions(&self) -> &[Function] {
+    &self.funcs
+  }
+
+  /// Get a compiled function by its index in the module's function index space.
+  pub fn get_function(&self, idx: FuncIdx) -> Option<&Function> {
+    let idx = (idx as usize).checked_sub(self.imports.len())?;
+    self.funcs.get(idx)
+  }
+
+  /// The exception tags imported by this module, they are at the start of
+  /// the tag index space.
+  pub fn tag_imports(&self) -> &[TagImport] {
+    &self.tag_imports
+  }
+
+  /// The params of the tags defined by this module.
+  pub fn tags(&self) -> &[Vec<ValueType>] {
+    &self.tags
+  }
+
+  pub fn find_tag(&self, name: &str) -> Result<u32> {
+    self.tag_exports.get(name).copied()
+      .ok_or(Error::TagNotFound)
+  }
+
+  pub fn start_function(&self) -> Option<FuncIdx> {
+    self.start
+  }
+
+  /// The memories imported by this module, they are at the start of the
+  /// memory index space.
+  pub fn memory_imports(&self) -> &[MemoryImport] {
+    &self.memory_imports
+  }
+
+  /// The types of the memories defined by this module.
+  pub fn memories(&self) -> &[loader::MemoryType] {
+    &self.memories
+  }
+
+  pub fn find_memory(&self, name: &str) -> Result<MemIdx> {
+    self.memory_exports.get(name).copied()
+      .ok_or(Error::MemoryNotFound)
+  }
+
+  pub fn find_function(&self, name: &str) -> Result<FuncIdx> {
+    self.exports.get(name).copied()
+      .ok_or(Error::FuncNotFound)
+  }
+
+  /// Setup a new instance's memories and globals.  `memories` are the
+  /// types of all memories, with the imported memories resolved.  The
+  /// imported shared memories are used instead of allocating new ones.
+  pub fn instantiate(&self, store: &mut Store, memories: &[loader::MemoryType], shared_imports: &[Option<SharedMemory>]) -> Result<()> {
+    // Initialize globals.
+    store.globals.clear();
+    for global in self.globals.iter() {
+      if global.is_imported() {
+        return Err(Error::ValidationError(format!("Unsupported global import: {}", global.name())));
+      }
+      let val = self.eval_init_expr(store, global.init_expr())?;
+      if global.value_type() == loader::ValueType::V128 {
+        store.globals.extend_from_slice(&StackValue::split_v128(val));
+      } else {
+        store.globals.push(StackValue(val as u64));
+      }
+    }
+
+    // Allocate memories, each instance has its own imported memories.
+    store.memories = memories.iter().enumerate().map(|(idx, memory_type)| {
+      if let Some(Some(shared)) = shared_imports.get(idx) {
+        return Ok(memory::Memory::Shared(shared.clone()));
+      }
+      let pages = memory_type.limits().initial() as u64;
+      let len = Some(pages)
+        .filter(|pages| *pages <= store.memory_limit(idx as MemIdx))
+        .and_then(memory::pages_to_bytes)
+        .ok_or_else(|| Error::LinkError(format!("memory {} of {} pages is larger than the memory limit", idx, pages)))?;
+      Ok(if memory_type.is_shared() {
+        memory::Memory::Shared(SharedMemory::with_type(*memory_type))
+      } else {
+        memory::Memory::Local(vec![0; len])
+      })
+    }).collect::<Result<_>>()?;
+
+    // Allocate tables.
+    store.tables.clear();
+    for table in self.tables.iter() {
+      if table.is_imported() {
+        return Err(Error::ValidationError("Unsupported table import".to_string()));
+      }
+      store.tables.push(vec![None; table.limits().initial() as usize]);
+    }
+
+    // Copy active element segments into the table.  Active and declared
+    // segments are dropped after instantiation.
+    store.dropped_elems.clear();
+    for init in self.table_inits.iter() {
+      if let Some(offset) = init.offset() {
+        let offset = self.eval_init_expr(store, offset)?as u32;
+        let len = init.entries().len() as u32;
+        let table = &mut store.tables[init.index() as usize];
+        table::init(table, offset, init.entries(), 0, len)?;
+      }
+      store.dropped_elems.push(init.is_active() || init.is_declared());
+    }
+
+    // Copy active data segments into memory.
+    store.dropped_data.clear();
+    for init in self.memory_inits.iter() {
+      if let Some(offset) = init.offset() {
+        let is_64 = memories[init.index() as usize].is_64();
+        let offset = self.eval_init_expr(store, offset)? as u64 & memory::address_mask(is_64);
+        let len = init.data().len() as u64;
+        store.memories[init.index() as usize].init(offset, init.data(), 0, len)?;
       }
+      store.dropped_data.push(init.is_active());
+    }
+    Ok(())
+  }
+
+  /// The elements of an element segment, empty once it is dropped.
+  pub(crate) fn elem_segment(&self, store: &Store, idx: u32) -> &[TableElement] {
+    match self.table_inits.get(idx as usize) {
+      Some(init) if !store.dropped_elems[idx as usize] => init.entries(),
+      _ => &[],
+    }
+  }
+
+  /// The bytes of a data segment, empty once it is dropped.
+  pub(crate) fn data_segment(&self, store: &Store, idx: u32) -> &[u8] {
+    match self.memory_inits.get(idx as usize) {
+      Some(init) if !store.dropped_data[idx as usize] => init.data(),
+      _ => &[],
     }
-    Err(Error::FuncNotFound)
   }
+
+  /// Evaluate a constant expression, to the bits of its value.  Only a
+  /// `v128` uses the high 64 bits.
+  fn eval_init_expr(&self, store: &Store, expr: &loader::InitExpr) -> Result<u128> {
+    use loader::InitExpr::*;
+    Ok(match *expr {
+      Binop(ty, op, ref args) => {
+        let left = self.eval_init_expr(store, &args.0)? as i64;
+        let right = self.eval_init_expr(store, &args.1)? as i64;
+        let val = match op {
+          loader::ConstOp::Add => left.wrapping_add(right),
+          loader::ConstOp::Sub => left.wrapping_sub(right),
+          loader::ConstOp::Mul => left.wrapping_mul(right),
+        };
+        // The low 32 bits are the same for `i32` operands.
+        if ty == loader::ValueType::I32 { StackValue::from(val as i32).0 as u128 } else { val as u64 as u128 }
+      },
+      I32Const(val) => StackValue::from(val).0 as u128,
+      I64Const(val) => val as u64 as u128,
+      F32Const(val) => StackValue::from(val).0 as u128,
+      F64Const(val) => StackValue::from(val).0 as u128,
+      V128Const(val) => val,
+      RefNull(_) => StackValue::from_ref(None).0 as u128,
+      RefFunc(idx) => StackValue::from_ref(Some(idx)).0 as u128,
+      Global(idx) => {
+        let slot = *self.global_slots.get(idx as usize)
+          .ok_or_else(|| Error::ValidationError(format!("Invalid global index: {}", idx)))? as usize;
+        let low = store.globals[slot];
+        if self.globals[idx as usize].value_type() == loader::ValueType::V128 {
+          StackValue::join_v128(low, store.globals[slot + 1])
+        } else {
+          low.0 as u128
+        }
+      },
+    })
+  }
+}
+
+/// A module instance - The immutable linked `State` and its own mutable `Store`.
+pub struct ModuleInstance {
+  pub state: State,
+  pub store: Store,
 }
 
+impl ModuleInstance {
+  pub fn new(module: Arc<Module>) -> Result<ModuleInstance> {
+    Self::with_imports(module, &Imports::default())
+  }
+
+  pub fn with_imports(module: Arc<Module>, imports: &Imports) -> Result<ModuleInstance> {
+    let state = State::with_imports(module, imports)?;
+    let store = state.new_store()?;
+    Ok(ModuleInstance {
+      state,
+      store,
+    })
+  }
+
+  pub fn find_function(&self, name: &str) -> Result<FuncAddr> {
+    self.state.get_exported(name)
+  }
+
+  pub fn find_tag(&self, name: &str) -> Result<Tag> {
+    self.state.get_exported_tag(name)
+  }
+
+  /// Get an exported memory.
+  pub fn memory(&self, name: &str) -> Result<&[u8]> {
+    let idx = self.state.module().find_memory(name)?;
+    self.store.memory(idx).ok_or(Error::MemoryNotFound)
+  }
+
+  pub fn memory_mut(&mut self, name: &str) -> Result<&mut [u8]> {
+    let idx = self.state.module().find_memory(name)?;
+    self.store.memory_mut(idx).ok_or(Error::MemoryNotFound)
+  }
+
+  pub fn call(&mut self, func_addr: FuncAddr, params: &[Value]) -> Result<RetValue> {
+    self.state.call(&mut self.store, func_addr, params)
+  }
+}
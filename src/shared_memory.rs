@@ -0,0 +1,274 @@
+//! Linear memories shared between threads, from the threads proposal.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use crate::*;
+use crate::error::*;
+use crate::memory::{atomic_addr, bulk_range, mem_range};
+
+/// A thread blocked by `memory.atomic.wait`.
+#[derive(Default)]
+struct Waiter {
+  notified: Mutex<bool>,
+  cond: Condvar,
+}
+
+struct SharedData {
+  memory_type: loader::MemoryType,
+  /// The bytes of the memory, in little-endian order in each word.
+  words: RwLock<Vec<AtomicU64>>,
+  /// The threads waiting on each address, in the order they started waiting.
+  waiters: Mutex<HashMap<u64, VecDeque<Arc<Waiter>>>>,
+}
+
+/// A memory shared between stores, which can run on different threads.
+/// Non-atomic accesses can tear, like on a native shared memory.  The host
+/// creates shared memories to share them with modules as imports.
+#[derive(Clone)]
+pub struct SharedMemory(Arc<SharedData>);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Mask of the low `bytes` bytes of a word.
+fn width_mask(bytes: u32) -> u64 {
+  if bytes >= 8 { u64::MAX } else { (1 << (bytes * 8)) - 1 }
+}
+
+/// Read the bytes at `start` into `buf`.
+fn read_bytes(words: &[AtomicU64], start: usize, buf: &mut [u8]) {
+  let mut idx = 0;
+  while idx < buf.len() {
+    let pos = start + idx;
+    let shift = pos % 8;
+    let count = (8 - shift).min(buf.len() - idx);
+    let word = words[pos / 8].load(Ordering::Relaxed).to_le_bytes();
+    buf[idx..idx + count].copy_from_slice(&word[shift..shift + count]);
+    idx += count;
+  }
+}
+
+/// Write `len` bytes at `start`, `byte(idx)` is the value of the byte at
+/// `start + idx`.
+fn write_bytes(words: &[AtomicU64], start: usize, len: usize, byte: impl Fn(usize) -> u8) {
+  let mut idx = 0;
+  while idx < len {
+    let pos = start + idx;
+    let shift = pos % 8;
+    let count = (8 - shift).min(len - idx);
+    let word = &words[pos / 8];
+    let update = |old: u64| {
+      let mut bytes = old.to_le_bytes();
+      for (offset, val) in bytes[shift..shift + count].iter_mut().enumerate() {
+        *val = byte(idx + offset);
+      }
+      u64::from_le_bytes(bytes)
+    };
+    if count == 8 {
+      word.store(update(0), Ordering::Relaxed);
+    } else {
+      let _ = word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| Some(update(old)));
+    }
+    idx += count;
+  }
+}
+
+impl SharedMemory {
+  /// Create a shared memory of `initial` pages.
+  pub fn new(initial: u32, maximum: u32) -> SharedMemory {
+    Self::with_type(loader::MemoryType::new(initial, Some(maximum)).shared())
+  }
+
+  /// Create a shared 64-bit memory of `initial` pages.
+  pub fn new_64(initial: u32, maximum: u32) -> SharedMemory {
+    Self::with_type(loader::MemoryType::new_64(initial, Some(maximum)).shared())
+  }
+
+  pub(crate) fn with_type(memory_type: loader::MemoryType) -> SharedMemory {
+    let len = memory_type.limits().initial() as usize * loader::PAGE_SIZE as usize;
+    SharedMemory(Arc::new(SharedData {
+      memory_type,
+      words: RwLock::new((0..len / 8).map(|_| AtomicU64::new(0)).collect()),
+      waiters: Mutex::new(HashMap::new()),
+    }))
+  }
+
+  pub fn memory_type(&self) -> &loader::MemoryType {
+    &self.0.memory_type
+  }
+
+  fn words(&self) -> RwLockReadGuard<'_, Vec<AtomicU64>> {
+    self.0.words.read().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// The size in bytes.
+  pub fn len(&self) -> usize {
+    self.words().len() * 8
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Copy the bytes at `addr` into `buf`.
+  pub fn read(&self, addr: u64, buf: &mut [u8]) -> Trap<()> {
+    let words = self.words();
+    let range = bulk_range(words.len() * 8, addr, buf.len() as u64)?;
+    read_bytes(&words, range.start, buf);
+    Ok(())
+  }
+
+  /// Copy `bytes` to `addr`.
+  pub fn write(&self, addr: u64, bytes: &[u8]) -> Trap<()> {
+    let words = self.words();
+    let range = bulk_range(words.len() * 8, addr, bytes.len() as u64)?;
+    write_bytes(&words, range.start, bytes.len(), |idx| bytes[idx]);
+    Ok(())
+  }
+
+  /// Set `len` bytes at `dst` to `val`.
+  pub fn fill(&self, dst: u64, val: u8, len: u64) -> Trap<()> {
+    let words = self.words();
+    let range = bulk_range(words.len() * 8, dst, len)?;
+    write_bytes(&words, range.start, range.len(), |_| val);
+    Ok(())
+  }
+
+  pub(crate) fn load<const N: usize>(&self, addr: u64, offset: u64) -> Trap<[u8; N]> {
+    let words = self.words();
+    let range = mem_range::<N>(words.len() * 8, addr, offset)?;
+    let mut buf = [0u8; N];
+    read_bytes(&words, range.start, &mut buf);
+    Ok(buf)
+  }
+
+  pub(crate) fn store<const N: usize>(&self, addr: u64, offset: u64, bytes: [u8; N]) -> Trap<()> {
+    let words = self.words();
+    let range = mem_range::<N>(words.len() * 8, addr, offset)?;
+    write_bytes(&words, range.start, N, |idx| bytes[idx]);
+    Ok(())
+  }
+
+  /// Grow memory by `delta` pages.  Returns the old size in pages or -1 on
+  /// failure.  Other threads see the new size on their next access.
+  pub fn grow(&self, max_pages: u64, delta: u64) -> i64 {
+    let mut words = self.0.words.write().unwrap_or_else(PoisonError::into_inner);
+    let old_len = words.len();
+    let old_pages = (old_len * 8 / loader::PAGE_SIZE as usize) as u64;
+    let len = old_pages.checked_add(delta)
+      .filter(|new_pages| *new_pages <= max_pages)
+      .and_then(memory::pages_to_bytes);
+    match len {
+      // Fail the grow, instead of aborting, when the allocation fails.
+      Some(len) if words.try_reserve_exact(len / 8 - old_len).is_ok() => {
+        words.resize_with(len / 8, || AtomicU64::new(0));
+        old_pages as i64
+      },
+      _ => -1,
+    }
+  }
+
+  /// Atomically load `bytes` bytes, zero-extended.
+  pub fn atomic_load(&self, addr: u64, offset: u64, bytes: u32) -> Trap<u64> {
+    let words = self.words();
+    let ea = atomic_addr(words.len() * 8, addr, offset, bytes)?;
+    let val = words[ea / 8].load(Ordering::SeqCst);
+    Ok((val >> (ea % 8 * 8)) & width_mask(bytes))
+  }
+
+  /// Atomically replace the `bytes` bytes at the address with the value
+  /// returned by `op`, unless it returns `None`.  Returns the old value.
+  pub fn atomic_rmw<F>(&self, addr: u64, offset: u64, bytes: u32, op: F) -> Trap<u64>
+    where F: Fn(u64) -> Option<u64>
+  {
+    let words = self.words();
+    let ea = atomic_addr(words.len() * 8, addr, offset, bytes)?;
+    // Aligned accesses are inside one word.
+    let (shift, mask) = (ea % 8 * 8, width_mask(bytes));
+    let old = words[ea / 8].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |word| {
+      op((word >> shift) & mask).map(|new| (word & !(mask << shift)) | ((new & mask) << shift))
+    }).unwrap_or_else(|word| word);
+    Ok((old >> shift) & mask)
+  }
+
+  /// Wait for a `notify` if the `bytes` bytes at the address are equal to
+  /// `expected`, for at most `timeout` nanoseconds when it isn't negative.
+  /// Returns 0 when notified, 1 if the value isn't `expected` and 2 on a
+  /// timeout.
+  pub fn wait(&self, addr: u64, offset: u64, bytes: u32, expected: u64, timeout: i64) -> Trap<u32> {
+    let ea = atomic_addr(self.len(), addr, offset, bytes)? as u64;
+    let waiter = {
+      // Notifies are blocked until the waiter is queued.
+      let mut waiters = lock(&self.0.waiters);
+      if self.atomic_load(ea, 0, bytes)? != expected & width_mask(bytes) {
+        return Ok(1);
+      }
+      let waiter = Arc::new(Waiter::default());
+      waiters.entry(ea).or_default().push_back(waiter.clone());
+      waiter
+    };
+
+    let deadline = u64::try_from(timeout).ok()
+      .and_then(|timeout| Instant::now().checked_add(Duration::from_nanos(timeout)));
+    let mut notified = lock(&waiter.notified);
+    while !*notified {
+      notified = match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            break;
+          }
+          waiter.cond.wait_timeout(notified, deadline - now)
+            .unwrap_or_else(PoisonError::into_inner).0
+        },
+        None => waiter.cond.wait(notified).unwrap_or_else(PoisonError::into_inner),
+      };
+    }
+    if *notified {
+      return Ok(0);
+    }
+    drop(notified);
+
+    // Timed out, unless it was notified before it could leave the queue.
+    let mut waiters = lock(&self.0.waiters);
+    if *lock(&waiter.notified) {
+      return Ok(0);
+    }
+    if let Some(queue) = waiters.get_mut(&ea) {
+      queue.retain(|other| !Arc::ptr_eq(other, &waiter));
+      if queue.is_empty() {
+        waiters.remove(&ea);
+      }
+    }
+    Ok(2)
+  }
+
+  /// Wake up to `count` threads waiting on the address.  Returns the number
+  /// of threads woken up.
+  pub fn notify(&self, addr: u64, offset: u64, count: u32) -> Trap<u32> {
+    let ea = atomic_addr(self.len(), addr, offset, 4)? as u64;
+    let mut waiters = lock(&self.0.waiters);
+    let mut woken = 0;
+    if let Some(queue) = waiters.get_mut(&ea) {
+      while woken < count {
+        match queue.pop_front() {
+          Some(waiter) => {
+            *lock(&waiter.notified) = true;
+            waiter.cond.notify_one();
+            woken += 1;
+          },
+          None => break,
+        }
+      }
+      if queue.is_empty() {
+        waiters.remove(&ea);
+      }
+    }
+    Ok(woken)
+  }
+}
@@ -0,0 +1,511 @@
+//! Lane-wise arithmetic of the SIMD instructions, in portable safe Rust.
+//!
+//! A `v128` value is held in a `u128`, lane 0 is in the least significant
+//! bits.  The compiler looks up the function of each instruction here.
+
+use std::ops::{Add, Mul};
+
+use crate::isa::SimdInstruction;
+
+/// Conversion between a `v128` and its lanes.
+pub trait Lanes: Sized {
+  fn from_v128(val: u128) -> Self;
+  fn to_v128(self) -> u128;
+}
+
+macro_rules! impl_lanes {
+  ($($t:ty, $n:literal;)*) => {
+    $(
+      impl Lanes for [$t; $n] {
+        #[inline]
+        fn from_v128(val: u128) -> Self {
+          const SIZE: usize = 16 / $n;
+          let bytes = val.to_le_bytes();
+          std::array::from_fn(|i| {
+            let mut lane = [0u8; SIZE];
+            lane.copy_from_slice(&bytes[i * SIZE..(i + 1) * SIZE]);
+            <$t>::from_le_bytes(lane)
+          })
+        }
+
+        #[inline]
+        fn to_v128(self) -> u128 {
+          const SIZE: usize = 16 / $n;
+          let mut bytes = [0u8; 16];
+          for (i, lane) in self.iter().enumerate() {
+            bytes[i * SIZE..(i + 1) * SIZE].copy_from_slice(&lane.to_le_bytes());
+          }
+          u128::from_le_bytes(bytes)
+        }
+      }
+    )*
+  };
+}
+
+impl_lanes! {
+  i8, 16; u8, 16;
+  i16, 8; u16, 8;
+  i32, 4; u32, 4;
+  i64, 2; u64, 2;
+  f32, 4; f64, 2;
+}
+
+#[inline]
+fn lanes<T, const N: usize>(val: u128) -> [T; N]
+  where [T; N]: Lanes
+{
+  <[T; N]>::from_v128(val)
+}
+
+/// Apply `op` to each lane.
+#[inline]
+fn map<T, const N: usize>(val: u128, op: impl Fn(T) -> T) -> u128
+  where [T; N]: Lanes
+{
+  lanes::<T, N>(val).map(op).to_v128()
+}
+
+/// Apply `op` to each pair of lanes.
+#[inline]
+fn zip<T: Copy, const N: usize>(left: u128, right: u128, op: impl Fn(T, T) -> T) -> u128
+  where [T; N]: Lanes
+{
+  let (left, right) = (lanes::<T, N>(left), lanes::<T, N>(right));
+  std::array::from_fn::<T, N, _>(|i| op(left[i], right[i])).to_v128()
+}
+
+/// Compare each pair of lanes, true lanes have all bits set.
+#[inline]
+fn cmp<T: Copy, const N: usize>(left: u128, right: u128, op: impl Fn(T, T) -> bool) -> u128
+  where [T; N]: Lanes
+{
+  let (left, right) = (lanes::<T, N>(left), lanes::<T, N>(right));
+  let bits = 128 / N;
+  let ones = u128::MAX >> (128 - bits);
+  (0..N).filter(|&i| op(left[i], right[i]))
+    .fold(0, |res, i| res | ones << (i * bits))
+}
+
+/// Convert each lane to a lane of another shape, lanes past the input are zero.
+#[inline]
+fn convert<T: Copy, U: Default, const N: usize, const M: usize>(val: u128, op: impl Fn(T) -> U) -> u128
+  where [T; N]: Lanes, [U; M]: Lanes
+{
+  let val = lanes::<T, N>(val);
+  std::array::from_fn::<U, M, _>(|i| if i < N { op(val[i]) } else { U::default() }).to_v128()
+}
+
+/// Widen the low or high half of the lanes, starting at lane `at`.
+#[inline]
+pub fn extend<T: Copy, U: From<T>, const N: usize, const M: usize>(val: u128, at: usize) -> u128
+  where [T; N]: Lanes, [U; M]: Lanes
+{
+  let val = lanes::<T, N>(val);
+  std::array::from_fn::<U, M, _>(|i| U::from(val[at + i])).to_v128()
+}
+
+/// Multiply the widened low or high half of the lanes, starting at lane `at`.
+#[inline]
+fn extmul<T: Copy, U: From<T> + Mul<Output = U>, const N: usize, const M: usize>(left: u128, right: u128, at: usize) -> u128
+  where [T; N]: Lanes, [U; M]: Lanes
+{
+  let (left, right) = (lanes::<T, N>(left), lanes::<T, N>(right));
+  std::array::from_fn::<U, M, _>(|i| U::from(left[at + i]) * U::from(right[at + i])).to_v128()
+}
+
+/// Add the widened pairs of adjacent lanes.
+#[inline]
+fn extadd_pairwise<T: Copy, U: From<T> + Add<Output = U>, const N: usize, const M: usize>(val: u128) -> u128
+  where [T; N]: Lanes, [U; M]: Lanes
+{
+  let val = lanes::<T, N>(val);
+  std::array::from_fn::<U, M, _>(|i| U::from(val[2 * i]) + U::from(val[2 * i + 1])).to_v128()
+}
+
+/// Narrow the lanes of both operands with saturation.
+#[inline]
+fn narrow<T: Copy, U, const N: usize, const M: usize>(left: u128, right: u128, op: impl Fn(T) -> U) -> u128
+  where [T; N]: Lanes, [U; M]: Lanes
+{
+  let (left, right) = (lanes::<T, N>(left), lanes::<T, N>(right));
+  std::array::from_fn::<U, M, _>(|i| op(if i < N { left[i] } else { right[i - N] })).to_v128()
+}
+
+/// The sign bit of each lane.
+#[inline]
+fn bitmask<T: Copy + Default + PartialOrd, const N: usize>(val: u128) -> u128
+  where [T; N]: Lanes
+{
+  let val = lanes::<T, N>(val);
+  (0..N).filter(|&i| val[i] < T::default())
+    .fold(0, |res, i| res | 1 << i)
+}
+
+#[inline]
+fn all_true<T: Copy + Default + PartialEq, const N: usize>(val: u128) -> u128
+  where [T; N]: Lanes
+{
+  lanes::<T, N>(val).iter().all(|lane| *lane != T::default()) as u128
+}
+
+macro_rules! impl_float_min_max {
+  ($min:ident, $max:ident, $t:ty) => {
+    fn $min(left: $t, right: $t) -> $t {
+      if left.is_nan() || right.is_nan() {
+        <$t>::NAN
+      } else if left == right {
+        // `-0.0` is less than `0.0`.
+        <$t>::from_bits(left.to_bits() | right.to_bits())
+      } else {
+        left.min(right)
+      }
+    }
+
+    fn $max(left: $t, right: $t) -> $t {
+      if left.is_nan() || right.is_nan() {
+        <$t>::NAN
+      } else if left == right {
+        <$t>::from_bits(left.to_bits() & right.to_bits())
+      } else {
+        left.max(right)
+      }
+    }
+  };
+}
+
+impl_float_min_max!(f32_min, f32_max, f32);
+impl_float_min_max!(f64_min, f64_max, f64);
+
+/// The function of a `v128 -> v128` instruction.
+pub fn unop(op: &SimdInstruction) -> Option<fn(u128) -> u128> {
+  use SimdInstruction::*;
+  let op: fn(u128) -> u128 = match op {
+    V128Not => |v| !v,
+
+    I8x16Abs => |v| map::<i8, 16>(v, i8::wrapping_abs),
+    I8x16Neg => |v| map::<i8, 16>(v, i8::wrapping_neg),
+    I8x16Popcnt => |v| map::<u8, 16>(v, |a| a.count_ones() as u8),
+    I16x8Abs => |v| map::<i16, 8>(v, i16::wrapping_abs),
+    I16x8Neg => |v| map::<i16, 8>(v, i16::wrapping_neg),
+    I32x4Abs => |v| map::<i32, 4>(v, i32::wrapping_abs),
+    I32x4Neg => |v| map::<i32, 4>(v, i32::wrapping_neg),
+    I64x2Abs => |v| map::<i64, 2>(v, i64::wrapping_abs),
+    I64x2Neg => |v| map::<i64, 2>(v, i64::wrapping_neg),
+
+    F32x4Abs => |v| map::<f32, 4>(v, f32::abs),
+    F32x4Neg => |v| map::<f32, 4>(v, |a| -a),
+    F32x4Sqrt => |v| map::<f32, 4>(v, f32::sqrt),
+    F32x4Ceil => |v| map::<f32, 4>(v, f32::ceil),
+    F32x4Floor => |v| map::<f32, 4>(v, f32::floor),
+    F32x4Trunc => |v| map::<f32, 4>(v, f32::trunc),
+    F32x4Nearest => |v| map::<f32, 4>(v, f32::round_ties_even),
+    F64x2Abs => |v| map::<f64, 2>(v, f64::abs),
+    F64x2Neg => |v| map::<f64, 2>(v, |a| -a),
+    F64x2Sqrt => |v| map::<f64, 2>(v, f64::sqrt),
+    F64x2Ceil => |v| map::<f64, 2>(v, f64::ceil),
+    F64x2Floor => |v| map::<f64, 2>(v, f64::floor),
+    F64x2Trunc => |v| map::<f64, 2>(v, f64::trunc),
+    F64x2Nearest => |v| map::<f64, 2>(v, f64::round_ties_even),
+
+    I16x8ExtaddPairwiseI8x16S => extadd_pairwise::<i8, i16, 16, 8>,
+    I16x8ExtaddPairwiseI8x16U => extadd_pairwise::<u8, u16, 16, 8>,
+    I32x4ExtaddPairwiseI16x8S => extadd_pairwise::<i16, i32, 8, 4>,
+    I32x4ExtaddPairwiseI16x8U => extadd_pairwise::<u16, u32, 8, 4>,
+
+    I16x8ExtendLowI8x16S => |v| extend::<i8, i16, 16, 8>(v, 0),
+    I16x8ExtendHighI8x16S => |v| extend::<i8, i16, 16, 8>(v, 8),
+    I16x8ExtendLowI8x16U => |v| extend::<u8, u16, 16, 8>(v, 0),
+    I16x8ExtendHighI8x16U => |v| extend::<u8, u16, 16, 8>(v, 8),
+    I32x4ExtendLowI16x8S => |v| extend::<i16, i32, 8, 4>(v, 0),
+    I32x4ExtendHighI16x8S => |v| extend::<i16, i32, 8, 4>(v, 4),
+    I32x4ExtendLowI16x8U => |v| extend::<u16, u32, 8, 4>(v, 0),
+    I32x4ExtendHighI16x8U => |v| extend::<u16, u32, 8, 4>(v, 4),
+    I64x2ExtendLowI32x4S => |v| extend::<i32, i64, 4, 2>(v, 0),
+    I64x2ExtendHighI32x4S => |v| extend::<i32, i64, 4, 2>(v, 2),
+    I64x2ExtendLowI32x4U => |v| extend::<u32, u64, 4, 2>(v, 0),
+    I64x2ExtendHighI32x4U => |v| extend::<u32, u64, 4, 2>(v, 2),
+
+    // Float to int conversions saturate, like `as`.
+    I32x4TruncSatF32x4S => |v| convert::<f32, i32, 4, 4>(v, |a| a as i32),
+    I32x4TruncSatF32x4U => |v| convert::<f32, u32, 4, 4>(v, |a| a as u32),
+    I32x4TruncSatF64x2SZero => |v| convert::<f64, i32, 2, 4>(v, |a| a as i32),
+    I32x4TruncSatF64x2UZero => |v| convert::<f64, u32, 2, 4>(v, |a| a as u32),
+    F32x4ConvertI32x4S => |v| convert::<i32, f32, 4, 4>(v, |a| a as f32),
+    F32x4ConvertI32x4U => |v| convert::<u32, f32, 4, 4>(v, |a| a as f32),
+    F64x2ConvertLowI32x4S => |v| convert::<i32, f64, 4, 2>(v, |a| a as f64),
+    F64x2ConvertLowI32x4U => |v| convert::<u32, f64, 4, 2>(v, |a| a as f64),
+    F32x4DemoteF64x2Zero => |v| convert::<f64, f32, 2, 4>(v, |a| a as f32),
+    F64x2PromoteLowF32x4 => |v| convert::<f32, f64, 4, 2>(v, |a| a as f64),
+    _ => return None,
+  };
+  Some(op)
+}
+
+/// The function of a `v128 v128 -> v128` instruction.
+pub fn binop(op: &SimdInstruction) -> Option<fn(u128, u128) -> u128> {
+  use SimdInstruction::*;
+  let op: fn(u128, u128) -> u128 = match op {
+    V128And => |a, b| a & b,
+    V128AndNot => |a, b| a & !b,
+    V128Or => |a, b| a | b,
+    V128Xor => |a, b| a ^ b,
+
+    I8x16Swizzle => |a, s| {
+      let a = lanes::<u8, 16>(a);
+      map::<u8, 16>(s, |idx| a.get(idx as usize).copied().unwrap_or(0))
+    },
+    I8x16NarrowI16x8S => |a, b| narrow::<i16, i8, 8, 16>(a, b, |a| a.clamp(i8::MIN as i16, i8::MAX as i16) as i8),
+    I8x16NarrowI16x8U => |a, b| narrow::<i16, u8, 8, 16>(a, b, |a| a.clamp(0, u8::MAX as i16) as u8),
+    I16x8NarrowI32x4S => |a, b| narrow::<i32, i16, 4, 8>(a, b, |a| a.clamp(i16::MIN as i32, i16::MAX as i32) as i16),
+    I16x8NarrowI32x4U => |a, b| narrow::<i32, u16, 4, 8>(a, b, |a| a.clamp(0, u16::MAX as i32) as u16),
+
+    I8x16Eq => |a, b| cmp::<i8, 16>(a, b, |a, b| a == b),
+    I8x16Ne => |a, b| cmp::<i8, 16>(a, b, |a, b| a != b),
+    I8x16LtS => |a, b| cmp::<i8, 16>(a, b, |a, b| a < b),
+    I8x16LtU => |a, b| cmp::<u8, 16>(a, b, |a, b| a < b),
+    I8x16GtS => |a, b| cmp::<i8, 16>(a, b, |a, b| a > b),
+    I8x16GtU => |a, b| cmp::<u8, 16>(a, b, |a, b| a > b),
+    I8x16LeS => |a, b| cmp::<i8, 16>(a, b, |a, b| a <= b),
+    I8x16LeU => |a, b| cmp::<u8, 16>(a, b, |a, b| a <= b),
+    I8x16GeS => |a, b| cmp::<i8, 16>(a, b, |a, b| a >= b),
+    I8x16GeU => |a, b| cmp::<u8, 16>(a, b, |a, b| a >= b),
+    I16x8Eq => |a, b| cmp::<i16, 8>(a, b, |a, b| a == b),
+    I16x8Ne => |a, b| cmp::<i16, 8>(a, b, |a, b| a != b),
+    I16x8LtS => |a, b| cmp::<i16, 8>(a, b, |a, b| a < b),
+    I16x8LtU => |a, b| cmp::<u16, 8>(a, b, |a, b| a < b),
+    I16x8GtS => |a, b| cmp::<i16, 8>(a, b, |a, b| a > b),
+    I16x8GtU => |a, b| cmp::<u16, 8>(a, b, |a, b| a > b),
+    I16x8LeS => |a, b| cmp::<i16, 8>(a, b, |a, b| a <= b),
+    I16x8LeU => |a, b| cmp::<u16, 8>(a, b, |a, b| a <= b),
+    I16x8GeS => |a, b| cmp::<i16, 8>(a, b, |a, b| a >= b),
+    I16x8GeU => |a, b| cmp::<u16, 8>(a, b, |a, b| a >= b),
+    I32x4Eq => |a, b| cmp::<i32, 4>(a, b, |a, b| a == b),
+    I32x4Ne => |a, b| cmp::<i32, 4>(a, b, |a, b| a != b),
+    I32x4LtS => |a, b| cmp::<i32, 4>(a, b, |a, b| a < b),
+    I32x4LtU => |a, b| cmp::<u32, 4>(a, b, |a, b| a < b),
+    I32x4GtS => |a, b| cmp::<i32, 4>(a, b, |a, b| a > b),
+    I32x4GtU => |a, b| cmp::<u32, 4>(a, b, |a, b| a > b),
+    I32x4LeS => |a, b| cmp::<i32, 4>(a, b, |a, b| a <= b),
+    I32x4LeU => |a, b| cmp::<u32, 4>(a, b, |a, b| a <= b),
+    I32x4GeS => |a, b| cmp::<i32, 4>(a, b, |a, b| a >= b),
+    I32x4GeU => |a, b| cmp::<u32, 4>(a, b, |a, b| a >= b),
+    I64x2Eq => |a, b| cmp::<i64, 2>(a, b, |a, b| a == b),
+    I64x2Ne => |a, b| cmp::<i64, 2>(a, b, |a, b| a != b),
+    I64x2LtS => |a, b| cmp::<i64, 2>(a, b, |a, b| a < b),
+    I64x2GtS => |a, b| cmp::<i64, 2>(a, b, |a, b| a > b),
+    I64x2LeS => |a, b| cmp::<i64, 2>(a, b, |a, b| a <= b),
+    I64x2GeS => |a, b| cmp::<i64, 2>(a, b, |a, b| a >= b),
+    F32x4Eq => |a, b| cmp::<f32, 4>(a, b, |a, b| a == b),
+    F32x4Ne => |a, b| cmp::<f32, 4>(a, b, |a, b| a != b),
+    F32x4Lt => |a, b| cmp::<f32, 4>(a, b, |a, b| a < b),
+    F32x4Gt => |a, b| cmp::<f32, 4>(a, b, |a, b| a > b),
+    F32x4Le => |a, b| cmp::<f32, 4>(a, b, |a, b| a <= b),
+    F32x4Ge => |a, b| cmp::<f32, 4>(a, b, |a, b| a >= b),
+    F64x2Eq => |a, b| cmp::<f64, 2>(a, b, |a, b| a == b),
+    F64x2Ne => |a, b| cmp::<f64, 2>(a, b, |a, b| a != b),
+    F64x2Lt => |a, b| cmp::<f64, 2>(a, b, |a, b| a < b),
+    F64x2Gt => |a, b| cmp::<f64, 2>(a, b, |a, b| a > b),
+    F64x2Le => |a, b| cmp::<f64, 2>(a, b, |a, b| a <= b),
+    F64x2Ge => |a, b| cmp::<f64, 2>(a, b, |a, b| a >= b),
+
+    I8x16Add => |a, b| zip::<i8, 16>(a, b, i8::wrapping_add),
+    I8x16AddSatS => |a, b| zip::<i8, 16>(a, b, i8::saturating_add),
+    I8x16AddSatU => |a, b| zip::<u8, 16>(a, b, u8::saturating_add),
+    I8x16Sub => |a, b| zip::<i8, 16>(a, b, i8::wrapping_sub),
+    I8x16SubSatS => |a, b| zip::<i8, 16>(a, b, i8::saturating_sub),
+    I8x16SubSatU => |a, b| zip::<u8, 16>(a, b, u8::saturating_sub),
+    I8x16MinS => |a, b| zip::<i8, 16>(a, b, Ord::min),
+    I8x16MinU => |a, b| zip::<u8, 16>(a, b, Ord::min),
+    I8x16MaxS => |a, b| zip::<i8, 16>(a, b, Ord::max),
+    I8x16MaxU => |a, b| zip::<u8, 16>(a, b, Ord::max),
+    I8x16AvgrU => |a, b| zip::<u8, 16>(a, b, |a, b| ((a as u16 + b as u16 + 1) >> 1) as u8),
+
+    I16x8Add => |a, b| zip::<i16, 8>(a, b, i16::wrapping_add),
+    I16x8AddSatS => |a, b| zip::<i16, 8>(a, b, i16::saturating_add),
+    I16x8AddSatU => |a, b| zip::<u16, 8>(a, b, u16::saturating_add),
+    I16x8Sub => |a, b| zip::<i16, 8>(a, b, i16::wrapping_sub),
+    I16x8SubSatS => |a, b| zip::<i16, 8>(a, b, i16::saturating_sub),
+    I16x8SubSatU => |a, b| zip::<u16, 8>(a, b, u16::saturating_sub),
+    I16x8Mul => |a, b| zip::<i16, 8>(a, b, i16::wrapping_mul),
+    I16x8MinS => |a, b| zip::<i16, 8>(a, b, Ord::min),
+    I16x8MinU => |a, b| zip::<u16, 8>(a, b, Ord::min),
+    I16x8MaxS => |a, b| zip::<i16, 8>(a, b, Ord::max),
+    I16x8MaxU => |a, b| zip::<u16, 8>(a, b, Ord::max),
+    I16x8AvgrU => |a, b| zip::<u16, 8>(a, b, |a, b| ((a as u32 + b as u32 + 1) >> 1) as u16),
+    I16x8Q15mulrSatS => |a, b| zip::<i16, 8>(a, b, |a, b| {
+      ((a as i32 * b as i32 + 0x4000) >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }),
+    I16x8ExtmulLowI8x16S => |a, b| extmul::<i8, i16, 16, 8>(a, b, 0),
+    I16x8ExtmulHighI8x16S => |a, b| extmul::<i8, i16, 16, 8>(a, b, 8),
+    I16x8ExtmulLowI8x16U => |a, b| extmul::<u8, u16, 16, 8>(a, b, 0),
+    I16x8ExtmulHighI8x16U => |a, b| extmul::<u8, u16, 16, 8>(a, b, 8),
+
+    I32x4Add => |a, b| zip::<i32, 4>(a, b, i32::wrapping_add),
+    I32x4Sub => |a, b| zip::<i32, 4>(a, b, i32::wrapping_sub),
+    I32x4Mul => |a, b| zip::<i32, 4>(a, b, i32::wrapping_mul),
+    I32x4MinS => |a, b| zip::<i32, 4>(a, b, Ord::min),
+    I32x4MinU => |a, b| zip::<u32, 4>(a, b, Ord::min),
+    I32x4MaxS => |a, b| zip::<i32, 4>(a, b, Ord::max),
+    I32x4MaxU => |a, b| zip::<u32, 4>(a, b, Ord::max),
+    I32x4DotI16x8S => |a, b| {
+      let (a, b) = (lanes::<i16, 8>(a), lanes::<i16, 8>(b));
+      std::array::from_fn::<i32, 4, _>(|i| {
+        (a[2 * i] as i32 * b[2 * i] as i32).wrapping_add(a[2 * i + 1] as i32 * b[2 * i + 1] as i32)
+      }).to_v128()
+    },
+    I32x4ExtmulLowI16x8S => |a, b| extmul::<i16, i32, 8, 4>(a, b, 0),
+    I32x4ExtmulHighI16x8S => |a, b| extmul::<i16, i32, 8, 4>(a, b, 4),
+    I32x4ExtmulLowI16x8U => |a, b| extmul::<u16, u32, 8, 4>(a, b, 0),
+    I32x4ExtmulHighI16x8U => |a, b| extmul::<u16, u32, 8, 4>(a, b, 4),
+
+    I64x2Add => |a, b| zip::<i64, 2>(a, b, i64::wrapping_add),
+    I64x2Sub => |a, b| zip::<i64, 2>(a, b, i64::wrapping_sub),
+    I64x2Mul => |a, b| zip::<i64, 2>(a, b, i64::wrapping_mul),
+    I64x2ExtmulLowI32x4S => |a, b| extmul::<i32, i64, 4, 2>(a, b, 0),
+    I64x2ExtmulHighI32x4S => |a, b| extmul::<i32, i64, 4, 2>(a, b, 2),
+    I64x2ExtmulLowI32x4U => |a, b| extmul::<u32, u64, 4, 2>(a, b, 0),
+    I64x2ExtmulHighI32x4U => |a, b| extmul::<u32, u64, 4, 2>(a, b, 2),
+
+    F32x4Add => |a, b| zip::<f32, 4>(a, b, |a, b| a + b),
+    F32x4Sub => |a, b| zip::<f32, 4>(a, b, |a, b| a - b),
+    F32x4Mul => |a, b| zip::<f32, 4>(a, b, |a, b| a * b),
+    F32x4Div => |a, b| zip::<f32, 4>(a, b, |a, b| a / b),
+    F32x4Min => |a, b| zip::<f32, 4>(a, b, f32_min),
+    F32x4Max => |a, b| zip::<f32, 4>(a, b, f32_max),
+    F32x4Pmin => |a, b| zip::<f32, 4>(a, b, |a, b| if b < a { b } else { a }),
+    F32x4Pmax => |a, b| zip::<f32, 4>(a, b, |a, b| if a < b { b } else { a }),
+    F64x2Add => |a, b| zip::<f64, 2>(a, b, |a, b| a + b),
+    F64x2Sub => |a, b| zip::<f64, 2>(a, b, |a, b| a - b),
+    F64x2Mul => |a, b| zip::<f64, 2>(a, b, |a, b| a * b),
+    F64x2Div => |a, b| zip::<f64, 2>(a, b, |a, b| a / b),
+    F64x2Min => |a, b| zip::<f64, 2>(a, b, f64_min),
+    F64x2Max => |a, b| zip::<f64, 2>(a, b, f64_max),
+    F64x2Pmin => |a, b| zip::<f64, 2>(a, b, |a, b| if b < a { b } else { a }),
+    F64x2Pmax => |a, b| zip::<f64, 2>(a, b, |a, b| if a < b { b } else { a }),
+    _ => return None,
+  };
+  Some(op)
+}
+
+/// `v128.bitselect`: bits of `left` where `mask` is set, else of `right`.
+#[inline]
+pub fn bitselect(left: u128, right: u128, mask: u128) -> u128 {
+  (left & mask) | (right & !mask)
+}
+
+/// The function of a `v128 -> i32` instruction.
+pub fn test(op: &SimdInstruction) -> Option<fn(u128) -> u128> {
+  use SimdInstruction::*;
+  let op: fn(u128) -> u128 = match op {
+    V128AnyTrue => |v| (v != 0) as u128,
+    I8x16AllTrue => all_true::<i8, 16>,
+    I16x8AllTrue => all_true::<i16, 8>,
+    I32x4AllTrue => all_true::<i32, 4>,
+    I64x2AllTrue => all_true::<i64, 2>,
+    I8x16Bitmask => bitmask::<i8, 16>,
+    I16x8Bitmask => bitmask::<i16, 8>,
+    I32x4Bitmask => bitmask::<i32, 4>,
+    I64x2Bitmask => bitmask::<i64, 2>,
+    _ => return None,
+  };
+  Some(op)
+}
+
+/// The function of a `v128 i32 -> v128` shift, the shift count is modulo
+/// the lane width.
+pub fn shift(op: &SimdInstruction) -> Option<fn(u128, u32) -> u128> {
+  use SimdInstruction::*;
+  let op: fn(u128, u32) -> u128 = match op {
+    I8x16Shl => |v, s| map::<i8, 16>(v, |a| a.wrapping_shl(s)),
+    I8x16ShrS => |v, s| map::<i8, 16>(v, |a| a.wrapping_shr(s)),
+    I8x16ShrU => |v, s| map::<u8, 16>(v, |a| a.wrapping_shr(s)),
+    I16x8Shl => |v, s| map::<i16, 8>(v, |a| a.wrapping_shl(s)),
+    I16x8ShrS => |v, s| map::<i16, 8>(v, |a| a.wrapping_shr(s)),
+    I16x8ShrU => |v, s| map::<u16, 8>(v, |a| a.wrapping_shr(s)),
+    I32x4Shl => |v, s| map::<i32, 4>(v, |a| a.wrapping_shl(s)),
+    I32x4ShrS => |v, s| map::<i32, 4>(v, |a| a.wrapping_shr(s)),
+    I32x4ShrU => |v, s| map::<u32, 4>(v, |a| a.wrapping_shr(s)),
+    I64x2Shl => |v, s| map::<i64, 2>(v, |a| a.wrapping_shl(s)),
+    I64x2ShrS => |v, s| map::<i64, 2>(v, |a| a.wrapping_shr(s)),
+    I64x2ShrU => |v, s| map::<u64, 2>(v, |a| a.wrapping_shr(s)),
+    _ => return None,
+  };
+  Some(op)
+}
+
+/// The function of a `t -> v128` splat, from the bits of the scalar.
+pub fn splat(op: &SimdInstruction) -> Option<fn(u128) -> u128> {
+  use SimdInstruction::*;
+  let op: fn(u128) -> u128 = match op {
+    I8x16Splat => |x| [x as u8; 16].to_v128(),
+    I16x8Splat => |x| [x as u16; 8].to_v128(),
+    I32x4Splat | F32x4Splat => |x| [x as u32; 4].to_v128(),
+    I64x2Splat | F64x2Splat => |x| [x as u64; 2].to_v128(),
+    _ => return None,
+  };
+  Some(op)
+}
+
+/// Select bytes of the two operands by the lane indices.
+pub fn shuffle(left: u128, right: u128, lanes: [u8; 16]) -> u128 {
+  let (left, right) = (left.to_le_bytes(), right.to_le_bytes());
+  u128::from_le_bytes(lanes.map(|idx| {
+    let idx = idx as usize;
+    if idx < 16 { left[idx] } else { right[idx - 16] }
+  }))
+}
+
+/// The bits of lane `lane` of `width` bytes, zero extended.
+#[inline]
+pub fn extract_lane(val: u128, width: u32, lane: u8) -> u128 {
+  let bits = width * 8;
+  (val >> (lane as u32 * bits)) & (u128::MAX >> (128 - bits))
+}
+
+/// Replace lane `lane` of `width` bytes with the low bits of `x`.
+#[inline]
+pub fn replace_lane(val: u128, width: u32, lane: u8, x: u128) -> u128 {
+  let bits = width * 8;
+  let shift = lane as u32 * bits;
+  let mask = (u128::MAX >> (128 - bits)) << shift;
+  (val & !mask) | ((x << shift) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use SimdInstruction::*;
+
+  fn i32x4(lanes: [i32; 4]) -> u128 {
+    lanes.to_v128()
+  }
+
+  #[test]
+  fn lanes() {
+    let val = 0x0f0e0d0c_0b0a0908_07060504_03020100u128;
+    assert_eq!(<[u8; 16]>::from_v128(val)[1], 1);
+    assert_eq!(<[u32; 4]>::from_v128(val)[3], 0x0f0e0d0c);
+    assert_eq!(<[u16; 8]>::from_v128(val).to_v128(), val);
+    assert_eq!(extract_lane(val, 2, 7), 0x0f0e);
+    assert_eq!(replace_lane(val, 8, 0, u128::MAX), val | u64::MAX as u128);
+  }
+
+  #[test]
+  fn lanewise() {
+    let add = binop(&I32x4Add).unwrap();
+    assert_eq!(add(i32x4([1, -1, i32::MAX, 4]), i32x4([1, 1, 1, 4])), i32x4([2, 0, i32::MIN, 8]));
+    let lt = binop(&I32x4LtS).unwrap();
+    assert_eq!(lt(i32x4([1, 2, 3, 4]), i32x4([2, 2, 2, 2])), i32x4([-1, 0, 0, 0]));
+    let narrow = binop(&I8x16NarrowI16x8U).unwrap();
+    assert_eq!(narrow([-1i16, 300, 5, 0, 0, 0, 0, 0].to_v128(), 0), [0u8, 255, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0].to_v128());
+    let shl = shift(&I32x4Shl).unwrap();
+    assert_eq!(shl(i32x4([1, 2, 3, 4]), 33), i32x4([2, 4, 6, 8]));
+    let bitmask = test(&I32x4Bitmask).unwrap();
+    assert_eq!(bitmask(i32x4([-1, 0, -5, 3])), 0b0101);
+    let min = binop(&F32x4Min).unwrap();
+    let res = <[f32; 4]>::from_v128(min([0.0f32, 1.0, f32::NAN, -0.0].to_v128(), [-0.0f32, 2.0, 1.0, 0.0].to_v128()));
+    assert_eq!(res[0].to_bits(), (-0.0f32).to_bits());
+    assert_eq!(res[1], 1.0);
+    assert!(res[2].is_nan());
+  }
+}
@@ -4,13 +4,44 @@ use crate::value::*;
 
 pub const DEFAULT_STACK_LIMIT: usize = 1024 * 1024;
 pub const INIT_STACK_SIZE: usize = 1024;
+/// Maximum depth of nested function calls.
+pub const DEFAULT_CALL_LIMIT: usize = 1024;
+/// Maximum native stack, in bytes, used by nested function calls.  Each wasm
+/// call uses native stack, more for each block the call is nested in.  Room
+/// for `DEFAULT_CALL_LIMIT` calls outside of blocks, in unoptimized builds
+/// too.
+pub const DEFAULT_NATIVE_STACK_LIMIT: usize = 6 * 1024 * 1024;
+/// Native stack, in bytes, left above the limit for the innermost function
+/// and the host functions it calls.
+pub const NATIVE_STACK_RESERVE: usize = 1024 * 1024;
 
 #[derive(Debug)]
 pub struct StackPtr(pub usize);
 
+/// A value on the stack.  A `v128` takes two stack values, its low half
+/// first.
 #[derive(Debug, Clone, Copy)]
 pub struct StackValue(pub u64);
 
+/// Number of locals each function keeps in registers.
+pub const REGISTERS: usize = 4;
+
+/// The locals of a function kept out of the stack.  The compiler moves a
+/// function's hottest locals to the first `REGISTERS` local indices, local 0
+/// is always the first parameter.
+#[derive(Debug, Clone, Copy)]
+pub struct Registers(pub [StackValue; REGISTERS]);
+
+impl Registers {
+  /// Registers for a call, with the first parameter in local 0.
+  #[inline]
+  pub fn new(first: StackValue) -> Registers {
+    let mut regs = Registers([StackValue(0); REGISTERS]);
+    regs.0[0] = first;
+    regs
+  }
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Frame {
   /// Base Pointer - for params/locals.
@@ -26,6 +57,16 @@ pub struct Stack {
   frame: Frame,
   /// Maximum stack size.
   limit: usize,
+  /// Current depth of nested function calls.
+  depth: usize,
+  /// Maximum depth of nested function calls.
+  call_limit: usize,
+  /// Address of the native stack at the outermost call.
+  native_base: usize,
+  /// Maximum native stack used by nested function calls.
+  native_limit: usize,
+  /// The high half of a `v128` result, the low half is returned.
+  ret_high: StackValue,
 }
 
 impl Stack {
@@ -38,7 +79,102 @@ impl Stack {
       stack: Vec::with_capacity(INIT_STACK_SIZE),
       frame: Default::default(),
       limit,
+      depth: 0,
+      call_limit: DEFAULT_CALL_LIMIT,
+      native_base: 0,
+      native_limit: DEFAULT_NATIVE_STACK_LIMIT,
+      ret_high: StackValue(0),
+    }
+  }
+
+  /// Change the maximum depth of nested function calls.
+  pub fn set_call_limit(&mut self, call_limit: usize) {
+    self.call_limit = call_limit;
+  }
+
+  /// Change the maximum native stack, in bytes, used by nested function
+  /// calls.
+  pub fn set_native_stack_limit(&mut self, native_limit: usize) {
+    self.native_limit = native_limit;
+  }
+
+  /// Native stack, in bytes, the outermost call needs.
+  pub fn native_stack_size(&self) -> usize {
+    self.native_limit.saturating_add(NATIVE_STACK_RESERVE)
+  }
+
+  /// Enter a function call.  Traps when the call is nested too deep, or the
+  /// calls around it used up the native stack limit.
+  #[inline]
+  pub fn enter_call(&mut self) -> Trap<()> {
+    let sp = native_stack_pointer();
+    if self.depth == 0 {
+      self.native_base = sp;
+    }
+    if self.depth >= self.call_limit || self.native_base.abs_diff(sp) > self.native_limit {
+      return Err(TrapKind::CallStackExhausted { limit: self.depth });
     }
+    self.depth += 1;
+    Ok(())
+  }
+
+  /// Leave a function call.
+  #[inline]
+  pub fn leave_call(&mut self) {
+    self.depth -= 1;
+  }
+
+  /// Save the high half of a `v128` result, the function returns the low
+  /// half.
+  #[inline]
+  pub fn set_ret_high(&mut self, val: StackValue) {
+    self.ret_high = val;
+  }
+
+  /// The high half of the last `v128` result.
+  #[inline]
+  pub fn ret_high(&self) -> StackValue {
+    self.ret_high
+  }
+
+  /// Convert a result to the stack value to return, saving the high half
+  /// of a `v128`.
+  pub fn ret_value(&mut self, val: Value) -> StackValue {
+    match val {
+      Value::V128(v) => {
+        let [low, high] = StackValue::split_v128(v);
+        self.ret_high = high;
+        low
+      },
+      val => StackValue::from(val),
+    }
+  }
+
+  /// Convert a returned stack value to a typed value, with the high half
+  /// of a `v128`.
+  pub fn ret_to_value(&self, val: StackValue, val_type: ValueType) -> Value {
+    match val_type {
+      ValueType::V128 => Value::V128(StackValue::join_v128(val, self.ret_high)),
+      val_type => val.to_value(val_type),
+    }
+  }
+
+  /// Get the current frame.
+  pub fn frame(&self) -> Frame {
+    self.frame
+  }
+
+  /// Unwind the stack after a trap, dropping all values above `len`
+  /// and restoring the frame.
+  pub fn unwind(&mut self, len: usize, frame: Frame, depth: usize) {
+    self.stack.truncate(len);
+    self.frame = frame;
+    self.depth = depth;
+  }
+
+  /// Current depth of nested function calls.
+  pub fn call_depth(&self) -> usize {
+    self.depth
   }
 
   #[inline]
@@ -46,17 +182,34 @@ impl Stack {
     self.stack.len()
   }
 
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.stack.is_empty()
+  }
+
+  /// All values on the stack, of every frame.
+  pub(crate) fn values(&self) -> &[StackValue] {
+    &self.stack
+  }
+
   /// How many values are on the current frame
   pub fn frame_size(&self) -> usize {
     self.len() - self.frame.sbp
   }
 
+  fn overflow(&self, need: usize) -> TrapKind {
+    TrapKind::StackOverflow { limit: self.limit, len: self.len().saturating_add(need) }
+  }
+
+  fn underflow(&self, need: usize) -> TrapKind {
+    TrapKind::StackUnderflow { len: self.frame_size(), needed: need }
+  }
+
   fn check_overflow(&mut self, need: usize) -> Trap<usize> {
     let len = self.len();
     let space = self.limit - len;
     if space < need {
-      eprintln!("StackOverflow: limit={}, len={}", self.limit, len);
-      return Err(TrapKind::StackOverflow);
+      return Err(self.overflow(need));
     }
 
     // Return the current stack size.
@@ -69,36 +222,38 @@ impl Stack {
     // the new function's parameters.
     let cur_size = self.frame_size();
     if cur_size < params {
-      eprintln!("StackOverflow: frame size={}, needed params={}", cur_size, params);
-      return Err(TrapKind::StackOverflow);
+      return Err(TrapKind::StackUnderflow { len: cur_size, needed: params });
     }
 
     // save old frame
     let old_frame = self.frame;
 
-    // Include the params in the new stack frame.
+    // Include the params in the new stack frame, the operands start after
+    // the params and locals.
     let bp = self.len() - params;
     self.frame = Frame{
       bp,
-      sbp: bp + locals,
+      sbp: bp + params + locals,
     };
 
     if locals > 0 {
-      self.reserve_locals(locals);
+      if let Err(err) = self.reserve_locals(locals) {
+        self.frame = old_frame;
+        return Err(err);
+      }
     }
 
     Ok(old_frame)
   }
 
-  pub fn reserve_locals(&mut self, locals: usize) {
-    //eprintln!("reserve_locals: len={}, locals={}", self.stack.len(), locals);
+  pub fn reserve_locals(&mut self, locals: usize) -> Trap<()> {
+    self.check_overflow(locals)?;
     // Push initial value for locals.
     // TODO: Try improving initialization of locals.
     for _idx in 0..locals {
-      //eprintln!("Push local({})", _idx);
       self.stack.push(StackValue(0));
     }
-    //eprintln!("reserve_locals: end len={}, locals={}", self.stack.len(), locals);
+    Ok(())
   }
 
   /// Remove current stack frame and restore previous frame.
@@ -109,22 +264,103 @@ impl Stack {
     self.frame = old_frame;
   }
 
-  pub fn push_params(&mut self, params: &[Value]) -> Trap<usize> {
+  /// Remove current stack frame and restore previous frame, keeping the top
+  /// `count` values.  They are the parameters of a tail call.
+  pub fn pop_frame_keep(&mut self, old_frame: Frame, count: usize) {
+    let len = self.len();
+    let bp = self.frame.bp;
+    self.stack.copy_within(len - count..len, bp);
+    self.stack.truncate(bp + count);
+    self.frame = old_frame;
+  }
+
+  /// Move the parameters of a new frame to their registers.  `swaps` are
+  /// the pairs of locals that traded places, a hot local with one of the
+  /// first `REGISTERS` locals.  The first parameter is in register 0.
+  pub fn load_params(&mut self, regs: &mut Registers, params: usize, swaps: &[(LocalIdx, LocalIdx)]) {
+    let bp = self.frame.bp;
+    let first = regs.0[0];
+    for idx in 1..params.min(REGISTERS) {
+      regs.0[idx] = self.stack[bp + idx];
+    }
+    for &(reg, local) in swaps {
+      let (reg, local) = (reg as usize, local as usize);
+      let param = |idx: usize| match idx {
+        0 => first,
+        idx if idx < params => self.stack[bp + idx],
+        _ => StackValue(0),
+      };
+      let (reg_val, local_val) = (param(reg), param(local));
+      regs.0[reg] = local_val;
+      self.stack[bp + local] = reg_val;
+    }
+  }
+
+  pub fn push_params(&mut self, params: &[StackValue]) -> Trap<usize> {
     // Check for stackoverflow and get current stack size.
     let len = self.check_overflow(params.len())?;
 
-    for val in params {
-      self.stack.push(StackValue::from(*val));
-    }
+    self.stack.extend_from_slice(params);
     // return original stack size.
     Ok(len)
   }
 
+  /// Drop all values above `len`.
+  pub fn truncate(&mut self, len: usize) {
+    self.stack.truncate(len);
+  }
+
+  /// Pop the top `count` values from the current frame.
+  pub fn pop_values(&mut self, count: usize) -> Trap<Vec<StackValue>> {
+    if self.frame_size() < count {
+      return Err(self.underflow(count));
+    }
+    let at = self.len() - count;
+    Ok(self.stack.split_off(at))
+  }
+
+  /// Get an operand value saved in the current frame.
+  #[inline]
+  pub fn get_operand(&self, slot: u32) -> StackValue {
+    self.stack[self.frame.sbp + slot as usize]
+  }
+
+  /// Save an operand value in the current frame.
+  #[inline]
+  pub fn set_operand(&mut self, slot: u32, val: StackValue) -> Trap<()> {
+    let idx = self.frame.sbp + slot as usize;
+    if idx >= self.len() {
+      self.reserve_operands(idx + 1)?;
+    }
+    self.stack[idx] = val;
+    Ok(())
+  }
+
+  /// Save operand values in the current frame, starting at `slot`.
+  #[inline]
+  pub fn set_operands(&mut self, slot: u32, vals: &[StackValue]) -> Trap<()> {
+    let idx = self.frame.sbp + slot as usize;
+    let end = idx + vals.len();
+    if end > self.len() {
+      self.reserve_operands(end)?;
+    }
+    self.stack[idx..end].copy_from_slice(vals);
+    Ok(())
+  }
+
+  fn reserve_operands(&mut self, len: usize) -> Trap<()> {
+    if len > self.limit {
+      return Err(TrapKind::StackOverflow { limit: self.limit, len });
+    }
+    self.stack.resize(len, StackValue(0));
+    Ok(())
+  }
+
   pub fn drop_values(&mut self, count: u32) -> Trap<()> {
     let len = self.len();
     let new_len = len
       .checked_sub(count as usize)
-      .ok_or(TrapKind::StackOverflow)?;
+      .ok_or_else(|| self.underflow(count as usize))?;
     self.stack.truncate(new_len);
     Ok(())
   }
@@ -159,9 +395,9 @@ impl Stack {
   }
 
   #[inline]
-  pub fn set_local_val(&mut self, local: LocalIdx, val: StackValue, l0: &mut StackValue) {
-    if local == 0 {
-      *l0 = val;
+  pub fn set_local_val(&mut self, local: LocalIdx, val: StackValue, regs: &mut Registers) {
+    if (local as usize) < REGISTERS {
+      regs.0[local as usize] = val;
       return;
     }
     let idx = self.frame.bp + local as usize;
@@ -170,10 +406,9 @@ impl Stack {
   }
 
   #[inline]
-  pub fn get_local_val(&mut self, local: LocalIdx, l0: &mut StackValue) -> StackValue {
-    //eprintln!("get_local_val: idx={}, len={}", local, self.stack.len());
-    if local == 0 {
-      return *l0;
+  pub fn get_local_val(&mut self, local: LocalIdx, regs: &mut Registers) -> StackValue {
+    if (local as usize) < REGISTERS {
+      return regs.0[local as usize];
     }
     let idx = self.frame.bp + local as usize;
 
@@ -183,10 +418,8 @@ impl Stack {
   #[inline]
   pub fn push_val(&mut self, val: StackValue) -> Trap<()> {
     if self.len() >= self.limit {
-      eprintln!("StackOverflow: limit={}, len={}", self.limit, self.len());
-      return Err(TrapKind::StackOverflow);
+      return Err(self.overflow(1));
     }
-    //eprintln!("-- Push: {:?}", val);
     self.stack.push(val);
     Ok(())
   }
@@ -195,12 +428,8 @@ impl Stack {
   pub fn push_values(&mut self, vals: &[StackValue]) -> Trap<()> {
     // Check for stack overflow.
     self.len().checked_add(vals.len())
-      .filter(|l| l < &self.limit)
-      .ok_or_else(|| {
-        eprintln!("StackOverflow: limit={}, len={}", self.limit, self.len());
-        TrapKind::StackOverflow
-      })?;
-    //eprintln!("-- Push: {:?}", val);
+      .filter(|l| l <= &self.limit)
+      .ok_or_else(|| self.overflow(vals.len()))?;
     self.stack.extend_from_slice(vals);
     Ok(())
   }
@@ -211,18 +440,23 @@ impl Stack {
       ValueType::I64 => self.pop().map(Value::I64),
       ValueType::F32 => self.pop().map(Value::F32),
       ValueType::F64 => self.pop().map(Value::F64),
+      ValueType::V128 => {
+        let high = self.pop_val()?;
+        self.pop_val().map(|low| Value::V128(StackValue::join_v128(low, high)))
+      },
+      ValueType::FuncRef | ValueType::ExternRef | ValueType::ExnRef => self.pop_val().map(|val| val.to_value(val_type)),
     }
   }
 
   #[inline]
   pub fn pop_val(&mut self) -> Trap<StackValue> {
-    self.stack.pop().ok_or(TrapKind::StackOverflow)
+    self.stack.pop().ok_or_else(|| self.underflow(1))
   }
 
   #[inline]
   pub fn top_val(&mut self) -> Trap<StackValue> {
-    self.stack.last().map(|x| *x)
-      .ok_or(TrapKind::StackOverflow)
+    self.stack.last().copied()
+      .ok_or_else(|| self.underflow(1))
   }
 
   /// Apply a 'unop' to top value, replacing it with the results.
@@ -230,9 +464,10 @@ impl Stack {
   pub fn unop<F>(&mut self, op: F) -> Trap<()>
     where F: FnOnce(&mut StackValue) -> Trap<()>
   {
-    let mut val = self.stack.last_mut()
-      .ok_or(TrapKind::StackOverflow)?;
-    op(&mut val)
+    match self.stack.last_mut() {
+      Some(val) => op(val),
+      None => Err(self.underflow(1)),
+    }
   }
 
   /// Apply a `binop` to the top two values, replacing them with the results.
@@ -241,12 +476,20 @@ impl Stack {
     where F: FnOnce(&mut StackValue, StackValue) -> Trap<()>
   {
     let right = self.pop_val()?;
-    let mut left = self.stack.last_mut()
-      .ok_or(TrapKind::StackOverflow)?;
-    op(&mut left, right)
+    match self.stack.last_mut() {
+      Some(left) => op(left, right),
+      None => Err(self.underflow(1)),
+    }
   }
 }
 
+/// The current address of the native stack.
+#[inline(always)]
+fn native_stack_pointer() -> usize {
+  let marker = 0u8;
+  std::hint::black_box(&marker) as *const u8 as usize
+}
+
 impl Default for Stack {
   fn default() -> Stack {
     Self::new()
@@ -343,6 +586,71 @@ macro_rules! impl_stack_value_float {
 
 impl_stack_value_float!(f32, f64);
 
+impl StackValue {
+  /// Convert to a typed value.  A `v128` only gets the low half, see
+  /// `from_stack_values`.
+  pub fn to_value(self, val_type: ValueType) -> Value {
+    match val_type {
+      ValueType::I32 => Value::I32(self.0 as _),
+      ValueType::I64 => Value::I64(self.0 as _),
+      ValueType::F32 => Value::F32(f32::from_bits(self.0 as _)),
+      ValueType::F64 => Value::F64(f64::from_bits(self.0 as _)),
+      ValueType::V128 => Value::V128(self.0 as u128),
+      ValueType::FuncRef => Value::FuncRef(self.to_ref()),
+      ValueType::ExternRef => Value::ExternRef(self.to_ref().map(ExternRef)),
+      ValueType::ExnRef => Value::ExnRef(self.to_ref()),
+    }
+  }
+
+  /// Encode a reference, a function address or an extern handle.  Zero is
+  /// the null reference, so zeroed locals and globals are null.
+  pub fn from_ref(val: Option<u32>) -> StackValue {
+    StackValue(val.map_or(0, |val| val as u64 + 1))
+  }
+
+  pub fn to_ref(self) -> Option<u32> {
+    self.0.checked_sub(1).map(|val| val as u32)
+  }
+
+  /// Split a `v128` in its low and high halves.
+  #[inline]
+  pub fn split_v128(val: u128) -> [StackValue; 2] {
+    [StackValue(val as u64), StackValue((val >> 64) as u64)]
+  }
+
+  /// Join the low and high halves of a `v128`.
+  #[inline]
+  pub fn join_v128(low: StackValue, high: StackValue) -> u128 {
+    (high.0 as u128) << 64 | low.0 as u128
+  }
+}
+
+/// Convert values to stack values, a `v128` takes two.
+pub fn to_stack_values(vals: &[Value]) -> Vec<StackValue> {
+  let mut stack_vals = Vec::with_capacity(vals.len());
+  for val in vals {
+    match *val {
+      Value::V128(v) => stack_vals.extend_from_slice(&StackValue::split_v128(v)),
+      val => stack_vals.push(StackValue::from(val)),
+    }
+  }
+  stack_vals
+}
+
+/// Convert stack values to values of `val_types`, a `v128` takes two.
+pub fn from_stack_values(vals: &[StackValue], val_types: &[ValueType]) -> Vec<Value> {
+  let mut vals = vals.iter().copied();
+  let mut next = move || vals.next().unwrap_or(StackValue(0));
+  val_types.iter().map(|val_type| match val_type {
+    ValueType::V128 => {
+      let low = next();
+      Value::V128(StackValue::join_v128(low, next()))
+    },
+    val_type => next().to_value(*val_type),
+  }).collect()
+}
+
+/// A `v128` only gives its low half, see `to_stack_values`.
 impl From<Value> for StackValue {
   fn from(val: Value) -> StackValue {
     match val {
@@ -350,6 +658,10 @@ impl From<Value> for StackValue {
       Value::I64(v) => StackValue(v as _),
       Value::F32(v) => StackValue(v.to_bits() as _),
       Value::F64(v) => StackValue(v.to_bits() as _),
+      Value::V128(v) => StackValue(v as u64),
+      Value::FuncRef(v) => StackValue::from_ref(v),
+      Value::ExternRef(v) => StackValue::from_ref(v.map(|ExternRef(handle)| handle)),
+      Value::ExnRef(v) => StackValue::from_ref(v),
     }
   }
 }
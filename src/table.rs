@@ -0,0 +1,77 @@
+use crate::error::*;
+
+/// A table element: a function address in a `funcref` table or a handle in
+/// an `externref` table, `None` for null references.
+pub type TableElement = Option<u32>;
+
+/// Maximum number of elements in a table without a maximum size.
+pub const MAX_TABLE_SIZE: u32 = 10_000_000;
+
+/// Get the range of `len` elements at `start`.
+#[inline]
+fn range(table_len: usize, start: u32, len: u32) -> Trap<std::ops::Range<usize>> {
+  let end = start as u64 + len as u64;
+  if end > table_len as u64 {
+    return Err(TrapKind::TableAccessOutOfBounds);
+  }
+  Ok(start as usize..end as usize)
+}
+
+#[inline]
+pub fn get(table: &[TableElement], idx: u32) -> Trap<TableElement> {
+  table.get(idx as usize).copied()
+    .ok_or(TrapKind::TableAccessOutOfBounds)
+}
+
+#[inline]
+pub fn set(table: &mut [TableElement], idx: u32, val: TableElement) -> Trap<()> {
+  let elem = table.get_mut(idx as usize)
+    .ok_or(TrapKind::TableAccessOutOfBounds)?;
+  *elem = val;
+  Ok(())
+}
+
+/// Grow the table by `delta` elements set to `val`.  Returns the old size,
+/// or -1 if the table can't grow past `max`.
+pub fn grow(table: &mut Vec<TableElement>, max: u32, delta: u32, val: TableElement) -> i32 {
+  let old_len = table.len();
+  let new_len = old_len as u64 + delta as u64;
+  if new_len > max.min(MAX_TABLE_SIZE) as u64 {
+    return -1;
+  }
+  table.resize(new_len as usize, val);
+  old_len as i32
+}
+
+/// Set `len` elements at `dst` to `val`.
+pub fn fill(table: &mut [TableElement], dst: u32, val: TableElement, len: u32) -> Trap<()> {
+  let dst = range(table.len(), dst, len)?;
+  table[dst].fill(val);
+  Ok(())
+}
+
+/// Copy `len` elements from `src` in `src_table` to `dst` in `dst_table`,
+/// the ranges can overlap when both are the same table.
+pub fn copy(tables: &mut [Vec<TableElement>], dst_table: u32, dst: u32, src_table: u32, src: u32, len: u32) -> Trap<()> {
+  let (dst_table, src_table) = (dst_table as usize, src_table as usize);
+  let src = range(tables[src_table].len(), src, len)?;
+  let dst = range(tables[dst_table].len(), dst, len)?;
+  if dst_table == src_table {
+    tables[dst_table].copy_within(src, dst.start);
+  } else if dst_table < src_table {
+    let (head, tail) = tables.split_at_mut(src_table);
+    head[dst_table][dst].copy_from_slice(&tail[0][src]);
+  } else {
+    let (head, tail) = tables.split_at_mut(dst_table);
+    tail[0][dst].copy_from_slice(&head[src_table][src]);
+  }
+  Ok(())
+}
+
+/// Copy `len` elements at `src` in an element segment to `dst`.
+pub fn init(table: &mut [TableElement], dst: u32, elems: &[TableElement], src: u32, len: u32) -> Trap<()> {
+  let src = range(elems.len(), src, len)?;
+  let dst = range(table.len(), dst, len)?;
+  table[dst].copy_from_slice(&elems[src]);
+  Ok(())
+}
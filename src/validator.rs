@@ -0,0 +1,1029 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::compiler::MAX_BLOCK_DEPTH;
+use crate::memory::{MAX_PAGES, MAX_PAGES_64};
+use crate::error::*;
+use crate::loader::{
+  self, AtomicInstruction, BlockType, Catch, InitExpr, Instruction, Internal, MemArg, ResizableLimits,
+  SimdInstruction, SimdKind, TableElementType, ValueType,
+};
+
+/// Validate a loaded module before it is compiled.
+///
+/// Function bodies are type-checked following the validation algorithm
+/// from the spec.  Errors in a function name the function index and the
+/// index of the instruction in the function body.
+pub fn validate_module(module: &loader::Module) -> Result<()> {
+  for table in module.tables() {
+    validate_limits("table", table.limits(), u32::MAX)?;
+  }
+  for memory in module.memories() {
+    let max = if memory.is_64() { MAX_PAGES_64 } else { MAX_PAGES };
+    validate_limits("memory", memory.limits(), max)?;
+    if memory.memory_type().is_shared() && memory.limits().maximum().is_none() {
+      return Err(invalid("shared memory must have a maximum".to_string()));
+    }
+  }
+
+  for (idx, tag) in module.tags().iter().enumerate() {
+    if tag.func_type().return_type().is_some() {
+      return Err(invalid(format!("tag {}: type mismatch: a tag type can't have results", idx)));
+    }
+  }
+
+  for (idx, global) in module.globals().iter().enumerate() {
+    if global.is_imported() {
+      continue;
+    }
+    // Initializers can only read the globals defined before them.
+    let ty = init_expr_type(module, global.init_expr(), idx)?;
+    if ty != global.value_type() {
+      return Err(invalid(format!("global {}: type mismatch: expected {}, found {}",
+        idx, global.value_type(), ty)));
+    }
+  }
+
+  let refs = declared_refs(module);
+  for (idx, func) in module.functions().iter().enumerate() {
+    if func.is_imported() {
+      continue;
+    }
+    FuncValidator::new(module, &refs, idx as u32, func).validate(func.instructions())?;
+  }
+
+  if let Some(idx) = module.start_func() {
+    let func = module.get_func(idx)
+      .ok_or_else(|| invalid(format!("unknown start function {}", idx)))?;
+    if func.param_count() > 0 || func.return_type().is_some() {
+      return Err(invalid(format!("start function {} must have type [] -> []", idx)));
+    }
+  }
+
+  let mut names = HashSet::new();
+  for export in module.exports() {
+    if !names.insert(export.field()) {
+      return Err(invalid(format!("duplicate export '{}'", export.field())));
+    }
+    let (kind, idx, count) = match *export.internal() {
+      Internal::Function(idx) => ("function", idx, module.functions().len()),
+      Internal::Table(idx) => ("table", idx, module.tables().len()),
+      Internal::Memory(idx) => ("memory", idx, module.memories().len()),
+      Internal::Global(idx) => ("global", idx, module.globals().len()),
+      Internal::Tag(idx) => ("tag", idx, module.tags().len()),
+    };
+    if idx as usize >= count {
+      return Err(invalid(format!("export '{}': unknown {} {}", export.field(), kind, idx)));
+    }
+  }
+
+  let globals = module.globals().len();
+  for init in module.table_inits() {
+    if let Some(offset) = init.offset() {
+      let table = module.tables().get(init.index() as usize)
+        .ok_or_else(|| invalid(format!("elem segment: unknown table {}", init.index())))?;
+      if table.elem_type() != init.elem_type() {
+        return Err(invalid(format!("elem segment: type mismatch: expected {}, found {}",
+          table.elem_type().value_type(), init.elem_type().value_type())));
+      }
+      validate_offset("elem", module, offset, globals, ValueType::I32)?;
+    }
+    for idx in init.entries().iter().flatten() {
+      if init.elem_type() != TableElementType::AnyFunc {
+        return Err(invalid(format!("elem segment: type mismatch: expected {}, found funcref",
+          init.elem_type().value_type())));
+      }
+      if module.get_func(*idx).is_none() {
+        return Err(invalid(format!("elem segment: unknown function {}", idx)));
+      }
+    }
+  }
+  for init in module.memory_inits() {
+    if let Some(offset) = init.offset() {
+      let memory = module.memories().get(init.index() as usize)
+        .ok_or_else(|| invalid(format!("data segment: unknown memory {}", init.index())))?;
+      validate_offset("data", module, offset, globals, memory.memory_type().index_type())?;
+    }
+  }
+  if let Some(count) = module.data_count() {
+    if count as usize != module.memory_inits().len() {
+      return Err(invalid(format!("data count {} doesn't match the {} data segments",
+        count, module.memory_inits().len())));
+    }
+  }
+  Ok(())
+}
+
+fn invalid(msg: String) -> Error {
+  Error::ValidationError(msg)
+}
+
+/// The functions that `ref.func` can reference: the functions used by
+/// element segments, exports and global initializers.
+fn declared_refs(module: &loader::Module) -> HashSet<u32> {
+  let elems = module.table_inits().iter()
+    .flat_map(|init| init.entries().iter().flatten().copied());
+  let exports = module.exports().iter()
+    .filter_map(|export| match *export.internal() {
+      Internal::Function(idx) => Some(idx),
+      _ => None,
+    });
+  let globals = module.globals().iter()
+    .filter_map(|global| match *global.init_expr() {
+      InitExpr::RefFunc(idx) => Some(idx),
+      _ => None,
+    });
+  elems.chain(exports).chain(globals).collect()
+}
+
+fn validate_limits(kind: &str, limits: &ResizableLimits, max: u32) -> Result<()> {
+  if limits.initial() > max {
+    return Err(invalid(format!("{} size must be at most {}", kind, max)));
+  }
+  if let Some(maximum) = limits.maximum() {
+    if maximum > max {
+      return Err(invalid(format!("{} size must be at most {}", kind, max)));
+    }
+    if limits.initial() > maximum {
+      return Err(invalid(format!("{} maximum {} is less than the minimum {}",
+        kind, maximum, limits.initial())));
+    }
+  }
+  Ok(())
+}
+
+fn validate_offset(kind: &str, module: &loader::Module, offset: &InitExpr, globals: usize, expected: ValueType) -> Result<()> {
+  match init_expr_type(module, offset, globals)? {
+    ty if ty == expected => Ok(()),
+    ty => Err(invalid(format!("{} segment: type mismatch: expected {} offset, found {}", kind, expected, ty))),
+  }
+}
+
+/// The type of a constant expression, it can read the first `globals` globals.
+fn init_expr_type(module: &loader::Module, expr: &InitExpr, globals: usize) -> Result<ValueType> {
+  Ok(match *expr {
+    InitExpr::I32Const(_) => ValueType::I32,
+    InitExpr::I64Const(_) => ValueType::I64,
+    InitExpr::F32Const(_) => ValueType::F32,
+    InitExpr::F64Const(_) => ValueType::F64,
+    InitExpr::V128Const(_) => ValueType::V128,
+    InitExpr::Global(idx) => {
+      let global = module.globals().get(idx as usize)
+        .filter(|_| (idx as usize) < globals)
+        .ok_or_else(|| invalid(format!("unknown global {} in constant expression", idx)))?;
+      if global.is_mutable() {
+        return Err(invalid(format!("constant expression reads mutable global {}", idx)));
+      }
+      global.value_type()
+    },
+    InitExpr::RefNull(ty) => ty,
+    InitExpr::RefFunc(idx) => {
+      if module.get_func(idx).is_none() {
+        return Err(invalid(format!("unknown function {} in constant expression", idx)));
+      }
+      ValueType::FuncRef
+    },
+    InitExpr::Binop(ty, _, ref args) => {
+      for arg in [&args.0, &args.1] {
+        let arg_ty = init_expr_type(module, arg, globals)?;
+        if arg_ty != ty {
+          return Err(invalid(format!("type mismatch: expected {}, found {} in constant expression", ty, arg_ty)));
+        }
+      }
+      ty
+    },
+  })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameKind {
+  Block,
+  Loop,
+  If,
+  Else,
+  /// The body of a legacy `try` block, and its handlers.
+  Try,
+  Catch,
+  CatchAll,
+}
+
+/// A control frame, for each block being validated.
+struct Frame {
+  kind: FrameKind,
+  result: Option<ValueType>,
+  /// Height of the operand stack at the start of the block.
+  height: usize,
+  /// The rest of the block is unreachable, the operand stack is polymorphic.
+  unreachable: bool,
+}
+
+/// Type of a value on the operand stack, `None` is an unknown type.  Only
+/// unreachable code can have values with an unknown type.
+type Operand = Option<ValueType>;
+
+struct FuncValidator<'a> {
+  module: &'a loader::Module,
+  /// Functions declared for `ref.func`.
+  refs: &'a HashSet<u32>,
+  func_idx: u32,
+  pc: usize,
+  locals: Vec<ValueType>,
+  result: Option<ValueType>,
+  operands: Vec<Operand>,
+  frames: Vec<Frame>,
+}
+
+impl<'a> FuncValidator<'a> {
+  fn new(module: &'a loader::Module, refs: &'a HashSet<u32>, func_idx: u32, func: &loader::Function) -> Self {
+    Self {
+      module,
+      refs,
+      func_idx,
+      pc: 0,
+      locals: func.params().iter().chain(func.locals()).copied().collect(),
+      result: func.return_type(),
+      operands: vec![],
+      frames: vec![],
+    }
+  }
+
+  fn validate(mut self, code: &[Instruction]) -> Result<()> {
+    self.push_frame(FrameKind::Block, self.result)?;
+    for (pc, op) in code.iter().enumerate() {
+      self.pc = pc;
+      if self.frames.is_empty() {
+        return Err(self.error("instructions after the end of the function"));
+      }
+      self.step(op)?;
+    }
+    if !self.frames.is_empty() {
+      self.pc = code.len();
+      return Err(self.error("missing 'end' at the end of the function"));
+    }
+    Ok(())
+  }
+
+  fn error(&self, msg: impl fmt::Display) -> Error {
+    Error::ValidationError(format!("func[{}]:{}: {}", self.func_idx, self.pc, msg))
+  }
+
+  fn push(&mut self, ty: Operand) {
+    self.operands.push(ty);
+  }
+
+  /// Pop an operand, checking that it has the `expected` type.
+  fn pop(&mut self, expected: Operand) -> Result<Operand> {
+    let frame = self.frame(0)?;
+    if self.operands.len() == frame.height {
+      if frame.unreachable {
+        return Ok(expected);
+      }
+      return Err(match expected {
+        Some(ty) => self.error(format!("type mismatch: expected {}, but the operand stack is empty", ty)),
+        None => self.error("type mismatch: the operand stack is empty"),
+      });
+    }
+    match (self.operands.pop().unwrap_or(None), expected) {
+      (Some(actual), Some(expected)) if actual != expected => {
+        Err(self.error(format!("type mismatch: expected {}, found {}", expected, actual)))
+      },
+      (None, expected) => Ok(expected),
+      (actual, _) => Ok(actual),
+    }
+  }
+
+  fn pop_type(&mut self, ty: ValueType) -> Result<()> {
+    self.pop(Some(ty))?;
+    Ok(())
+  }
+
+  fn frame(&self, depth: u32) -> Result<&Frame> {
+    self.frames.len().checked_sub(depth as usize + 1)
+      .and_then(|idx| self.frames.get(idx))
+      .ok_or_else(|| self.error(format!("invalid branch depth: {}", depth)))
+  }
+
+  /// The type of the value passed by a branch to the block at `depth`.
+  fn label_type(&self, depth: u32) -> Result<Option<ValueType>> {
+    let frame = self.frame(depth)?;
+    Ok(match frame.kind {
+      // Branches to a loop restart it, without any values.
+      FrameKind::Loop => None,
+      _ => frame.result,
+    })
+  }
+
+  fn push_frame(&mut self, kind: FrameKind, result: Option<ValueType>) -> Result<()> {
+    if self.frames.len() >= MAX_BLOCK_DEPTH as usize {
+      return Err(self.error(format!("blocks are nested deeper than {}", MAX_BLOCK_DEPTH)));
+    }
+    self.frames.push(Frame {
+      kind,
+      result,
+      height: self.operands.len(),
+      unreachable: false,
+    });
+    Ok(())
+  }
+
+  fn pop_frame(&mut self) -> Result<Frame> {
+    let (result, height) = {
+      let frame = self.frame(0)?;
+      (frame.result, frame.height)
+    };
+    if let Some(ty) = result {
+      self.pop_type(ty)?;
+    }
+    if self.operands.len() != height {
+      return Err(self.error("type mismatch: values remaining on the operand stack at the end of the block"));
+    }
+    self.frames.pop()
+      .ok_or_else(|| self.error("unexpected end of block"))
+  }
+
+  /// The rest of the current block is unreachable.
+  fn set_unreachable(&mut self) {
+    if let Some(frame) = self.frames.last_mut() {
+      self.operands.truncate(frame.height);
+      frame.unreachable = true;
+    }
+  }
+
+  fn local(&self, idx: u32) -> Result<ValueType> {
+    self.locals.get(idx as usize).copied()
+      .ok_or_else(|| self.error(format!("unknown local {}", idx)))
+  }
+
+  fn global(&self, idx: u32) -> Result<&loader::Global> {
+    self.module.globals().get(idx as usize)
+      .ok_or_else(|| self.error(format!("unknown global {}", idx)))
+  }
+
+  fn tag(&self, idx: u32) -> Result<&'a loader::Tag> {
+    self.module.tags().get(idx as usize)
+      .ok_or_else(|| self.error(format!("unknown tag {}", idx)))
+  }
+
+  /// Check that a handler of a `try_table` passes the values expected by
+  /// its label: the values of the exception, then the `exnref`.
+  fn catch(&self, catch: &Catch) -> Result<()> {
+    let (tag, is_ref, depth) = match *catch {
+      Catch::Catch(tag, depth) => (Some(tag), false, depth),
+      Catch::CatchRef(tag, depth) => (Some(tag), true, depth),
+      Catch::CatchAll(depth) => (None, false, depth),
+      Catch::CatchAllRef(depth) => (None, true, depth),
+    };
+    let mut values = match tag {
+      Some(idx) => self.tag(idx)?.params().to_vec(),
+      None => vec![],
+    };
+    if is_ref {
+      values.push(ValueType::ExnRef);
+    }
+    if values[..] != *self.label_type(depth)?.as_slice() {
+      return Err(self.error(format!("type mismatch: the values of the exception don't match label {}", depth)));
+    }
+    Ok(())
+  }
+
+  /// Get the type of the addresses of a memory, `i64` for 64-bit memories.
+  fn memory_index_type(&self, idx: u32) -> Result<ValueType> {
+    self.module.memories().get(idx as usize)
+      .map(|memory| memory.memory_type().index_type())
+      .ok_or_else(|| self.error(format!("unknown memory {}", idx)))
+  }
+
+  /// Get the type of the elements of a table.
+  fn table_type(&self, idx: u32) -> Result<ValueType> {
+    self.module.tables().get(idx as usize)
+      .map(|table| table.elem_type().value_type())
+      .ok_or_else(|| self.error(format!("unknown table {}", idx)))
+  }
+
+  /// `memory.init` and `data.drop` need the data count section, so the code
+  /// can be validated before the data section.
+  fn require_data(&self, idx: u32) -> Result<()> {
+    let count = self.module.data_count()
+      .ok_or_else(|| self.error("data count section required"))?;
+    if idx >= count {
+      return Err(self.error(format!("unknown data segment {}", idx)));
+    }
+    Ok(())
+  }
+
+  /// Get the type of the elements of an element segment.
+  fn elem_type(&self, idx: u32) -> Result<ValueType> {
+    self.module.table_inits().get(idx as usize)
+      .map(|init| init.elem_type().value_type())
+      .ok_or_else(|| self.error(format!("unknown elem segment {}", idx)))
+  }
+
+  /// Check that a table or segment with elements of type `actual` can be
+  /// used where `expected` elements are needed.
+  fn check_elem_type(&self, expected: ValueType, actual: ValueType) -> Result<()> {
+    if expected != actual {
+      return Err(self.error(format!("type mismatch: expected {}, found {}", expected, actual)));
+    }
+    Ok(())
+  }
+
+  /// Pop the `i32` operands of a bulk table operation.
+  fn bulk_op(&mut self) -> Result<()> {
+    self.memory_op([ValueType::I32; 3])
+  }
+
+  /// Pop the operands of a bulk memory operation, `i64` for the addresses
+  /// of 64-bit memories.
+  fn memory_op(&mut self, types: [ValueType; 3]) -> Result<()> {
+    for ty in types.iter().rev() {
+      self.pop_type(*ty)?;
+    }
+    Ok(())
+  }
+
+  fn call(&mut self, params: &[ValueType], ret: Option<ValueType>) -> Result<()> {
+    for ty in params.iter().rev() {
+      self.pop_type(*ty)?;
+    }
+    if ret.is_some() {
+      self.push(ret);
+    }
+    Ok(())
+  }
+
+  /// A tail call returns the callee's result from the current function.
+  fn return_call(&mut self, params: &[ValueType], ret: Option<ValueType>) -> Result<()> {
+    if ret != self.result {
+      return Err(self.error("type mismatch: tail call result doesn't match the function's result"));
+    }
+    for ty in params.iter().rev() {
+      self.pop_type(*ty)?;
+    }
+    self.set_unreachable();
+    Ok(())
+  }
+
+  /// Check the memory, the offset and the alignment of a memory access of
+  /// `bytes`, the alignment is a log2.  Returns the type of the address.
+  fn check_memarg(&self, memarg: MemArg, bytes: u32) -> Result<ValueType> {
+    let index_type = self.memory_index_type(memarg.memory)?;
+    if index_type == ValueType::I32 && memarg.offset > u32::MAX as u64 {
+      return Err(self.error(format!("offset {} is out of range for a 32-bit memory", memarg.offset)));
+    }
+    let align = memarg.align;
+    if align >= 32 || (1u32 << align) > bytes {
+      return Err(self.error(format!("alignment 2**{} is larger than natural alignment {}", align, bytes)));
+    }
+    Ok(index_type)
+  }
+
+  fn load(&mut self, ty: ValueType, memarg: MemArg, bytes: u32) -> Result<()> {
+    let index_type = self.check_memarg(memarg, bytes)?;
+    self.pop_type(index_type)?;
+    self.push(Some(ty));
+    Ok(())
+  }
+
+  fn store(&mut self, ty: ValueType, memarg: MemArg, bytes: u32) -> Result<()> {
+    let index_type = self.check_memarg(memarg, bytes)?;
+    self.pop_type(ty)?;
+    self.pop_type(index_type)
+  }
+
+  fn unop(&mut self, ty: ValueType) -> Result<()> {
+    self.cvtop(ty, ty)
+  }
+
+  fn binop(&mut self, ty: ValueType) -> Result<()> {
+    self.pop_type(ty)?;
+    self.pop_type(ty)?;
+    self.push(Some(ty));
+    Ok(())
+  }
+
+  fn testop(&mut self, ty: ValueType) -> Result<()> {
+    self.cvtop(ty, ValueType::I32)
+  }
+
+  fn relop(&mut self, ty: ValueType) -> Result<()> {
+    self.pop_type(ty)?;
+    self.pop_type(ty)?;
+    self.push(Some(ValueType::I32));
+    Ok(())
+  }
+
+  fn cvtop(&mut self, from: ValueType, to: ValueType) -> Result<()> {
+    self.pop_type(from)?;
+    self.push(Some(to));
+    Ok(())
+  }
+
+  fn step(&mut self, op: &Instruction) -> Result<()> {
+    use Instruction::*;
+    use ValueType::*;
+    match op {
+      Unreachable => self.set_unreachable(),
+      Nop => (),
+      Block(block_type) => self.push_frame(FrameKind::Block, block_result(block_type))?,
+      Loop(block_type) => self.push_frame(FrameKind::Loop, block_result(block_type))?,
+      If(block_type) => {
+        self.pop_type(I32)?;
+        self.push_frame(FrameKind::If, block_result(block_type))?;
+      },
+      Else => {
+        let frame = self.pop_frame()?;
+        if frame.kind != FrameKind::If {
+          return Err(self.error("'else' without a matching 'if'"));
+        }
+        self.push_frame(FrameKind::Else, frame.result)?;
+      },
+      End => {
+        let frame = self.pop_frame()?;
+        if frame.kind == FrameKind::If && frame.result.is_some() {
+          return Err(self.error("type mismatch: 'if' without 'else' can't have a result"));
+        }
+        if let Some(ty) = frame.result {
+          self.push(Some(ty));
+        }
+      },
+      Br(depth) => {
+        if let Some(ty) = self.label_type(*depth)? {
+          self.pop_type(ty)?;
+        }
+        self.set_unreachable();
+      },
+      BrIf(depth) => {
+        self.pop_type(I32)?;
+        if let Some(ty) = self.label_type(*depth)? {
+          self.pop_type(ty)?;
+          self.push(Some(ty));
+        }
+      },
+      BrTable(br_table) => {
+        self.pop_type(I32)?;
+        let ty = self.label_type(br_table.default)?;
+        for depth in br_table.table.iter() {
+          if self.label_type(*depth)? != ty {
+            return Err(self.error("type mismatch: br_table targets have different types"));
+          }
+        }
+        if let Some(ty) = ty {
+          self.pop_type(ty)?;
+        }
+        self.set_unreachable();
+      },
+      Return => {
+        if let Some(ty) = self.result {
+          self.pop_type(ty)?;
+        }
+        self.set_unreachable();
+      },
+
+      TryTable(block_type, catches) => {
+        for catch in catches.iter() {
+          self.catch(catch)?;
+        }
+        self.push_frame(FrameKind::Block, block_result(block_type))?;
+      },
+      Throw(idx) => {
+        for ty in self.tag(*idx)?.params().iter().rev() {
+          self.pop_type(*ty)?;
+        }
+        self.set_unreachable();
+      },
+      ThrowRef => {
+        self.pop_type(ExnRef)?;
+        self.set_unreachable();
+      },
+      Try(block_type) => self.push_frame(FrameKind::Try, block_result(block_type))?,
+      Catch(idx) => {
+        let tag = self.tag(*idx)?;
+        let frame = self.pop_frame()?;
+        if !matches!(frame.kind, FrameKind::Try | FrameKind::Catch) {
+          return Err(self.error("'catch' without a matching 'try'"));
+        }
+        self.push_frame(FrameKind::Catch, frame.result)?;
+        for ty in tag.params() {
+          self.push(Some(*ty));
+        }
+      },
+      CatchAll => {
+        let frame = self.pop_frame()?;
+        if !matches!(frame.kind, FrameKind::Try | FrameKind::Catch) {
+          return Err(self.error("'catch_all' without a matching 'try'"));
+        }
+        self.push_frame(FrameKind::CatchAll, frame.result)?;
+      },
+      Rethrow(depth) => {
+        if !matches!(self.frame(*depth)?.kind, FrameKind::Catch | FrameKind::CatchAll) {
+          return Err(self.error(format!("invalid rethrow label {}, it isn't a 'catch' block", depth)));
+        }
+        self.set_unreachable();
+      },
+      Delegate(depth) => {
+        let frame = self.pop_frame()?;
+        if frame.kind != FrameKind::Try {
+          return Err(self.error("'delegate' without a matching 'try'"));
+        }
+        // The label is outside of the `try` block.
+        self.frame(*depth)?;
+        if let Some(ty) = frame.result {
+          self.push(Some(ty));
+        }
+      },
+
+      Call(func_idx) => {
+        let func = self.module.get_func(*func_idx)
+          .ok_or_else(|| self.error(format!("unknown function {}", func_idx)))?;
+        self.call(func.params(), func.return_type())?;
+      },
+      CallIndirect(type_idx, table_idx) => {
+        let elem_type = self.table_type(*table_idx)?;
+        self.check_elem_type(FuncRef, elem_type)?;
+        let func_type = self.module.types().get(*type_idx as usize)
+          .ok_or_else(|| self.error(format!("unknown type {}", type_idx)))?;
+        self.pop_type(I32)?;
+        self.call(func_type.params(), func_type.return_type())?;
+      },
+      ReturnCall(func_idx) => {
+        let func = self.module.get_func(*func_idx)
+          .ok_or_else(|| self.error(format!("unknown function {}", func_idx)))?;
+        self.return_call(func.params(), func.return_type())?;
+      },
+      ReturnCallIndirect(type_idx, table_idx) => {
+        let elem_type = self.table_type(*table_idx)?;
+        self.check_elem_type(FuncRef, elem_type)?;
+        let func_type = self.module.types().get(*type_idx as usize)
+          .ok_or_else(|| self.error(format!("unknown type {}", type_idx)))?;
+        self.pop_type(I32)?;
+        self.return_call(func_type.params(), func_type.return_type())?;
+      },
+
+      Drop => {
+        self.pop(None)?;
+      },
+      Select => {
+        self.pop_type(I32)?;
+        let ty = self.pop(None)?;
+        let ty = self.pop(ty)?.or(ty);
+        if ty.is_some_and(|ty| ty.is_ref()) {
+          return Err(self.error("type mismatch: select without a type needs numeric operands"));
+        }
+        self.push(ty);
+      },
+      TypedSelect(ty) => {
+        self.pop_type(I32)?;
+        self.pop_type(*ty)?;
+        self.pop_type(*ty)?;
+        self.push(Some(*ty));
+      },
+
+      GetLocal(idx) => {
+        let ty = self.local(*idx)?;
+        self.push(Some(ty));
+      },
+      SetLocal(idx) => {
+        let ty = self.local(*idx)?;
+        self.pop_type(ty)?;
+      },
+      TeeLocal(idx) => {
+        let ty = self.local(*idx)?;
+        self.pop_type(ty)?;
+        self.push(Some(ty));
+      },
+      GetGlobal(idx) => {
+        let ty = self.global(*idx)?.value_type();
+        self.push(Some(ty));
+      },
+      SetGlobal(idx) => {
+        let global = self.global(*idx)?;
+        if !global.is_mutable() {
+          return Err(self.error(format!("global {} is immutable", idx)));
+        }
+        let ty = global.value_type();
+        self.pop_type(ty)?;
+      },
+
+      I32Load(memarg) => self.load(I32, *memarg, 4)?,
+      I64Load(memarg) => self.load(I64, *memarg, 8)?,
+      F32Load(memarg) => self.load(F32, *memarg, 4)?,
+      F64Load(memarg) => self.load(F64, *memarg, 8)?,
+      I32Load8S(memarg) | I32Load8U(memarg) => self.load(I32, *memarg, 1)?,
+      I32Load16S(memarg) | I32Load16U(memarg) => self.load(I32, *memarg, 2)?,
+      I64Load8S(memarg) | I64Load8U(memarg) => self.load(I64, *memarg, 1)?,
+      I64Load16S(memarg) | I64Load16U(memarg) => self.load(I64, *memarg, 2)?,
+      I64Load32S(memarg) | I64Load32U(memarg) => self.load(I64, *memarg, 4)?,
+      I32Store(memarg) => self.store(I32, *memarg, 4)?,
+      I64Store(memarg) => self.store(I64, *memarg, 8)?,
+      F32Store(memarg) => self.store(F32, *memarg, 4)?,
+      F64Store(memarg) => self.store(F64, *memarg, 8)?,
+      I32Store8(memarg) => self.store(I32, *memarg, 1)?,
+      I32Store16(memarg) => self.store(I32, *memarg, 2)?,
+      I64Store8(memarg) => self.store(I64, *memarg, 1)?,
+      I64Store16(memarg) => self.store(I64, *memarg, 2)?,
+      I64Store32(memarg) => self.store(I64, *memarg, 4)?,
+
+      CurrentMemory(idx) => {
+        let index_type = self.memory_index_type(*idx)?;
+        self.push(Some(index_type));
+      },
+      GrowMemory(idx) => {
+        let index_type = self.memory_index_type(*idx)?;
+        self.unop(index_type)?;
+      },
+
+      I32Const(_) => self.push(Some(I32)),
+      I64Const(_) => self.push(Some(I64)),
+      F32Const(_) => self.push(Some(F32)),
+      F64Const(_) => self.push(Some(F64)),
+
+      I32Eqz => self.testop(I32)?,
+      I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU => {
+        self.relop(I32)?
+      },
+      I64Eqz => self.testop(I64)?,
+      I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU => {
+        self.relop(I64)?
+      },
+      F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge => self.relop(F32)?,
+      F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => self.relop(F64)?,
+
+      I32Clz | I32Ctz | I32Popcnt => self.unop(I32)?,
+      I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or | I32Xor |
+      I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr => self.binop(I32)?,
+      I64Clz | I64Ctz | I64Popcnt => self.unop(I64)?,
+      I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor |
+      I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr => self.binop(I64)?,
+      F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt => self.unop(F32)?,
+      F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => self.binop(F32)?,
+      F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => self.unop(F64)?,
+      F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => self.binop(F64)?,
+
+      I32WrapI64 => self.cvtop(I64, I32)?,
+      I32TruncSF32 | I32TruncUF32 => self.cvtop(F32, I32)?,
+      I32TruncSF64 | I32TruncUF64 => self.cvtop(F64, I32)?,
+      I64ExtendSI32 | I64ExtendUI32 => self.cvtop(I32, I64)?,
+      I64TruncSF32 | I64TruncUF32 => self.cvtop(F32, I64)?,
+      I64TruncSF64 | I64TruncUF64 => self.cvtop(F64, I64)?,
+      F32ConvertSI32 | F32ConvertUI32 => self.cvtop(I32, F32)?,
+      F32ConvertSI64 | F32ConvertUI64 => self.cvtop(I64, F32)?,
+      F32DemoteF64 => self.cvtop(F64, F32)?,
+      F64ConvertSI32 | F64ConvertUI32 => self.cvtop(I32, F64)?,
+      F64ConvertSI64 | F64ConvertUI64 => self.cvtop(I64, F64)?,
+      F64PromoteF32 => self.cvtop(F32, F64)?,
+      I32ReinterpretF32 => self.cvtop(F32, I32)?,
+      I64ReinterpretF64 => self.cvtop(F64, I64)?,
+      F32ReinterpretI32 => self.cvtop(I32, F32)?,
+      F64ReinterpretI64 => self.cvtop(I64, F64)?,
+
+      I32Extend8S | I32Extend16S => self.unop(I32)?,
+      I64Extend8S | I64Extend16S | I64Extend32S => self.unop(I64)?,
+
+      I32TruncSatSF32 | I32TruncSatUF32 => self.cvtop(F32, I32)?,
+      I32TruncSatSF64 | I32TruncSatUF64 => self.cvtop(F64, I32)?,
+      I64TruncSatSF32 | I64TruncSatUF32 => self.cvtop(F32, I64)?,
+      I64TruncSatSF64 | I64TruncSatUF64 => self.cvtop(F64, I64)?,
+
+      I64Add128 | I64Sub128 | I64MulWideS | I64MulWideU => {
+        let operands = if let I64Add128 | I64Sub128 = op { 4 } else { 2 };
+        for _ in 0..operands {
+          self.pop_type(I64)?;
+        }
+        self.push(Some(I64));
+        self.push(Some(I64));
+      },
+
+      MemoryInit(data_idx, mem_idx) => {
+        let index_type = self.memory_index_type(*mem_idx)?;
+        self.require_data(*data_idx)?;
+        self.memory_op([index_type, I32, I32])?;
+      },
+      DataDrop(idx) => self.require_data(*idx)?,
+      MemoryCopy(dst, src) => {
+        let dst_type = self.memory_index_type(*dst)?;
+        let src_type = self.memory_index_type(*src)?;
+        // The length is `i64` only when both memories are 64-bit.
+        let len_type = if dst_type == I64 && src_type == I64 { I64 } else { I32 };
+        self.memory_op([dst_type, src_type, len_type])?;
+      },
+      MemoryFill(idx) => {
+        let index_type = self.memory_index_type(*idx)?;
+        self.memory_op([index_type, I32, index_type])?;
+      },
+      TableInit(elem_idx, table_idx) => {
+        let table_type = self.table_type(*table_idx)?;
+        let elem_type = self.elem_type(*elem_idx)?;
+        self.check_elem_type(table_type, elem_type)?;
+        self.bulk_op()?;
+      },
+      ElemDrop(idx) => {
+        self.elem_type(*idx)?;
+      },
+      TableCopy(dst, src) => {
+        let dst_type = self.table_type(*dst)?;
+        let src_type = self.table_type(*src)?;
+        self.check_elem_type(dst_type, src_type)?;
+        self.bulk_op()?;
+      },
+
+      RefNull(ty) => self.push(Some(*ty)),
+      RefIsNull => {
+        if let Some(ty) = self.pop(None)? {
+          if !ty.is_ref() {
+            return Err(self.error(format!("type mismatch: expected a reference, found {}", ty)));
+          }
+        }
+        self.push(Some(I32));
+      },
+      RefFunc(idx) => {
+        if self.module.get_func(*idx).is_none() {
+          return Err(self.error(format!("unknown function {}", idx)));
+        }
+        if !self.refs.contains(idx) {
+          return Err(self.error(format!("undeclared function reference {}", idx)));
+        }
+        self.push(Some(FuncRef));
+      },
+      TableGet(idx) => {
+        let ty = self.table_type(*idx)?;
+        self.cvtop(I32, ty)?;
+      },
+      TableSet(idx) => {
+        let ty = self.table_type(*idx)?;
+        self.pop_type(ty)?;
+        self.pop_type(I32)?;
+      },
+      TableSize(idx) => {
+        self.table_type(*idx)?;
+        self.push(Some(I32));
+      },
+      TableGrow(idx) => {
+        let ty = self.table_type(*idx)?;
+        self.pop_type(I32)?;
+        self.pop_type(ty)?;
+        self.push(Some(I32));
+      },
+      TableFill(idx) => {
+        let ty = self.table_type(*idx)?;
+        self.pop_type(I32)?;
+        self.pop_type(ty)?;
+        self.pop_type(I32)?;
+      },
+
+      Simd(op) => self.simd(op)?,
+      Atomic(op) => self.atomic(op)?,
+    }
+    Ok(())
+  }
+
+  /// Check the lane index of a lane instruction for a shape of `lanes` lanes.
+  fn check_lane(&self, lane: u8, lanes: u8) -> Result<()> {
+    if lane >= lanes {
+      return Err(self.error(format!("invalid lane index {}", lane)));
+    }
+    Ok(())
+  }
+
+  fn extract_lane(&mut self, ty: ValueType, lane: u8, lanes: u8) -> Result<()> {
+    self.check_lane(lane, lanes)?;
+    self.cvtop(ValueType::V128, ty)
+  }
+
+  fn replace_lane(&mut self, ty: ValueType, lane: u8, lanes: u8) -> Result<()> {
+    self.check_lane(lane, lanes)?;
+    self.pop_type(ty)?;
+    self.unop(ValueType::V128)
+  }
+
+  /// Load or store `bytes` into lane `lane` of a vector.
+  fn lane_access(&mut self, memarg: MemArg, bytes: u32, lane: u8, is_store: bool) -> Result<()> {
+    let index_type = self.check_memarg(memarg, bytes)?;
+    self.check_lane(lane, (16 / bytes) as u8)?;
+    self.pop_type(ValueType::V128)?;
+    self.pop_type(index_type)?;
+    if !is_store {
+      self.push(Some(ValueType::V128));
+    }
+    Ok(())
+  }
+
+  fn simd(&mut self, op: &SimdInstruction) -> Result<()> {
+    use SimdInstruction::*;
+    use ValueType::*;
+    if let Some(kind) = op.kind() {
+      match kind {
+        SimdKind::Unop => self.unop(V128)?,
+        SimdKind::Binop => self.binop(V128)?,
+        SimdKind::Ternop => {
+          self.pop_type(V128)?;
+          self.binop(V128)?;
+        },
+        SimdKind::Test => self.testop(V128)?,
+        SimdKind::Shift => {
+          self.pop_type(I32)?;
+          self.unop(V128)?;
+        },
+        SimdKind::Splat(ty) => self.cvtop(ty, V128)?,
+      }
+      return Ok(());
+    }
+    match *op {
+      V128Load(memarg) => self.load(V128, memarg, 16)?,
+      V128Load8x8S(memarg) | V128Load8x8U(memarg) |
+      V128Load16x4S(memarg) | V128Load16x4U(memarg) |
+      V128Load32x2S(memarg) | V128Load32x2U(memarg) => self.load(V128, memarg, 8)?,
+      V128Load8Splat(memarg) => self.load(V128, memarg, 1)?,
+      V128Load16Splat(memarg) => self.load(V128, memarg, 2)?,
+      V128Load32Splat(memarg) | V128Load32Zero(memarg) => self.load(V128, memarg, 4)?,
+      V128Load64Splat(memarg) | V128Load64Zero(memarg) => self.load(V128, memarg, 8)?,
+      V128Store(memarg) => self.store(V128, memarg, 16)?,
+      V128Load8Lane(memarg, lane) => self.lane_access(memarg, 1, lane, false)?,
+      V128Load16Lane(memarg, lane) => self.lane_access(memarg, 2, lane, false)?,
+      V128Load32Lane(memarg, lane) => self.lane_access(memarg, 4, lane, false)?,
+      V128Load64Lane(memarg, lane) => self.lane_access(memarg, 8, lane, false)?,
+      V128Store8Lane(memarg, lane) => self.lane_access(memarg, 1, lane, true)?,
+      V128Store16Lane(memarg, lane) => self.lane_access(memarg, 2, lane, true)?,
+      V128Store32Lane(memarg, lane) => self.lane_access(memarg, 4, lane, true)?,
+      V128Store64Lane(memarg, lane) => self.lane_access(memarg, 8, lane, true)?,
+
+      V128Const(_) => self.push(Some(V128)),
+      I8x16Shuffle(lanes) => {
+        if let Some(lane) = lanes.iter().find(|lane| **lane >= 32) {
+          return Err(self.error(format!("invalid lane index {}", lane)));
+        }
+        self.binop(V128)?;
+      },
+
+      I8x16ExtractLaneS(lane) | I8x16ExtractLaneU(lane) => self.extract_lane(I32, lane, 16)?,
+      I16x8ExtractLaneS(lane) | I16x8ExtractLaneU(lane) => self.extract_lane(I32, lane, 8)?,
+      I32x4ExtractLane(lane) => self.extract_lane(I32, lane, 4)?,
+      I64x2ExtractLane(lane) => self.extract_lane(I64, lane, 2)?,
+      F32x4ExtractLane(lane) => self.extract_lane(F32, lane, 4)?,
+      F64x2ExtractLane(lane) => self.extract_lane(F64, lane, 2)?,
+      I8x16ReplaceLane(lane) => self.replace_lane(I32, lane, 16)?,
+      I16x8ReplaceLane(lane) => self.replace_lane(I32, lane, 8)?,
+      I32x4ReplaceLane(lane) => self.replace_lane(I32, lane, 4)?,
+      I64x2ReplaceLane(lane) => self.replace_lane(I64, lane, 2)?,
+      F32x4ReplaceLane(lane) => self.replace_lane(F32, lane, 4)?,
+      F64x2ReplaceLane(lane) => self.replace_lane(F64, lane, 2)?,
+      // The instructions without immediates are handled by their kind.
+      _ => (),
+    }
+    Ok(())
+  }
+
+  /// Atomic accesses must be naturally aligned.
+  fn atomic(&mut self, op: &AtomicInstruction) -> Result<()> {
+    use AtomicInstruction::*;
+    use ValueType::*;
+    let index_type = match op.memarg() {
+      Some(memarg) => {
+        let index_type = self.check_memarg(memarg, op.bytes())?;
+        if 1u32 << memarg.align != op.bytes() {
+          return Err(self.error(format!("atomic alignment must be the natural alignment {}", op.bytes())));
+        }
+        index_type
+      },
+      None => I32,
+    };
+    // Pop the operands after the address, get the result type.
+    let ret = match *op {
+      MemoryAtomicNotify(_) => {
+        self.pop_type(I32)?;
+        Some(I32)
+      },
+      MemoryAtomicWait32(_) | MemoryAtomicWait64(_) => {
+        self.pop_type(I64)?;
+        self.pop_type(if op.bytes() == 8 { I64 } else { I32 })?;
+        Some(I32)
+      },
+      AtomicFence => return Ok(()),
+      Load(ty, ..) => Some(ty),
+      Store(ty, ..) => {
+        self.pop_type(ty)?;
+        None
+      },
+      Rmw(_, ty, ..) => {
+        self.pop_type(ty)?;
+        Some(ty)
+      },
+      Cmpxchg(ty, ..) => {
+        self.pop_type(ty)?;
+        self.pop_type(ty)?;
+        Some(ty)
+      },
+    };
+    self.pop_type(index_type)?;
+    if ret.is_some() {
+      self.push(ret);
+    }
+    Ok(())
+  }
+}
+
+fn block_result(block_type: &BlockType) -> Option<ValueType> {
+  match block_type {
+    BlockType::NoResult => None,
+    BlockType::Value(ty) => Some(*ty),
+  }
+}
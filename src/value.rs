@@ -1,19 +1,53 @@
 use std::fmt;
 
-#[derive(Debug, Clone, Copy)]
+use crate::loader;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValueType {
   I32,
   I64,
   F32,
   F64,
+  V128,
+  FuncRef,
+  ExternRef,
+  ExnRef,
 }
 
+/// An opaque reference passed by the host.  The guest can only store it
+/// and pass it back, the host decides what the handle refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExternRef(pub u32);
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
   I32(i32),
   I64(i64),
   F32(f32),
   F64(f64),
+  /// A SIMD vector, lane 0 is the least significant.
+  V128(u128),
+  /// A function reference, `None` is a null reference.
+  FuncRef(Option<FuncAddr>),
+  ExternRef(Option<ExternRef>),
+  /// A caught exception, by its handle in the `Store`.  `None` is a null
+  /// reference.
+  ExnRef(Option<u32>),
+}
+
+impl Value {
+  pub fn value_type(&self) -> ValueType {
+    match self {
+      Value::I32(_) => ValueType::I32,
+      Value::I64(_) => ValueType::I64,
+      Value::F32(_) => ValueType::F32,
+      Value::F64(_) => ValueType::F64,
+      Value::V128(_) => ValueType::V128,
+      Value::FuncRef(_) => ValueType::FuncRef,
+      Value::ExternRef(_) => ValueType::ExternRef,
+      Value::ExnRef(_) => ValueType::ExnRef,
+    }
+  }
 }
 
 impl fmt::Display for Value {
@@ -23,31 +57,54 @@ impl fmt::Display for Value {
       Value::I64(v) => write!(f, "{}", v),
       Value::F32(v) => write!(f, "{}", v),
       Value::F64(v) => write!(f, "{}", v),
+      Value::V128(v) => write!(f, "{:#034x}", v),
+      Value::FuncRef(Some(addr)) => write!(f, "ref.func {}", addr),
+      Value::ExternRef(Some(ExternRef(handle))) => write!(f, "ref.extern {}", handle),
+      Value::ExnRef(Some(handle)) => write!(f, "ref.exn {}", handle),
+      Value::FuncRef(None) | Value::ExternRef(None) | Value::ExnRef(None) => write!(f, "ref.null"),
     }
   }
 }
 
-impl From<bwasm::ValueType> for ValueType {
-  fn from(val_type: bwasm::ValueType) -> Self {
+impl From<loader::ValueType> for ValueType {
+  fn from(val_type: loader::ValueType) -> Self {
     match val_type {
-      bwasm::ValueType::I32 => ValueType::I32,
-      bwasm::ValueType::I64 => ValueType::I64,
-      bwasm::ValueType::F32 => ValueType::F32,
-      bwasm::ValueType::F64 => ValueType::F64,
+      loader::ValueType::I32 => ValueType::I32,
+      loader::ValueType::I64 => ValueType::I64,
+      loader::ValueType::F32 => ValueType::F32,
+      loader::ValueType::F64 => ValueType::F64,
+      loader::ValueType::V128 => ValueType::V128,
+      loader::ValueType::FuncRef => ValueType::FuncRef,
+      loader::ValueType::ExternRef => ValueType::ExternRef,
+      loader::ValueType::ExnRef => ValueType::ExnRef,
     }
   }
 }
 
-impl From<&bwasm::ValueType> for ValueType {
-  fn from(val_type: &bwasm::ValueType) -> Self {
+impl From<&loader::ValueType> for ValueType {
+  fn from(val_type: &loader::ValueType) -> Self {
     ValueType::from(*val_type)
   }
 }
 
 impl ValueType {
-  pub fn from_slice(val_types: &[bwasm::ValueType]) -> Vec<ValueType> {
+  pub fn from_slice(val_types: &[loader::ValueType]) -> Vec<ValueType> {
     val_types.iter().map(ValueType::from).collect()
   }
+
+  /// Number of stack values a value of this type takes, a `v128` is split
+  /// in its low and high halves.
+  pub fn slots(self) -> usize {
+    match self {
+      ValueType::V128 => 2,
+      _ => 1,
+    }
+  }
+
+  /// Number of stack values taken by values of `val_types`.
+  pub fn count_slots(val_types: &[loader::ValueType]) -> usize {
+    val_types.iter().map(|val_type| ValueType::from(val_type).slots()).sum()
+  }
 }
 
 pub type RetValue = Option<Value>;
@@ -71,7 +128,7 @@ pub type TableAddr = u32;
 pub type MemAddr = u32;
 pub type GlobalAddr = u32;
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct FunctionType {
   pub params: Vec<ValueType>,
   pub ret_type: Option<ValueType>,
@@ -82,19 +139,31 @@ impl FunctionType {
     Default::default()
   }
 
+  pub fn with_types(params: &[ValueType], ret_type: Option<ValueType>) -> FunctionType {
+    FunctionType {
+      params: params.to_vec(),
+      ret_type,
+    }
+  }
+
   pub fn param_count(&self) -> usize {
     self.params.len()
   }
+
+  /// Number of stack values of the parameters.
+  pub fn param_slots(&self) -> usize {
+    self.params.iter().map(|val_type| val_type.slots()).sum()
+  }
 }
 
-impl From<bwasm::FunctionType> for FunctionType {
-  fn from(func_type: bwasm::FunctionType) -> Self {
+impl From<loader::FunctionType> for FunctionType {
+  fn from(func_type: loader::FunctionType) -> Self {
     FunctionType::from(&func_type)
   }
 }
 
-impl From<&bwasm::FunctionType> for FunctionType {
-  fn from(func_type: &bwasm::FunctionType) -> Self {
+impl From<&loader::FunctionType> for FunctionType {
+  fn from(func_type: &loader::FunctionType) -> Self {
     FunctionType {
       params: ValueType::from_slice(func_type.params()),
       ret_type: func_type.return_type().map(ValueType::from),
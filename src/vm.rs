@@ -1,122 +1,392 @@
-use std::collections::HashMap;
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::convert::TryFrom;
+use std::sync::Arc;
 
 use crate::*;
-use crate::compiler::Compiler;
 use crate::error::*;
 
+/// Number of caught exceptions a store keeps before looking for unreferenced
+/// ones.
+const MIN_EXCEPTION_LIMIT: usize = 1024;
+
 /// VM Store - Mutable data
+///
+/// Nested calls trap with `CallStackExhausted` after `DEFAULT_CALL_LIMIT`
+/// calls, or once they used `DEFAULT_NATIVE_STACK_LIMIT` bytes of native
+/// stack, see `Stack::set_call_limit` and `Stack::set_native_stack_limit`.
+/// Calls outside of blocks reach the call limit first, in every build
+/// profile.  The outermost call runs on a new stack when the thread's stack
+/// has less than `Stack::native_stack_size` left, like the 2 MiB stacks of
+/// `std::thread::spawn`.
 #[derive(Default)]
 pub struct Store {
-  pub mem: Vec<u8>,
+  /// Linear memories, the imported memories first.
+  pub memories: Vec<memory::Memory>,
+  pub globals: Vec<StackValue>,
+  pub tables: Vec<Vec<TableElement>>,
   pub stack: Stack,
+  /// Data and element segments dropped by `data.drop` and `elem.drop`.
+  pub(crate) dropped_data: Vec<bool>,
+  pub(crate) dropped_elems: Vec<bool>,
+  /// Host data, used by host functions.  One value per type.
+  data: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+  /// Index of the instruction that raised the current trap.
+  trap_instr: Option<u32>,
+  /// Frames unwound by the current trap.
+  trap_frames: Vec<FrameInfo>,
+  /// Caught exceptions, referenced by `exnref` values by handle.
+  exceptions: HashMap<u32, Exception>,
+  /// The handle to give the next caught exception.
+  next_exception: u32,
+  /// Collect unreferenced exceptions once there are this many.
+  exception_limit: usize,
+  /// Exceptions handled by the legacy `catch` blocks being run, the
+  /// innermost last.  Used by `rethrow`.
+  pub(crate) caught: Vec<Exception>,
+  /// The handler the current exception was delegated to, by `delegate`.
+  delegate: Option<u32>,
+  /// Cap on the size of each memory in pages, `None` for the default.
+  memory_limit: Option<u64>,
+  /// Caps of single memories, by memory index.  They override `memory_limit`.
+  memory_limits: HashMap<MemIdx, u64>,
 }
 
-/// VM State - Immutable, only changes when loading a module.
-#[derive(Default)]
-pub struct State {
-  funcs: Vec<Function>,
-  // Loaded modules
-  module_instances: Vec<ModuleInstance>,
-  modules: HashMap<String, ModuleInstanceAddr>,
-}
+impl Store {
+  /// Add host data, replacing the old value of the same type.
+  pub fn insert_data<T: Any + Send + Sync>(&mut self, data: T) {
+    self.data.insert(TypeId::of::<T>(), Box::new(data));
+  }
 
-impl State {
-  pub fn new() -> State {
-    State {
-      ..Default::default()
+  pub fn get_data<T: Any + Send + Sync>(&self) -> Option<&T> {
+    self.data.get(&TypeId::of::<T>())
+      .and_then(|data| data.downcast_ref())
+  }
+
+  pub fn get_data_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+    self.data.get_mut(&TypeId::of::<T>())
+      .and_then(|data| data.downcast_mut())
+  }
+
+  pub fn remove_data<T: Any + Send + Sync>(&mut self) -> Option<T> {
+    self.data.remove(&TypeId::of::<T>())
+      .and_then(|data| data.downcast().ok())
+      .map(|data| *data)
+  }
+
+  /// Record the instruction that raised a trap.  Traps pass through the
+  /// enclosing instructions, the innermost one is kept.
+  pub(crate) fn trap_at(&mut self, instr_index: usize, trap: TrapKind) -> TrapKind {
+    self.trap_instr.get_or_insert(instr_index as u32);
+    trap
+  }
+
+  /// Add the frame of a function unwound by a trap.
+  pub(crate) fn push_trap_frame(&mut self, func: &Function) {
+    // Handlers are local to a function, a delegated exception that leaves
+    // the function can be caught by any handler of the caller.
+    self.delegate = None;
+    let instr_index = self.trap_instr.take();
+    self.trap_frames.push(FrameInfo {
+      module: None,
+      func_index: func.index,
+      func_name: func.debug_name.clone(),
+      instr_index: match func.body {
+        FunctionBody::Compiled(_) => instr_index,
+        FunctionBody::Host(_) => None,
+      },
+    });
+  }
+
+  /// Get a caught exception, by the handle of an `exnref`.  Exceptions the
+  /// store no longer references are dropped while catching others, a handle
+  /// returned to the host is valid until the next call.
+  pub fn exception(&self, handle: u32) -> Option<&Exception> {
+    self.exceptions.get(&handle)
+  }
+
+  /// Keep a caught exception for an `exnref`, returning its handle.
+  pub(crate) fn add_exception(&mut self, exn: Exception) -> u32 {
+    if self.exceptions.len() >= self.exception_limit {
+      self.collect_exceptions(&exn);
+      self.exception_limit = (self.exceptions.len() * 2).max(MIN_EXCEPTION_LIMIT);
+    }
+    loop {
+      let handle = self.next_exception;
+      self.next_exception = handle.wrapping_add(1);
+      if let Entry::Vacant(entry) = self.exceptions.entry(handle) {
+        entry.insert(exn);
+        return handle;
+      }
     }
   }
 
-  pub fn load_file(&mut self, name: &str, file: &str) -> Result<ModuleInstanceAddr> {
-    if self.modules.contains_key(name) {
-      return Err(Error::ModuleExists)
+  /// Drop the caught exceptions no `exnref` refers to, other than from
+  /// `exn`.  Values on the stack and in globals are not typed, any value
+  /// that could be a handle keeps its exception.  The compiler keeps
+  /// `exnref` locals out of registers, and spills pending operations before
+  /// calls, so that every `exnref` of the callers is on the stack.
+  fn collect_exceptions(&mut self, exn: &Exception) {
+    let mut pending: Vec<u32> = self.stack.values().iter().chain(&self.globals)
+      .filter_map(|val| u32::try_from(val.0.checked_sub(1)?).ok())
+      .collect();
+    pending.extend(self.caught.iter().chain(Some(exn)).flat_map(Exception::handles));
+    let mut live = HashSet::new();
+    while let Some(handle) = pending.pop() {
+      if let Some(exn) = self.exceptions.get(&handle) {
+        if live.insert(handle) {
+          pending.extend(exn.handles());
+        }
+      }
     }
-    // load new module from file.
-    let module = bwasm::Module::from_file(file)?;
+    self.exceptions.retain(|handle, _| live.contains(handle));
+  }
 
-    self.compile_module(name, &module)
+  #[cfg(all(test, feature = "wat"))]
+  pub(crate) fn exception_count(&self) -> usize {
+    self.exceptions.len()
   }
 
-  fn compile_module(&mut self, name: &str, module: &bwasm::Module) -> Result<ModuleInstanceAddr> {
-    let mod_addr = self.module_instances.len() as ModuleInstanceAddr;
-    // create new module instance.
-    let mut mod_inst = ModuleInstance::new();
-    // compile functions
-    let compiler = Compiler::new(module);
-    self.funcs = compiler.compile()?;
-    for addr in 0..self.funcs.len() {
-      mod_inst.add_function(addr as u32);
+  /// Check if the exception handler `id` can catch the current exception.
+  /// Handlers inside the handler an exception was delegated to are skipped.
+  pub(crate) fn can_catch(&mut self, id: u32) -> bool {
+    match self.delegate {
+      Some(target) if target != id => false,
+      _ => {
+        self.delegate = None;
+        true
+      },
     }
-    // load exports
-    for export in module.exports().into_iter() {
-      match export.internal() {
-        bwasm::Internal::Function(idx) => {
-          //eprintln!("-Export function '{}' at {}", export.field(), *idx);
-          mod_inst.add_export(export.field(), *idx)?;
-        },
-        _ => {
-          println!("Unhandled export: {:?}", export);
-        },
+  }
+
+  /// Delegate the current exception to the handler `id`.
+  pub(crate) fn delegate_to(&mut self, id: u32) {
+    self.delegate = Some(id);
+  }
+
+  /// The current exception was caught, forget the frames it unwound.
+  pub(crate) fn catch_exception(&mut self) {
+    self.trap_instr = None;
+    self.trap_frames.clear();
+  }
+
+  fn take_backtrace(&mut self) -> Backtrace {
+    self.trap_instr = None;
+    Backtrace::new(std::mem::take(&mut self.trap_frames))
+  }
+
+  /// Limit each memory to `pages` pages of 64 KiB, including its initial
+  /// size.  `memory.grow` fails past the limit.  Memories can't be larger
+  /// than their declared maximum, so the limit mostly caps 64-bit memories.
+  /// Memories with their own limit, see `set_memory_limit_of`, ignore it.
+  pub fn set_memory_limit(&mut self, pages: u64) {
+    self.memory_limit = Some(pages);
+  }
+
+  /// Limit the memory at index `idx` to `pages` pages of 64 KiB, in place of
+  /// the limit of all memories.
+  pub fn set_memory_limit_of(&mut self, idx: MemIdx, pages: u64) {
+    self.memory_limits.insert(idx, pages);
+  }
+
+  /// The limit of the memory at index `idx` in pages.
+  pub fn memory_limit(&self, idx: MemIdx) -> u64 {
+    self.memory_limits.get(&idx).copied()
+      .or(self.memory_limit)
+      .unwrap_or(memory::DEFAULT_MEMORY_LIMIT)
+  }
+
+  /// Get the bytes of a memory, `None` for shared memories.
+  pub fn memory(&self, idx: MemIdx) -> Option<&[u8]> {
+    self.memories.get(idx as usize).and_then(memory::Memory::as_slice)
+  }
+
+  pub fn memory_mut(&mut self, idx: MemIdx) -> Option<&mut [u8]> {
+    self.memories.get_mut(idx as usize).and_then(memory::Memory::as_mut_slice)
+  }
+
+  /// Get a shared memory, to share it with stores on other threads.
+  pub fn shared_memory(&self, idx: MemIdx) -> Option<&SharedMemory> {
+    match self.memories.get(idx as usize) {
+      Some(memory::Memory::Shared(memory)) => Some(memory),
+      _ => None,
+    }
+  }
+
+  /// Get both host data and memory 0, for host functions that need to
+  /// access the memory.  The memory is empty if the module doesn't have one,
+  /// or if it is shared.
+  pub fn data_and_mem_mut<T: Any + Send + Sync>(&mut self) -> Option<(&mut T, &mut [u8])> {
+    let data = self.data.get_mut(&TypeId::of::<T>())
+      .and_then(|data| data.downcast_mut())?;
+    let mem = self.memories.first_mut().and_then(memory::Memory::as_mut_slice).unwrap_or_default();
+    Some((data, mem))
+  }
+}
+
+/// VM State - Immutable.  The compiled module linked for execution.
+///
+/// `State` is `Send + Sync`, it can be shared (with an `Arc`) between threads,
+/// each thread using its own `Store`.
+pub struct State {
+  module: Arc<Module>,
+  /// Host functions resolved for the module's imports.
+  imports: Vec<Function>,
+  /// The types of the memories, the imported memories first.
+  memories: Vec<loader::MemoryType>,
+  /// The imported shared memories, by memory index.
+  shared_imports: Vec<Option<SharedMemory>>,
+  /// Exception tags, the imported tags first.
+  tags: Vec<Tag>,
+}
+
+impl State {
+  pub fn new(module: Arc<Module>) -> Result<State> {
+    Self::with_imports(module, &Imports::default())
+  }
+
+  /// Link the module's imports to host functions.
+  pub fn with_imports(module: Arc<Module>, imports: &Imports) -> Result<State> {
+    let funcs = module.imports().iter().enumerate().map(|(idx, import)| {
+      let mut func = imports.resolve(&import.module, &import.field, &import.func_type)?;
+      func.index = idx as FuncIdx;
+      Ok(func)
+    }).collect::<Result<Vec<_>>>()?;
+    let mut shared_imports = Vec::new();
+    let memories = module.memory_imports().iter().map(|import| {
+      if import.memory_type.is_shared() {
+        let memory = imports.resolve_shared_memory(&import.module, &import.field, &import.memory_type)?;
+        let memory_type = *memory.memory_type();
+        shared_imports.push(Some(memory));
+        Ok(memory_type)
+      } else {
+        shared_imports.push(None);
+        imports.resolve_memory(&import.module, &import.field, &import.memory_type)
       }
+    }).chain(module.memories().iter().copied().map(Ok)).collect::<Result<Vec<_>>>()?;
+    let imported_tags = module.tag_imports().iter().map(|import| {
+      imports.resolve_tag(&import.module, &import.field, &import.params)
+    }).collect::<Result<Vec<_>>>()?;
+    // Each instance has its own tags.
+    let tags = imported_tags.into_iter()
+      .chain(module.tags().iter().map(|params| Tag::new(params)))
+      .collect();
+    Ok(State {
+      module,
+      imports: funcs,
+      memories,
+      shared_imports,
+      tags,
+    })
+  }
+
+  pub fn module(&self) -> &Arc<Module> {
+    &self.module
+  }
+
+  /// Create a new isolated `Store` for this state.
+  pub fn new_store(&self) -> Result<Store> {
+    self.init_store(Store::default())
+  }
+
+  /// Instantiate the module in `store`, which can be configured first, like
+  /// with `Store::set_memory_limit`.
+  pub fn init_store(&self, mut store: Store) -> Result<Store> {
+    self.module.instantiate(&mut store, &self.memories, &self.shared_imports)?;
+    if let Some(start) = self.module.start_function() {
+      self.call(&mut store, start, &[])?;
     }
-    // Finished compile module.
-    self.module_instances.push(mod_inst);
-    self.modules.insert(name.to_string(), mod_addr);
-    Ok(mod_addr)
+    Ok(store)
   }
 
   pub fn get_function(&self, addr: FuncAddr) -> Trap<&Function> {
-    self.funcs.get(addr as usize).ok_or(TrapKind::InvalidFunctionAddr)
+    match self.imports.get(addr as usize) {
+      Some(func) => Ok(func),
+      None => self.module.get_function(addr).ok_or(TrapKind::InvalidFunctionAddr),
+    }
   }
 
-  fn get_module_instance(&self, module: &str) -> Result<&ModuleInstance> {
-    if let Some(addr) = self.modules.get(module) {
-      if let Some(inst) = self.module_instances.get(*addr as usize) {
-        return Ok(inst)
-      }
+  pub fn get_exported(&self, name: &str) -> Result<FuncAddr> {
+    self.module.find_function(name)
+  }
+
+  /// The maximum number of pages of a memory, from its type.
+  pub fn memory_max_pages(&self, idx: MemIdx) -> u32 {
+    match self.memories.get(idx as usize) {
+      Some(memory) => memory.limits().maximum()
+        .unwrap_or(if memory.is_64() { memory::MAX_PAGES_64 } else { memory::MAX_PAGES }),
+      None => memory::MAX_PAGES,
     }
-    Err(Error::ModuleNotFound)
   }
 
-  pub fn get_exported(&self, module: &str, name: &str) -> Result<FuncAddr> {
-    let mod_inst = self.get_module_instance(module)?;
-    mod_inst.find_function(name)
+  /// Get a tag by index, the index was checked by the validator.
+  pub(crate) fn tag(&self, idx: u32) -> &Tag {
+    &self.tags[idx as usize]
+  }
+
+  /// Get an exception tag by its index in the module's tag index space.
+  pub fn get_tag(&self, idx: u32) -> Option<&Tag> {
+    self.tags.get(idx as usize)
   }
 
-  pub fn invoke_function(&self, store: &mut Store, func_addr: FuncAddr, l0: &mut StackValue) -> Trap<Option<StackValue>> {
+  pub fn get_exported_tag(&self, name: &str) -> Result<Tag> {
+    let idx = self.module.find_tag(name)?;
+    self.get_tag(idx).cloned().ok_or(Error::TagNotFound)
+  }
+
+  pub fn invoke_function(&self, store: &mut Store, func_addr: FuncAddr, regs: &mut Registers) -> Trap<Option<StackValue>> {
     let func = self.get_function(func_addr)?;
-    func.call(self, store, l0)
+    func.call(self, store, regs)
   }
 
   pub fn call(&self, store: &mut Store, func_addr: FuncAddr, params: &[Value]) -> Result<RetValue> {
-    store.stack.push_params(params)?;
     let func = self.get_function(func_addr)?;
-    let mut l0 = StackValue::from(params[0]);
-    let ret = func.call(self, store, &mut l0)?;
-    if let Some(ret) = ret {
-      if let Some(ret_type) = func.ret_type() {
-        Ok(Some(match ret_type {
-          ValueType::I32 => Value::I32(ret.0 as _),
-          ValueType::I64 => Value::I64(ret.0 as _),
-          ValueType::F32 => Value::F32(f32::from_bits(ret.0 as _)),
-          ValueType::F64 => Value::F64(f64::from_bits(ret.0 as _)),
-        }))
-      } else {
-        Err(Error::RuntimeError(TrapKind::UnexpectedSignature))
-      }
+    let param_types = params.iter().map(|p| p.value_type());
+    if !param_types.eq(func.func_type.params.iter().copied()) {
+      return Err(Error::from(TrapKind::UnexpectedSignature));
+    }
+    store.take_backtrace();
+    let frame = store.stack.frame();
+    let depth = store.stack.call_depth();
+    let params = to_stack_values(params);
+    let len = store.stack.push_params(&params)?;
+    let mut regs = Registers::new(params.first().copied().unwrap_or(StackValue(0)));
+    let ret = if depth == 0 {
+      // Run the outermost call on a stack with room for the native stack
+      // limit, a new one if the thread's stack is too small.
+      let size = store.stack.native_stack_size();
+      stacker::maybe_grow(size, size, || func.call(self, store, &mut regs))
     } else {
-      Ok(None)
+      func.call(self, store, &mut regs)
+    };
+    // Drop any params left by a fast function call, or values left by a trap.
+    store.stack.unwind(len, frame, depth);
+    let ret = match ret {
+      Ok(ret) => ret,
+      Err(trap) => {
+        let mut backtrace = store.take_backtrace();
+        if let Some(name) = self.module.name() {
+          backtrace.set_module_name(name);
+        }
+        return Err(Error::RuntimeError(trap, backtrace));
+      },
+    };
+    match (ret, func.ret_type()) {
+      (Some(ret), Some(ret_type)) => Ok(Some(store.stack.ret_to_value(ret, ret_type))),
+      (None, None) => Ok(None),
+      _ => Err(Error::from(TrapKind::UnexpectedSignature)),
     }
   }
 }
 
 #[derive(Default)]
 pub struct VM {
-  // Mutable store
-  store: Store,
-
-  /// Immutable state.  Only mutable when loading modules.
-  state: State,
+  // Host functions
+  imports: Imports,
+  // Loaded modules
+  module_instances: Vec<ModuleInstance>,
+  modules: HashMap<String, ModuleInstanceAddr>,
 }
 
 impl VM {
@@ -126,17 +396,76 @@ impl VM {
     }
   }
 
+  /// Host functions available to modules loaded after they are added.
+  pub fn imports_mut(&mut self) -> &mut Imports {
+    &mut self.imports
+  }
+
+  /// Instantiate a compiled module.
+  pub fn add_module(&mut self, name: &str, module: Arc<Module>) -> Result<ModuleInstanceAddr> {
+    if self.modules.contains_key(name) {
+      return Err(Error::ModuleExists)
+    }
+    let mod_addr = self.module_instances.len() as ModuleInstanceAddr;
+    self.module_instances.push(ModuleInstance::with_imports(module, &self.imports)?);
+    self.modules.insert(name.to_string(), mod_addr);
+    Ok(mod_addr)
+  }
+
   pub fn load_file(&mut self, name: &str, file: &str) -> Result<ModuleInstanceAddr> {
-    self.state.load_file(name, file)
+    self.add_module(name, Arc::new(Module::from_file(file)?))
   }
 
-  pub fn get_exported(&self, module: &str, name: &str) -> Result<FuncAddr> {
-    self.state.get_exported(module, name)
+  pub fn load_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<ModuleInstanceAddr> {
+    self.add_module(name, Arc::new(Module::from_bytes(bytes)?))
+  }
+
+  pub fn load_reader<R: std::io::Read>(&mut self, name: &str, reader: R) -> Result<ModuleInstanceAddr> {
+    self.add_module(name, Arc::new(Module::from_reader(reader)?))
+  }
+
+  #[cfg(feature = "wat")]
+  pub fn load_wat(&mut self, name: &str, text: &str) -> Result<ModuleInstanceAddr> {
+    self.add_module(name, Arc::new(Module::from_wat(text)?))
+  }
+
+  /// Load an already parsed `parity_wasm` module, see
+  /// `loader::Module::from_parity`.
+  #[cfg(feature = "parity-wasm")]
+  pub fn load_parity(&mut self, name: &str, module: parity_wasm::elements::Module) -> Result<ModuleInstanceAddr> {
+    self.add_module(name, Arc::new(Module::from_parity(module)?))
+  }
+
+  pub fn get_module_instance(&mut self, module: &str) -> Result<&mut ModuleInstance> {
+    if let Some(addr) = self.modules.get(module) {
+      if let Some(inst) = self.module_instances.get_mut(*addr as usize) {
+        return Ok(inst)
+      }
+    }
+    Err(Error::ModuleNotFound)
+  }
+
+  pub fn get_exported(&mut self, module: &str, name: &str) -> Result<FuncAddr> {
+    self.get_module_instance(module)?.find_function(name)
+  }
+
+  pub fn get_exported_tag(&mut self, module: &str, name: &str) -> Result<Tag> {
+    self.get_module_instance(module)?.find_tag(name)
+  }
+
+  pub fn get_exported_memory(&mut self, module: &str, name: &str) -> Result<&mut [u8]> {
+    self.get_module_instance(module)?.memory_mut(name)
   }
 
   pub fn call(&mut self, module: &str, name: &str, params: &[Value]) -> Result<RetValue> {
-    let func_addr = self.state.get_exported(module, name)?;
-    self.state.call(&mut self.store, func_addr, params)
+    let mod_inst = self.get_module_instance(module)?;
+    let func_addr = mod_inst.find_function(name)?;
+    mod_inst.call(func_addr, params).map_err(|mut err| {
+      if let Error::RuntimeError(_, backtrace) = &mut err {
+        backtrace.set_module_name(module);
+      }
+      err
+    })
   }
 }
 
@@ -0,0 +1,710 @@
+//! WASI (preview1) host functions.
+//!
+//! Add the functions to the imports with `wasi::add_to_imports` and a
+//! `WasiCtx` to the store with `Store::insert_data`.  Only the host
+//! directories preopened in the `WasiCtx` can be accessed by the module.
+//!
+//! The `WasiCtx` is required: a WASI function called from a store without
+//! one traps with a `MissingWasiCtx` host error, no default context (with
+//! the host's stdio) is made for it.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::*;
+use crate::error::*;
+
+/// The import module name used by WASI preview1.
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+pub type Errno = u16;
+
+pub mod errno {
+  use super::Errno;
+
+  pub const SUCCESS: Errno = 0;
+  pub const ACCES: Errno = 2;
+  pub const BADF: Errno = 8;
+  pub const EXIST: Errno = 20;
+  pub const FAULT: Errno = 21;
+  pub const INVAL: Errno = 28;
+  pub const IO: Errno = 29;
+  pub const ISDIR: Errno = 31;
+  pub const LOOP: Errno = 32;
+  pub const NOENT: Errno = 44;
+  pub const NOSYS: Errno = 52;
+  pub const NOTDIR: Errno = 54;
+  pub const NOTEMPTY: Errno = 55;
+  pub const NOTSUP: Errno = 58;
+  pub const OVERFLOW: Errno = 61;
+  pub const PERM: Errno = 63;
+  pub const SPIPE: Errno = 70;
+  pub const NOTCAPABLE: Errno = 76;
+}
+
+type WasiResult<T = ()> = std::result::Result<T, Errno>;
+
+/// The host error of a WASI function called from a store without a
+/// `WasiCtx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingWasiCtx;
+
+impl std::fmt::Display for MissingWasiCtx {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "no WasiCtx in the store, add one with `Store::insert_data`")
+  }
+}
+
+impl std::error::Error for MissingWasiCtx {}
+
+mod filetype {
+  pub const UNKNOWN: u8 = 0;
+  pub const CHARACTER_DEVICE: u8 = 2;
+  pub const DIRECTORY: u8 = 3;
+  pub const REGULAR_FILE: u8 = 4;
+  pub const SYMBOLIC_LINK: u8 = 7;
+}
+
+mod oflags {
+  pub const CREAT: u32 = 1;
+  pub const DIRECTORY: u32 = 2;
+  pub const EXCL: u32 = 4;
+  pub const TRUNC: u32 = 8;
+}
+
+mod rights {
+  pub const FD_READ: u64 = 1 << 1;
+  pub const FD_WRITE: u64 = 1 << 6;
+}
+
+const FDFLAGS_APPEND: u32 = 1;
+
+/// Follow a symlink in the last component of a path.
+const LOOKUP_SYMLINK_FOLLOW: u32 = 1;
+
+/// Maximum number of symlinks expanded while resolving a path.
+const MAX_SYMLINKS: u32 = 32;
+
+fn io_errno(err: io::Error) -> Errno {
+  use io::ErrorKind::*;
+  #[cfg(unix)]
+  {
+    if err.raw_os_error() == Some(libc::ELOOP) {
+      return errno::LOOP;
+    }
+  }
+  match err.kind() {
+    NotFound => errno::NOENT,
+    PermissionDenied => errno::ACCES,
+    AlreadyExists => errno::EXIST,
+    InvalidInput => errno::INVAL,
+    NotADirectory => errno::NOTDIR,
+    IsADirectory => errno::ISDIR,
+    DirectoryNotEmpty => errno::NOTEMPTY,
+    _ => errno::IO,
+  }
+}
+
+/// An open file descriptor.
+enum Descriptor {
+  Reader(Box<dyn Read + Send + Sync>),
+  Writer(Box<dyn Write + Send + Sync>),
+  Dir(Dir),
+  File(fs::File),
+}
+
+/// A directory inside a preopened host directory.
+#[derive(Clone)]
+struct Dir {
+  /// The preopened host directory.  Nothing outside of it can be accessed.
+  root: PathBuf,
+  /// Path of this directory, relative to `root`.
+  path: PathBuf,
+  /// Name of the preopened directory, as seen by the module.
+  preopen: Option<String>,
+}
+
+/// Push the components of `path` onto `pending` in reverse order, `None`
+/// is a parent directory.  Absolute paths are not allowed.
+fn push_components(pending: &mut Vec<Option<OsString>>, path: &Path) -> WasiResult {
+  let mut components = vec![];
+  for component in path.components() {
+    match component {
+      Component::Normal(name) => components.push(Some(name.to_os_string())),
+      Component::CurDir => (),
+      Component::ParentDir => components.push(None),
+      Component::RootDir | Component::Prefix(_) => {
+        return Err(errno::NOTCAPABLE);
+      },
+    }
+  }
+  pending.extend(components.into_iter().rev());
+  Ok(())
+}
+
+impl Dir {
+  /// Resolve a path relative to this directory, without leaving the root.
+  ///
+  /// Symlinks are expanded one component at a time, so the returned path
+  /// has no symlinks that could lead out of the root.  A symlink in the
+  /// last component is only expanded with `follow`, otherwise the path of
+  /// the link itself is returned.
+  fn resolve(&self, path: &str, follow: bool) -> WasiResult<PathBuf> {
+    let mut resolved = self.path.clone();
+    let mut pending = vec![];
+    push_components(&mut pending, Path::new(path))?;
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+      let name = match component {
+        Some(name) => name,
+        None => {
+          if !resolved.pop() {
+            return Err(errno::NOTCAPABLE);
+          }
+          continue;
+        },
+      };
+      resolved.push(&name);
+      if !follow && pending.is_empty() {
+        break;
+      }
+      let host_path = self.root.join(&resolved);
+      // A missing entry can still be created.
+      let is_symlink = fs::symlink_metadata(&host_path)
+        .is_ok_and(|meta| meta.file_type().is_symlink());
+      if is_symlink {
+        links += 1;
+        if links > MAX_SYMLINKS {
+          return Err(errno::LOOP);
+        }
+        let target = fs::read_link(&host_path).map_err(io_errno)?;
+        resolved.pop();
+        push_components(&mut pending, &target)?;
+      }
+    }
+    Ok(self.root.join(resolved))
+  }
+}
+
+/// Check that the last component of a resolved path is not a symlink.
+fn no_symlink(path: &Path) -> WasiResult {
+  match fs::symlink_metadata(path) {
+    Ok(meta) if meta.file_type().is_symlink() => Err(errno::LOOP),
+    _ => Ok(()),
+  }
+}
+
+/// WASI state of a module instance.
+pub struct WasiCtx {
+  args: Vec<String>,
+  env: Vec<String>,
+  fds: Vec<Option<Descriptor>>,
+  start: Instant,
+}
+
+impl Default for WasiCtx {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl WasiCtx {
+  /// New context using the host's stdio, without any args, environment
+  /// variables or preopened directories.
+  pub fn new() -> WasiCtx {
+    WasiCtx {
+      args: vec![],
+      env: vec![],
+      fds: vec![
+        Some(Descriptor::Reader(Box::new(io::stdin()))),
+        Some(Descriptor::Writer(Box::new(io::stdout()))),
+        Some(Descriptor::Writer(Box::new(io::stderr()))),
+      ],
+      start: Instant::now(),
+    }
+  }
+
+  pub fn push_arg(&mut self, arg: &str) {
+    self.args.push(arg.to_string());
+  }
+
+  pub fn push_env(&mut self, key: &str, value: &str) {
+    self.env.push(format!("{}={}", key, value));
+  }
+
+  pub fn set_stdin(&mut self, stdin: Box<dyn Read + Send + Sync>) {
+    self.fds[0] = Some(Descriptor::Reader(stdin));
+  }
+
+  pub fn set_stdout(&mut self, stdout: Box<dyn Write + Send + Sync>) {
+    self.fds[1] = Some(Descriptor::Writer(stdout));
+  }
+
+  pub fn set_stderr(&mut self, stderr: Box<dyn Write + Send + Sync>) {
+    self.fds[2] = Some(Descriptor::Writer(stderr));
+  }
+
+  /// Give the module access to the host directory `host_path`, it will be
+  /// visible to the module as `guest_path`.
+  pub fn preopen_dir<P: AsRef<Path>>(&mut self, host_path: P, guest_path: &str) -> io::Result<()> {
+    let root = host_path.as_ref().canonicalize()?;
+    if !root.is_dir() {
+      return Err(io::Error::new(io::ErrorKind::NotADirectory, "preopen must be a directory"));
+    }
+    self.fds.push(Some(Descriptor::Dir(Dir {
+      root,
+      path: PathBuf::new(),
+      preopen: Some(guest_path.to_string()),
+    })));
+    Ok(())
+  }
+
+  fn get_fd(&mut self, fd: u32) -> WasiResult<&mut Descriptor> {
+    self.fds.get_mut(fd as usize)
+      .and_then(|fd| fd.as_mut())
+      .ok_or(errno::BADF)
+  }
+
+  fn get_dir(&mut self, fd: u32) -> WasiResult<&Dir> {
+    match self.get_fd(fd)? {
+      Descriptor::Dir(dir) => Ok(dir),
+      _ => Err(errno::NOTDIR),
+    }
+  }
+
+  fn add_fd(&mut self, desc: Descriptor) -> u32 {
+    if let Some(idx) = self.fds.iter().position(|fd| fd.is_none()) {
+      self.fds[idx] = Some(desc);
+      idx as u32
+    } else {
+      self.fds.push(Some(desc));
+      (self.fds.len() - 1) as u32
+    }
+  }
+}
+
+fn mem_slice(mem: &[u8], ptr: u32, len: u32) -> WasiResult<&[u8]> {
+  memory::slice(mem, ptr, len).map_err(|_| errno::FAULT)
+}
+
+fn mem_slice_mut(mem: &mut [u8], ptr: u32, len: u32) -> WasiResult<&mut [u8]> {
+  memory::slice_mut(mem, ptr, len).map_err(|_| errno::FAULT)
+}
+
+fn read_u32(mem: &[u8], ptr: u32) -> WasiResult<u32> {
+  let bytes = memory::load(mem, ptr as u64, 0).map_err(|_| errno::FAULT)?;
+  Ok(u32::from_le_bytes(bytes))
+}
+
+fn write_u32(mem: &mut [u8], ptr: u32, val: u32) -> WasiResult {
+  memory::store(mem, ptr as u64, 0, val.to_le_bytes()).map_err(|_| errno::FAULT)
+}
+
+fn write_u64(mem: &mut [u8], ptr: u32, val: u64) -> WasiResult {
+  memory::store(mem, ptr as u64, 0, val.to_le_bytes()).map_err(|_| errno::FAULT)
+}
+
+fn read_str(mem: &[u8], ptr: u32, len: u32) -> WasiResult<String> {
+  let bytes = mem_slice(mem, ptr, len)?;
+  String::from_utf8(bytes.to_vec()).map_err(|_| errno::INVAL)
+}
+
+/// Get the list of `(ptr, len)` buffers of an iovec array.
+fn read_iovs(mem: &[u8], iovs: u32, iovs_len: u32) -> WasiResult<Vec<(u32, u32)>> {
+  (0..iovs_len).map(|idx| {
+    let iov = idx.checked_mul(8).and_then(|off| iovs.checked_add(off)).ok_or(errno::FAULT)?;
+    Ok((read_u32(mem, iov)?, read_u32(mem, iov.checked_add(4).ok_or(errno::FAULT)?)?))
+  }).collect()
+}
+
+/// Write a list of strings (args or environment) and the pointers to them.
+fn write_strings(mem: &mut [u8], strings: &[String], ptrs: u32, buf: u32) -> WasiResult {
+  let mut offset = buf;
+  for (idx, string) in strings.iter().enumerate() {
+    let ptr = u32::try_from(idx).ok()
+      .and_then(|idx| idx.checked_mul(4))
+      .and_then(|off| ptrs.checked_add(off))
+      .ok_or(errno::FAULT)?;
+    write_u32(mem, ptr, offset)?;
+    let len = str_size(string)?;
+    let dst = mem_slice_mut(mem, offset, len)?;
+    dst[..string.len()].copy_from_slice(string.as_bytes());
+    dst[string.len()] = 0;
+    offset = offset.checked_add(len).ok_or(errno::FAULT)?;
+  }
+  Ok(())
+}
+
+/// The size of a string with its nul terminator.
+fn str_size(string: &str) -> WasiResult<u32> {
+  u32::try_from(string.len()).ok()
+    .and_then(|len| len.checked_add(1))
+    .ok_or(errno::OVERFLOW)
+}
+
+fn write_sizes(mem: &mut [u8], strings: &[String], count_ptr: u32, size_ptr: u32) -> WasiResult {
+  let size = strings.iter().try_fold(0u32, |size, s| {
+    size.checked_add(str_size(s)?).ok_or(errno::OVERFLOW)
+  })?;
+  let count = u32::try_from(strings.len()).map_err(|_| errno::OVERFLOW)?;
+  write_u32(mem, count_ptr, count)?;
+  write_u32(mem, size_ptr, size)
+}
+
+fn filestat_type(meta: &fs::Metadata) -> u8 {
+  let file_type = meta.file_type();
+  if file_type.is_dir() {
+    filetype::DIRECTORY
+  } else if file_type.is_file() {
+    filetype::REGULAR_FILE
+  } else if file_type.is_symlink() {
+    filetype::SYMBOLIC_LINK
+  } else {
+    filetype::UNKNOWN
+  }
+}
+
+fn nanos(time: io::Result<SystemTime>) -> u64 {
+  time.ok()
+    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+    .map(|time| time.as_nanos() as u64)
+    .unwrap_or(0)
+}
+
+fn write_filestat(mem: &mut [u8], buf: u32, meta: &fs::Metadata) -> WasiResult {
+  let stat = mem_slice_mut(mem, buf, 64)?;
+  stat.fill(0);
+  stat[16] = filestat_type(meta);
+  stat[24..32].copy_from_slice(&1u64.to_le_bytes());
+  stat[32..40].copy_from_slice(&meta.len().to_le_bytes());
+  stat[40..48].copy_from_slice(&nanos(meta.accessed()).to_le_bytes());
+  stat[48..56].copy_from_slice(&nanos(meta.modified()).to_le_bytes());
+  stat[56..64].copy_from_slice(&nanos(meta.modified()).to_le_bytes());
+  Ok(())
+}
+
+fn arg_u32(args: &[Value], idx: usize) -> WasiResult<u32> {
+  match args.get(idx) {
+    Some(Value::I32(val)) => Ok(*val as u32),
+    _ => Err(errno::INVAL),
+  }
+}
+
+fn arg_u64(args: &[Value], idx: usize) -> WasiResult<u64> {
+  match args.get(idx) {
+    Some(Value::I64(val)) => Ok(*val as u64),
+    _ => Err(errno::INVAL),
+  }
+}
+
+type WasiFunc = fn(&mut WasiCtx, &mut [u8], &[Value]) -> WasiResult;
+
+fn args_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  write_strings(mem, &ctx.args, arg_u32(args, 0)?, arg_u32(args, 1)?)
+}
+
+fn args_sizes_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  write_sizes(mem, &ctx.args, arg_u32(args, 0)?, arg_u32(args, 1)?)
+}
+
+fn environ_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  write_strings(mem, &ctx.env, arg_u32(args, 0)?, arg_u32(args, 1)?)
+}
+
+fn environ_sizes_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  write_sizes(mem, &ctx.env, arg_u32(args, 0)?, arg_u32(args, 1)?)
+}
+
+fn clock_res_get(_ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  match arg_u32(args, 0)? {
+    0..=3 => write_u64(mem, arg_u32(args, 1)?, 1_000),
+    _ => Err(errno::INVAL),
+  }
+}
+
+fn clock_time_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let time = match arg_u32(args, 0)? {
+    // Realtime
+    0 => nanos(Ok(SystemTime::now())),
+    // Monotonic, process and thread cputime.
+    1..=3 => ctx.start.elapsed().as_nanos() as u64,
+    _ => return Err(errno::INVAL),
+  };
+  write_u64(mem, arg_u32(args, 2)?, time)
+}
+
+fn random_get(_ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let buf = mem_slice_mut(mem, arg_u32(args, 0)?, arg_u32(args, 1)?)?;
+  getrandom::getrandom(buf).map_err(|_| errno::IO)
+}
+
+fn fd_write(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let iovs = read_iovs(mem, arg_u32(args, 1)?, arg_u32(args, 2)?)?;
+  let writer: &mut dyn Write = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::Writer(writer) => writer,
+    Descriptor::File(file) => file,
+    _ => return Err(errno::BADF),
+  };
+  let mut written = 0u32;
+  for (ptr, len) in iovs {
+    writer.write_all(mem_slice(mem, ptr, len)?).map_err(io_errno)?;
+    written = written.checked_add(len).ok_or(errno::OVERFLOW)?;
+  }
+  writer.flush().map_err(io_errno)?;
+  write_u32(mem, arg_u32(args, 3)?, written)
+}
+
+fn fd_read(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let iovs = read_iovs(mem, arg_u32(args, 1)?, arg_u32(args, 2)?)?;
+  let reader: &mut dyn Read = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::Reader(reader) => reader,
+    Descriptor::File(file) => file,
+    _ => return Err(errno::BADF),
+  };
+  let mut read = 0u32;
+  for (ptr, len) in iovs {
+    let buf = mem_slice_mut(mem, ptr, len)?;
+    let n = reader.read(buf).map_err(io_errno)?;
+    read = read.checked_add(n as u32).ok_or(errno::OVERFLOW)?;
+    if n < buf.len() {
+      break;
+    }
+  }
+  write_u32(mem, arg_u32(args, 3)?, read)
+}
+
+fn fd_close(ctx: &mut WasiCtx, _mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let fd = arg_u32(args, 0)?;
+  ctx.get_fd(fd)?;
+  ctx.fds[fd as usize] = None;
+  Ok(())
+}
+
+fn fd_seek(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let offset = arg_u64(args, 1)? as i64;
+  let pos = match arg_u32(args, 2)? {
+    0 => SeekFrom::Start(offset as u64),
+    1 => SeekFrom::Current(offset),
+    2 => SeekFrom::End(offset),
+    _ => return Err(errno::INVAL),
+  };
+  let pos = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::File(file) => file.seek(pos).map_err(io_errno)?,
+    Descriptor::Dir(_) => return Err(errno::BADF),
+    _ => return Err(errno::SPIPE),
+  };
+  write_u64(mem, arg_u32(args, 3)?, pos)
+}
+
+fn fd_tell(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let pos = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::File(file) => file.stream_position().map_err(io_errno)?,
+    Descriptor::Dir(_) => return Err(errno::BADF),
+    _ => return Err(errno::SPIPE),
+  };
+  write_u64(mem, arg_u32(args, 1)?, pos)
+}
+
+fn fd_fdstat_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let file_type = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::Reader(_) | Descriptor::Writer(_) => filetype::CHARACTER_DEVICE,
+    Descriptor::Dir(_) => filetype::DIRECTORY,
+    Descriptor::File(_) => filetype::REGULAR_FILE,
+  };
+  let stat = mem_slice_mut(mem, arg_u32(args, 1)?, 24)?;
+  stat.fill(0);
+  stat[0] = file_type;
+  // All rights.
+  stat[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+  stat[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+  Ok(())
+}
+
+fn fd_fdstat_set_flags(ctx: &mut WasiCtx, _mem: &mut [u8], args: &[Value]) -> WasiResult {
+  ctx.get_fd(arg_u32(args, 0)?)?;
+  match arg_u32(args, 1)? {
+    0 => Ok(()),
+    _ => Err(errno::NOTSUP),
+  }
+}
+
+fn fd_filestat_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let meta = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::File(file) => file.metadata().map_err(io_errno)?,
+    Descriptor::Dir(dir) => dir.root.join(&dir.path).metadata().map_err(io_errno)?,
+    _ => {
+      let stat = mem_slice_mut(mem, arg_u32(args, 1)?, 64)?;
+      stat.fill(0);
+      stat[16] = filetype::CHARACTER_DEVICE;
+      return Ok(());
+    },
+  };
+  write_filestat(mem, arg_u32(args, 1)?, &meta)
+}
+
+fn fd_prestat_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let name = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::Dir(Dir { preopen: Some(name), .. }) => name,
+    _ => return Err(errno::BADF),
+  };
+  let len = name.len() as u32;
+  let ptr = arg_u32(args, 1)?;
+  // Tag 0: directory.
+  write_u32(mem, ptr, 0)?;
+  write_u32(mem, ptr.checked_add(4).ok_or(errno::FAULT)?, len)
+}
+
+fn fd_prestat_dir_name(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let name = match ctx.get_fd(arg_u32(args, 0)?)? {
+    Descriptor::Dir(Dir { preopen: Some(name), .. }) => name,
+    _ => return Err(errno::BADF),
+  };
+  let len = arg_u32(args, 2)?;
+  if (len as usize) < name.len() {
+    return Err(errno::INVAL);
+  }
+  let buf = mem_slice_mut(mem, arg_u32(args, 1)?, name.len() as u32)?;
+  buf.copy_from_slice(name.as_bytes());
+  Ok(())
+}
+
+fn path_open(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let dir = ctx.get_dir(arg_u32(args, 0)?)?.clone();
+  let follow = arg_u32(args, 1)? & LOOKUP_SYMLINK_FOLLOW != 0;
+  let path = read_str(mem, arg_u32(args, 2)?, arg_u32(args, 3)?)?;
+  let oflags = arg_u32(args, 4)?;
+  let rights = arg_u64(args, 5)?;
+  let fdflags = arg_u32(args, 7)?;
+  let host_path = dir.resolve(&path, follow)?;
+  // Opening a path follows symlinks.
+  no_symlink(&host_path)?;
+
+  let desc = if oflags & oflags::DIRECTORY != 0 || host_path.is_dir() {
+    if !host_path.is_dir() {
+      return Err(errno::NOTDIR);
+    }
+    let path = host_path.strip_prefix(&dir.root)
+      .map_err(|_| errno::NOTCAPABLE)?
+      .to_path_buf();
+    Descriptor::Dir(Dir {
+      root: dir.root,
+      path,
+      preopen: None,
+    })
+  } else {
+    let write = rights & rights::FD_WRITE != 0;
+    let mut options = fs::OpenOptions::new();
+    options.read(rights & rights::FD_READ != 0 || !write)
+      .write(write)
+      .append(fdflags & FDFLAGS_APPEND != 0)
+      .create(oflags & oflags::CREAT != 0)
+      .create_new(oflags & oflags::EXCL != 0)
+      .truncate(oflags & oflags::TRUNC != 0);
+    // Don't follow a symlink created after the path was resolved.
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.custom_flags(libc::O_NOFOLLOW);
+    }
+    let file = options.open(&host_path).map_err(io_errno)?;
+    Descriptor::File(file)
+  };
+  let fd = ctx.add_fd(desc);
+  write_u32(mem, arg_u32(args, 8)?, fd)
+}
+
+fn path_filestat_get(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let dir = ctx.get_dir(arg_u32(args, 0)?)?;
+  let follow = arg_u32(args, 1)? & LOOKUP_SYMLINK_FOLLOW != 0;
+  let path = read_str(mem, arg_u32(args, 2)?, arg_u32(args, 3)?)?;
+  // A resolved path only ends with a symlink when it isn't followed.
+  let meta = fs::symlink_metadata(dir.resolve(&path, follow)?).map_err(io_errno)?;
+  write_filestat(mem, arg_u32(args, 4)?, &meta)
+}
+
+fn path_create_directory(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let dir = ctx.get_dir(arg_u32(args, 0)?)?;
+  let path = read_str(mem, arg_u32(args, 1)?, arg_u32(args, 2)?)?;
+  fs::create_dir(dir.resolve(&path, false)?).map_err(io_errno)
+}
+
+fn path_remove_directory(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let dir = ctx.get_dir(arg_u32(args, 0)?)?;
+  let path = read_str(mem, arg_u32(args, 1)?, arg_u32(args, 2)?)?;
+  fs::remove_dir(dir.resolve(&path, false)?).map_err(io_errno)
+}
+
+fn path_unlink_file(ctx: &mut WasiCtx, mem: &mut [u8], args: &[Value]) -> WasiResult {
+  let dir = ctx.get_dir(arg_u32(args, 0)?)?;
+  let path = read_str(mem, arg_u32(args, 1)?, arg_u32(args, 2)?)?;
+  fs::remove_file(dir.resolve(&path, false)?).map_err(io_errno)
+}
+
+fn not_supported(_ctx: &mut WasiCtx, _mem: &mut [u8], _args: &[Value]) -> WasiResult {
+  Err(errno::NOSYS)
+}
+
+fn sched_yield(_ctx: &mut WasiCtx, _mem: &mut [u8], _args: &[Value]) -> WasiResult {
+  std::thread::yield_now();
+  Ok(())
+}
+
+/// Add a WASI function that returns an errno.  It traps if the store has
+/// no `WasiCtx`.
+fn add_function(imports: &mut Imports, name: &str, params: &[ValueType], func: WasiFunc) {
+  imports.add_function(MODULE, name, params, Some(ValueType::I32), move |store, args| {
+    if store.get_data::<WasiCtx>().is_none() {
+      return Err(TrapKind::host(MissingWasiCtx));
+    }
+    let (ctx, mem) = store.data_and_mem_mut::<WasiCtx>()
+      .ok_or(TrapKind::UnexpectedSignature)?;
+    let errno = match func(ctx, mem, args) {
+      Ok(()) => errno::SUCCESS,
+      Err(errno) => errno,
+    };
+    Ok(Some(Value::I32(errno as i32)))
+  });
+}
+
+/// Add the WASI functions to `imports`.  The store of the module that
+/// imports them needs a `WasiCtx`, see `Store::insert_data`.
+pub fn add_to_imports(imports: &mut Imports) {
+  use ValueType::*;
+  add_function(imports, "args_get", &[I32, I32], args_get);
+  add_function(imports, "args_sizes_get", &[I32, I32], args_sizes_get);
+  add_function(imports, "environ_get", &[I32, I32], environ_get);
+  add_function(imports, "environ_sizes_get", &[I32, I32], environ_sizes_get);
+  add_function(imports, "clock_res_get", &[I32, I32], clock_res_get);
+  add_function(imports, "clock_time_get", &[I32, I64, I32], clock_time_get);
+  add_function(imports, "random_get", &[I32, I32], random_get);
+  add_function(imports, "fd_write", &[I32, I32, I32, I32], fd_write);
+  add_function(imports, "fd_read", &[I32, I32, I32, I32], fd_read);
+  add_function(imports, "fd_close", &[I32], fd_close);
+  add_function(imports, "fd_seek", &[I32, I64, I32, I32], fd_seek);
+  add_function(imports, "fd_tell", &[I32, I32], fd_tell);
+  add_function(imports, "fd_fdstat_get", &[I32, I32], fd_fdstat_get);
+  add_function(imports, "fd_fdstat_set_flags", &[I32, I32], fd_fdstat_set_flags);
+  add_function(imports, "fd_filestat_get", &[I32, I32], fd_filestat_get);
+  add_function(imports, "fd_prestat_get", &[I32, I32], fd_prestat_get);
+  add_function(imports, "fd_prestat_dir_name", &[I32, I32, I32], fd_prestat_dir_name);
+  add_function(imports, "fd_readdir", &[I32, I32, I32, I64, I32], not_supported);
+  add_function(imports, "path_open", &[I32, I32, I32, I32, I32, I64, I64, I32, I32], path_open);
+  add_function(imports, "path_filestat_get", &[I32, I32, I32, I32, I32], path_filestat_get);
+  add_function(imports, "path_create_directory", &[I32, I32, I32], path_create_directory);
+  add_function(imports, "path_remove_directory", &[I32, I32, I32], path_remove_directory);
+  add_function(imports, "path_unlink_file", &[I32, I32, I32], path_unlink_file);
+  add_function(imports, "poll_oneoff", &[I32, I32, I32, I32], not_supported);
+  add_function(imports, "proc_raise", &[I32], not_supported);
+  add_function(imports, "sched_yield", &[], sched_yield);
+  imports.add_function(MODULE, "proc_exit", &[I32], None, |_store, args| {
+    let code = match args.first() {
+      Some(Value::I32(code)) => *code,
+      _ => 0,
+    };
+    Err(TrapKind::Exit(code))
+  });
+}